@@ -3,11 +3,20 @@ use bytes::{Buf, BufMut, BytesMut};
 use log::{info, trace};
 use std::collections::HashMap;
 
-use crate::config::{get_config, reload_config, VERSION};
+use crate::auth_passthrough;
+use crate::config::{get_config, reload_config, RangeBound, Role, ShardingRange, VERSION};
+use crate::db_connections;
+use crate::drain;
+use crate::error_response::*;
 use crate::errors::Error;
 use crate::messages::*;
-use crate::pool::get_all_pools;
+use crate::pool::{get_all_pools, ConnectionPool, PoolSettings};
+use crate::replica_lag;
+use crate::resharding::{self, Phase, PhaseError};
+use crate::sharding::Sharder;
+use crate::sharding_directory;
 use crate::stats::get_stats;
+use crate::tls;
 use crate::ClientServerMap;
 
 pub fn generate_server_info_for_admin() -> BytesMut {
@@ -38,9 +47,8 @@ where
     }
 
     let len = query.get_i32() as usize;
-    let query = String::from_utf8_lossy(&query[..len - 5])
-        .to_string()
-        .to_ascii_uppercase();
+    let original = String::from_utf8_lossy(&query[..len - 5]).to_string();
+    let query = original.to_ascii_uppercase();
 
     trace!("Admin query: {}", query);
 
@@ -65,11 +73,39 @@ where
     } else if query.starts_with("SHOW VERSION") {
         trace!("SHOW VERSION");
         show_version(stream).await
+    } else if query.starts_with("FLUSH AUTH CACHE") {
+        trace!("FLUSH AUTH CACHE");
+        flush_auth_cache(stream).await
+    } else if query.starts_with("SET POOL_SIZE") {
+        trace!("SET POOL_SIZE");
+        set_pool_size(stream, &original, client_server_map).await
+    } else if query.starts_with("DRAIN SERVER") {
+        trace!("DRAIN SERVER");
+        drain_server(stream, &original).await
+    } else if query.starts_with("RESUME SERVER") {
+        trace!("RESUME SERVER");
+        resume_server(stream, &original).await
+    } else if query.starts_with("SET RESHARDING_PHASE") {
+        trace!("SET RESHARDING_PHASE");
+        set_resharding_phase(stream, &original).await
+    } else if query.starts_with("SHOW SHARDS") {
+        trace!("SHOW SHARDS");
+        show_shards(stream).await
+    } else if query.starts_with("SHOW RESHARDING") {
+        trace!("SHOW RESHARDING");
+        show_resharding(stream).await
     } else if query.starts_with("SET ") {
         trace!("SET");
         ignore_set(stream).await
     } else {
-        error_response(stream, "Unsupported query against the admin database").await
+        error_response(
+            stream,
+            &ErrorResponse::error(
+                FEATURE_NOT_SUPPORTED,
+                "Unsupported query against the admin database",
+            ),
+        )
+        .await
     }
 }
 
@@ -132,6 +168,15 @@ where
             .sum::<i64>()
             .to_string(),
     ]));
+    let (full_handshakes, resumed_handshakes) = tls::handshakes();
+    res.put(data_row(&vec![
+        "tls_full_handshakes".to_string(),
+        full_handshakes.to_string(),
+    ]));
+    res.put(data_row(&vec![
+        "tls_resumed_handshakes".to_string(),
+        resumed_handshakes.to_string(),
+    ]));
     res.put(data_row(&vec!["dns_names".to_string(), "0".to_string()]));
     res.put(data_row(&vec!["dns_zones".to_string(), "0".to_string()]));
     res.put(data_row(&vec!["dns_queries".to_string(), "0".to_string()]));
@@ -177,6 +222,7 @@ where
         ("cl_active", DataType::Numeric),
         ("cl_waiting", DataType::Numeric),
         ("cl_cancel_req", DataType::Numeric),
+        ("cl_pinned", DataType::Numeric),
         ("sv_active", DataType::Numeric),
         ("sv_idle", DataType::Numeric),
         ("sv_used", DataType::Numeric),
@@ -242,6 +288,7 @@ where
         ("current_connections", DataType::Int4),
         ("paused", DataType::Int4),
         ("disabled", DataType::Int4),
+        ("replica_lag", DataType::Text),
     ];
 
     let mut res = BytesMut::new();
@@ -256,21 +303,38 @@ where
             for server in 0..pool.servers(shard) {
                 let address = pool.address(shard, server);
                 let pool_state = pool.pool_state(shard, server);
+                let max_connections = pool.pool_size(shard, server) + pool_config.reserve_pool_size;
+                let current_connections =
+                    pool_state.connections + pool.reserve_connections(shard, server);
+
+                // Measured for the pools with max_replica_lag.
+                let replica_lag = match address.role {
+                    Role::Replica => {
+                        replica_lag::get(&db_connections::server(&address.host, &address.port))
+                            .map(|lag| format!("{}ms", lag))
+                            .unwrap_or_default()
+                    }
+                    Role::Primary => String::new(),
+                };
+
+                let disabled =
+                    drain::is_drained(&db_connections::server(&address.host, &address.port)) as u8;
 
                 res.put(data_row(&vec![
-                    address.name(),                         // name
-                    address.host.to_string(),               // host
-                    address.port.to_string(),               // port
-                    database_name.to_string(),              // database
-                    pool_config.user.username.to_string(),  // force_user
-                    pool_config.user.pool_size.to_string(), // pool_size
-                    "0".to_string(),                        // min_pool_size
-                    "0".to_string(),                        // reserve_pool
-                    pool_config.pool_mode.to_string(),      // pool_mode
-                    pool_config.user.pool_size.to_string(), // max_connections
-                    pool_state.connections.to_string(),     // current_connections
-                    "0".to_string(),                        // paused
-                    "0".to_string(),                        // disabled
+                    address.name(),                            // name
+                    address.host.to_string(),                  // host
+                    address.port.to_string(),                  // port
+                    database_name.to_string(),                 // database
+                    pool_config.user.username.to_string(),     // force_user
+                    pool.pool_size(shard, server).to_string(), // pool_size
+                    pool_config.min_pool_size.to_string(),     // min_pool_size
+                    pool_config.reserve_pool_size.to_string(), // reserve_pool
+                    pool_config.pool_mode.to_string(),         // pool_mode
+                    max_connections.to_string(),               // max_connections
+                    current_connections.to_string(),           // current_connections
+                    "0".to_string(),                           // paused
+                    disabled.to_string(),                      // disabled
+                    replica_lag,                               // replica_lag
                 ]));
             }
         }
@@ -285,6 +349,175 @@ where
     write_all_half(stream, res).await
 }
 
+/// The shards of each pool: their servers, the ones banned or drained, and the keys they
+/// get, i.e. where the query router sends the sharding keys.
+async fn show_shards<T>(stream: &mut T) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let columns = vec![
+        ("database", DataType::Text),
+        ("user", DataType::Text),
+        ("shard", DataType::Int4),
+        ("primary", DataType::Text),
+        ("replicas", DataType::Text),
+        ("banned", DataType::Text),
+        ("disabled", DataType::Text),
+        ("sharding", DataType::Text),
+        ("keys", DataType::Text),
+        ("resharding_phase", DataType::Text),
+        ("old_keys", DataType::Text),
+    ];
+
+    let pools = get_all_pools();
+    let mut identifiers = pools.keys().collect::<Vec<_>>();
+    identifiers.sort();
+
+    let mut res = BytesMut::new();
+    res.put(row_description(&columns));
+
+    for identifier in identifiers {
+        let pool = &pools[identifier];
+        let settings = &pool.settings;
+        let (pool_name, user) = identifier;
+
+        let sharding = if settings.sharding_directory {
+            String::from("directory")
+        } else if !settings.sharding_ranges.is_empty() {
+            String::from("ranges")
+        } else {
+            match settings.sharding_seed {
+                Some(seed) => format!(
+                    "{} {}, seed {}",
+                    settings.sharding_function, settings.sharding_mode, seed
+                ),
+                None => format!("{} {}", settings.sharding_function, settings.sharding_mode),
+            }
+        };
+
+        for shard in 0..pool.shards() {
+            let servers = (0..pool.servers(shard))
+                .map(|server| pool.address(shard, server))
+                .collect::<Vec<_>>();
+
+            let names = |role: Role| {
+                servers
+                    .iter()
+                    .filter(|address| address.role == role)
+                    .map(|address| db_connections::server(&address.host, &address.port))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+
+            let banned = pool
+                .banned(shard)
+                .iter()
+                .map(|address| db_connections::server(&address.host, &address.port))
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            let disabled = servers
+                .iter()
+                .map(|address| db_connections::server(&address.host, &address.port))
+                .filter(|server| drain::is_drained(server))
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            let keys = if settings.sharding_directory {
+                format!(
+                    "{} keys in the directory",
+                    sharding_directory::keys(pool_name, shard)
+                )
+            } else {
+                shard_keys(settings, &settings.sharding_ranges, pool.shards(), shard)
+            };
+
+            let (resharding_phase, old_keys) = match settings.resharding {
+                Some(ref resharding) => (
+                    resharding::phase(pool_name, resharding).to_string(),
+                    shard_keys(
+                        settings,
+                        &resharding.from_ranges,
+                        resharding.from_shards.unwrap_or_default(),
+                        shard,
+                    ),
+                ),
+                None => (String::new(), String::new()),
+            };
+
+            res.put(data_row(&vec![
+                pool_name.clone(),
+                user.clone(),
+                shard.to_string(),
+                names(Role::Primary),
+                names(Role::Replica),
+                banned,
+                disabled,
+                sharding.clone(),
+                keys,
+                resharding_phase,
+                old_keys,
+            ]));
+        }
+    }
+
+    res.put(command_complete("SHOW"));
+
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, res).await
+}
+
+/// The keys of the shard: its ranges, e.g. `1000..2000`, or its hashes with the pool's
+/// sharding function over that many shards, e.g. `hash % 4 = 1`. Empty if it gets none.
+fn shard_keys(
+    settings: &PoolSettings,
+    ranges: &[ShardingRange],
+    shards: usize,
+    shard: usize,
+) -> String {
+    let bound = |bound: &Option<RangeBound>| match bound {
+        Some(RangeBound::Integer(bound)) => bound.to_string(),
+        Some(RangeBound::Text(bound)) => bound.clone(),
+        None => String::new(),
+    };
+
+    if !ranges.is_empty() {
+        return ranges
+            .iter()
+            .filter(|range| range.shard == shard)
+            .map(|range| format!("{}..{}", bound(&range.from), bound(&range.to)))
+            .collect::<Vec<_>>()
+            .join(", ");
+    }
+
+    if shard >= shards {
+        return String::new();
+    }
+
+    match settings.sharding_mode.as_ref() {
+        "range" => {
+            let (from, to) = Sharder::from_settings(
+                shards,
+                &settings.sharding_function,
+                &settings.sharding_mode,
+                settings.sharding_seed,
+            )
+            .hash_range(shard);
+
+            format!(
+                "hash {}..{}",
+                from,
+                to.map(|to| to.to_string()).unwrap_or_default()
+            )
+        }
+
+        _ => format!("hash % {} = {}", shards, shard),
+    }
+}
+
 /// Ignore any SET commands the client sends.
 /// This is common initialization done by ORMs.
 async fn ignore_set<T>(stream: &mut T) -> Result<(), Error>
@@ -294,6 +527,330 @@ where
     custom_protocol_response_ok(stream, "SET").await
 }
 
+/// Resize a pool without a reload, with `SET pool_size = 40 FOR POOL mydb`.
+async fn set_pool_size<T>(
+    stream: &mut T,
+    query: &str,
+    client_server_map: ClientServerMap,
+) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let query = query.trim().trim_end_matches(';').replace('=', " = ");
+    let words = query.split_whitespace().collect::<Vec<&str>>();
+
+    let (pool_size, pool_name) = match words[..] {
+        [_set, _pool_size, to, pool_size, for_, pool, pool_name]
+            if (to == "=" || to.eq_ignore_ascii_case("TO"))
+                && for_.eq_ignore_ascii_case("FOR")
+                && pool.eq_ignore_ascii_case("POOL") =>
+        {
+            (pool_size.parse::<u32>().ok(), pool_name)
+        }
+
+        _ => {
+            return error_response(
+                stream,
+                &ErrorResponse::error(
+                    PROTOCOL_VIOLATION,
+                    "Usage: SET pool_size = <size> FOR POOL <database>",
+                ),
+            )
+            .await
+        }
+    };
+
+    let config = get_config();
+    let pool_config = match config.pools.get(pool_name) {
+        Some(pool_config) => pool_config,
+        None => {
+            return error_response(
+                stream,
+                &ErrorResponse::error(
+                    INVALID_CATALOG_NAME,
+                    &format!("No pool named {}", pool_name),
+                ),
+            )
+            .await
+        }
+    };
+
+    let pool_size = match pool_size {
+        Some(pool_size) if pool_size > 0 && pool_size >= pool_config.min_pool_size => pool_size,
+        _ => {
+            return error_response(
+                stream,
+                &ErrorResponse::error(
+                    INVALID_PARAMETER_VALUE,
+                    &format!(
+                        "pool_size must be at least 1 and the min_pool_size of pool {} ({})",
+                        pool_name, pool_config.min_pool_size
+                    ),
+                ),
+            )
+            .await
+        }
+    };
+
+    if let Err(err) = ConnectionPool::resize(pool_name, pool_size, client_server_map).await {
+        return error_response(
+            stream,
+            &ErrorResponse::error(
+                CONNECTION_FAILURE,
+                &format!("Could not resize pool {}: {:?}", pool_name, err),
+            )
+            .hint("The pool keeps its size."),
+        )
+        .await;
+    }
+
+    custom_protocol_response_ok(stream, "SET").await
+}
+
+/// Stop routing transactions to a server, with `DRAIN SERVER 10.0.0.2:5432`, and reply
+/// once all its connections are released and closed, or with the ones still in use
+/// after drain_timeout.
+async fn drain_server<T>(stream: &mut T, query: &str) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let server = match server_argument(query) {
+        Some(server) => server,
+        None => {
+            return error_response(
+                stream,
+                &ErrorResponse::error(PROTOCOL_VIOLATION, "Usage: DRAIN SERVER <host:port>"),
+            )
+            .await
+        }
+    };
+
+    match drain::drain(&server).await {
+        drain::Drain::Done => custom_protocol_response_ok(stream, "DRAIN").await,
+
+        drain::Drain::Unknown => {
+            error_response(
+                stream,
+                &ErrorResponse::error(
+                    INVALID_PARAMETER_VALUE,
+                    &format!("No pool has server {}", server),
+                ),
+            )
+            .await
+        }
+
+        drain::Drain::Busy(busy) => {
+            error_response(
+                stream,
+                &ErrorResponse::error(
+                    OBJECT_IN_USE,
+                    &format!("Server {} is still busy after the drain timeout", server),
+                )
+                .detail(&busy.join("; "))
+                .hint("The server stays drained. Run DRAIN SERVER again to keep waiting."),
+            )
+            .await
+        }
+    }
+}
+
+/// Route transactions to a drained server again, with `RESUME SERVER 10.0.0.2:5432`.
+async fn resume_server<T>(stream: &mut T, query: &str) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let server = match server_argument(query) {
+        Some(server) => server,
+        None => {
+            return error_response(
+                stream,
+                &ErrorResponse::error(PROTOCOL_VIOLATION, "Usage: RESUME SERVER <host:port>"),
+            )
+            .await
+        }
+    };
+
+    if !drain::resume(&server) {
+        return error_response(
+            stream,
+            &ErrorResponse::error(
+                INVALID_PARAMETER_VALUE,
+                &format!("Server {} isn't drained", server),
+            ),
+        )
+        .await;
+    }
+
+    custom_protocol_response_ok(stream, "RESUME").await
+}
+
+/// Move the keys of a pool's resharding to the next phase, or back, with
+/// `SET resharding_phase = 'dual_write' FOR POOL mydb`.
+async fn set_resharding_phase<T>(stream: &mut T, query: &str) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let query = query.trim().trim_end_matches(';').replace('=', " = ");
+    let words = query.split_whitespace().collect::<Vec<&str>>();
+
+    let (phase, pool_name) = match words[..] {
+        [_set, _resharding_phase, to, phase, for_, pool, pool_name]
+            if (to == "=" || to.eq_ignore_ascii_case("TO"))
+                && for_.eq_ignore_ascii_case("FOR")
+                && pool.eq_ignore_ascii_case("POOL") =>
+        {
+            (Phase::parse(phase.trim_matches('\'')), pool_name)
+        }
+
+        _ => (None, ""),
+    };
+
+    let phase = match phase {
+        Some(phase) => phase,
+        None => {
+            return error_response(
+                stream,
+                &ErrorResponse::error(
+                    PROTOCOL_VIOLATION,
+                    "Usage: SET resharding_phase = 'old' | 'dual_write' | 'cutover' | 'new' FOR POOL <database>",
+                ),
+            )
+            .await
+        }
+    };
+
+    if !get_config().pools.contains_key(pool_name) {
+        return error_response(
+            stream,
+            &ErrorResponse::error(
+                INVALID_CATALOG_NAME,
+                &format!("No pool named {}", pool_name),
+            ),
+        )
+        .await;
+    }
+
+    match resharding::set_phase(pool_name, phase) {
+        Ok(()) => custom_protocol_response_ok(stream, "SET").await,
+
+        Err(PhaseError::NotResharding) => {
+            error_response(
+                stream,
+                &ErrorResponse::error(
+                    INVALID_PARAMETER_VALUE,
+                    &format!("Pool {} isn't resharding", pool_name),
+                ),
+            )
+            .await
+        }
+
+        Err(PhaseError::Missed(missed)) => {
+            error_response(
+                stream,
+                &ErrorResponse::error(
+                    OBJECT_IN_USE,
+                    &format!("Pool {} can't move to phase {}", pool_name, phase),
+                )
+                .detail(&format!(
+                    "The shard the keys would be read from missed {} writes, which failed there.",
+                    missed
+                ))
+                .hint("Copy the keys again, then set the current phase again."),
+            )
+            .await
+        }
+    }
+}
+
+/// The pools resharding: the shard map their keys move from, the phase, and the
+/// queries mirrored to the other shard of their key.
+async fn show_resharding<T>(stream: &mut T) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let config = get_config();
+
+    let columns = vec![
+        ("database", DataType::Text),
+        ("from_shards", DataType::Text),
+        ("shards", DataType::Int4),
+        ("phase", DataType::Text),
+        ("mirrored", DataType::Numeric),
+        ("mirror_errors", DataType::Numeric),
+        ("phase_mirror_errors", DataType::Numeric),
+    ];
+
+    let mut pool_names = config.pools.keys().collect::<Vec<_>>();
+    pool_names.sort();
+
+    let mut res = BytesMut::new();
+    res.put(row_description(&columns));
+
+    for pool_name in pool_names {
+        let pool = &config.pools[pool_name];
+
+        let resharding = match pool.resharding {
+            Some(ref resharding) => resharding,
+            None => continue,
+        };
+
+        let (mirrored, mirror_errors, phase_mirror_errors) = resharding::mirror_stats(pool_name);
+
+        res.put(data_row(&vec![
+            pool_name.clone(),
+            match resharding.from_shards {
+                Some(from_shards) => from_shards.to_string(),
+                None => format!("{} ranges", resharding.from_ranges.len()),
+            },
+            pool.shards.len().to_string(),
+            resharding::phase(pool_name, resharding).to_string(),
+            mirrored.to_string(),
+            mirror_errors.to_string(),
+            phase_mirror_errors.to_string(),
+        ]));
+    }
+
+    res.put(command_complete("SHOW"));
+
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, res).await
+}
+
+/// The host:port of `DRAIN SERVER` and `RESUME SERVER`, quoted or not.
+fn server_argument(query: &str) -> Option<String> {
+    let query = query.trim().trim_end_matches(';');
+    let words = query.split_whitespace().collect::<Vec<&str>>();
+
+    match words[..] {
+        [_command, server, host_port] if server.eq_ignore_ascii_case("SERVER") => {
+            let host_port = host_port.trim_matches('\'');
+
+            match host_port.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                    Some(host_port.to_string())
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Forget the cached auth_query results, e.g. after changing a password.
+async fn flush_auth_cache<T>(stream: &mut T) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    info!("Flushing the auth_query cache");
+
+    auth_passthrough::flush_caches();
+
+    custom_protocol_response_ok(stream, "FLUSH").await
+}
+
 /// Reload the configuration file without restarting the process.
 async fn reload<T>(stream: &mut T, client_server_map: ClientServerMap) -> Result<(), Error>
 where
@@ -326,7 +883,7 @@ where
     let config: HashMap<String, String> = config.into();
 
     // Configs that cannot be changed without restarting.
-    let immutables = ["host", "port", "connect_timeout"];
+    let immutables = ["host", "port"];
 
     // Columns
     let columns = vec![
@@ -384,6 +941,7 @@ where
         ("avg_xact_time", DataType::Numeric),
         ("avg_query_time", DataType::Numeric),
         ("avg_wait_time", DataType::Numeric),
+        ("total_fallback_count", DataType::Numeric),
     ];
 
     let stats = get_stats();
@@ -419,3 +977,60 @@ where
 
     write_all_half(stream, res).await
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shard_keys() {
+        let settings = PoolSettings::default();
+        let ranges = vec![
+            ShardingRange {
+                shard: 0,
+                from: None,
+                to: Some(RangeBound::Integer(1000)),
+            },
+            ShardingRange {
+                shard: 1,
+                from: Some(RangeBound::Integer(1000)),
+                to: Some(RangeBound::Integer(2000)),
+            },
+            ShardingRange {
+                shard: 0,
+                from: Some(RangeBound::Integer(2000)),
+                to: None,
+            },
+        ];
+
+        assert_eq!(shard_keys(&settings, &ranges, 2, 0), "..1000, 2000..");
+        assert_eq!(shard_keys(&settings, &ranges, 2, 1), "1000..2000");
+        assert_eq!(shard_keys(&settings, &[], 4, 1), "hash % 4 = 1");
+        assert_eq!(shard_keys(&settings, &[], 2, 2), "");
+
+        let settings = PoolSettings {
+            sharding_function: String::from("murmur"),
+            sharding_mode: String::from("range"),
+            ..PoolSettings::default()
+        };
+        assert_eq!(shard_keys(&settings, &[], 2, 0), "hash 0..2147483648");
+        assert_eq!(shard_keys(&settings, &[], 2, 1), "hash 2147483648..");
+    }
+
+    #[test]
+    fn test_server_argument() {
+        assert_eq!(
+            server_argument("DRAIN SERVER 10.0.0.2:5432"),
+            Some(String::from("10.0.0.2:5432"))
+        );
+        assert_eq!(
+            server_argument("resume server 'replica.internal:5432';"),
+            Some(String::from("replica.internal:5432"))
+        );
+
+        assert_eq!(server_argument("DRAIN SERVER 10.0.0.2"), None);
+        assert_eq!(server_argument("DRAIN SERVER :5432"), None);
+        assert_eq!(server_argument("DRAIN SERVER"), None);
+        assert_eq!(server_argument("DRAIN 10.0.0.2:5432"), None);
+    }
+}
@@ -0,0 +1,198 @@
+/// Users and passwords from a pgbouncer-style auth file (`userlist.txt`),
+/// reloaded automatically when the file changes.
+use arc_swap::ArcSwap;
+use log::{error, info};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::config::get_config;
+use crate::errors::Error;
+use crate::pool::ConnectionPool;
+
+/// The auth file currently loaded.
+static AUTH_FILE: Lazy<ArcSwap<AuthFile>> =
+    Lazy::new(|| ArcSwap::from_pointee(AuthFile::default()));
+
+/// The auth file we couldn't read, so it's only logged when it goes missing
+/// and when it comes back, not every time we check it.
+static FAILED: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+
+#[derive(Clone, Debug, Default)]
+struct AuthFile {
+    /// Where the file was loaded from and when it was last modified,
+    /// so we know when to reload it.
+    path: Option<String>,
+    modified: Option<SystemTime>,
+
+    /// User name to password, plain text, md5 hash or SCRAM verifier.
+    users: HashMap<String, String>,
+}
+
+/// Parse the auth file. Each line is a quoted user name followed
+/// by a quoted password, e.g. `"sharding_user" "md5..."`; quotes inside
+/// are escaped by doubling them. Other lines are ignored, like pgbouncer does.
+pub fn parse(contents: &str) -> HashMap<String, String> {
+    let mut users = HashMap::new();
+
+    for line in contents.lines() {
+        let mut fields = Vec::new();
+        let mut chars = line.trim_start().chars().peekable();
+
+        while chars.peek() == Some(&'"') {
+            chars.next();
+
+            let mut field = String::new();
+            let mut closed = false;
+
+            while let Some(c) = chars.next() {
+                if c == '"' {
+                    if chars.peek() == Some(&'"') {
+                        chars.next();
+                        field.push('"');
+                    } else {
+                        closed = true;
+                        break;
+                    }
+                } else {
+                    field.push(c);
+                }
+            }
+
+            if !closed {
+                break;
+            }
+
+            fields.push(field);
+
+            while chars.peek().is_some_and(|c| c.is_whitespace()) {
+                chars.next();
+            }
+        }
+
+        if fields.len() >= 2 {
+            users.insert(fields[0].clone(), fields[1].clone());
+        }
+    }
+
+    users
+}
+
+/// Load the auth file configured in `general.auth_file` if it changed since we last
+/// loaded it. Returns true if it was reloaded.
+pub async fn reload() -> Result<bool, Error> {
+    let path = get_config().general.auth_file;
+    let current = AUTH_FILE.load();
+
+    let path = match path {
+        Some(path) => path,
+        None => {
+            if current.path.is_some() {
+                AUTH_FILE.store(Arc::new(AuthFile::default()));
+                remove_users(&current.users, &HashMap::new());
+                return Ok(true);
+            }
+
+            return Ok(false);
+        }
+    };
+
+    let modified = match tokio::fs::metadata(&path).await {
+        Ok(metadata) => metadata.modified().ok(),
+        Err(err) => {
+            failed(&path, &err);
+            return Err(Error::BadConfig);
+        }
+    };
+
+    if current.path.as_ref() == Some(&path) && current.modified == modified {
+        recovered(&path);
+        return Ok(false);
+    }
+
+    let contents = match tokio::fs::read_to_string(&path).await {
+        Ok(contents) => contents,
+        Err(err) => {
+            failed(&path, &err);
+            return Err(Error::BadConfig);
+        }
+    };
+
+    recovered(&path);
+
+    let users = parse(&contents);
+
+    info!("Loaded {} users from auth file '{}'", users.len(), path);
+
+    remove_users(&current.users, &users);
+
+    AUTH_FILE.store(Arc::new(AuthFile {
+        path: Some(path),
+        modified,
+        users,
+    }));
+
+    Ok(true)
+}
+
+/// Log that the auth file can't be read, unless we already did.
+fn failed(path: &str, err: &std::io::Error) {
+    let mut failed = FAILED.lock();
+
+    if failed.as_deref() != Some(path) {
+        error!("Could not read auth file '{}': {}", path, err);
+        *failed = Some(path.to_string());
+    }
+}
+
+/// Log that the auth file can be read again, if it couldn't.
+fn recovered(path: &str) {
+    if FAILED.lock().take().is_some() {
+        info!("Auth file '{}' is readable again", path);
+    }
+}
+
+/// Users that were removed or had their password changed
+/// need their pools recreated with the new password.
+fn remove_users(old: &HashMap<String, String>, new: &HashMap<String, String>) {
+    for (username, password) in old {
+        if new.get(username) != Some(password) {
+            ConnectionPool::remove_user(username);
+        }
+    }
+}
+
+/// Is the auth file configured.
+pub fn enabled() -> bool {
+    AUTH_FILE.load().path.is_some()
+}
+
+/// Get the password of the user from the auth file.
+pub fn get_password(username: &str) -> Option<String> {
+    AUTH_FILE.load().users.get(username).cloned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let users = parse(
+            "\"sharding_user\" \"sharding_user\"\n\
+             ;\"commented\" \"out\"\n\
+             \n\
+             \"other_user\"   \"md5f7ca6e8a2ec9b8fb3ab43dc2a1bd8b1b\" \"ignored\"\n\
+             \"quote\"\"d\" \"pass\"\"word\"\n\
+             \"no_password\"\n\
+             \"unterminated\" \"password\n",
+        );
+
+        assert_eq!(users.len(), 3);
+        assert_eq!(users["sharding_user"], "sharding_user");
+        assert_eq!(users["other_user"], "md5f7ca6e8a2ec9b8fb3ab43dc2a1bd8b1b");
+        assert_eq!(users["quote\"d"], "pass\"word");
+    }
+}
@@ -0,0 +1,324 @@
+/// Fetch passwords of users that aren't in the config from the database,
+/// using `auth_query`, like pgbouncer does.
+use arc_swap::ArcSwap;
+use bb8::Pool;
+use log::{debug, error};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::{get_config, Address, Pool as PoolConfig, Role, User};
+use crate::errors::Error;
+use crate::pool::{ClientServerMap, ServerPool};
+use crate::stats::get_reporter;
+
+pub type AuthPassthroughMap = HashMap<String, AuthPassthrough>;
+pub type ScramKeys = (Vec<u8>, Vec<u8>);
+
+/// A password hash returned by the auth query, or `None` if the user wasn't found,
+/// and when it was fetched.
+type CachedHash = (Option<String>, Instant);
+
+/// Locked by the clients of a user while one of them runs the auth query.
+type InFlight = Arc<tokio::sync::Mutex<()>>;
+
+/// The auth query pools, one per pool that has `auth_query` configured.
+/// Recreated with the connection pools when the config is reloaded.
+pub static AUTH_PASSTHROUGHS: Lazy<ArcSwap<AuthPassthroughMap>> =
+    Lazy::new(|| ArcSwap::from_pointee(HashMap::default()));
+
+/// Client and server keys of users authenticated with a SCRAM verifier.
+/// The verifier alone isn't enough to log into the server, so we keep
+/// the client key the client proved it has.
+static SCRAM_KEYS: Lazy<Mutex<HashMap<String, ScramKeys>>> =
+    Lazy::new(|| Mutex::new(HashMap::default()));
+
+/// Server connections used by the auth query are small in number.
+const AUTH_POOL_SIZE: u32 = 2;
+
+#[derive(Clone, Debug)]
+pub struct AuthPassthrough {
+    /// The query, e.g. `SELECT usename, passwd FROM pg_shadow WHERE usename = $1`.
+    query: String,
+
+    /// Connections to the first server of shard 0 as `auth_query_user`.
+    pool: Pool<ServerPool>,
+
+    cache: Arc<Cache>,
+
+    /// The users the query is running for, so clients connecting at the same time
+    /// as the same user share one query, without making the other users wait.
+    in_flight: Arc<Mutex<HashMap<String, InFlight>>>,
+}
+
+/// The results of the auth query, reused for a while.
+#[derive(Debug)]
+struct Cache {
+    /// How long to reuse found password hashes, and users that weren't found.
+    ttl: Duration,
+    negative_ttl: Duration,
+
+    hashes: Mutex<HashMap<String, CachedHash>>,
+}
+
+impl Cache {
+    fn new(ttl: Duration, negative_ttl: Duration) -> Cache {
+        Cache {
+            ttl,
+            negative_ttl,
+            hashes: Mutex::new(HashMap::default()),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        !self.ttl.is_zero() || !self.negative_ttl.is_zero()
+    }
+
+    /// The result for the user, if it's still fresh.
+    fn get(&self, username: &str) -> Option<Option<String>> {
+        let hashes = self.hashes.lock();
+        let (hash, fetched_at) = hashes.get(username)?;
+
+        let ttl = match hash {
+            Some(_) => self.ttl,
+            None => self.negative_ttl,
+        };
+
+        match fetched_at.elapsed() < ttl {
+            true => Some(hash.clone()),
+            false => None,
+        }
+    }
+
+    fn insert(&self, username: &str, hash: Option<String>) {
+        let mut hashes = self.hashes.lock();
+
+        // Don't let clients trying random user names grow the cache forever.
+        let max_ttl = self.ttl.max(self.negative_ttl);
+        hashes.retain(|_, (_, fetched_at)| fetched_at.elapsed() < max_ttl);
+
+        hashes.insert(username.to_string(), (hash, Instant::now()));
+    }
+
+    fn clear(&self) {
+        self.hashes.lock().clear();
+    }
+}
+
+impl AuthPassthrough {
+    /// Create the auth query pool if the pool has `auth_query` configured.
+    pub fn from_pool_config(
+        pool_config: &PoolConfig,
+        client_server_map: ClientServerMap,
+    ) -> Option<AuthPassthrough> {
+        let query = pool_config.auth_query.clone()?;
+
+        let user = User {
+            username: pool_config.auth_query_user.clone()?,
+            password: pool_config.auth_query_password.clone()?,
+            secondary_password: None,
+            server_username: None,
+            server_password: None,
+            pool_size: AUTH_POOL_SIZE,
+            primary_pool_size: None,
+            replica_pool_size: None,
+            require_tls: false,
+            read_only: false,
+        };
+
+        let shard = pool_config.shards.get("0")?;
+
+        // Prefer the primary, it has the most up to date passwords.
+        let server = shard
+            .servers
+            .iter()
+            .find(|server| server.2 == "primary")
+            .or_else(|| shard.servers.first())?;
+
+        let address = Address {
+            // Not a server clients use, so it's not reported in the stats.
+            id: usize::MAX,
+            database: shard.database.clone(),
+            host: server.0.clone(),
+            port: server.1.to_string(),
+            role: Role::Primary,
+            replica_number: 0,
+            shard: 0,
+        };
+
+        let manager = ServerPool::new(
+            address,
+            user,
+            &shard.database,
+            client_server_map,
+            get_reporter(),
+            pool_config.rds_iam_region.clone(),
+            pool_config.gcp_iam_credentials.clone(),
+            // auth_query_user has its own password.
+            None,
+        );
+
+        let pool = Pool::builder()
+            .max_size(AUTH_POOL_SIZE)
+            .min_idle(Some(0))
+            .connection_timeout(std::time::Duration::from_millis(
+                get_config().general.connect_timeout,
+            ))
+            .test_on_check_out(false)
+            .build_unchecked(manager);
+
+        Some(AuthPassthrough {
+            query,
+            pool,
+            cache: Arc::new(Cache::new(
+                Duration::from_secs(pool_config.auth_query_cache_ttl),
+                Duration::from_secs(pool_config.auth_query_negative_cache_ttl),
+            )),
+            in_flight: Arc::new(Mutex::new(HashMap::default())),
+        })
+    }
+
+    /// Fetch the password hash of the user, from the cache if it's still fresh.
+    /// Returns `None` if the user doesn't exist or doesn't have a password.
+    pub async fn fetch_hash(&self, username: &str) -> Result<Option<String>, Error> {
+        if !self.cache.enabled() {
+            return self.query_hash(username).await;
+        }
+
+        if let Some(hash) = self.cache.get(username) {
+            debug!("Using cached auth_query result for {}", username);
+            return Ok(hash);
+        }
+
+        let in_flight = self
+            .in_flight
+            .lock()
+            .entry(username.to_string())
+            .or_default()
+            .clone();
+
+        let result = {
+            let _running = in_flight.lock().await;
+
+            // Another client of the user ran it while we waited.
+            match self.cache.get(username) {
+                Some(hash) => Ok(hash),
+                None => {
+                    let result = self.query_hash(username).await;
+
+                    if let Ok(ref hash) = result {
+                        self.cache.insert(username, hash.clone());
+                    }
+
+                    result
+                }
+            }
+        };
+
+        // The last client of the user is done.
+        let mut users = self.in_flight.lock();
+
+        if Arc::strong_count(&in_flight) == 2 {
+            users.remove(username);
+        }
+
+        result
+    }
+
+    /// Forget the cached password hashes.
+    pub fn flush_cache(&self) {
+        self.cache.clear();
+    }
+
+    /// Run the auth query to get the password hash of the user.
+    async fn query_hash(&self, username: &str) -> Result<Option<String>, Error> {
+        let mut conn = match self.pool.get().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                error!("Could not connect to run auth_query: {:?}", err);
+                return Err(Error::AllServersDown);
+            }
+        };
+
+        debug!("Running auth_query for {}", username);
+
+        // The user name is bound to $1, it's not authenticated yet.
+        let rows = match conn.fetch_params(&self.query, &[username]).await {
+            Ok(rows) => rows,
+            Err(err) => {
+                error!("auth_query failed: {:?}", err);
+                return Err(err);
+            }
+        };
+
+        // The password is the last column, so the query can return
+        // the user name too, like pgbouncer's.
+        Ok(rows
+            .into_iter()
+            .next()
+            .and_then(|row| row.into_iter().last())
+            .flatten())
+    }
+}
+
+/// Get the auth query pool for the pool, if it's configured.
+pub fn get_auth_passthrough(pool_name: &str) -> Option<AuthPassthrough> {
+    AUTH_PASSTHROUGHS.load().get(pool_name).cloned()
+}
+
+/// Forget the cached auth query results of all pools.
+pub fn flush_caches() {
+    for auth_passthrough in AUTH_PASSTHROUGHS.load().values() {
+        auth_passthrough.flush_cache();
+    }
+}
+
+/// Remember the keys of a user authenticated with a SCRAM verifier.
+pub fn save_scram_keys(verifier: &str, client_key: Vec<u8>, server_key: Vec<u8>) {
+    SCRAM_KEYS
+        .lock()
+        .insert(verifier.to_string(), (client_key, server_key));
+}
+
+/// Get the keys to log into the server for a user that has a SCRAM verifier.
+pub fn get_scram_keys(verifier: &str) -> Option<ScramKeys> {
+    SCRAM_KEYS.lock().get(verifier).cloned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cache() {
+        let cache = Cache::new(Duration::from_millis(200), Duration::from_millis(50));
+        assert!(cache.enabled());
+
+        cache.insert("alice", Some(String::from("md5abc")));
+        cache.insert("nobody", None);
+
+        assert_eq!(cache.get("alice"), Some(Some(String::from("md5abc"))));
+        assert_eq!(cache.get("nobody"), Some(None));
+        assert_eq!(cache.get("bob"), None);
+
+        // The users that weren't found expire first.
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(cache.get("nobody"), None);
+        assert_eq!(cache.get("alice"), Some(Some(String::from("md5abc"))));
+
+        // FLUSH AUTH CACHE.
+        cache.clear();
+        assert_eq!(cache.get("alice"), None);
+
+        // The expired ones are dropped as others are added.
+        cache.insert("nobody", None);
+        std::thread::sleep(Duration::from_millis(210));
+        cache.insert("alice", Some(String::from("md5def")));
+        assert_eq!(cache.hashes.lock().len(), 1);
+        assert_eq!(cache.get("alice"), Some(Some(String::from("md5def"))));
+
+        assert!(!Cache::new(Duration::ZERO, Duration::ZERO).enabled());
+    }
+}
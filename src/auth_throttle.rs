@@ -0,0 +1,115 @@
+/// Slow down and lock out clients that keep failing to authenticate,
+/// tracked per client IP address and user.
+use log::warn;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::config::{get_config, General};
+
+/// Recent failed attempts of each client address and user.
+static FAILURES: Lazy<Mutex<HashMap<(IpAddr, String), Failures>>> =
+    Lazy::new(|| Mutex::new(HashMap::default()));
+
+#[derive(Clone, Debug)]
+struct Failures {
+    count: u32,
+    last_failure: Instant,
+}
+
+/// What to do with a new attempt.
+#[derive(Debug, PartialEq)]
+pub enum Throttle {
+    Allow,
+    Delay(Duration),
+    LockedOut,
+}
+
+/// Failures are forgotten this long after the last one.
+fn window() -> Duration {
+    Duration::from_secs(get_config().general.auth_lockout_time)
+}
+
+/// Check if the client can try to authenticate now.
+pub fn check(ip: IpAddr, user: &str) -> Throttle {
+    let key = (ip, user.to_string());
+    let mut failures = FAILURES.lock();
+
+    let count = match failures.get(&key) {
+        Some(entry) if entry.last_failure.elapsed() < window() => entry.count,
+        _ => 0,
+    };
+
+    if count == 0 {
+        failures.remove(&key);
+    }
+
+    throttle(count, &get_config().general)
+}
+
+/// What to do with a client that failed this many times recently.
+fn throttle(count: u32, general: &General) -> Throttle {
+    if general.auth_lockout_threshold > 0 && count >= general.auth_lockout_threshold {
+        Throttle::LockedOut
+    } else if general.auth_failure_delay > 0 && count >= general.auth_failure_delay_threshold {
+        Throttle::Delay(Duration::from_millis(general.auth_failure_delay))
+    } else {
+        Throttle::Allow
+    }
+}
+
+/// Remember a failed attempt.
+pub fn failure(ip: IpAddr, user: &str) {
+    let config = get_config();
+    let window = window();
+    let mut failures = FAILURES.lock();
+
+    // Don't keep clients around forever.
+    failures.retain(|_, entry| entry.last_failure.elapsed() < window);
+
+    let entry = failures.entry((ip, user.to_string())).or_insert(Failures {
+        count: 0,
+        last_failure: Instant::now(),
+    });
+
+    entry.count += 1;
+    entry.last_failure = Instant::now();
+
+    if entry.count == config.general.auth_lockout_threshold {
+        warn!(
+            "Locking out {} for user {} after {} failed authentication attempts",
+            ip, user, entry.count
+        );
+    }
+}
+
+/// The client authenticated, forget its failures.
+pub fn success(ip: IpAddr, user: &str) {
+    FAILURES.lock().remove(&(ip, user.to_string()));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let mut general = General::default();
+
+        // Disabled by default.
+        assert_eq!(throttle(100, &general), Throttle::Allow);
+
+        general.auth_failure_delay = 1000;
+        general.auth_failure_delay_threshold = 3;
+        general.auth_lockout_threshold = 5;
+
+        assert_eq!(throttle(2, &general), Throttle::Allow);
+        assert_eq!(
+            throttle(3, &general),
+            Throttle::Delay(Duration::from_millis(1000))
+        );
+        assert_eq!(throttle(5, &general), Throttle::LockedOut);
+    }
+}
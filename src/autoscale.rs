@@ -0,0 +1,196 @@
+/// Let the pool size of each server float between min_pool_size and pool_size,
+/// instead of using all of pool_size: it grows when the clients wait for the
+/// servers in use, and shrinks when half of them weren't used for a while.
+/// The size is checked every second.
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// The samples, one per second, the pool shrinks based on.
+const WINDOW: usize = 10;
+
+/// The pool grows when a client waited longer than this for a server.
+const WAIT_THRESHOLD: Duration = Duration::from_millis(10);
+
+/// The checkouts during one second.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Sample {
+    /// The longest a client waited.
+    wait: Duration,
+
+    /// The most servers in use at once.
+    peak: u32,
+}
+
+#[derive(Debug)]
+pub struct Autoscaler {
+    min: u32,
+    max: u32,
+    size: AtomicU32,
+
+    /// One for each server that can be in use.
+    permits: Arc<Semaphore>,
+
+    /// The last one is the current second.
+    samples: Mutex<VecDeque<Sample>>,
+}
+
+impl Autoscaler {
+    /// The pool starts with the smallest size, at least one.
+    pub fn new(min: u32, max: u32) -> Autoscaler {
+        let min = min.clamp(1, max);
+
+        Autoscaler {
+            min,
+            max,
+            size: AtomicU32::new(min),
+            permits: Arc::new(Semaphore::new(min as usize)),
+            samples: Mutex::new(VecDeque::from([Sample::default()])),
+        }
+    }
+
+    /// The servers that can be in use now.
+    pub fn size(&self) -> u32 {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    fn in_use(&self) -> u32 {
+        self.size()
+            .saturating_sub(self.permits.available_permits() as u32)
+    }
+
+    /// Wait until one more server can be in use. The server counts
+    /// until the permit is dropped.
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        let start = Instant::now();
+
+        // The semaphore is never closed.
+        let permit = self.permits.clone().acquire_owned().await.unwrap();
+        let in_use = self.in_use();
+
+        let mut samples = self.samples.lock();
+        if let Some(sample) = samples.back_mut() {
+            sample.wait = sample.wait.max(start.elapsed());
+            sample.peak = sample.peak.max(in_use);
+        }
+
+        permit
+    }
+
+    /// Resize the pool for the last samples, and start the next one.
+    /// Returns the new size.
+    pub fn tick(&self) -> u32 {
+        let in_use = self.in_use();
+        let mut samples = self.samples.lock();
+
+        // The servers can be in use for longer than a second.
+        if let Some(sample) = samples.back_mut() {
+            sample.peak = sample.peak.max(in_use);
+        }
+
+        let size = self.size();
+        let new_size = next_size(&samples, size, self.min, self.max);
+
+        if new_size > size {
+            self.permits.add_permits((new_size - size) as usize);
+            self.size.store(new_size, Ordering::Relaxed);
+        } else if new_size < size {
+            // The permits in use can't be taken back, it'll shrink next time.
+            let forgotten = self.permits.forget_permits((size - new_size) as usize);
+            self.size.store(size - forgotten as u32, Ordering::Relaxed);
+        }
+
+        samples.push_back(Sample::default());
+
+        if samples.len() > WINDOW {
+            samples.pop_front();
+        }
+
+        self.size()
+    }
+}
+
+/// The pool size for the samples: a quarter more if the clients waited during
+/// the last second, one less if half of it wasn't used for the whole window.
+fn next_size(samples: &VecDeque<Sample>, size: u32, min: u32, max: u32) -> u32 {
+    if let Some(last) = samples.back() {
+        if last.wait >= WAIT_THRESHOLD {
+            return (size + size.div_ceil(4)).min(max);
+        }
+    }
+
+    let peak = samples.iter().map(|sample| sample.peak).max().unwrap_or(0);
+
+    if samples.len() == WINDOW && peak <= size / 2 {
+        return size.saturating_sub(1).max(min);
+    }
+
+    size
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn samples(peak: u32, count: usize) -> VecDeque<Sample> {
+        (0..count)
+            .map(|_| Sample {
+                wait: Duration::ZERO,
+                peak,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_next_size() {
+        // A client waited 20ms.
+        let mut waited = samples(4, 3);
+        waited.push_back(Sample {
+            wait: Duration::from_millis(20),
+            peak: 4,
+        });
+        assert_eq!(next_size(&waited, 4, 1, 10), 5);
+        assert_eq!(next_size(&waited, 8, 1, 10), 10);
+        assert_eq!(next_size(&waited, 10, 1, 10), 10);
+
+        // Busy, but nobody waited.
+        assert_eq!(next_size(&samples(4, WINDOW), 4, 1, 10), 4);
+
+        // Half of it unused, but not for long enough.
+        assert_eq!(next_size(&samples(2, WINDOW - 1), 4, 1, 10), 4);
+        assert_eq!(next_size(&samples(2, WINDOW), 4, 1, 10), 3);
+        assert_eq!(next_size(&samples(0, WINDOW), 2, 2, 10), 2);
+    }
+
+    #[tokio::test]
+    async fn test_tick() {
+        let autoscaler = Autoscaler::new(0, 4);
+        assert_eq!(autoscaler.size(), 1);
+
+        let permit = autoscaler.acquire().await;
+
+        // The next client waits for the first one.
+        let waiting = async {
+            let permit = autoscaler.acquire().await;
+            drop(permit);
+        };
+        let done = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(permit);
+        };
+        tokio::join!(waiting, done);
+
+        assert_eq!(autoscaler.tick(), 2);
+        assert_eq!(autoscaler.permits.available_permits(), 2);
+
+        // Nothing's used for a while.
+        for _ in 0..WINDOW {
+            autoscaler.tick();
+        }
+        assert_eq!(autoscaler.size(), 1);
+        assert_eq!(autoscaler.permits.available_permits(), 1);
+    }
+}
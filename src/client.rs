@@ -1,20 +1,39 @@
 /// Handle clients by pretending to be a PostgreSQL server.
+use bb8::PooledConnection;
 use bytes::{Buf, BufMut, BytesMut};
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
+use once_cell::sync::Lazy;
 use std::collections::HashMap;
-use tokio::io::{split, AsyncReadExt, BufReader, ReadHalf, WriteHalf};
-use tokio::net::TcpStream;
+use std::time::Duration;
+use tokio::io::{split, AsyncBufReadExt, AsyncReadExt, BufReader, ReadHalf, WriteHalf};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::watch;
 
 use crate::admin::{generate_server_info_for_admin, handle_admin};
-use crate::config::get_config;
+use crate::auth_file;
+use crate::auth_passthrough::{get_auth_passthrough, save_scram_keys};
+use crate::auth_throttle::{self, Throttle};
+use crate::config::{get_config, Jwt, Ldap, Listener, Role, User};
 use crate::constants::*;
+use crate::error_response::*;
 use crate::errors::Error;
+use crate::gssapi;
+use crate::hba;
+use crate::jwt;
+use crate::ldap;
 use crate::messages::*;
-use crate::pool::{get_pool, ClientServerMap, ConnectionPool};
+use crate::pam;
+use crate::parameters::{parse_options, ServerParameters};
+use crate::pool::{get_pool, route_application_name, ClientServerMap, ConnectionPool, ServerPool};
+use crate::prepared_statements::PreparedStatements;
 use crate::query_router::{Command, QueryRouter};
+use crate::resharding;
+use crate::revocation;
+use crate::scatter_gather;
+use crate::scram::{is_scram_verifier, ScramSha256Server};
 use crate::server::Server;
 use crate::stats::{get_reporter, Reporter};
-use crate::tls::Tls;
+use crate::tls::{self, certificate_names, TlsInfo};
 
 use tokio_rustls::server::TlsStream;
 
@@ -22,9 +41,75 @@ use tokio_rustls::server::TlsStream;
 enum ClientConnectionType {
     Startup,
     Tls,
+    GssEncryption,
     CancelQuery,
 }
 
+/// Why an idle client stopped waiting for its next message.
+enum Wait {
+    Message,
+    IdleTimeout,
+    Shutdown,
+}
+
+/// Set when pgcat shuts down, then the clients are disconnected when they're idle.
+static SHUTDOWN: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(false).0);
+
+/// The clients are given this long to be told pgcat is shutting down.
+const SHUTDOWN_WAIT: Duration = Duration::from_secs(1);
+
+/// Tell the clients pgcat is shutting down: the idle ones are disconnected
+/// with an error right away, the others once their query is done, if it's soon.
+pub async fn shutdown() {
+    SHUTDOWN.send_replace(true);
+
+    let _ = tokio::time::timeout(SHUTDOWN_WAIT, async {
+        while SHUTDOWN.receiver_count() > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await;
+}
+
+/// Where the client connected from.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ClientAddress {
+    Tcp(std::net::SocketAddr),
+
+    /// The unix socket, which has no address. Only HBA `local` rules match it.
+    Unix,
+}
+
+impl ClientAddress {
+    /// The IP address the failed authentications are counted for.
+    /// Unix socket clients are local.
+    pub fn ip(&self) -> std::net::IpAddr {
+        match self {
+            ClientAddress::Tcp(addr) => addr.ip(),
+            ClientAddress::Unix => std::net::IpAddr::from([127, 0, 0, 1]),
+        }
+    }
+}
+
+impl std::fmt::Display for ClientAddress {
+    /// The host, like Postgres names it in its errors.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientAddress::Tcp(addr) => write!(f, "{}", addr.ip()),
+            ClientAddress::Unix => write!(f, "[local]"),
+        }
+    }
+}
+
+impl std::fmt::Debug for ClientAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientAddress::Tcp(addr) => write!(f, "{:?}", addr),
+            ClientAddress::Unix => write!(f, "[local]"),
+        }
+    }
+}
+
 /// The client state. One of these is created per client.
 pub struct Client<S, T> {
     /// The reads are buffered (8K by default).
@@ -38,8 +123,26 @@ pub struct Client<S, T> {
     /// them to the backend.
     buffer: BytesMut,
 
+    /// Named prepared statements, renamed so they can be used on any server.
+    prepared_statements: PreparedStatements,
+
+    /// CopyData messages larger than this are sent to the server as they're read.
+    streaming_threshold: usize,
+
+    /// The client is disconnected if it sends a larger message.
+    max_message_size: usize,
+
+    /// The client is disconnected if it's idle, i.e. not in a transaction, for longer (seconds).
+    client_idle_timeout: u64,
+
+    /// Answer the sequences the client pipelines with an error.
+    reject_pipelining: bool,
+
+    /// Set when pgcat shuts down.
+    shutdown: watch::Receiver<bool>,
+
     /// Address
-    addr: std::net::SocketAddr,
+    addr: ClientAddress,
 
     /// The client was started with the sole reason to cancel another running query.
     cancel_mode: bool,
@@ -60,6 +163,10 @@ pub struct Client<S, T> {
     #[allow(dead_code)]
     parameters: HashMap<String, String>,
 
+    /// The server settings the client was told about, e.g. TimeZone.
+    /// The servers it uses are set to them.
+    server_parameters: ServerParameters,
+
     /// Statistics
     stats: Reporter,
 
@@ -73,23 +180,73 @@ pub struct Client<S, T> {
     last_server_id: Option<i32>,
 
     target_pool: ConnectionPool,
+
+    /// The server role the route of the client's application_name sends it to, if any.
+    application_name_role: Option<String>,
 }
 
-/// Client entrypoint.
+/// Client entrypoint. `listener` is the extra listener the client connected to,
+/// if it's not the main one.
 pub async fn client_entrypoint(
     mut stream: TcpStream,
     client_server_map: ClientServerMap,
+    listener: Option<Listener>,
 ) -> Result<(), Error> {
     // Figure out if the client wants TLS or not.
-    let addr = stream.peer_addr().unwrap();
+    let addr = ClientAddress::Tcp(stream.peer_addr().unwrap());
+    let local_addr = stream.local_addr().ok();
+
+    let startup = match get_startup::<TcpStream>(&mut stream).await {
+        // GSSAPI encryption isn't supported. The client is told so, and can
+        // ask for TLS or start in plain text instead, like with Postgres,
+        // unless the listener refuses them.
+        Ok((ClientConnectionType::GssEncryption, _)) => {
+            let config = get_config();
 
-    match get_startup::<TcpStream>(&mut stream).await {
+            let gssenc_mode = match listener {
+                Some(ref listener) => listener.gssenc_mode(&config.general),
+                None => &config.general.gssenc_mode,
+            };
+
+            if gssenc_mode == "refuse" {
+                debug!("Refusing GSSAPI encryption request");
+
+                fatal_error_response(
+                    &mut stream,
+                    &ErrorResponse::fatal(
+                        FEATURE_NOT_SUPPORTED,
+                        "GSSAPI encryption is not supported",
+                    ),
+                )
+                .await?;
+
+                return Err(Error::ClientError);
+            }
+
+            debug!("Declining GSSAPI encryption request");
+
+            let mut no = BytesMut::new();
+            no.put_u8(b'N');
+            write_all(&mut stream, no).await?;
+
+            get_startup::<TcpStream>(&mut stream).await
+        }
+
+        startup => startup,
+    };
+
+    match startup {
         // Client requested a TLS connection.
         Ok((ClientConnectionType::Tls, _)) => {
             let config = get_config();
 
+            let tls = match listener {
+                Some(ref listener) => listener.tls(&config.general),
+                None => config.general.tls(),
+            };
+
             // TLS settings are configured, will setup TLS now.
-            if config.general.tls_certificate != None {
+            if let Some((certificate, private_key)) = tls {
                 debug!("Accepting TLS request");
 
                 let mut yes = BytesMut::new();
@@ -97,7 +254,7 @@ pub async fn client_entrypoint(
                 write_all(&mut stream, yes).await?;
 
                 // Negotiate TLS.
-                match startup_tls(stream, client_server_map).await {
+                match startup_tls(stream, client_server_map, &certificate, &private_key).await {
                     Ok(mut client) => {
                         info!("Client {:?} connected (TLS)", addr);
 
@@ -121,7 +278,17 @@ pub async fn client_entrypoint(
                         let (read, write) = split(stream);
 
                         // Continue with regular startup.
-                        match Client::startup(read, write, addr, bytes, client_server_map).await {
+                        match Client::startup(
+                            read,
+                            write,
+                            addr,
+                            local_addr,
+                            bytes,
+                            client_server_map,
+                            None,
+                        )
+                        .await
+                        {
                             Ok(mut client) => {
                                 info!("Client {:?} connected (plain)", addr);
 
@@ -131,6 +298,21 @@ pub async fn client_entrypoint(
                         }
                     }
 
+                    // Client wants to cancel a query, it tried TLS first.
+                    Ok((ClientConnectionType::CancelQuery, bytes)) => {
+                        let (read, write) = split(stream);
+
+                        match Client::cancel(read, write, addr, bytes, client_server_map).await {
+                            Ok(mut client) => {
+                                info!("Client {:?} issued a cancel query request", addr);
+
+                                client.handle().await
+                            }
+
+                            Err(err) => Err(err),
+                        }
+                    }
+
                     // Client probably disconnected rejecting our plain text connection.
                     _ => Err(Error::ProtocolSyncError),
                 }
@@ -142,7 +324,17 @@ pub async fn client_entrypoint(
             let (read, write) = split(stream);
 
             // Continue with regular startup.
-            match Client::startup(read, write, addr, bytes, client_server_map).await {
+            match Client::startup(
+                read,
+                write,
+                addr,
+                local_addr,
+                bytes,
+                client_server_map,
+                None,
+            )
+            .await
+            {
                 Ok(mut client) => {
                     info!("Client {:?} connected (plain)", addr);
 
@@ -168,19 +360,78 @@ pub async fn client_entrypoint(
             }
         }
 
+        // Client asked for GSSAPI encryption again.
+        Ok((ClientConnectionType::GssEncryption, _)) => Err(Error::ProtocolSyncError),
+
         // Something failed, probably the socket.
         Err(err) => Err(err),
     }
 }
 
+/// Client entrypoint for the unix socket. There is no TLS over the unix socket,
+/// like in Postgres. Clients are reported as connecting from 127.0.0.1.
+pub async fn unix_entrypoint(
+    mut stream: UnixStream,
+    client_server_map: ClientServerMap,
+) -> Result<(), Error> {
+    let addr = ClientAddress::Unix;
+
+    let (connection_type, bytes) = match get_startup::<UnixStream>(&mut stream).await? {
+        (ClientConnectionType::Tls | ClientConnectionType::GssEncryption, _) => {
+            let mut no = BytesMut::new();
+            no.put_u8(b'N');
+            write_all(&mut stream, no).await?;
+
+            get_startup::<UnixStream>(&mut stream).await?
+        }
+
+        startup => startup,
+    };
+
+    let (read, write) = split(stream);
+
+    match connection_type {
+        ClientConnectionType::Startup => {
+            match Client::startup(read, write, addr, None, bytes, client_server_map, None).await {
+                Ok(mut client) => {
+                    info!("Client connected (unix socket)");
+
+                    client.handle().await
+                }
+                Err(err) => Err(err),
+            }
+        }
+
+        ClientConnectionType::CancelQuery => {
+            match Client::cancel(read, write, addr, bytes, client_server_map).await {
+                Ok(mut client) => {
+                    info!("Client issued a cancel query request (unix socket)");
+
+                    client.handle().await
+                }
+                Err(err) => Err(err),
+            }
+        }
+
+        ClientConnectionType::Tls | ClientConnectionType::GssEncryption => {
+            Err(Error::ProtocolSyncError)
+        }
+    }
+}
+
 /// Handle the first message the client sends.
 async fn get_startup<S>(stream: &mut S) -> Result<(ClientConnectionType, BytesMut), Error>
 where
     S: tokio::io::AsyncRead + std::marker::Unpin + tokio::io::AsyncWrite,
 {
     // Get startup message length.
+    // It has at least the protocol version.
     let len = match stream.read_i32().await {
-        Ok(len) => len,
+        Ok(len) if (8..=MAX_STARTUP_PACKET_LENGTH).contains(&len) => len,
+        Ok(len) => {
+            warn!("Invalid startup packet length: {}", len);
+            return Err(Error::ClientBadStartup);
+        }
         Err(_) => return Err(Error::ClientBadStartup),
     };
 
@@ -198,8 +449,32 @@ where
         // Client is requesting SSL (TLS).
         SSL_REQUEST_CODE => Ok((ClientConnectionType::Tls, bytes)),
 
-        // Client wants to use plain text, requesting regular startup.
-        PROTOCOL_VERSION_NUMBER => Ok((ClientConnectionType::Startup, bytes)),
+        // Client is requesting GSSAPI encryption.
+        GSSENC_REQUEST_CODE => Ok((ClientConnectionType::GssEncryption, bytes)),
+
+        // Client wants to use plain text, requesting regular startup. We speak
+        // protocol 3.0 without options (`_pq_.*`), so if the client asked for a newer
+        // minor version or for options, it's told to do without them.
+        code if code >> 16 == PROTOCOL_VERSION_NUMBER >> 16 => {
+            let mut options = parse_params(bytes.clone())?
+                .into_keys()
+                .filter(|name| name.starts_with("_pq_."))
+                .collect::<Vec<String>>();
+
+            options.sort();
+
+            if code != PROTOCOL_VERSION_NUMBER || !options.is_empty() {
+                debug!(
+                    "Client asked for protocol 3.{} with options {:?}, negotiating 3.0",
+                    code & 0xFFFF,
+                    options
+                );
+
+                negotiate_protocol_version(stream, &options).await?;
+            }
+
+            Ok((ClientConnectionType::Startup, bytes))
+        }
 
         // Client is requesting to cancel a running query (plain text connection).
         CANCEL_REQUEST_CODE => Ok((ClientConnectionType::CancelQuery, bytes)),
@@ -214,10 +489,13 @@ where
 pub async fn startup_tls(
     stream: TcpStream,
     client_server_map: ClientServerMap,
+    certificate: &str,
+    private_key: &str,
 ) -> Result<Client<ReadHalf<TlsStream<TcpStream>>, WriteHalf<TlsStream<TcpStream>>>, Error> {
     // Negotiate TLS.
-    let tls = Tls::new()?;
-    let addr = stream.peer_addr().unwrap();
+    let tls = tls::acceptor(certificate, private_key)?;
+    let addr = ClientAddress::Tcp(stream.peer_addr().unwrap());
+    let local_addr = stream.local_addr().ok();
 
     let mut stream = match tls.acceptor.accept(stream).await {
         Ok(stream) => stream,
@@ -229,15 +507,70 @@ pub async fn startup_tls(
         }
     };
 
+    tls::handshake();
+
+    let client_certs = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .map(|certs| certs.to_vec())
+        .unwrap_or_default();
+
+    // Names in the client certificate, if the client sent one.
+    // It's been verified against our CA during the handshake.
+    let tls_info = TlsInfo {
+        client_cert_names: match client_certs.first() {
+            Some(cert) => certificate_names(cert),
+            None => Vec::new(),
+        },
+        server_end_point: tls.server_end_point.clone(),
+        server_name: stream
+            .get_ref()
+            .1
+            .sni_hostname()
+            .map(|name| name.to_string()),
+    };
+
     // TLS negotitation successful.
     // Continue with regular startup using encrypted connection.
     match get_startup::<TlsStream<TcpStream>>(&mut stream).await {
         // Got good startup message, proceeding like normal except we
         // are encrypted now.
         Ok((ClientConnectionType::Startup, bytes)) => {
+            let config = get_config();
+
+            if !client_certs.is_empty()
+                && revocation::revoked(
+                    &client_certs,
+                    &tls.ca_certificates,
+                    &config.general.tls_crl_file,
+                    config.general.tls_ocsp,
+                )
+                .await
+            {
+                invalid_authorization(&mut stream, "client certificate has been revoked").await?;
+                return Err(Error::TlsError);
+            }
+
+            let (read, write) = split(stream);
+
+            Client::startup(
+                read,
+                write,
+                addr,
+                local_addr,
+                bytes,
+                client_server_map,
+                Some(tls_info),
+            )
+            .await
+        }
+
+        // Client wants to cancel a query over TLS, e.g. libpq 17 does.
+        Ok((ClientConnectionType::CancelQuery, bytes)) => {
             let (read, write) = split(stream);
 
-            Client::startup(read, write, addr, bytes, client_server_map).await
+            Client::cancel(read, write, addr, bytes, client_server_map).await
         }
 
         // Bad Postgres client.
@@ -245,195 +578,960 @@ pub async fn startup_tls(
     }
 }
 
-impl<S, T> Client<S, T>
+/// Authenticate the client with an MD5 password challenge.
+/// The `hash_user` is the user name the password hash is salted with.
+/// The client can use either password, if there is a secondary one.
+async fn md5_authenticate<S, T>(
+    read: &mut S,
+    write: &mut T,
+    user: &str,
+    hash_user: &str,
+    password: &str,
+    secondary_password: Option<&str>,
+) -> Result<(), Error>
 where
     S: tokio::io::AsyncRead + std::marker::Unpin,
     T: tokio::io::AsyncWrite + std::marker::Unpin,
 {
-    /// Handle Postgres client startup after TLS negotiation is complete
-    /// or over plain text.
-    pub async fn startup(
-        mut read: S,
-        mut write: T,
-        addr: std::net::SocketAddr,
-        bytes: BytesMut, // The rest of the startup message.
-        client_server_map: ClientServerMap,
-    ) -> Result<Client<S, T>, Error> {
-        let config = get_config();
-        let stats = get_reporter();
+    let salt = md5_challenge(write).await?;
+    let password_response = read_password(read).await?;
 
-        trace!("Got StartupMessage");
-        let parameters = parse_startup(bytes.clone())?;
-        let database = match parameters.get("database") {
-            Some(db) => db,
-            None => return Err(Error::ClientError),
-        };
+    // Compare server and client hashes.
+    let matches = |password| md5_hash_password(hash_user, password, &salt) == password_response;
 
-        let user = match parameters.get("user") {
-            Some(user) => user,
-            None => return Err(Error::ClientError),
-        };
+    if !matches(password) && !secondary_password.is_some_and(matches) {
+        debug!("Password authentication failed");
+        wrong_password(write, user).await?;
+        return Err(Error::ClientError);
+    }
 
-        let admin = ["pgcat", "pgbouncer"]
-            .iter()
-            .filter(|db| *db == &database)
-            .count()
-            == 1;
+    Ok(())
+}
 
-        // Generate random backend ID and secret key
-        let process_id: i32 = rand::random();
-        let secret_key: i32 = rand::random();
+/// Tell a client that connected in plain text that it must use TLS.
+async fn tls_required<T>(write: &mut T, user: &str, database: &str) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    debug!("Refusing {} without TLS", user);
+
+    fatal_error_response(
+        write,
+        &ErrorResponse::fatal(
+            INVALID_AUTHORIZATION_SPECIFICATION,
+            &format!(
+                "TLS is required for user \"{}\", database \"{}\"",
+                user, database
+            ),
+        )
+        .hint("Connect with sslmode=require."),
+    )
+    .await
+}
+
+/// Authenticate the client with SCRAM-SHA-256.
+async fn scram_authenticate<S, T>(
+    read: &mut S,
+    write: &mut T,
+    user: &str,
+    scram: &mut ScramSha256Server,
+    server_end_point: Option<&[u8]>, // None without TLS.
+) -> Result<(), Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    if let Some(server_end_point) = server_end_point {
+        scram.set_channel_binding(server_end_point.to_vec());
+    }
 
-        // Perform MD5 authentication.
-        // TODO: Add SASL support.
-        let salt = md5_challenge(&mut write).await?;
+    scram_start_challenge(write, server_end_point.is_some()).await?;
 
-        let code = match read.read_u8().await {
-            Ok(p) => p,
-            Err(_) => return Err(Error::SocketError),
-        };
+    // SASLInitialResponse
+    let response = read_password(read).await?;
+    let (mechanism, client_first) = parse_sasl_initial_response(BytesMut::from(&response[..]))?;
 
-        // PasswordMessage
-        if code as char != 'p' {
-            debug!("Expected p, got {}", code as char);
+    let plus = match mechanism.as_ref() {
+        SCRAM_SHA_256 => false,
+        SCRAM_SHA_256_PLUS if server_end_point.is_some() => true,
+        _ => {
+            debug!("Client selected unsupported SASL mechanism: {}", mechanism);
             return Err(Error::ProtocolSyncError);
         }
+    };
 
-        let len = match read.read_i32().await {
-            Ok(len) => len,
-            Err(_) => return Err(Error::SocketError),
-        };
+    let server_first = scram.server_first(&client_first)?;
 
-        let mut password_response = vec![0u8; (len - 4) as usize];
+    // SCRAM-SHA-256-PLUS requires channel binding, and only it can use it.
+    if scram.channel_bound() != plus {
+        debug!(
+            "SCRAM channel binding doesn't match the mechanism {}",
+            mechanism
+        );
+        return Err(Error::ProtocolSyncError);
+    }
 
-        match read.read_exact(&mut password_response).await {
-            Ok(_) => (),
-            Err(_) => return Err(Error::SocketError),
-        };
+    scram_server_response(write, SASL_CONTINUE, &server_first).await?;
 
-        let (target_pool, transaction_mode, server_info) = if admin {
-            let correct_user = config.general.admin_username.as_str();
-            let correct_password = config.general.admin_password.as_str();
+    // SASLResponse
+    let client_final = read_password(read).await?;
 
-            // Compare server and client hashes.
-            let password_hash = md5_hash_password(correct_user, correct_password, &salt);
-            if password_hash != password_response {
-                debug!("Password authentication failed");
-                wrong_password(&mut write, user).await?;
-                return Err(Error::ClientError);
-            }
-            (
-                ConnectionPool::default(),
-                false,
-                generate_server_info_for_admin(),
-            )
-        } else {
-            let target_pool = match get_pool(database.clone(), user.clone()) {
-                Some(pool) => pool,
-                None => {
-                    error_response(
-                        &mut write,
-                        &format!(
-                            "No pool configured for database: {:?}, user: {:?}",
-                            database, user
-                        ),
-                    )
-                    .await?;
-                    return Err(Error::ClientError);
-                }
-            };
-            let transaction_mode = target_pool.settings.pool_mode == "transaction";
-            let server_info = target_pool.server_info();
-            // Compare server and client hashes.
-            let correct_password = target_pool.settings.user.password.as_str();
-            let password_hash = md5_hash_password(user, correct_password, &salt);
-
-            if password_hash != password_response {
-                debug!("Password authentication failed");
-                wrong_password(&mut write, user).await?;
-                return Err(Error::ClientError);
-            }
-            (target_pool, transaction_mode, server_info)
-        };
+    match scram.server_final(&client_final) {
+        Ok(server_final) => scram_server_response(write, SASL_FINAL, &server_final).await,
 
-        debug!("Password authentication successful");
+        Err(Error::ClientError) => {
+            debug!("SCRAM authentication failed");
+            wrong_password(write, user).await?;
+            Err(Error::ClientError)
+        }
 
-        auth_ok(&mut write).await?;
-        write_all(&mut write, server_info).await?;
-        backend_key_data(&mut write, process_id, secret_key).await?;
-        ready_for_query(&mut write).await?;
+        Err(err) => Err(err),
+    }
+}
 
-        trace!("Startup OK");
+/// Ask the client for its password in plain text.
+async fn cleartext_password<S, T>(read: &mut S, write: &mut T) -> Result<String, Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    cleartext_password_challenge(write).await?;
 
-        // Split the read and write streams
-        // so we can control buffering.
+    let password = read_password(read).await?;
 
-        return Ok(Client {
-            read: BufReader::new(read),
-            write: write,
-            addr,
-            buffer: BytesMut::with_capacity(8196),
-            cancel_mode: false,
-            transaction_mode: transaction_mode,
-            process_id: process_id,
-            secret_key: secret_key,
-            client_server_map: client_server_map,
-            parameters: parameters.clone(),
-            stats: stats,
-            admin: admin,
-            last_address_id: None,
-            last_server_id: None,
-            target_pool: target_pool,
-        });
-    }
+    // The password is a null-terminated string.
+    let password = String::from_utf8_lossy(&password);
 
-    /// Handle cancel request.
-    pub async fn cancel(
-        read: S,
-        write: T,
-        addr: std::net::SocketAddr,
-        mut bytes: BytesMut, // The rest of the startup message.
-        client_server_map: ClientServerMap,
-    ) -> Result<Client<S, T>, Error> {
-        let process_id = bytes.get_i32();
-        let secret_key = bytes.get_i32();
-        return Ok(Client {
-            read: BufReader::new(read),
-            write: write,
-            addr,
-            buffer: BytesMut::with_capacity(8196),
-            cancel_mode: true,
-            transaction_mode: false,
-            process_id: process_id,
-            secret_key: secret_key,
-            client_server_map: client_server_map,
-            parameters: HashMap::new(),
-            stats: get_reporter(),
-            admin: false,
-            last_address_id: None,
-            last_server_id: None,
-            target_pool: ConnectionPool::default(),
-        });
-    }
+    Ok(password.trim_end_matches(char::from(0)).to_string())
+}
 
-    /// Handle a connected and authenticated client.
-    pub async fn handle(&mut self) -> Result<(), Error> {
-        // The client wants to cancel a query it has issued previously.
-        if self.cancel_mode {
-            trace!("Sending CancelRequest");
+/// Authenticate the client with its password in plain text,
+/// checked by the LDAP server.
+async fn ldap_authenticate<S, T>(
+    read: &mut S,
+    write: &mut T,
+    user: &str,
+    ldap: &Ldap,
+) -> Result<(), Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let password = cleartext_password(read, write).await?;
 
-            let (process_id, secret_key, address, port) = {
-                let guard = self.client_server_map.lock();
+    match ldap::authenticate(ldap, user, &password).await {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            debug!("LDAP authentication failed");
+            wrong_password(write, user).await?;
+            Err(err)
+        }
+    }
+}
 
-                match guard.get(&(self.process_id, self.secret_key)) {
-                    // Drop the mutex as soon as possible.
-                    // We found the server the client is using for its query
+/// Authenticate the client with its password in plain text,
+/// checked by the host's PAM stack.
+async fn pam_authenticate<S, T>(
+    read: &mut S,
+    write: &mut T,
+    user: &str,
+    service: &str,
+) -> Result<(), Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let password = cleartext_password(read, write).await?;
+    let (service, pam_user) = (service.to_string(), user.to_string());
+
+    // PAM modules block, e.g. while talking to sssd.
+    let authenticated =
+        tokio::task::spawn_blocking(move || pam::authenticate(&service, &pam_user, &password))
+            .await
+            .unwrap_or(false);
+
+    if !authenticated {
+        debug!("PAM authentication failed");
+        wrong_password(write, user).await?;
+        return Err(Error::ClientError);
+    }
+
+    Ok(())
+}
+
+/// Authenticate the client with a JWT sent instead of the password.
+async fn jwt_authenticate<S, T>(
+    read: &mut S,
+    write: &mut T,
+    user: &str,
+    jwt: &Jwt,
+) -> Result<(), Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let token = cleartext_password(read, write).await?;
+
+    match jwt::authenticate(jwt, user, &token).await {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            debug!("JWT authentication failed");
+            wrong_password(write, user).await?;
+            Err(err)
+        }
+    }
+}
+
+/// One of the certificate names is the user, or is mapped to it in the ident_map.
+fn cert_mapped(
+    user: &str,
+    client_cert_names: &[String],
+    ident_map: &HashMap<String, String>,
+) -> bool {
+    client_cert_names.iter().any(|name| {
+        if ident_map.is_empty() {
+            name == user
+        } else {
+            ident_map.get(name).map(|mapped| mapped == user) == Some(true)
+        }
+    })
+}
+
+/// Authenticate the client with the certificate it sent during the TLS handshake.
+/// One of the certificate names must be mapped to the user in the pool's ident_map,
+/// or be the user name itself if there is no ident_map.
+async fn cert_authenticate<T>(
+    write: &mut T,
+    user: &str,
+    client_cert_names: &[String],
+    ident_map: &HashMap<String, String>,
+) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    if client_cert_names.is_empty() {
+        invalid_authorization(
+            write,
+            &format!(
+                "connection requires a valid client certificate for user \"{}\"",
+                user
+            ),
+        )
+        .await?;
+        return Err(Error::ClientError);
+    }
+
+    if !cert_mapped(user, client_cert_names, ident_map) {
+        debug!(
+            "Client certificate names {:?} are not mapped to user {}",
+            client_cert_names, user
+        );
+        invalid_authorization(
+            write,
+            &format!("certificate authentication failed for user \"{}\"", user),
+        )
+        .await?;
+        return Err(Error::ClientError);
+    }
+
+    Ok(())
+}
+
+/// Authenticate the client with Kerberos, using GSSAPI. The client principal must be
+/// mapped to the user in the pool's ident_map, or be the user name and a realm.
+async fn gss_authenticate<S, T>(
+    read: &mut S,
+    write: &mut T,
+    user: &str,
+    ident_map: &HashMap<String, String>,
+) -> Result<(), Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let keytab = get_config().general.krb_server_keyfile.unwrap_or_default();
+
+    let mut context = match gssapi::Context::new(&keytab) {
+        Ok(context) => context,
+        Err(err) => {
+            fatal_error_response(
+                write,
+                &ErrorResponse::fatal(
+                    INVALID_AUTHORIZATION_SPECIFICATION,
+                    "GSSAPI is not available",
+                ),
+            )
+            .await?;
+            return Err(err);
+        }
+    };
+
+    gss_challenge(write).await?;
+
+    let principal = loop {
+        let token = read_password(read).await?;
+
+        match context.step(&token) {
+            Ok((response, principal)) => {
+                if !response.is_empty() {
+                    gss_continue(write, &response).await?;
+                }
+
+                if let Some(principal) = principal {
+                    break principal;
+                }
+            }
+
+            Err(err) => {
+                invalid_authorization(
+                    write,
+                    &format!("GSSAPI authentication failed for user \"{}\"", user),
+                )
+                .await?;
+                return Err(err);
+            }
+        }
+    };
+
+    if !gssapi::principal_matches(&principal, user, ident_map) {
+        debug!(
+            "Kerberos principal {} is not mapped to user {}",
+            principal, user
+        );
+        invalid_authorization(
+            write,
+            &format!("GSSAPI authentication failed for user \"{}\"", user),
+        )
+        .await?;
+        return Err(Error::ClientError);
+    }
+
+    Ok(())
+}
+
+/// How the client authenticates: the pool's auth_type, unless an HBA rule says otherwise.
+/// The pool's trust only applies to clients connected to one of its trusted listen
+/// addresses, the others authenticate with their password.
+fn auth_method<'a>(
+    hba_method: &'a Option<String>,
+    auth_type: &'a str,
+    trust_listen_addresses: &[String],
+    local_addr: Option<std::net::SocketAddr>,
+) -> &'a str {
+    if let Some(hba_method) = hba_method {
+        return hba_method;
+    }
+
+    if auth_type != "trust" {
+        return auth_type;
+    }
+
+    let trusted = trust_listen_addresses
+        .iter()
+        .any(|address| match local_addr {
+            Some(local_addr) => address.parse::<std::net::IpAddr>() == Ok(local_addr.ip()),
+            None => address == "unix",
+        });
+
+    match trusted {
+        true => "trust",
+        false => "md5",
+    }
+}
+
+/// Authenticate the client with the password of a pool user.
+/// Users found with auth_query only have their password hash,
+/// an md5 hash or a SCRAM verifier, so the method is picked to match it.
+/// With LDAP and JWT, the password is only used to connect to the server.
+/// The secondary password, if any, is also accepted, except with a SCRAM verifier.
+/// Over TLS, SCRAM can be bound to the connection with the server end point data.
+#[allow(clippy::too_many_arguments)]
+async fn authenticate<S, T>(
+    read: &mut S,
+    write: &mut T,
+    user: &str,
+    password: &str,
+    secondary_password: Option<&str>,
+    auth_type: &str,
+    ldap: &Option<Ldap>,
+    jwt: &Option<Jwt>,
+    server_end_point: Option<&[u8]>,
+) -> Result<(), Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    if let (Some(ldap), "ldap") = (ldap, auth_type) {
+        ldap_authenticate(read, write, user, ldap).await
+    } else if let (Some(jwt), "jwt") = (jwt, auth_type) {
+        jwt_authenticate(read, write, user, jwt).await
+    } else if is_scram_verifier(password) {
+        let mut scram = ScramSha256Server::from_verifier(password)?;
+        scram_authenticate(read, write, user, &mut scram, server_end_point).await?;
+
+        // Keep the client key so the pool can log into the server as this user.
+        if let Some(client_key) = scram.client_key() {
+            save_scram_keys(password, client_key, scram.server_key());
+        }
+
+        Ok(())
+    } else if auth_type == "scram-sha-256" && !is_md5_hash(password) {
+        let mut scram = ScramSha256Server::new(password);
+
+        if let Some(secondary_password) = secondary_password {
+            if !is_md5_hash(secondary_password) && !is_scram_verifier(secondary_password) {
+                scram.add_secondary_password(secondary_password);
+            }
+        }
+
+        scram_authenticate(read, write, user, &mut scram, server_end_point).await
+    } else {
+        md5_authenticate(read, write, user, user, password, secondary_password).await
+    }
+}
+
+impl<S, T> Client<S, T>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    /// Handle Postgres client startup after TLS negotiation is complete
+    /// or over plain text.
+    pub async fn startup(
+        mut read: S,
+        mut write: T,
+        addr: ClientAddress,
+        local_addr: Option<std::net::SocketAddr>, // None for the unix socket.
+        bytes: BytesMut,                          // The rest of the startup message.
+        client_server_map: ClientServerMap,
+        tls: Option<TlsInfo>, // None without TLS.
+    ) -> Result<Client<S, T>, Error> {
+        let config = get_config();
+        let stats = get_reporter();
+
+        trace!("Got StartupMessage");
+        let mut parameters = parse_startup(bytes.clone())?;
+
+        // TLS clients can pick the database with the host name they connect to.
+        if let Some(database) = tls
+            .as_ref()
+            .and_then(|tls| tls.server_name.as_ref())
+            .and_then(|server_name| config.general.sni_database(server_name))
+        {
+            debug!("Using database {} from the TLS server name", database);
+            parameters.insert(String::from("database"), database);
+        }
+
+        // Tenants use the servers of their pool, set to their own settings.
+        let tenant = match parameters
+            .get("database")
+            .and_then(|database| config.tenant(database))
+        {
+            Some((pool_name, tenant)) => {
+                debug!(
+                    "Using pool {} for tenant {}",
+                    pool_name, parameters["database"]
+                );
+                parameters.insert(String::from("database"), pool_name);
+                Some(tenant)
+            }
+            None => None,
+        };
+
+        let database = match parameters.get("database") {
+            Some(db) => db,
+            None => return Err(Error::ClientError),
+        };
+
+        let user = match parameters.get("user") {
+            Some(user) => user,
+            None => return Err(Error::ClientError),
+        };
+
+        let admin = ["pgcat", "pgbouncer"]
+            .iter()
+            .filter(|db| *db == &database)
+            .count()
+            == 1;
+
+        match auth_throttle::check(addr.ip(), user) {
+            Throttle::LockedOut => {
+                warn!(
+                    "Refusing {} for user {}, too many failed authentication attempts",
+                    addr.ip(),
+                    user
+                );
+                fatal_error_response(
+                    &mut write,
+                    &ErrorResponse::fatal(
+                        INVALID_AUTHORIZATION_SPECIFICATION,
+                        "too many failed authentication attempts",
+                    )
+                    .hint("Try again later."),
+                )
+                .await?;
+                return Err(Error::ClientError);
+            }
+
+            Throttle::Delay(delay) => tokio::time::sleep(delay).await,
+
+            Throttle::Allow => (),
+        };
+
+        // Find how the client must authenticate in the HBA rules, if there are any.
+        let hba_method = if config.hba.is_empty() {
+            None
+        } else {
+            let encryption = match tls {
+                Some(_) => "SSL on",
+                None => "SSL off",
+            };
+
+            match hba::find_rule(&config.hba, addr, tls.is_some(), database, user) {
+                Some(rule) if rule.method == "reject" => {
+                    invalid_authorization(
+                        &mut write,
+                        &format!(
+                            "pg_hba.conf rejects connection for host \"{}\", user \"{}\", database \"{}\", {}",
+                            addr, user, database, encryption
+                        ),
+                    )
+                    .await?;
+                    return Err(Error::ClientError);
+                }
+
+                Some(rule) => Some(rule.method.clone()),
+
+                None => {
+                    invalid_authorization(
+                        &mut write,
+                        &format!(
+                            "no pg_hba.conf entry for host \"{}\", user \"{}\", database \"{}\", {}",
+                            addr, user, database, encryption
+                        ),
+                    )
+                    .await?;
+                    return Err(Error::ClientError);
+                }
+            }
+        };
+
+        let (client_cert_names, server_end_point) = match tls {
+            Some(tls) => (tls.client_cert_names, Some(tls.server_end_point)),
+            None => (Vec::new(), None),
+        };
+
+        // Generate random backend ID and secret key
+        let process_id: i32 = rand::random();
+        let secret_key: i32 = rand::random();
+
+        // Failed attempts are counted against the client address and user.
+        let authenticated = async {
+            let result = if admin {
+                let correct_user = config.general.admin_username.as_str();
+                let correct_password = config.general.admin_password.as_str();
+
+                if hba_method.as_deref() != Some("trust") {
+                    md5_authenticate(
+                        &mut read,
+                        &mut write,
+                        user,
+                        correct_user,
+                        correct_password,
+                        None,
+                    )
+                    .await?;
+                }
+
+                (
+                    ConnectionPool::default(),
+                    false,
+                    generate_server_info_for_admin(),
+                    None,
+                )
+            } else {
+                let target_pool = match get_pool(database.clone(), user.clone()) {
+                    Some(pool) => {
+                        if pool.settings.require_tls && server_end_point.is_none() {
+                            tls_required(&mut write, user, database).await?;
+                            return Err(Error::TlsError);
+                        }
+
+                        let auth_type = auth_method(
+                            &hba_method,
+                            &pool.settings.auth_type,
+                            &pool.settings.trust_listen_addresses,
+                            local_addr,
+                        );
+
+                        if auth_type == "trust" {
+                            debug!("Trusting {} without authentication", user);
+                        } else if auth_type == "gss" {
+                            gss_authenticate(&mut read, &mut write, user, &pool.settings.ident_map)
+                                .await?;
+                        } else if auth_type == "pam" {
+                            pam_authenticate(
+                                &mut read,
+                                &mut write,
+                                user,
+                                &pool.settings.pam_service,
+                            )
+                            .await?;
+                        } else if auth_type == "cert" {
+                            cert_authenticate(
+                                &mut write,
+                                user,
+                                &client_cert_names,
+                                &pool.settings.ident_map,
+                            )
+                            .await?;
+                        } else {
+                            authenticate(
+                                &mut read,
+                                &mut write,
+                                user,
+                                &pool.settings.user.password,
+                                pool.settings.user.secondary_password.as_deref(),
+                                auth_type,
+                                &pool.settings.ldap,
+                                &pool.settings.jwt,
+                                server_end_point.as_deref(),
+                            )
+                            .await?;
+                        }
+
+                        pool
+                    }
+
+                    // The user isn't in the config, but we may be able to find it
+                    // in the auth file or in the database.
+                    None => {
+                        let auth_passthrough = get_auth_passthrough(database);
+
+                        let pool_config = match config.pools.get(database) {
+                            Some(pool_config)
+                                if auth_file::enabled() || auth_passthrough.is_some() =>
+                            {
+                                pool_config
+                            }
+
+                            _ => {
+                                fatal_error_response(
+                                    &mut write,
+                                    &ErrorResponse::fatal(
+                                        INVALID_CATALOG_NAME,
+                                        &format!(
+                                            "No pool configured for database: {:?}, user: {:?}",
+                                            database, user
+                                        ),
+                                    ),
+                                )
+                                .await?;
+                                return Err(Error::ClientError);
+                            }
+                        };
+
+                        if pool_config.require_tls && server_end_point.is_none() {
+                            tls_required(&mut write, user, database).await?;
+                            return Err(Error::TlsError);
+                        }
+
+                        let password = match auth_file::get_password(user) {
+                            Some(password) => Some(password),
+                            None => match auth_passthrough {
+                                Some(auth_passthrough) => auth_passthrough.fetch_hash(user).await?,
+                                None => None,
+                            },
+                        };
+
+                        // Don't let the client know the user doesn't exist: it authenticates
+                        // like the pool's users, with a password it can't know, and fails
+                        // like with a wrong one.
+                        let (password, found) = match password {
+                            Some(password) => (password, true),
+                            None => {
+                                debug!(
+                                    "User {} not found in the auth file or with auth_query",
+                                    user
+                                );
+                                (format!("{}", rand::random::<u64>()), false)
+                            }
+                        };
+
+                        let auth_type = auth_method(
+                            &hba_method,
+                            &pool_config.auth_type,
+                            &pool_config.trust_listen_addresses,
+                            local_addr,
+                        );
+
+                        if auth_type == "trust" {
+                            debug!("Trusting {} without authentication", user);
+                        } else if auth_type == "gss" {
+                            gss_authenticate(&mut read, &mut write, user, &pool_config.ident_map)
+                                .await?;
+                        } else if auth_type == "pam" {
+                            pam_authenticate(&mut read, &mut write, user, &pool_config.pam_service)
+                                .await?;
+                        } else if auth_type == "cert" {
+                            cert_authenticate(
+                                &mut write,
+                                user,
+                                &client_cert_names,
+                                &pool_config.ident_map,
+                            )
+                            .await?;
+                        } else {
+                            authenticate(
+                                &mut read,
+                                &mut write,
+                                user,
+                                &password,
+                                None,
+                                auth_type,
+                                &pool_config.ldap,
+                                &pool_config.jwt,
+                                server_end_point.as_deref(),
+                            )
+                            .await?;
+                        }
+
+                        // Only the methods without a password get here.
+                        if !found {
+                            wrong_password(&mut write, user).await?;
+                            return Err(Error::ClientError);
+                        }
+
+                        let user_info = User {
+                            username: user.clone(),
+                            password,
+                            secondary_password: None,
+                            server_username: None,
+                            server_password: None,
+                            pool_size: pool_config.default_pool_size,
+                            primary_pool_size: None,
+                            replica_pool_size: None,
+                            require_tls: false,
+                            read_only: false,
+                        };
+
+                        match ConnectionPool::add_user(
+                            database,
+                            user_info,
+                            client_server_map.clone(),
+                        )
+                        .await
+                        {
+                            Ok(pool) => pool,
+                            Err(err) => {
+                                fatal_error_response(
+                                    &mut write,
+                                    &ErrorResponse::fatal(
+                                        CONNECTION_FAILURE,
+                                        &format!(
+                                            "Could not create pool for database: {:?}, user: {:?}",
+                                            database, user
+                                        ),
+                                    ),
+                                )
+                                .await?;
+                                return Err(err);
+                            }
+                        }
+                    }
+                };
+
+                // The client's application_name can send it to another pool of its user,
+                // authenticated like the pool it asked for.
+                let (target_pool, application_name_role) =
+                    route_application_name(target_pool, parameters.get("application_name"));
+
+                let transaction_mode = target_pool.settings.pool_mode == "transaction";
+                let server_info = match target_pool.server_info().await {
+                    Ok(server_info) => server_info,
+                    Err(err) => {
+                        fatal_error_response(
+                            &mut write,
+                            &ErrorResponse::fatal(
+                                CONNECTION_FAILURE,
+                                &format!(
+                                    "Could not connect to the servers of database: {:?}, user: {:?}",
+                                    database, user
+                                ),
+                            ),
+                        )
+                        .await?;
+                        return Err(err);
+                    }
+                };
+
+                (
+                    target_pool,
+                    transaction_mode,
+                    server_info,
+                    application_name_role,
+                )
+            };
+
+            Ok::<_, Error>(result)
+        }
+        .await;
+
+        let (target_pool, transaction_mode, server_info, application_name_role) =
+            match authenticated {
+                Ok(result) => {
+                    auth_throttle::success(addr.ip(), user);
+                    result
+                }
+
+                Err(Error::ClientError) => {
+                    auth_throttle::failure(addr.ip(), user);
+                    return Err(Error::ClientError);
+                }
+
+                Err(err) => return Err(err),
+            };
+
+        debug!("Password authentication successful");
+
+        // The settings the client asked for, e.g. TimeZone or application_name, replace
+        // the servers' defaults.
+        let mut server_parameters = ServerParameters::from_messages(&server_info);
+        server_parameters.startup(&parameters, &target_pool.settings.allowed_options);
+
+        // The settings in `options` are set on the servers too, if the pool allows them.
+        if let Some(options) = parameters.get("options") {
+            let options = parse_options(options).and_then(|options| {
+                options
+                    .into_iter()
+                    .map(|(name, value)| {
+                        match target_pool
+                            .settings
+                            .allowed_options
+                            .iter()
+                            .find(|allowed| allowed.eq_ignore_ascii_case(&name))
+                        {
+                            Some(allowed) => Ok((allowed.clone(), value)),
+                            None => Err(format!("setting \"{}\" is not allowed in options", name)),
+                        }
+                    })
+                    .collect::<Result<Vec<(String, String)>, String>>()
+            });
+
+            match options {
+                Ok(options) => server_parameters.startup_options(options),
+                Err(err) => {
+                    warn!("Client {:?} has invalid options: {}", addr, err);
+                    fatal_error_response(
+                        &mut write,
+                        &ErrorResponse::fatal(INVALID_PARAMETER_VALUE, &err),
+                    )
+                    .await?;
+                    return Err(Error::ClientBadStartup);
+                }
+            }
+        }
+
+        // The tenant's settings win over the client's.
+        if let Some(ref tenant) = tenant {
+            server_parameters.startup_options(tenant.options());
+        }
+
+        auth_ok(&mut write).await?;
+        write_all(&mut write, server_parameters.messages()).await?;
+        backend_key_data(&mut write, process_id, secret_key).await?;
+        ready_for_query(&mut write).await?;
+
+        trace!("Startup OK");
+
+        // Split the read and write streams
+        // so we can control buffering.
+
+        return Ok(Client {
+            read: BufReader::new(read),
+            write: write,
+            addr,
+            buffer: BytesMut::with_capacity(8196),
+            prepared_statements: PreparedStatements::new(
+                target_pool.settings.max_prepared_statements,
+            ),
+            streaming_threshold: config.general.message_streaming_threshold,
+            max_message_size: config.general.max_message_size,
+            client_idle_timeout: config.general.client_idle_timeout,
+            reject_pipelining: target_pool.settings.reject_pipelining,
+            shutdown: SHUTDOWN.subscribe(),
+            cancel_mode: false,
+            transaction_mode: transaction_mode,
+            process_id: process_id,
+            secret_key: secret_key,
+            client_server_map: client_server_map,
+            parameters: parameters.clone(),
+            server_parameters,
+            stats: stats,
+            admin: admin,
+            last_address_id: None,
+            last_server_id: None,
+            target_pool: target_pool,
+            application_name_role,
+        });
+    }
+
+    /// Handle cancel request.
+    pub async fn cancel(
+        read: S,
+        write: T,
+        addr: ClientAddress,
+        mut bytes: BytesMut, // The rest of the startup message.
+        client_server_map: ClientServerMap,
+    ) -> Result<Client<S, T>, Error> {
+        let process_id = bytes.get_i32();
+        let secret_key = bytes.get_i32();
+        return Ok(Client {
+            read: BufReader::new(read),
+            write: write,
+            addr,
+            buffer: BytesMut::with_capacity(8196),
+            prepared_statements: PreparedStatements::new(0),
+            streaming_threshold: 0,
+            max_message_size: 0,
+            client_idle_timeout: 0,
+            reject_pipelining: false,
+            shutdown: SHUTDOWN.subscribe(),
+            cancel_mode: true,
+            transaction_mode: false,
+            process_id: process_id,
+            secret_key: secret_key,
+            client_server_map: client_server_map,
+            parameters: HashMap::new(),
+            server_parameters: ServerParameters::default(),
+            stats: get_reporter(),
+            admin: false,
+            last_address_id: None,
+            last_server_id: None,
+            target_pool: ConnectionPool::default(),
+            application_name_role: None,
+        });
+    }
+
+    /// Handle a connected and authenticated client.
+    pub async fn handle(&mut self) -> Result<(), Error> {
+        // The client wants to cancel a query it has issued previously.
+        if self.cancel_mode {
+            trace!("Sending CancelRequest");
+
+            let (process_id, secret_key, address, port, delivered) = {
+                let guard = self.client_server_map.lock();
+
+                match guard.get(&(self.process_id, self.secret_key)) {
+                    // Drop the mutex as soon as possible.
+                    // We found the server the client is using for its query
                     // that it wants to cancel.
                     Some((process_id, secret_key, address, port)) => (
                         process_id.clone(),
                         secret_key.clone(),
                         address.clone(),
                         port.clone(),
+                        Server::cancel_requested(*process_id, *secret_key),
                     ),
 
                     // The client doesn't know / got the wrong server,
@@ -445,7 +1543,17 @@ where
             // Opens a new separate connection to the server, sends the backend_id
             // and secret_key and then closes it for security reasons. No other interactions
             // take place.
-            return Ok(Server::cancel(&address, &port, process_id, secret_key).await?);
+            return Server::cancel(&address, &port, process_id, secret_key, delivered).await;
+        }
+
+        // Replication connections (`replication=true` or `replication=database`) speak
+        // their own protocol, e.g. START_REPLICATION and CopyBoth, so they get a server
+        // of their own.
+        match self.parameters.get("replication").map(|r| r.to_lowercase()) {
+            Some(replication) if !["false", "off", "no", "0"].contains(&replication.as_str()) => {
+                return self.replicate(&replication).await;
+            }
+            _ => (),
         }
 
         // The query router determines where the query is going to go,
@@ -453,6 +1561,10 @@ where
         let mut query_router = QueryRouter::new(self.target_pool.clone());
         let mut round_robin = 0;
 
+        if let Some(ref role) = self.application_name_role {
+            query_router.set_server_role(role);
+        }
+
         // Our custom protocol loop.
         // We expect the client to either start a transaction with regular queries
         // or issue commands for our sharding and server selection protocol.
@@ -467,12 +1579,22 @@ where
             // We can parse it here before grabbing a server from the pool,
             // in case the client is sending some custom protocol messages, e.g.
             // SET SHARDING KEY TO 'bigint';
-            let mut message = read_message(&mut self.read).await?;
+            match self.wait_for_message().await {
+                Wait::Message => (),
+                Wait::IdleTimeout => return self.idle_timeout().await,
+                Wait::Shutdown => return self.admin_shutdown().await,
+            };
+
+            let mut message = self.read_whole_message().await?;
 
             // Get a pool instance referenced by the most up-to-date
             // pointer. This ensures we always read the latest config
             // when starting a query.
-            let mut pool = self.target_pool.clone();
+            if self.target_pool.is_retired() {
+                self.refresh_pool(&mut query_router).await;
+            }
+
+            let pool = self.target_pool.clone();
 
             // Avoid taking a server if the client just wants to disconnect.
             if message[0] as char == 'X' {
@@ -487,6 +1609,8 @@ where
                 continue;
             }
 
+            query_router.use_hints(&message);
+
             let current_shard = query_router.shard();
 
             // Handle all custom protocol commands, if any.
@@ -507,11 +1631,14 @@ where
 
                         error_response(
                             &mut self.write,
-                            &format!(
-                                "shard {} is more than configured {}, staying on shard {}",
-                                query_router.shard(),
-                                pool.shards(),
-                                current_shard,
+                            &ErrorResponse::error(
+                                INVALID_PARAMETER_VALUE,
+                                &format!(
+                                    "shard {} is more than configured {}, staying on shard {}",
+                                    query_router.shard(),
+                                    pool.shards(),
+                                    current_shard,
+                                ),
                             ),
                         )
                         .await?;
@@ -528,8 +1655,23 @@ where
                 }
 
                 // SET SHARDING KEY TO
-                Some((Command::SetShardingKey, _)) => {
-                    custom_protocol_response_ok(&mut self.write, "SET SHARDING KEY").await?;
+                Some((Command::SetShardingKey, shard)) => {
+                    // Out of the sharding ranges, or a date without them.
+                    if shard.is_empty() {
+                        error_response(
+                            &mut self.write,
+                            &ErrorResponse::error(
+                                INVALID_PARAMETER_VALUE,
+                                &format!(
+                                    "no shard has this sharding key, staying on shard {}",
+                                    current_shard,
+                                ),
+                            ),
+                        )
+                        .await?;
+                    } else {
+                        custom_protocol_response_ok(&mut self.write, "SET SHARDING KEY").await?;
+                    }
                     continue;
                 }
 
@@ -558,6 +1700,30 @@ where
                 }
             };
 
+            // The SELECTs that don't pick a shard go to all of them, with scatter_gather.
+            if self.transaction_mode {
+                if let Some(merge) = query_router.scatter_gather(&message) {
+                    let response = scatter_gather::execute(
+                        &pool,
+                        query_router.role(),
+                        self.process_id,
+                        round_robin,
+                        message,
+                        &self.server_parameters,
+                        merge,
+                    )
+                    .await;
+
+                    round_robin += 1;
+
+                    match response {
+                        Ok(response) => write_all_half(&mut self.write, response).await?,
+                        Err(error) => error_response(&mut self.write, &error).await?,
+                    };
+                    continue;
+                }
+            }
+
             debug!("Waiting for connection from pool");
 
             // Grab a server from the pool.
@@ -576,8 +1742,59 @@ where
                 }
                 Err(err) => {
                     error!("Could not get connection from pool: {:?}", err);
-                    error_response(&mut self.write, "could not get connection from the pool")
-                        .await?;
+
+                    // The client sends the whole extended protocol sequence
+                    // before it waits for an answer, so answer at the Sync.
+                    if let 'P' | 'B' | 'D' | 'E' | 'C' = message[0] as char {
+                        while self.read_whole_message().await?[0] as char != 'S' {}
+                    }
+
+                    let config = get_config();
+                    let error = match err {
+                        Error::CheckoutTimeout => ErrorResponse::error(
+                            TOO_MANY_CONNECTIONS,
+                            "no server connection available",
+                        )
+                        .detail(&format!(
+                            "All {} server connections of the pool were in use for checkout_timeout ({}ms).",
+                            // The autoscaled pools may be smaller than pool_size for now.
+                            (0..pool.servers(query_router.shard()))
+                                .filter(|&server| query_router.role().is_none_or(|role| {
+                                    pool.address(query_router.shard(), server).role == role
+                                }))
+                                .map(|server| pool.pool_size(query_router.shard(), server))
+                                .max()
+                                .unwrap_or_default()
+                                + pool.settings.reserve_pool_size,
+                            config.general.checkout_timeout
+                        )),
+
+                        Error::QueueFull => ErrorResponse::error(
+                            TOO_MANY_CONNECTIONS,
+                            "too many clients waiting for a server connection",
+                        )
+                        .detail(&format!(
+                            "{} clients of the pool are waiting already (max_waiting_clients).",
+                            config.general.max_waiting_clients
+                        )),
+
+                        Error::TooManyConnections => ErrorResponse::error(
+                            TOO_MANY_CONNECTIONS,
+                            "no server connection available",
+                        )
+                        .detail(&format!(
+                            "All the pools have the server connections max_db_connections ({}) or max_server_connections ({}) allows.",
+                            config.general.max_db_connections,
+                            config.general.max_server_connections
+                        )),
+
+                        _ => ErrorResponse::error(
+                            TOO_MANY_CONNECTIONS,
+                            "could not get connection from the pool",
+                        ),
+                    };
+
+                    error_response(&mut self.write, &error).await?;
                     continue;
                 }
             };
@@ -609,14 +1826,81 @@ where
                 server.address()
             );
 
-            // Set application_name if any.
-            // TODO: investigate other parameters and set them too.
-            if self.parameters.contains_key("application_name") {
-                server
-                    .set_name(&self.parameters["application_name"])
+            // The server may have another client's settings, e.g. in transaction mode.
+            match server.set_parameters(&self.server_parameters).await {
+                Ok(()) => (),
+                Err(err) if server.is_bad() => return Err(err),
+
+                // The values in the client's options are invalid.
+                Err(_) => {
+                    if let 'P' | 'B' | 'D' | 'E' | 'C' = message[0] as char {
+                        while self.read_whole_message().await?[0] as char != 'S' {}
+                    }
+
+                    error_response(
+                        &mut self.write,
+                        &ErrorResponse::error(INVALID_PARAMETER_VALUE, "invalid value in options"),
+                    )
                     .await?;
+                    self.release();
+                    continue;
+                }
             }
 
+            server.set_copy_throttle(self.target_pool.settings.copy_throttle.clone());
+
+            // The writes of a sharding key moving between shards go to both, with resharding.
+            let mirror_pool = self.target_pool.clone();
+            let mut mirror = match query_router.mirror_shard() {
+                Some(shard) => match mirror_pool
+                    .get(shard, Some(Role::Primary), self.process_id, round_robin)
+                    .await
+                {
+                    Ok((mut mirror, _)) => {
+                        if let Err(err) = mirror.set_parameters(&self.server_parameters).await {
+                            warn!(
+                                "Could not set the parameters of client {:?} on mirror server {:?}: {:?}",
+                                self.addr,
+                                mirror.address(),
+                                err
+                            );
+                        }
+
+                        Some(mirror)
+                    }
+
+                    Err(err) => {
+                        error!("Could not get mirror connection from pool: {:?}", err);
+
+                        if let 'P' | 'B' | 'D' | 'E' | 'C' = message[0] as char {
+                            while self.read_whole_message().await?[0] as char != 'S' {}
+                        }
+
+                        error_response(
+                            &mut self.write,
+                            &ErrorResponse::error(
+                                TOO_MANY_CONNECTIONS,
+                                "no server connection available",
+                            )
+                            .detail(&format!(
+                                "The sharding key is moving between shards {} and {}, and its writes go to both.",
+                                address.shard, shard
+                            )),
+                        )
+                        .await?;
+                        self.release();
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            // The messages of the extended protocol sequence, for the mirror.
+            let mut mirror_messages = Vec::new();
+
+            // The mirror is checked out once, at most: a failed one isn't replaced.
+            let mut mirror_checked_out = mirror.is_some();
+
             // Transaction loop. Multiple queries can be issued by the client here.
             // The connection belongs to the client until the transaction is over,
             // or until the client disconnects if we are in session mode.
@@ -627,17 +1911,43 @@ where
                 let mut message = if message.len() == 0 {
                     trace!("Waiting for message inside transaction or in session mode");
 
-                    match read_message(&mut self.read).await {
+                    // The client may wait for the answers to the sequences it pipelined
+                    // before it sends the rest.
+                    if server.is_pipelining() && self.read.buffer().is_empty() {
+                        self.pipeline_answers(server).await?;
+                    }
+
+                    // Forward the notices and notifications the server sends while the client is idle.
+                    match self.forward_async_messages(server).await? {
+                        Wait::Message => (),
+
+                        Wait::IdleTimeout => {
+                            self.cleanup(server).await?;
+                            self.release();
+
+                            return self.idle_timeout().await;
+                        }
+
+                        // Not cleaned up, no one else gets it.
+                        Wait::Shutdown => {
+                            server.mark_bad();
+                            self.release();
+
+                            return self.admin_shutdown().await;
+                        }
+                    }
+
+                    match self.read_message(server).await {
                         Ok(message) => message,
+
+                        // The server got part of a message, it can't be cleaned up.
+                        Err(err) if server.is_bad() => return Err(err),
+
                         Err(err) => {
                             // Client disconnected inside a transaction.
                             // Clean up the server and re-use it.
                             // This prevents connection thrashing by bad clients.
-                            if server.in_transaction() {
-                                server.query("ROLLBACK").await?;
-                                server.query("DISCARD ALL").await?;
-                                server.set_name("pgcat").await?;
-                            }
+                            self.cleanup(server).await?;
 
                             return Err(err);
                         }
@@ -657,11 +1967,37 @@ where
 
                 trace!("Message: {}", code);
 
+                // Only extended protocol sequences are pipelined, anything else comes after the answers.
+                if server.is_pipelining() && !matches!(code, 'P' | 'B' | 'D' | 'E' | 'C' | 'S') {
+                    self.pipeline_answers(server).await?;
+                }
+
+                // The session, or the transaction, read first: it's mirrored from its first write on.
+                if !mirror_checked_out && matches!(code, 'Q' | 'P') {
+                    if let Some(shard) = query_router.mirror_write(&original) {
+                        mirror_checked_out = true;
+                        mirror = self
+                            .mirror_server(
+                                &mirror_pool,
+                                shard,
+                                round_robin,
+                                server.in_transaction(),
+                            )
+                            .await;
+                    }
+                }
+
                 match code {
-                    // ReadyForQuery
-                    'Q' => {
+                    // Query
+                    //
+                    // FunctionCall
+                    // Call a function by its OID, e.g. lo_open() with the large object API.
+                    // The FunctionCallResponse comes before the ReadyForQuery, like the results of a query.
+                    'Q' | 'F' => {
                         debug!("Sending query to server");
 
+                        let mirror_query = mirror.as_ref().map(|_| original.clone());
+
                         server.send(original).await?;
 
                         // Read all data the server has to offer, which can be multiple messages
@@ -683,16 +2019,30 @@ where
                             }
                         }
 
+                        // What failed on the shard isn't run on the other one either.
+                        if let (Some(mirror_server), Some(query)) = (&mut mirror, mirror_query) {
+                            if !server.failed() && !self.mirror(mirror_server, vec![query]).await {
+                                mirror = None;
+                            }
+                        }
+
                         // Report query executed statistics.
                         self.stats.query(self.process_id, address.id);
 
-                        if !server.in_transaction() {
+                        // COPY FROM STDIN is over when the client sends all the data.
+                        if !server.in_transaction() && !server.in_copy_mode() {
                             // Report transaction executed statistics.
                             self.stats.transaction(self.process_id, address.id);
 
                             // Release server back to the pool if we are in transaction mode.
-                            // If we are in session mode, we keep the server until the client disconnects.
-                            if self.transaction_mode {
+                            // If we are in session mode, we keep the server until the client disconnects,
+                            // and in transaction mode if it's listening for notifications, has cursors,
+                            // temporary tables or advisory locks.
+                            if self.transaction_mode
+                                && !server.is_listening()
+                                && !server.has_held_cursors().await?
+                                && !server.is_pinned().await?
+                            {
                                 self.stats.server_idle(server.process_id(), address.id);
                                 break;
                             }
@@ -705,10 +2055,21 @@ where
                         // connection before releasing into the pool.
                         // Pgbouncer closes the connection which leads to
                         // connection thrashing when clients misbehave.
-                        if server.in_transaction() {
-                            server.query("ROLLBACK").await?;
-                            server.query("DISCARD ALL").await?;
-                            server.set_name("pgcat").await?;
+                        // The client still disconnected normally if the server couldn't be.
+                        if let Err(err) = self.cleanup(server).await {
+                            warn!(
+                                "Could not clean up server {:?} after client {:?} disconnected: {:?}",
+                                server.address(),
+                                self.addr,
+                                err
+                            );
+                            server.mark_bad();
+                        }
+
+                        if let Some(ref mut mirror_server) = mirror {
+                            if self.cleanup(mirror_server).await.is_err() {
+                                mirror_server.mark_bad();
+                            }
                         }
 
                         self.release();
@@ -718,26 +2079,63 @@ where
 
                     // Parse
                     // The query with placeholders is here, e.g. `SELECT * FROM users WHERE email = $1 AND active = $2`.
-                    'P' => {
-                        self.buffer.put(&original[..]);
-                    }
-
+                    //
                     // Bind
                     // The placeholder's replacements are here, e.g. 'user@email.com' and 'true'
-                    'B' => {
-                        self.buffer.put(&original[..]);
-                    }
-
+                    //
                     // Describe
                     // Command a client can issue to describe a previously prepared named statement.
-                    'D' => {
-                        self.buffer.put(&original[..]);
-                    }
-
+                    //
                     // Execute
                     // Execute a prepared statement prepared in `P` and bound in `B`.
-                    'E' => {
+                    //
+                    // Close
+                    // Close a prepared statement or portal.
+                    'P' | 'B' | 'D' | 'E' | 'C' => {
+                        if mirror.is_some() {
+                            mirror_messages.push(original.clone());
+                        }
+
+                        let messages = self.prepared_statements.message(original, server);
+                        self.buffer.put(messages);
+                    }
+
+                    // Flush
+                    // Frontend wants the responses to what it sent so far,
+                    // but the sequence isn't over until the Sync, e.g. it executes
+                    // the portal again after PortalSuspended, so it keeps the server.
+                    'H' => {
+                        if mirror.is_some() {
+                            mirror_messages.push(original.clone());
+                        }
+
                         self.buffer.put(&original[..]);
+
+                        server
+                            .send_extended(
+                                self.buffer.clone(),
+                                self.prepared_statements.responses(),
+                                self.prepared_statements.max(),
+                            )
+                            .await?;
+
+                        self.buffer.clear();
+
+                        loop {
+                            let response = server.recv_flush().await?;
+
+                            match write_all_half(&mut self.write, response).await {
+                                Ok(_) => (),
+                                Err(err) => {
+                                    server.mark_bad();
+                                    return Err(err);
+                                }
+                            };
+
+                            if !server.is_data_available() {
+                                break;
+                            }
+                        }
                     }
 
                     // Sync
@@ -747,10 +2145,30 @@ where
 
                         self.buffer.put(&original[..]);
 
-                        server.send(self.buffer.clone()).await?;
+                        server
+                            .send_extended(
+                                self.buffer.clone(),
+                                self.prepared_statements.responses(),
+                                self.prepared_statements.max(),
+                            )
+                            .await?;
 
                         self.buffer.clear();
 
+                        if mirror.is_some() {
+                            mirror_messages.push(original);
+                        }
+
+                        // The client pipelined more sequences, i.e. sent them without waiting
+                        // for the answers. They're sent to the server too, and the answers are
+                        // read when the client is done, or waits for them. Unless they're
+                        // mirrored: only the ones that worked are, so they're answered first.
+                        let pipelined = self.pipelined();
+
+                        if pipelined && !self.reject_pipelining && mirror.is_none() {
+                            continue;
+                        }
+
                         // Read all data the server has to offer, which can be multiple messages
                         // buffered in 8196 bytes chunks.
                         loop {
@@ -769,15 +2187,35 @@ where
                             }
                         }
 
+                        // What failed on the shard isn't run on the other one either.
+                        if let Some(ref mut mirror_server) = mirror {
+                            let messages = std::mem::take(&mut mirror_messages);
+
+                            if !server.failed() && !self.mirror(mirror_server, messages).await {
+                                mirror = None;
+                            }
+                        }
+
+                        if pipelined && self.reject_pipelining {
+                            self.reject_pipeline(server).await?;
+                        }
+
                         // Report query executed statistics.
                         self.stats.query(self.process_id, address.id);
 
-                        if !server.in_transaction() {
+                        // COPY FROM STDIN is over when the client sends all the data.
+                        if !server.in_transaction() && !server.in_copy_mode() {
                             self.stats.transaction(self.process_id, address.id);
 
                             // Release server back to the pool if we are in transaction mode.
-                            // If we are in session mode, we keep the server until the client disconnects.
-                            if self.transaction_mode {
+                            // If we are in session mode, we keep the server until the client disconnects,
+                            // and in transaction mode if it's listening for notifications, has cursors,
+                            // temporary tables or advisory locks.
+                            if self.transaction_mode
+                                && !server.is_listening()
+                                && !server.has_held_cursors().await?
+                                && !server.is_pinned().await?
+                            {
                                 self.stats.server_idle(server.process_id(), address.id);
                                 break;
                             }
@@ -786,32 +2224,58 @@ where
 
                     // CopyData
                     'd' => {
-                        // Forward the data to the server,
-                        // don't buffer it since it can be rather large.
-                        server.send(original).await?;
+                        // Forward the data to the server as it comes,
+                        // only buffering a little of it.
+                        self.buffer.put(&original[..]);
+
+                        if self.buffer.len() >= 8196 {
+                            server.send(self.buffer.clone()).await?;
+                            self.buffer.clear();
+                        }
                     }
 
                     // CopyDone or CopyFail
                     // Copy is done, successfully or not.
                     'c' | 'f' => {
-                        server.send(original).await?;
+                        self.buffer.put(&original[..]);
 
-                        let response = server.recv().await?;
+                        server.send(self.buffer.clone()).await?;
 
-                        match write_all_half(&mut self.write, response).await {
-                            Ok(_) => (),
-                            Err(err) => {
-                                server.mark_bad();
-                                return Err(err);
+                        self.buffer.clear();
+
+                        // The answer comes with the Sync that follows.
+                        if server.in_extended_copy() {
+                            continue;
+                        }
+
+                        loop {
+                            let response = server.recv().await?;
+
+                            match write_all_half(&mut self.write, response).await {
+                                Ok(_) => (),
+                                Err(err) => {
+                                    server.mark_bad();
+                                    return Err(err);
+                                }
+                            };
+
+                            if !server.is_data_available() {
+                                break;
                             }
-                        };
+                        }
 
-                        if !server.in_transaction() {
+                        if !server.in_transaction() && !server.in_copy_mode() {
                             self.stats.transaction(self.process_id, address.id);
 
                             // Release server back to the pool if we are in transaction mode.
-                            // If we are in session mode, we keep the server until the client disconnects.
-                            if self.transaction_mode {
+                            // If we are in session mode, we keep the server until the client disconnects,
+                            // and in transaction mode if it's listening for notifications, has cursors,
+                            // temporary tables or advisory locks.
+                            if self.transaction_mode
+                                && !server.is_listening()
+                                && !server.has_held_cursors().await?
+                                && !server.is_pinned().await?
+                            {
                                 self.stats.server_idle(server.process_id(), address.id);
                                 break;
                             }
@@ -826,13 +2290,507 @@ where
                 }
             }
 
+            // The client was told about the changes to the settings.
+            self.server_parameters = server.parameters().clone();
+
             // The server is no longer bound to us, we can't cancel it's queries anymore.
+            // The ones the client cancelled already get to the server before it's checked in.
             debug!("Releasing server back into the pool");
             self.release();
+            query_router.server_released();
+            server.wait_for_cancel().await;
+
+            // The pool was replaced by a config reload, the server is closed instead, unless
+            // the new one kept its shard. Otherwise, the next client of the server doesn't
+            // get the session state this one left.
+            if self.target_pool.retires(address.shard) {
+                server.retire();
+            } else {
+                server
+                    .checkin_cleanup(
+                        &self.target_pool.settings.server_reset_query,
+                        self.target_pool.settings.server_reset_query_always,
+                    )
+                    .await?;
+            }
+
+            if let Some(ref mut mirror_server) = mirror {
+                if self.cleanup(mirror_server).await.is_err() {
+                    mirror_server.mark_bad();
+                }
+            }
+
             self.stats.client_idle(self.process_id, address.id);
         }
     }
 
+    /// Forward the messages the server sends on its own, i.e. notices, notifications
+    /// and settings changes, until the client sends one. If the client is gone,
+    /// reading its next message fails and the server is cleaned up there.
+    async fn forward_async_messages(&mut self, server: &mut Server) -> Result<Wait, Error> {
+        // The client in session mode can be idle with its server too.
+        let idle = self.client_idle_timeout > 0
+            && !server.in_transaction()
+            && !server.in_copy_mode()
+            && !server.is_unsynced();
+        let timeout = tokio::time::sleep(Duration::from_secs(self.client_idle_timeout));
+        tokio::pin!(timeout);
+
+        loop {
+            let code = tokio::select! {
+                _ = self.read.fill_buf() => return Ok(Wait::Message),
+                code = server.wait_for_message() => code,
+                _ = &mut timeout, if idle => return Ok(Wait::IdleTimeout),
+                _ = self.shutdown.wait_for(|shutdown| *shutdown) => return Ok(Wait::Shutdown),
+            };
+
+            match code {
+                // NoticeResponse, NotificationResponse, ParameterStatus
+                Some(b'N') | Some(b'A') | Some(b'S') => (),
+
+                // Anything else is the answer to the client's next messages,
+                // e.g. an error during COPY, or the server is gone.
+                _ => return Ok(Wait::Message),
+            }
+
+            let message = server.recv_async().await?;
+
+            if write_all_half(&mut self.write, message).await.is_err() {
+                return Ok(Wait::Message);
+            }
+        }
+    }
+
+    /// Wait for the idle client to send its next message, until `client_idle_timeout`
+    /// or pgcat shuts down.
+    async fn wait_for_message(&mut self) -> Wait {
+        let timeout = tokio::time::sleep(Duration::from_secs(self.client_idle_timeout));
+
+        // The read that follows gets the message, or the error.
+        tokio::select! {
+            _ = self.read.fill_buf() => Wait::Message,
+            _ = timeout, if self.client_idle_timeout > 0 => Wait::IdleTimeout,
+            _ = self.shutdown.wait_for(|shutdown| *shutdown) => Wait::Shutdown,
+        }
+    }
+
+    /// Disconnect the client that was idle for longer than `client_idle_timeout`,
+    /// like Postgres does with `idle_session_timeout`.
+    async fn idle_timeout(&mut self) -> Result<(), Error> {
+        info!(
+            "Client {:?} was idle for more than {}s, disconnecting",
+            self.addr, self.client_idle_timeout
+        );
+
+        fatal_error_response(
+            &mut self.write,
+            &ErrorResponse::fatal(
+                IDLE_SESSION_TIMEOUT,
+                "terminating connection due to idle-session timeout",
+            )
+            .detail(&format!(
+                "The client was idle for more than client_idle_timeout ({}s).",
+                self.client_idle_timeout
+            )),
+        )
+        .await
+    }
+
+    /// Disconnect the client because pgcat is shutting down, like Postgres does.
+    async fn admin_shutdown(&mut self) -> Result<(), Error> {
+        info!("Client {:?} disconnected, shutting down", self.addr);
+
+        fatal_error_response(
+            &mut self.write,
+            &ErrorResponse::fatal(
+                ADMIN_SHUTDOWN,
+                "terminating connection due to administrator command",
+            ),
+        )
+        .await
+    }
+
+    /// If the client sent more extended protocol messages without waiting for the answers.
+    fn pipelined(&self) -> bool {
+        matches!(
+            self.read.buffer().first(),
+            Some(b'P' | b'B' | b'D' | b'E' | b'C' | b'S')
+        )
+    }
+
+    /// Forward the answers to the sequences the client pipelined.
+    async fn pipeline_answers(&mut self, server: &mut Server) -> Result<(), Error> {
+        // A COPY FROM STDIN is answered when the client sends the data.
+        while server.is_pipelining() && !server.in_copy_mode() {
+            let response = server.recv().await?;
+
+            match write_all_half(&mut self.write, response).await {
+                Ok(_) => (),
+                Err(err) => {
+                    server.mark_bad();
+                    return Err(err);
+                }
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Answer the sequences the client pipelined with an error, without running them.
+    async fn reject_pipeline(&mut self, server: &Server) -> Result<(), Error> {
+        while self.pipelined() {
+            while self.read_whole_message().await?[0] as char != 'S' {}
+
+            transaction_error_response(
+                &mut self.write,
+                &ErrorResponse::error(
+                    FEATURE_NOT_SUPPORTED,
+                    "pipelining is disabled for this pool",
+                ),
+                server.transaction_status(),
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Read the code and the length of the next message from the client. A client
+    /// sending a message larger than `max_message_size`, e.g. a broken client
+    /// with a bogus length, is told so and disconnected.
+    async fn read_header(&mut self) -> Result<(u8, i32), Error> {
+        let (code, len) = read_message_header(&mut self.read).await?;
+
+        if len as usize > self.max_message_size {
+            warn!(
+                "Client {:?} sent a {} message of {} bytes, more than max_message_size",
+                self.addr, code as char, len
+            );
+
+            fatal_error_response(
+                &mut self.write,
+                &ErrorResponse::fatal(PROTOCOL_VIOLATION, "invalid message length")
+                    .detail(&format!("{} bytes is more than max_message_size", len)),
+            )
+            .await?;
+
+            return Err(Error::ProtocolSyncError);
+        }
+
+        Ok((code, len))
+    }
+
+    /// Read the next message from the client, whole.
+    async fn read_whole_message(&mut self) -> Result<BytesMut, Error> {
+        let (code, len) = self.read_header().await?;
+
+        read_message_body(&mut self.read, code, len).await
+    }
+
+    /// Read the next message from the client. CopyData messages larger than
+    /// `streaming_threshold` aren't read whole, they're sent to the server
+    /// in chunks as they come instead.
+    async fn read_message(&mut self, server: &mut Server) -> Result<BytesMut, Error> {
+        loop {
+            let (code, len) = self.read_header().await?;
+
+            if code != b'd' || len as usize <= self.streaming_threshold || !server.in_copy_mode() {
+                return read_message_body(&mut self.read, code, len).await;
+            }
+
+            debug!("Streaming {} bytes of COPY data", len);
+
+            // The COPY data buffered so far goes first.
+            let mut messages = self.buffer.split();
+            messages.put_u8(code);
+            messages.put_i32(len);
+
+            server.send(messages).await?;
+
+            let mut left = len as usize - 4;
+
+            while left > 0 {
+                let mut chunk = vec![0u8; left.min(8196)];
+
+                if self.read.read_exact(&mut chunk).await.is_err() {
+                    server.mark_bad();
+                    return Err(Error::SocketError);
+                }
+
+                left -= chunk.len();
+
+                server.send(BytesMut::from(&chunk[..])).await?;
+            }
+        }
+    }
+
+    /// Connect the replication client to the primary of the first shard,
+    /// and forward everything between them until either disconnects.
+    async fn replicate(&mut self, replication: &str) -> Result<(), Error> {
+        let mut server = match self
+            .target_pool
+            .replication(
+                0,
+                replication,
+                self.parameters
+                    .get("application_name")
+                    .map(|name| name.as_str()),
+                self.client_server_map.clone(),
+            )
+            .await
+        {
+            Ok(server) => server,
+            Err(err) => {
+                error!(
+                    "Could not connect to the primary for replication: {:?}",
+                    err
+                );
+                fatal_error_response(
+                    &mut self.write,
+                    &ErrorResponse::fatal(CONNECTION_FAILURE, "could not connect to the primary"),
+                )
+                .await?;
+                return Err(err);
+            }
+        };
+
+        // The client can cancel its queries, e.g. a slow pg_basebackup.
+        server.claim(self.process_id, self.secret_key);
+
+        debug!(
+            "Client {:?} replicating from server {:?}",
+            self.addr,
+            server.address()
+        );
+
+        server.proxy(&mut self.read, &mut self.write).await
+    }
+
+    /// Check out the primary of the shard the writes of the client are mirrored to, once its
+    /// transaction, or session, writes after reading. It starts the transaction the client is
+    /// in, so its rollback is mirrored too. Without it, the writes only go to the client's
+    /// shard: they're counted as missed.
+    async fn mirror_server<'a>(
+        &self,
+        pool: &'a ConnectionPool,
+        shard: usize,
+        round_robin: usize,
+        in_transaction: bool,
+    ) -> Option<PooledConnection<'a, ServerPool>> {
+        let result = async {
+            let (mut mirror, _) = pool
+                .get(shard, Some(Role::Primary), self.process_id, round_robin)
+                .await?;
+
+            if let Err(err) = mirror.set_parameters(&self.server_parameters).await {
+                warn!(
+                    "Could not set the parameters of client {:?} on mirror server {:?}: {:?}",
+                    self.addr,
+                    mirror.address(),
+                    err
+                );
+            }
+
+            if in_transaction {
+                mirror.query("BEGIN").await?;
+            }
+
+            Ok::<_, Error>(mirror)
+        }
+        .await;
+
+        match result {
+            Ok(mirror) => Some(mirror),
+
+            Err(err) => {
+                error!(
+                    "Could not mirror the writes of client {:?} to shard {}: {:?}",
+                    self.addr, shard, err
+                );
+
+                resharding::mirrored(&pool.settings.pool_name, true);
+                None
+            }
+        }
+    }
+
+    /// Send the messages of the client to the server of the shard its sharding key is
+    /// mirrored to as well, while it moves with the pool's resharding, and read the answers.
+    /// They don't go to the client, and a COPY is cancelled: its data isn't mirrored. The
+    /// errors are counted, and stop the keys from moving to the shard that missed the writes.
+    /// False if the server failed, it's closed.
+    async fn mirror(&mut self, mirror: &mut Server, messages: Vec<BytesMut>) -> bool {
+        let pool_name = self.target_pool.settings.pool_name.clone();
+
+        let result: Result<bool, Error> = async {
+            let mut buffer = BytesMut::new();
+
+            for message in messages {
+                match message[0] as char {
+                    'P' | 'B' | 'D' | 'E' | 'C' => {
+                        buffer.put(self.prepared_statements.message(message, mirror))
+                    }
+                    _ => buffer.put(message),
+                }
+            }
+
+            if buffer[buffer.len() - 5] == b'S' {
+                mirror
+                    .send_extended(
+                        buffer,
+                        self.prepared_statements.responses(),
+                        self.prepared_statements.max(),
+                    )
+                    .await?;
+            } else {
+                mirror.send(buffer).await?;
+            }
+
+            let mut response = BytesMut::new();
+
+            loop {
+                response.put(mirror.recv().await?);
+
+                if !mirror.is_data_available() {
+                    break;
+                }
+            }
+
+            if mirror.in_copy_mode() {
+                mirror
+                    .copy_fail("COPY isn't mirrored while resharding")
+                    .await?;
+            }
+
+            Ok(resharding::has_error(&response))
+        }
+        .await;
+
+        match result {
+            Ok(failed) => {
+                if failed {
+                    warn!(
+                        "Mirrored query of client {:?} failed on server {:?}",
+                        self.addr,
+                        mirror.address()
+                    );
+                }
+
+                resharding::mirrored(&pool_name, failed);
+                true
+            }
+
+            Err(err) => {
+                warn!(
+                    "Mirror server {:?} of client {:?} failed: {:?}",
+                    mirror.address(),
+                    self.addr,
+                    err
+                );
+
+                resharding::mirrored(&pool_name, true);
+                mirror.mark_bad();
+                false
+            }
+        }
+    }
+
+    /// Clean up the server the client is leaving, e.g. roll back its transaction, so it
+    /// goes back to the pool. Unless the server is in the middle of an extended protocol
+    /// sequence or still has answers to send: it's closed instead.
+    async fn cleanup(&self, server: &mut Server) -> Result<(), Error> {
+        // The queries the client cancelled could cancel the cleanup's.
+        self.release();
+        server.wait_for_cancel().await;
+
+        // It's closed, so whatever the client left doesn't matter.
+        if self.target_pool.retires(server.address().shard) {
+            server.retire();
+            return Ok(());
+        }
+
+        if server.is_unsynced() || server.is_pipelining() {
+            debug!(
+                "Client {:?} left server {:?} without a Sync, closing it",
+                self.addr,
+                server.address()
+            );
+            server.mark_bad();
+            return Ok(());
+        }
+
+        if server.in_copy_mode() {
+            server.copy_fail("client disconnected").await?;
+        }
+
+        if server.in_transaction() {
+            server.query("ROLLBACK").await?;
+        }
+
+        // In session mode, the client could do anything with the server, e.g. take
+        // advisory locks, not only what we can tell changed the session state.
+        server
+            .checkin_cleanup(
+                &self.target_pool.settings.server_reset_query,
+                self.target_pool.settings.server_reset_query_always || !self.transaction_mode,
+            )
+            .await?;
+
+        if server.is_listening() {
+            server.unlisten().await?;
+        }
+
+        if server.has_held_cursors().await? {
+            server.query("CLOSE ALL").await?;
+        }
+
+        if server.is_pinned().await? {
+            server.unpin().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Move to the pool that replaced the client's after a config reload. The client
+    /// stays in the old one if its user or database was removed.
+    async fn refresh_pool(&mut self, query_router: &mut QueryRouter) {
+        let database = self.parameters["database"].clone();
+        let user = self.parameters["user"].clone();
+
+        let pool = match get_pool(database.clone(), user) {
+            Some(pool) => {
+                Some(route_application_name(pool, self.parameters.get("application_name")).0)
+            }
+
+            // The pools of the users found with auth_query are created again
+            // when they connect, this one is already authenticated.
+            None if get_config()
+                .pools
+                .get(&database)
+                .map(|pool_config| pool_config.auth_query.is_some())
+                == Some(true) =>
+            {
+                ConnectionPool::add_user(
+                    &database,
+                    self.target_pool.settings.user.clone(),
+                    self.client_server_map.clone(),
+                )
+                .await
+                .ok()
+            }
+
+            None => None,
+        };
+
+        // The client doesn't have a server, so it can change pool_mode too.
+        if let Some(pool) = pool {
+            debug!("Client {:?} moved to the reloaded pool", self.addr);
+            query_router.update_pool_settings(pool.settings.clone());
+            self.transaction_mode = pool.settings.pool_mode == "transaction";
+            self.target_pool = pool;
+        }
+    }
+
     /// Release the server from the client: it can't cancel its queries anymore.
     pub fn release(&self) {
         let mut guard = self.client_server_map.lock();
@@ -857,3 +2815,79 @@ impl<S, T> Drop for Client<S, T> {
         // self.release();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_get_startup_negotiates_protocol_version() {
+        let (mut client, mut server) = duplex(1024);
+
+        let mut startup = BytesMut::new();
+        startup.put_i32(196610); // 3.2
+        startup.put_slice(b"user\0pgcat\0_pq_.test\0on\0\0");
+
+        let mut packet = BytesMut::new();
+        packet.put_i32(startup.len() as i32 + 4);
+        packet.put(startup);
+
+        client.write_all(&packet).await.unwrap();
+
+        match get_startup(&mut server).await {
+            Ok((ClientConnectionType::Startup, bytes)) => {
+                assert_eq!(parse_startup(bytes).unwrap()["user"], "pgcat");
+            }
+            _ => panic!("expected a startup"),
+        }
+
+        let mut negotiate = vec![0u8; 23];
+        client.read_exact(&mut negotiate).await.unwrap();
+
+        assert_eq!(&negotiate[..], b"v\0\0\0\x16\0\0\0\0\0\0\0\x01_pq_.test\0");
+    }
+
+    #[tokio::test]
+    async fn test_get_startup_gssenc_request() {
+        let (mut client, mut server) = duplex(1024);
+
+        client.write_i32(8).await.unwrap();
+        client.write_i32(GSSENC_REQUEST_CODE).await.unwrap();
+
+        assert!(matches!(
+            get_startup(&mut server).await,
+            Ok((ClientConnectionType::GssEncryption, _))
+        ));
+    }
+
+    #[test]
+    fn test_cert_mapped() {
+        let names = vec![String::from("alice"), String::from("alice@example.com")];
+
+        // Without an ident_map, a name must be the user.
+        assert!(cert_mapped("alice", &names, &HashMap::new()));
+        assert!(!cert_mapped("bob", &names, &HashMap::new()));
+        assert!(!cert_mapped("alice", &[], &HashMap::new()));
+
+        // With one, only the mapped users, and not the names themselves.
+        let ident_map = HashMap::from([(String::from("alice@example.com"), String::from("app"))]);
+        assert!(cert_mapped("app", &names, &ident_map));
+        assert!(!cert_mapped("alice", &names, &ident_map));
+    }
+
+    #[tokio::test]
+    async fn test_get_startup_invalid_length() {
+        for len in [-1, 0, 7, MAX_STARTUP_PACKET_LENGTH + 1] {
+            let (mut client, mut server) = duplex(1024);
+
+            client.write_i32(len).await.unwrap();
+            client.write_i32(PROTOCOL_VERSION_NUMBER).await.unwrap();
+
+            assert_eq!(
+                get_startup(&mut server).await.err(),
+                Some(Error::ClientBadStartup)
+            );
+        }
+    }
+}
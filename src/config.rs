@@ -12,7 +12,11 @@ use tokio::io::AsyncReadExt;
 use toml;
 
 use crate::errors::Error;
-use crate::tls::{load_certs, load_keys};
+use crate::pool::reload_routing;
+use crate::revocation;
+use crate::secrets;
+use crate::sharding::ShardingKey;
+use crate::tls::{self, load_certs, load_keys};
 use crate::{ClientServerMap, ConnectionPool};
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -20,6 +24,9 @@ pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 /// Globally available configuration.
 static CONFIG: Lazy<ArcSwap<Config>> = Lazy::new(|| ArcSwap::from_pointee(Config::default()));
 
+/// Held while the config is reloaded, or changed from the admin database.
+static RELOAD_LOCK: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()));
+
 /// Server role: primary or replica.
 #[derive(Clone, PartialEq, Serialize, Deserialize, Hash, std::cmp::Eq, Debug, Copy)]
 pub enum Role {
@@ -99,7 +106,16 @@ impl Address {
 pub struct User {
     pub username: String,
     pub password: String,
+    pub secondary_password: Option<String>,
+    pub server_username: Option<String>,
+    pub server_password: Option<String>,
     pub pool_size: u32,
+    pub primary_pool_size: Option<u32>,
+    pub replica_pool_size: Option<u32>,
+    #[serde(default)]
+    pub require_tls: bool,
+    #[serde(default)]
+    pub read_only: bool,
 }
 
 impl Default for User {
@@ -107,8 +123,27 @@ impl Default for User {
         User {
             username: String::from("postgres"),
             password: String::new(),
+            secondary_password: None,
+            server_username: None,
+            server_password: None,
             pool_size: 15,
+            primary_pool_size: None,
+            replica_pool_size: None,
+            require_tls: false,
+            read_only: false,
+        }
+    }
+}
+
+impl User {
+    /// The user's server connections to each server with the role: primary_pool_size
+    /// or replica_pool_size, or pool_size if it isn't set.
+    pub fn role_pool_size(&self, role: Role) -> u32 {
+        match role {
+            Role::Primary => self.primary_pool_size,
+            Role::Replica => self.replica_pool_size,
         }
+        .unwrap_or(self.pool_size)
     }
 }
 
@@ -118,13 +153,82 @@ pub struct General {
     pub host: String,
     pub port: i16,
     pub connect_timeout: u64,
+    #[serde(default = "default_connect_retries")]
+    pub connect_retries: u32,
+    #[serde(default = "default_checkout_timeout")]
+    pub checkout_timeout: u64,
+    #[serde(default)]
+    pub max_waiting_clients: usize,
+    #[serde(default)]
+    pub max_db_connections: u32,
+    #[serde(default)]
+    pub max_server_connections: u32,
+    #[serde(default)]
+    pub server_login_rate: u32,
+    pub locality: Option<String>,
     pub healthcheck_timeout: u64,
     pub ban_time: i64,
+    #[serde(default = "default_server_lifetime")]
+    pub server_lifetime: u64,
+    #[serde(default = "default_server_idle_timeout")]
+    pub server_idle_timeout: u64,
+    #[serde(default)]
+    pub client_idle_timeout: u64,
+    #[serde(default = "default_rebalance_interval")]
+    pub rebalance_interval: u64,
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
     pub autoreload: bool,
     pub tls_certificate: Option<String>,
     pub tls_private_key: Option<String>,
+    pub tls_ca_certificate: Option<String>,
     pub admin_username: String,
     pub admin_password: String,
+    pub auth_file: Option<String>,
+    pub unix_socket_dir: Option<String>,
+    pub krb_server_keyfile: Option<String>,
+    #[serde(default = "default_gssenc_mode")]
+    pub gssenc_mode: String,
+    #[serde(default = "default_auth_failure_delay")]
+    pub auth_failure_delay: u64,
+    #[serde(default = "default_auth_failure_delay_threshold")]
+    pub auth_failure_delay_threshold: u32,
+    #[serde(default = "default_auth_lockout_threshold")]
+    pub auth_lockout_threshold: u32,
+    #[serde(default = "default_auth_lockout_time")]
+    pub auth_lockout_time: u64,
+    #[serde(default = "default_server_tls_sslmode")]
+    pub server_tls_sslmode: String,
+    pub server_tls_ca_file: Option<String>,
+    pub server_tls_certificate: Option<String>,
+    pub server_tls_private_key: Option<String>,
+    #[serde(default = "default_tls_min_version")]
+    pub tls_min_version: String,
+    #[serde(default)]
+    pub tls_ciphers: Vec<String>,
+    #[serde(default = "default_tls_min_version")]
+    pub server_tls_min_version: String,
+    #[serde(default)]
+    pub server_tls_ciphers: Vec<String>,
+    #[serde(default = "default_tls_session_cache_size")]
+    pub tls_session_cache_size: usize,
+    #[serde(default = "default_tls_session_tickets")]
+    pub tls_session_tickets: bool,
+    pub tls_crl_file: Option<String>,
+    #[serde(default)]
+    pub tls_ocsp: bool,
+    pub server_tls_crl_file: Option<String>,
+    #[serde(default)]
+    pub server_tls_ocsp: bool,
+    pub sni_database_suffix: Option<String>,
+    #[serde(default = "default_message_streaming_threshold")]
+    pub message_streaming_threshold: usize,
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: usize,
+
+    // Tables go last.
+    #[serde(default)]
+    pub listeners: Vec<Listener>,
 }
 
 impl Default for General {
@@ -133,23 +237,291 @@ impl Default for General {
             host: String::from("localhost"),
             port: 5432,
             connect_timeout: 5000,
+            connect_retries: default_connect_retries(),
+            checkout_timeout: default_checkout_timeout(),
+            max_waiting_clients: 0,
+            max_db_connections: 0,
+            max_server_connections: 0,
+            server_login_rate: 0,
+            locality: None,
             healthcheck_timeout: 1000,
             ban_time: 60,
+            server_lifetime: default_server_lifetime(),
+            server_idle_timeout: default_server_idle_timeout(),
+            client_idle_timeout: 0,
+            rebalance_interval: default_rebalance_interval(),
+            drain_timeout: default_drain_timeout(),
             autoreload: false,
             tls_certificate: None,
             tls_private_key: None,
+            tls_ca_certificate: None,
             admin_username: String::from("admin"),
             admin_password: String::from("admin"),
+            auth_file: None,
+            unix_socket_dir: None,
+            krb_server_keyfile: None,
+            gssenc_mode: default_gssenc_mode(),
+            auth_failure_delay: default_auth_failure_delay(),
+            auth_failure_delay_threshold: default_auth_failure_delay_threshold(),
+            auth_lockout_threshold: default_auth_lockout_threshold(),
+            auth_lockout_time: default_auth_lockout_time(),
+            server_tls_sslmode: default_server_tls_sslmode(),
+            server_tls_ca_file: None,
+            server_tls_certificate: None,
+            server_tls_private_key: None,
+            tls_min_version: default_tls_min_version(),
+            tls_ciphers: Vec::new(),
+            server_tls_min_version: default_tls_min_version(),
+            server_tls_ciphers: Vec::new(),
+            tls_session_cache_size: default_tls_session_cache_size(),
+            tls_session_tickets: default_tls_session_tickets(),
+            tls_crl_file: None,
+            tls_ocsp: false,
+            server_tls_crl_file: None,
+            server_tls_ocsp: false,
+            sni_database_suffix: None,
+            message_streaming_threshold: default_message_streaming_threshold(),
+            max_message_size: default_max_message_size(),
+            listeners: Vec::new(),
+        }
+    }
+}
+
+/// Another address to accept clients on, e.g. a private network with its own certificate.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Listener {
+    pub host: String,
+    pub port: i16,
+
+    /// Used instead of the general TLS certificate and key.
+    pub tls_certificate: Option<String>,
+    pub tls_private_key: Option<String>,
+
+    /// Used instead of the general gssenc_mode.
+    #[serde(default)]
+    pub gssenc_mode: Option<String>,
+}
+
+impl Listener {
+    /// The TLS certificate and private key clients of this listener get, if any.
+    pub fn tls(&self, general: &General) -> Option<(String, String)> {
+        match (&self.tls_certificate, &self.tls_private_key) {
+            (Some(certificate), Some(private_key)) => {
+                Some((certificate.clone(), private_key.clone()))
+            }
+            _ => general.tls(),
+        }
+    }
+
+    /// What clients of this listener asking for GSSAPI encryption are told.
+    pub fn gssenc_mode<'a>(&'a self, general: &'a General) -> &'a str {
+        self.gssenc_mode.as_deref().unwrap_or(&general.gssenc_mode)
+    }
+}
+
+impl General {
+    /// The TLS certificate and private key clients get, if TLS is enabled.
+    pub fn tls(&self) -> Option<(String, String)> {
+        match (&self.tls_certificate, &self.tls_private_key) {
+            (Some(certificate), Some(private_key)) => {
+                Some((certificate.clone(), private_key.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    /// The database a TLS client asked for with the host name it connected to,
+    /// e.g. `tenant1` for `tenant1.db.example.com` with the suffix `.db.example.com`.
+    pub fn sni_database(&self, server_name: &str) -> Option<String> {
+        let suffix = self.sni_database_suffix.as_ref()?.to_lowercase();
+
+        match server_name.to_lowercase().strip_suffix(&suffix) {
+            Some(database) if !database.is_empty() => Some(database.to_string()),
+            _ => None,
         }
     }
 }
+
+fn default_auth_failure_delay() -> u64 {
+    0
+}
+
+fn default_auth_failure_delay_threshold() -> u32 {
+    3
+}
+
+fn default_auth_lockout_threshold() -> u32 {
+    0
+}
+
+fn default_auth_lockout_time() -> u64 {
+    300
+}
+
+fn default_tls_min_version() -> String {
+    String::from("1.2")
+}
+
+fn default_tls_session_cache_size() -> usize {
+    256
+}
+
+fn default_tls_session_tickets() -> bool {
+    true
+}
+
+fn default_gssenc_mode() -> String {
+    String::from("decline")
+}
+
+fn default_message_streaming_threshold() -> usize {
+    1024 * 1024
+}
+
+fn default_connect_retries() -> u32 {
+    2
+}
+
+fn default_checkout_timeout() -> u64 {
+    5000
+}
+
+fn default_server_lifetime() -> u64 {
+    30 * 60
+}
+
+fn default_server_idle_timeout() -> u64 {
+    10 * 60
+}
+
+fn default_rebalance_interval() -> u64 {
+    0
+}
+
+fn default_drain_timeout() -> u64 {
+    60
+}
+
+/// Postgres doesn't accept larger messages either.
+fn default_max_message_size() -> usize {
+    1024 * 1024 * 1024
+}
+
+fn default_reserve_pool_timeout() -> u64 {
+    5000
+}
+
+fn default_server_tls_sslmode() -> String {
+    String::from("disable")
+}
+
+fn default_load_balancing_mode() -> String {
+    String::from("round_robin")
+}
+
+fn default_server_reuse() -> String {
+    String::from("fifo")
+}
+
+fn default_latency_sensitivity() -> u32 {
+    1
+}
+
+fn default_sharding_mode() -> String {
+    String::from("modulo")
+}
+
+fn default_sharding_key_type() -> String {
+    String::from("bigint")
+}
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Pool {
     pub pool_mode: String,
+    #[serde(default)]
+    pub lazy: bool,
+    #[serde(default)]
+    pub autoscale: bool,
+    #[serde(default)]
+    pub min_pool_size: u32,
+    #[serde(default)]
+    pub reserve_pool_size: u32,
+    #[serde(default = "default_reserve_pool_timeout")]
+    pub reserve_pool_timeout: u64,
     pub default_role: String,
     pub query_parser_enabled: bool,
     pub primary_reads_enabled: bool,
+    #[serde(default)]
+    pub primary_reads_fallback: bool,
+    #[serde(default)]
+    pub primary_reads_after_write: u64,
+    #[serde(default = "default_load_balancing_mode")]
+    pub load_balancing_mode: String,
+    #[serde(default = "default_latency_sensitivity")]
+    pub latency_sensitivity: u32,
+    #[serde(default)]
+    pub max_replica_lag: u64,
+    #[serde(default)]
+    pub slow_start: u64,
+    #[serde(default = "default_server_reuse")]
+    pub server_reuse: String,
     pub sharding_function: String,
+    #[serde(default = "default_sharding_mode")]
+    pub sharding_mode: String,
+    pub sharding_seed: Option<u64>,
+    #[serde(default = "default_sharding_key_type")]
+    pub sharding_key_type: String,
+    #[serde(default)]
+    pub sharding_ranges: Vec<ShardingRange>,
+    pub sharding_directory: Option<ShardingDirectory>,
+    #[serde(default)]
+    pub scatter_gather: bool,
+    pub resharding: Option<Resharding>,
+    #[serde(default = "default_auth_type")]
+    pub auth_type: String,
+    #[serde(default = "default_trust_listen_addresses")]
+    pub trust_listen_addresses: Vec<String>,
+    pub auth_query: Option<String>,
+    pub auth_query_user: Option<String>,
+    pub auth_query_password: Option<String>,
+    #[serde(default)]
+    pub auth_query_cache_ttl: u64,
+    #[serde(default)]
+    pub auth_query_negative_cache_ttl: u64,
+    #[serde(default = "default_pool_size")]
+    pub default_pool_size: u32,
+    pub rds_iam_region: Option<String>,
+    pub gcp_iam_credentials: Option<String>,
+    pub server_tls_sslmode: Option<String>,
+    pub server_tls_ca_file: Option<String>,
+    pub server_tls_certificate: Option<String>,
+    pub server_tls_private_key: Option<String>,
+    #[serde(default = "default_pam_service")]
+    pub pam_service: String,
+    #[serde(default)]
+    pub require_tls: bool,
+    #[serde(default)]
+    pub max_prepared_statements: usize,
+    #[serde(default)]
+    pub reject_pipelining: bool,
+    #[serde(default)]
+    pub copy_bytes_per_second: u64,
+    #[serde(default)]
+    pub allowed_options: Vec<String>,
+    #[serde(default = "default_server_reset_query")]
+    pub server_reset_query: String,
+    #[serde(default)]
+    pub server_reset_query_always: bool,
+    #[serde(default)]
+    pub application_name_routes: Vec<ApplicationNameRoute>,
+    pub ldap: Option<Ldap>,
+    pub jwt: Option<Jwt>,
+    pub vault: Option<Vault>,
+    #[serde(default)]
+    pub ident_map: HashMap<String, String>,
+    #[serde(default)]
+    pub server_startup_parameters: HashMap<String, String>,
+    #[serde(default)]
+    pub tenants: HashMap<String, Tenant>,
     pub shards: HashMap<String, Shard>,
     pub users: HashMap<String, User>,
 }
@@ -157,21 +529,183 @@ impl Default for Pool {
     fn default() -> Pool {
         Pool {
             pool_mode: String::from("transaction"),
+            lazy: false,
+            autoscale: false,
+            min_pool_size: 0,
+            reserve_pool_size: 0,
+            reserve_pool_timeout: default_reserve_pool_timeout(),
             shards: HashMap::from([(String::from("1"), Shard::default())]),
             users: HashMap::default(),
             default_role: String::from("any"),
             query_parser_enabled: false,
             primary_reads_enabled: true,
+            primary_reads_fallback: false,
+            primary_reads_after_write: 0,
+            load_balancing_mode: default_load_balancing_mode(),
+            latency_sensitivity: default_latency_sensitivity(),
+            max_replica_lag: 0,
+            slow_start: 0,
+            server_reuse: default_server_reuse(),
             sharding_function: "pg_bigint_hash".to_string(),
+            sharding_mode: default_sharding_mode(),
+            sharding_seed: None,
+            sharding_key_type: default_sharding_key_type(),
+            sharding_ranges: Vec::new(),
+            sharding_directory: None,
+            scatter_gather: false,
+            resharding: None,
+            auth_type: default_auth_type(),
+            trust_listen_addresses: default_trust_listen_addresses(),
+            auth_query: None,
+            auth_query_user: None,
+            auth_query_password: None,
+            auth_query_cache_ttl: 0,
+            auth_query_negative_cache_ttl: 0,
+            default_pool_size: default_pool_size(),
+            rds_iam_region: None,
+            gcp_iam_credentials: None,
+            server_tls_sslmode: None,
+            server_tls_ca_file: None,
+            server_tls_certificate: None,
+            server_tls_private_key: None,
+            ldap: None,
+            jwt: None,
+            pam_service: default_pam_service(),
+            require_tls: false,
+            max_prepared_statements: 0,
+            reject_pipelining: false,
+            copy_bytes_per_second: 0,
+            allowed_options: Vec::new(),
+            server_reset_query: default_server_reset_query(),
+            server_reset_query_always: false,
+            vault: None,
+            ident_map: HashMap::default(),
+            server_startup_parameters: HashMap::default(),
+            tenants: HashMap::default(),
+            application_name_routes: Vec::new(),
+        }
+    }
+}
+
+impl Pool {
+    /// The settings the server connections start their sessions with, by name.
+    pub fn server_startup_parameters(&self) -> Vec<(String, String)> {
+        let mut parameters = self
+            .server_startup_parameters
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect::<Vec<(String, String)>>();
+
+        parameters.sort();
+        parameters
+    }
+
+    /// Give all the users, including the ones found with auth_query, pool_size server
+    /// connections to each server, whatever its role.
+    pub fn set_pool_size(&mut self, pool_size: u32) -> Result<(), Error> {
+        if pool_size == 0 || pool_size < self.min_pool_size {
+            error!(
+                "pool_size ({}) must be at least 1 and min_pool_size ({})",
+                pool_size, self.min_pool_size
+            );
+            return Err(Error::BadConfig);
+        }
+
+        for user in self.users.values_mut() {
+            user.pool_size = pool_size;
+            user.primary_pool_size = None;
+            user.replica_pool_size = None;
         }
+
+        self.default_pool_size = pool_size;
+
+        Ok(())
+    }
+
+    /// The settings are the same, but maybe for the weights and the localities of the
+    /// servers, which apply right away without re-creating the pool.
+    pub fn eq_but_routing(&self, other: &Pool) -> bool {
+        let without_routing = |pool: &Pool| {
+            let mut pool = pool.clone();
+
+            for shard in pool.shards.values_mut() {
+                shard.weights.clear();
+                shard.localities.clear();
+            }
+
+            pool
+        };
+
+        without_routing(self) == without_routing(other)
+    }
+
+    /// The settings are the same, but maybe for the shards and how the keys are sharded,
+    /// so the pool keeps the servers of the shards that didn't change, e.g. when one is added.
+    pub fn eq_but_shard_map(&self, other: &Pool) -> bool {
+        let without_shard_map = |pool: &Pool| Pool {
+            shards: HashMap::new(),
+            sharding_function: String::new(),
+            sharding_mode: String::new(),
+            sharding_seed: None,
+            sharding_key_type: String::new(),
+            sharding_ranges: Vec::new(),
+            sharding_directory: None,
+            scatter_gather: false,
+            resharding: None,
+            ..pool.clone()
+        };
+
+        without_shard_map(self) == without_shard_map(other)
     }
 }
 
+fn default_auth_type() -> String {
+    String::from("md5")
+}
+
+fn default_trust_listen_addresses() -> Vec<String> {
+    vec![
+        String::from("127.0.0.1"),
+        String::from("::1"),
+        String::from("unix"),
+    ]
+}
+
+fn default_pam_service() -> String {
+    String::from("pgcat")
+}
+
+fn default_server_reset_query() -> String {
+    String::from("DISCARD ALL")
+}
+
+fn default_pool_size() -> u32 {
+    15
+}
+
 /// Shard configuration.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Shard {
     pub database: String,
     pub servers: Vec<(String, u16, String)>,
+    pub server_tls_sslmode: Option<String>,
+    pub server_tls_ca_file: Option<String>,
+    pub server_tls_certificate: Option<String>,
+    pub server_tls_private_key: Option<String>,
+
+    /// TLS settings of some of the servers, by "host:port".
+    #[serde(default)]
+    pub server_tls: HashMap<String, ServerTls>,
+
+    /// The share of the transactions of some of the servers, by "host:port",
+    /// relative to the others. The servers not in it have a weight of 1.
+    #[serde(default)]
+    pub weights: HashMap<String, u32>,
+
+    /// Where some of the servers are, by "host:port", e.g. their availability zone.
+    /// The ones in PgCat's locality are used first.
+    #[serde(default)]
+    pub localities: HashMap<String, String>,
 }
 
 impl Default for Shard {
@@ -179,10 +713,296 @@ impl Default for Shard {
         Shard {
             servers: vec![(String::from("localhost"), 5432, String::from("primary"))],
             database: String::from("postgres"),
+            server_tls_sslmode: None,
+            server_tls_ca_file: None,
+            server_tls_certificate: None,
+            server_tls_private_key: None,
+            server_tls: HashMap::default(),
+            weights: HashMap::default(),
+            localities: HashMap::default(),
+        }
+    }
+}
+
+impl Shard {
+    /// The shard is the same, but maybe for the weights and the localities of its servers.
+    pub fn eq_but_routing(&self, other: &Shard) -> bool {
+        let without_routing = |shard: &Shard| Shard {
+            weights: HashMap::new(),
+            localities: HashMap::new(),
+            ..shard.clone()
+        };
+
+        without_routing(self) == without_routing(other)
+    }
+}
+
+/// TLS settings for connecting to the servers, set in the general settings,
+/// a pool, a shard or for one server. Unset ones are taken from the level above.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ServerTls {
+    pub server_tls_sslmode: Option<String>,
+    pub server_tls_ca_file: Option<String>,
+    pub server_tls_certificate: Option<String>,
+    pub server_tls_private_key: Option<String>,
+}
+
+impl From<&General> for ServerTls {
+    fn from(general: &General) -> ServerTls {
+        ServerTls {
+            server_tls_sslmode: Some(general.server_tls_sslmode.clone()),
+            server_tls_ca_file: general.server_tls_ca_file.clone(),
+            server_tls_certificate: general.server_tls_certificate.clone(),
+            server_tls_private_key: general.server_tls_private_key.clone(),
+        }
+    }
+}
+
+impl From<&Pool> for ServerTls {
+    fn from(pool: &Pool) -> ServerTls {
+        ServerTls {
+            server_tls_sslmode: pool.server_tls_sslmode.clone(),
+            server_tls_ca_file: pool.server_tls_ca_file.clone(),
+            server_tls_certificate: pool.server_tls_certificate.clone(),
+            server_tls_private_key: pool.server_tls_private_key.clone(),
+        }
+    }
+}
+
+impl From<&Shard> for ServerTls {
+    fn from(shard: &Shard) -> ServerTls {
+        ServerTls {
+            server_tls_sslmode: shard.server_tls_sslmode.clone(),
+            server_tls_ca_file: shard.server_tls_ca_file.clone(),
+            server_tls_certificate: shard.server_tls_certificate.clone(),
+            server_tls_private_key: shard.server_tls_private_key.clone(),
+        }
+    }
+}
+
+/// How we connect to a server: the TLS mode, the CA that signs its certificate,
+/// and the client certificate and key we log in with, if any.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ServerTlsSettings {
+    pub sslmode: String,
+    pub ca_file: Option<String>,
+    pub client_certificate: Option<(String, String)>,
+}
+
+impl ServerTlsSettings {
+    /// The most specific settings, e.g. the server's, then its shard's,
+    /// its pool's and the general ones.
+    pub fn new(levels: &[ServerTls]) -> ServerTlsSettings {
+        ServerTlsSettings {
+            sslmode: levels
+                .iter()
+                .find_map(|level| level.server_tls_sslmode.clone())
+                .unwrap_or_else(default_server_tls_sslmode),
+            ca_file: levels
+                .iter()
+                .find_map(|level| level.server_tls_ca_file.clone()),
+            client_certificate: levels.iter().find_map(|level| {
+                match (&level.server_tls_certificate, &level.server_tls_private_key) {
+                    (Some(certificate), Some(private_key)) => {
+                        Some((certificate.clone(), private_key.clone()))
+                    }
+                    _ => None,
+                }
+            }),
+        }
+    }
+}
+
+/// LDAP server used to check client passwords with `auth_type = "ldap"`.
+/// Either `prefix`/`suffix` (simple bind) or `base_dn` (search+bind) must be set.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Ldap {
+    pub url: String,
+    pub prefix: Option<String>,
+    pub suffix: Option<String>,
+    pub base_dn: Option<String>,
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    #[serde(default = "default_ldap_search_attribute")]
+    pub search_attribute: String,
+}
+
+fn default_ldap_search_attribute() -> String {
+    String::from("uid")
+}
+
+/// Send the clients whose application_name matches to another pool of their user,
+/// e.g. one with the analytics replicas, or to the primary or the replicas.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApplicationNameRoute {
+    /// A regex, e.g. `^analytics-`.
+    pub application_name: String,
+    pub pool: Option<String>,
+
+    /// Like `SET SERVER ROLE`: primary, replica, any or auto.
+    pub role: Option<String>,
+}
+
+/// Range sharding: the keys of `SET SHARDING KEY` from `from` (included) to `to`
+/// (excluded) go to the shard. Without one of them, the range has no end on that side.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShardingRange {
+    pub shard: usize,
+    pub from: Option<RangeBound>,
+    pub to: Option<RangeBound>,
+}
+
+/// A bigint, or a date like `"2024-01-31"`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum RangeBound {
+    Integer(i64),
+    Text(String),
+}
+
+impl RangeBound {
+    /// The bound as a sharding key, none if it's not a date.
+    pub fn key(&self) -> Option<ShardingKey> {
+        match self {
+            RangeBound::Integer(key) => Some(ShardingKey::Integer(*key)),
+            RangeBound::Text(date) => ShardingKey::parse(date, "date"),
+        }
+    }
+
+    /// The sharding_key_type of the keys the bound is compared with.
+    fn key_type(&self) -> &'static str {
+        match self {
+            RangeBound::Integer(_) => "bigint",
+            RangeBound::Text(_) => "date",
+        }
+    }
+}
+
+/// Lookup-table sharding: the shard of each key is listed in a file, one `key,shard`
+/// per line, or returned by a query, two columns, on the first shard's primary of a pool.
+/// Reloaded every refresh_interval seconds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShardingDirectory {
+    pub file: Option<String>,
+    pub query: Option<String>,
+
+    /// The pool the query runs on, the metadata database. This one by default.
+    pub pool: Option<String>,
+
+    #[serde(default = "default_sharding_directory_refresh_interval")]
+    pub refresh_interval: u64,
+}
+
+fn default_sharding_directory_refresh_interval() -> u64 {
+    60
+}
+
+/// Resharding: the shard map the keys are moving from, the first from_shards shards with
+/// the pool's sharding function or the from_ranges, to the pool's, and how far they are.
+/// The phase is changed with `SET resharding_phase` in the admin database too.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Resharding {
+    pub from_shards: Option<usize>,
+    #[serde(default)]
+    pub from_ranges: Vec<ShardingRange>,
+
+    /// old, dual_write, cutover or new.
+    #[serde(default = "default_resharding_phase")]
+    pub phase: String,
+}
+
+fn default_resharding_phase() -> String {
+    String::from("old")
+}
+
+/// A virtual database of a pool: its clients connect with the tenant's name and
+/// share the pool's servers, set to the tenant's schemas or role while they use them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Tenant {
+    pub search_path: Option<String>,
+
+    /// Like `SET ROLE`.
+    pub role: Option<String>,
+}
+
+impl Tenant {
+    /// The settings set on the servers, like the ones in the client's `options`.
+    pub fn options(&self) -> Vec<(String, String)> {
+        let mut options = Vec::new();
+
+        if let Some(ref search_path) = self.search_path {
+            options.push((String::from("search_path"), search_path.clone()));
+        }
+
+        if let Some(ref role) = self.role {
+            options.push((String::from("role"), role.clone()));
         }
+
+        options
     }
 }
 
+/// Identity provider used to check client tokens with `auth_type = "jwt"`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Jwt {
+    pub jwks_url: String,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    #[serde(default = "default_jwt_user_claim")]
+    pub user_claim: String,
+}
+
+fn default_jwt_user_claim() -> String {
+    String::from("sub")
+}
+
+/// Vault database secrets engine role the server credentials are leased from,
+/// instead of using the users' passwords.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Vault {
+    pub address: String,
+    pub role: String,
+    #[serde(default = "default_vault_mount")]
+    pub mount: String,
+    #[serde(default = "default_vault_auth_method")]
+    pub auth_method: String,
+    pub token: Option<String>,
+    pub role_id: Option<String>,
+    pub secret_id: Option<String>,
+}
+
+fn default_vault_mount() -> String {
+    String::from("database")
+}
+
+fn default_vault_auth_method() -> String {
+    String::from("token")
+}
+
+/// Client access rule, like a line in pg_hba.conf. The first rule that matches
+/// the connection type, database, user and client address decides how the client
+/// authenticates, or rejects it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Hba {
+    #[serde(rename = "type", default = "default_hba_type")]
+    pub connection_type: String,
+    #[serde(default = "default_hba_all")]
+    pub database: String,
+    #[serde(default = "default_hba_all")]
+    pub user: String,
+    #[serde(default = "default_hba_all")]
+    pub address: String,
+    pub method: String,
+}
+
+fn default_hba_type() -> String {
+    String::from("host")
+}
+
+fn default_hba_all() -> String {
+    String::from("all")
+}
+
 fn default_path() -> String {
     String::from("pgcat.toml")
 }
@@ -204,6 +1024,10 @@ pub struct Config {
     #[serde(default = "default_path")]
     pub path: String,
 
+    // Empty, this is a value rather than a table.
+    #[serde(default)]
+    pub hba: Vec<Hba>,
+
     pub general: General,
     pub pools: HashMap<String, Pool>,
 }
@@ -212,6 +1036,7 @@ impl Default for Config {
     fn default() -> Config {
         Config {
             path: String::from("pgcat.toml"),
+            hba: Vec::default(),
             general: General::default(),
             pools: HashMap::default(),
         }
@@ -229,10 +1054,55 @@ impl From<&Config> for std::collections::HashMap<String, String> {
                         format!("pools.{}.pool_mode", pool_name),
                         pool.pool_mode.clone(),
                     ),
+                    (format!("pools.{}.lazy", pool_name), pool.lazy.to_string()),
+                    (
+                        format!("pools.{}.autoscale", pool_name),
+                        pool.autoscale.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.min_pool_size", pool_name),
+                        pool.min_pool_size.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.reserve_pool_size", pool_name),
+                        pool.reserve_pool_size.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.reserve_pool_timeout", pool_name),
+                        pool.reserve_pool_timeout.to_string(),
+                    ),
                     (
                         format!("pools.{}.primary_reads_enabled", pool_name),
                         pool.primary_reads_enabled.to_string(),
                     ),
+                    (
+                        format!("pools.{}.primary_reads_fallback", pool_name),
+                        pool.primary_reads_fallback.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.primary_reads_after_write", pool_name),
+                        pool.primary_reads_after_write.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.load_balancing_mode", pool_name),
+                        pool.load_balancing_mode.clone(),
+                    ),
+                    (
+                        format!("pools.{}.latency_sensitivity", pool_name),
+                        pool.latency_sensitivity.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.max_replica_lag", pool_name),
+                        pool.max_replica_lag.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.slow_start", pool_name),
+                        pool.slow_start.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.server_reuse", pool_name),
+                        pool.server_reuse.clone(),
+                    ),
                     (
                         format!("pools.{}.query_parser_enabled", pool_name),
                         pool.query_parser_enabled.to_string(),
@@ -246,34 +1116,297 @@ impl From<&Config> for std::collections::HashMap<String, String> {
                         pool.sharding_function.clone(),
                     ),
                     (
-                        format!("pools.{:?}.shard_count", pool_name),
-                        pool.shards.len().to_string(),
+                        format!("pools.{}.sharding_mode", pool_name),
+                        pool.sharding_mode.clone(),
                     ),
                     (
-                        format!("pools.{:?}.users", pool_name),
-                        pool.users
-                            .iter()
-                            .map(|(_username, user)| &user.username)
-                            .cloned()
-                            .collect::<Vec<String>>()
-                            .join(", "),
+                        format!("pools.{}.sharding_seed", pool_name),
+                        match pool.sharding_seed {
+                            Some(seed) => seed.to_string(),
+                            None => String::from("default"),
+                        },
                     ),
-                ]
-            })
-            .collect();
-
-        let mut static_settings = vec![
-            ("host".to_string(), config.general.host.to_string()),
-            ("port".to_string(), config.general.port.to_string()),
-            (
-                "connect_timeout".to_string(),
-                config.general.connect_timeout.to_string(),
-            ),
-            (
-                "healthcheck_timeout".to_string(),
-                config.general.healthcheck_timeout.to_string(),
+                    (
+                        format!("pools.{}.sharding_key_type", pool_name),
+                        pool.sharding_key_type.clone(),
+                    ),
+                    (
+                        format!("pools.{}.scatter_gather", pool_name),
+                        pool.scatter_gather.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.resharding_phase", pool_name),
+                        match pool.resharding {
+                            Some(ref resharding) => resharding.phase.clone(),
+                            None => String::from("none"),
+                        },
+                    ),
+                    (
+                        format!("pools.{}.auth_type", pool_name),
+                        pool.auth_type.clone(),
+                    ),
+                    (
+                        format!("pools.{}.pam_service", pool_name),
+                        pool.pam_service.clone(),
+                    ),
+                    (
+                        format!("pools.{}.require_tls", pool_name),
+                        pool.require_tls.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.max_prepared_statements", pool_name),
+                        pool.max_prepared_statements.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.reject_pipelining", pool_name),
+                        pool.reject_pipelining.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.copy_bytes_per_second", pool_name),
+                        pool.copy_bytes_per_second.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.allowed_options", pool_name),
+                        pool.allowed_options.join(", "),
+                    ),
+                    (
+                        format!("pools.{}.server_startup_parameters", pool_name),
+                        pool.server_startup_parameters()
+                            .iter()
+                            .map(|(name, value)| format!("{}={}", name, value))
+                            .collect::<Vec<String>>()
+                            .join(", "),
+                    ),
+                    (
+                        format!("pools.{}.server_reset_query", pool_name),
+                        pool.server_reset_query.clone(),
+                    ),
+                    (
+                        format!("pools.{}.server_reset_query_always", pool_name),
+                        pool.server_reset_query_always.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.auth_query", pool_name),
+                        pool.auth_query.clone().unwrap_or_default(),
+                    ),
+                    (
+                        format!("pools.{}.auth_query_cache_ttl", pool_name),
+                        pool.auth_query_cache_ttl.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.auth_query_negative_cache_ttl", pool_name),
+                        pool.auth_query_negative_cache_ttl.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.default_pool_size", pool_name),
+                        pool.default_pool_size.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.rds_iam_region", pool_name),
+                        pool.rds_iam_region.clone().unwrap_or_default(),
+                    ),
+                    (
+                        format!("pools.{}.gcp_iam_credentials", pool_name),
+                        pool.gcp_iam_credentials.clone().unwrap_or_default(),
+                    ),
+                    (
+                        format!("pools.{}.server_tls_sslmode", pool_name),
+                        pool.server_tls_sslmode.clone().unwrap_or_default(),
+                    ),
+                    (
+                        format!("pools.{}.server_tls_certificate", pool_name),
+                        pool.server_tls_certificate.clone().unwrap_or_default(),
+                    ),
+                    (
+                        format!("pools.{:?}.shard_count", pool_name),
+                        pool.shards.len().to_string(),
+                    ),
+                    (
+                        format!("pools.{:?}.users", pool_name),
+                        pool.users
+                            .iter()
+                            .map(|(_username, user)| &user.username)
+                            .cloned()
+                            .collect::<Vec<String>>()
+                            .join(", "),
+                    ),
+                ]
+            })
+            .collect();
+
+        let mut static_settings = vec![
+            ("host".to_string(), config.general.host.to_string()),
+            ("port".to_string(), config.general.port.to_string()),
+            (
+                "connect_timeout".to_string(),
+                config.general.connect_timeout.to_string(),
+            ),
+            (
+                "connect_retries".to_string(),
+                config.general.connect_retries.to_string(),
+            ),
+            (
+                "checkout_timeout".to_string(),
+                config.general.checkout_timeout.to_string(),
+            ),
+            (
+                "max_waiting_clients".to_string(),
+                config.general.max_waiting_clients.to_string(),
+            ),
+            (
+                "max_db_connections".to_string(),
+                config.general.max_db_connections.to_string(),
+            ),
+            (
+                "max_server_connections".to_string(),
+                config.general.max_server_connections.to_string(),
+            ),
+            (
+                "server_login_rate".to_string(),
+                config.general.server_login_rate.to_string(),
+            ),
+            (
+                "locality".to_string(),
+                config.general.locality.clone().unwrap_or_default(),
+            ),
+            (
+                "healthcheck_timeout".to_string(),
+                config.general.healthcheck_timeout.to_string(),
             ),
             ("ban_time".to_string(), config.general.ban_time.to_string()),
+            (
+                "server_lifetime".to_string(),
+                config.general.server_lifetime.to_string(),
+            ),
+            (
+                "server_idle_timeout".to_string(),
+                config.general.server_idle_timeout.to_string(),
+            ),
+            (
+                "client_idle_timeout".to_string(),
+                config.general.client_idle_timeout.to_string(),
+            ),
+            (
+                "rebalance_interval".to_string(),
+                config.general.rebalance_interval.to_string(),
+            ),
+            (
+                "drain_timeout".to_string(),
+                config.general.drain_timeout.to_string(),
+            ),
+            (
+                "auth_file".to_string(),
+                config.general.auth_file.clone().unwrap_or_default(),
+            ),
+            (
+                "unix_socket_dir".to_string(),
+                config.general.unix_socket_dir.clone().unwrap_or_default(),
+            ),
+            (
+                "krb_server_keyfile".to_string(),
+                config
+                    .general
+                    .krb_server_keyfile
+                    .clone()
+                    .unwrap_or_default(),
+            ),
+            (
+                "gssenc_mode".to_string(),
+                config.general.gssenc_mode.clone(),
+            ),
+            (
+                "auth_failure_delay".to_string(),
+                config.general.auth_failure_delay.to_string(),
+            ),
+            (
+                "auth_failure_delay_threshold".to_string(),
+                config.general.auth_failure_delay_threshold.to_string(),
+            ),
+            (
+                "auth_lockout_threshold".to_string(),
+                config.general.auth_lockout_threshold.to_string(),
+            ),
+            (
+                "auth_lockout_time".to_string(),
+                config.general.auth_lockout_time.to_string(),
+            ),
+            (
+                "server_tls_sslmode".to_string(),
+                config.general.server_tls_sslmode.clone(),
+            ),
+            (
+                "sni_database_suffix".to_string(),
+                config
+                    .general
+                    .sni_database_suffix
+                    .clone()
+                    .unwrap_or_default(),
+            ),
+            (
+                "message_streaming_threshold".to_string(),
+                config.general.message_streaming_threshold.to_string(),
+            ),
+            (
+                "max_message_size".to_string(),
+                config.general.max_message_size.to_string(),
+            ),
+            (
+                "server_tls_ca_file".to_string(),
+                config
+                    .general
+                    .server_tls_ca_file
+                    .clone()
+                    .unwrap_or_default(),
+            ),
+            (
+                "tls_min_version".to_string(),
+                config.general.tls_min_version.clone(),
+            ),
+            (
+                "tls_ciphers".to_string(),
+                config.general.tls_ciphers.join(","),
+            ),
+            (
+                "tls_session_cache_size".to_string(),
+                config.general.tls_session_cache_size.to_string(),
+            ),
+            (
+                "tls_session_tickets".to_string(),
+                config.general.tls_session_tickets.to_string(),
+            ),
+            (
+                "tls_crl_file".to_string(),
+                config.general.tls_crl_file.clone().unwrap_or_default(),
+            ),
+            ("tls_ocsp".to_string(), config.general.tls_ocsp.to_string()),
+            (
+                "server_tls_crl_file".to_string(),
+                config
+                    .general
+                    .server_tls_crl_file
+                    .clone()
+                    .unwrap_or_default(),
+            ),
+            (
+                "server_tls_ocsp".to_string(),
+                config.general.server_tls_ocsp.to_string(),
+            ),
+            (
+                "server_tls_min_version".to_string(),
+                config.general.server_tls_min_version.clone(),
+            ),
+            (
+                "server_tls_ciphers".to_string(),
+                config.general.server_tls_ciphers.join(","),
+            ),
+            (
+                "server_tls_certificate".to_string(),
+                config
+                    .general
+                    .server_tls_certificate
+                    .clone()
+                    .unwrap_or_default(),
+            ),
         ];
 
         r.append(&mut static_settings);
@@ -289,7 +1422,53 @@ impl Config {
             "Healthcheck timeout: {}ms",
             self.general.healthcheck_timeout
         );
-        info!("Connection timeout: {}ms", self.general.connect_timeout);
+        info!(
+            "Connection timeout: {}ms, retries: {}",
+            self.general.connect_timeout, self.general.connect_retries
+        );
+        info!("Checkout timeout: {}ms", self.general.checkout_timeout);
+        match self.general.max_waiting_clients {
+            0 => info!("Max waiting clients: unlimited"),
+            max_waiting_clients => info!("Max waiting clients: {}", max_waiting_clients),
+        };
+        match self.general.max_db_connections {
+            0 => info!("Max database connections: unlimited"),
+            max_db_connections => info!("Max database connections: {}", max_db_connections),
+        };
+        match self.general.max_server_connections {
+            0 => info!("Max connections per server: unlimited"),
+            max_server_connections => {
+                info!("Max connections per server: {}", max_server_connections)
+            }
+        };
+        match self.general.server_login_rate {
+            0 => info!("Server login rate: unlimited"),
+            server_login_rate => info!("Server login rate: {}/s", server_login_rate),
+        };
+        match self.general.locality {
+            Some(ref locality) => info!("Locality: {}", locality),
+            None => info!("Locality: none"),
+        };
+        match self.general.server_lifetime {
+            0 => info!("Server lifetime: unlimited"),
+            server_lifetime => info!("Server lifetime: {}s", server_lifetime),
+        };
+        match self.general.server_idle_timeout {
+            0 => info!("Server idle timeout: disabled"),
+            server_idle_timeout => info!("Server idle timeout: {}s", server_idle_timeout),
+        };
+        match self.general.client_idle_timeout {
+            0 => info!("Client idle timeout: disabled"),
+            client_idle_timeout => info!("Client idle timeout: {}s", client_idle_timeout),
+        };
+        match self.general.rebalance_interval {
+            0 => info!("Rebalance interval: disabled"),
+            rebalance_interval => info!("Rebalance interval: {}s", rebalance_interval),
+        };
+        match self.general.drain_timeout {
+            0 => info!("Drain timeout: disabled"),
+            drain_timeout => info!("Drain timeout: {}s", drain_timeout),
+        };
         match self.general.tls_certificate.clone() {
             Some(tls_certificate) => {
                 info!("TLS certificate: {}", tls_certificate);
@@ -298,6 +1477,10 @@ impl Config {
                     Some(tls_private_key) => {
                         info!("TLS private key: {}", tls_private_key);
                         info!("TLS support is enabled");
+
+                        if let Some(ref tls_ca_certificate) = self.general.tls_ca_certificate {
+                            info!("TLS client certificates CA: {}", tls_ca_certificate);
+                        }
                     }
 
                     None => (),
@@ -308,6 +1491,99 @@ impl Config {
                 info!("TLS support is disabled");
             }
         };
+        if let Some(ref sni_database_suffix) = self.general.sni_database_suffix {
+            info!("TLS server name database suffix: {}", sni_database_suffix);
+        }
+        info!(
+            "TLS minimum version: {}, for servers: {}",
+            self.general.tls_min_version, self.general.server_tls_min_version
+        );
+        info!(
+            "TLS session cache size: {}, session tickets: {}",
+            self.general.tls_session_cache_size, self.general.tls_session_tickets
+        );
+        if !self.general.tls_ciphers.is_empty() {
+            info!("TLS cipher suites: {}", self.general.tls_ciphers.join(", "));
+        }
+        if let Some(ref tls_crl_file) = self.general.tls_crl_file {
+            info!("TLS client certificates CRL: {}", tls_crl_file);
+        }
+        if let Some(ref server_tls_crl_file) = self.general.server_tls_crl_file {
+            info!("Server TLS CRL: {}", server_tls_crl_file);
+        }
+        info!(
+            "TLS OCSP checks: {}, for servers: {}",
+            self.general.tls_ocsp, self.general.server_tls_ocsp
+        );
+        if !self.general.server_tls_ciphers.is_empty() {
+            info!(
+                "Server TLS cipher suites: {}",
+                self.general.server_tls_ciphers.join(", ")
+            );
+        }
+        info!("Server TLS mode: {}", self.general.server_tls_sslmode);
+        if let Some(ref server_tls_ca_file) = self.general.server_tls_ca_file {
+            info!("Server TLS CA: {}", server_tls_ca_file);
+        }
+        if let Some(ref server_tls_certificate) = self.general.server_tls_certificate {
+            info!("Server TLS client certificate: {}", server_tls_certificate);
+        }
+
+        info!(
+            "Message streaming threshold: {}",
+            self.general.message_streaming_threshold
+        );
+        info!("Max message size: {}", self.general.max_message_size);
+
+        match self.general.auth_file {
+            Some(ref auth_file) => info!("Auth file: {}", auth_file),
+            None => info!("Auth file: disabled"),
+        };
+        match self.general.unix_socket_dir {
+            Some(ref unix_socket_dir) => info!("Unix socket directory: {}", unix_socket_dir),
+            None => info!("Unix socket: disabled"),
+        };
+        if let Some(ref krb_server_keyfile) = self.general.krb_server_keyfile {
+            info!("Kerberos keytab: {}", krb_server_keyfile);
+        }
+        info!("GSSAPI encryption requests: {}", self.general.gssenc_mode);
+        match self.general.auth_failure_delay {
+            0 => info!("Failed authentication delay: disabled"),
+            delay => info!(
+                "Failed authentication delay: {}ms after {} failures",
+                delay, self.general.auth_failure_delay_threshold
+            ),
+        };
+        match self.general.auth_lockout_threshold {
+            0 => info!("Failed authentication lockout: disabled"),
+            threshold => info!(
+                "Failed authentication lockout: {}s after {} failures",
+                self.general.auth_lockout_time, threshold
+            ),
+        };
+
+        for listener in &self.general.listeners {
+            match listener.tls_certificate {
+                Some(ref tls_certificate) => info!(
+                    "Listener {}:{}, TLS certificate: {}, GSSAPI encryption requests: {}",
+                    listener.host,
+                    listener.port,
+                    tls_certificate,
+                    listener.gssenc_mode(&self.general)
+                ),
+                None => info!(
+                    "Listener {}:{}, GSSAPI encryption requests: {}",
+                    listener.host,
+                    listener.port,
+                    listener.gssenc_mode(&self.general)
+                ),
+            };
+        }
+
+        match self.hba.len() {
+            0 => info!("HBA rules: disabled"),
+            rules => info!("HBA rules: {}", rules),
+        };
 
         for (pool_name, pool_config) in &self.pools {
             info!("--- Settings for pool {} ---", pool_name);
@@ -321,34 +1597,256 @@ impl Config {
                     .to_string()
             );
             info!("Pool mode: {}", pool_config.pool_mode);
+            info!("Lazy: {}", pool_config.lazy);
+            info!("Autoscale: {}", pool_config.autoscale);
+            info!("Minimum pool size: {}", pool_config.min_pool_size);
+            info!(
+                "Reserve pool size: {}, timeout: {}ms",
+                pool_config.reserve_pool_size, pool_config.reserve_pool_timeout
+            );
             info!("Sharding function: {}", pool_config.sharding_function);
+            info!("Sharding mode: {}", pool_config.sharding_mode);
+            if let Some(sharding_seed) = pool_config.sharding_seed {
+                info!("Sharding seed: {}", sharding_seed);
+            }
+            info!("Sharding key type: {}", pool_config.sharding_key_type);
+            info!("Authentication: {}", pool_config.auth_type);
+            if pool_config.auth_type == "trust" {
+                info!(
+                    "Trusted listen addresses: {}",
+                    pool_config.trust_listen_addresses.join(", ")
+                );
+            }
+            if let Some(ref ldap) = pool_config.ldap {
+                info!("LDAP server: {}", ldap.url);
+            }
+            if let Some(ref jwt) = pool_config.jwt {
+                info!("JWKS: {}", jwt.jwks_url);
+            }
+            if pool_config.auth_type == "pam" {
+                info!("PAM service: {}", pool_config.pam_service);
+            }
+            info!("Require TLS: {}", pool_config.require_tls);
+            info!(
+                "Max prepared statements: {}",
+                pool_config.max_prepared_statements
+            );
+            info!("Reject pipelining: {}", pool_config.reject_pipelining);
+            info!(
+                "Server reset query: {}, always: {}",
+                pool_config.server_reset_query, pool_config.server_reset_query_always
+            );
+            if !pool_config.allowed_options.is_empty() {
+                info!(
+                    "Allowed options: {}",
+                    pool_config.allowed_options.join(", ")
+                );
+            }
+            if !pool_config.server_startup_parameters.is_empty() {
+                info!(
+                    "Server startup parameters: {}",
+                    pool_config
+                        .server_startup_parameters()
+                        .iter()
+                        .map(|(name, value)| format!("{}={}", name, value))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                );
+            }
+            if !pool_config.tenants.is_empty() {
+                info!("Tenants: {}", pool_config.tenants.len());
+            }
+            if !pool_config.sharding_ranges.is_empty() {
+                info!("Sharding ranges: {}", pool_config.sharding_ranges.len());
+            }
+            if let Some(ref sharding_directory) = pool_config.sharding_directory {
+                match sharding_directory.file {
+                    Some(ref file) => info!("Sharding directory: {}", file),
+                    None => info!(
+                        "Sharding directory: query on pool {}",
+                        sharding_directory.pool.as_ref().unwrap_or(pool_name)
+                    ),
+                };
+            }
+            if pool_config.scatter_gather {
+                info!("Scatter-gather: enabled");
+            }
+            if let Some(ref resharding) = pool_config.resharding {
+                match resharding.from_shards {
+                    Some(from_shards) => info!(
+                        "Resharding from {} shards, phase: {}",
+                        from_shards, resharding.phase
+                    ),
+                    None => info!(
+                        "Resharding from {} sharding ranges, phase: {}",
+                        resharding.from_ranges.len(),
+                        resharding.phase
+                    ),
+                };
+            }
+            if !pool_config.application_name_routes.is_empty() {
+                info!(
+                    "Application name routes: {}",
+                    pool_config.application_name_routes.len()
+                );
+            }
+            if pool_config.copy_bytes_per_second > 0 {
+                info!(
+                    "COPY throughput limit: {} bytes/s",
+                    pool_config.copy_bytes_per_second
+                );
+            }
+            if let Some(ref vault) = pool_config.vault {
+                info!(
+                    "Server authentication: Vault role {} at {}",
+                    vault.role, vault.address
+                );
+            }
+            if let Some(ref rds_iam_region) = pool_config.rds_iam_region {
+                info!("Server authentication: RDS IAM in {}", rds_iam_region);
+            }
+            if let Some(ref gcp_iam_credentials) = pool_config.gcp_iam_credentials {
+                info!(
+                    "Server authentication: Google Cloud IAM with {}",
+                    gcp_iam_credentials
+                );
+            }
+            if let Some(ref server_tls_sslmode) = pool_config.server_tls_sslmode {
+                info!("Server TLS mode: {}", server_tls_sslmode);
+            }
+            if let Some(ref server_tls_ca_file) = pool_config.server_tls_ca_file {
+                info!("Server TLS CA: {}", server_tls_ca_file);
+            }
+            if let Some(ref server_tls_certificate) = pool_config.server_tls_certificate {
+                info!("Server TLS client certificate: {}", server_tls_certificate);
+            }
+            match pool_config.auth_query {
+                Some(ref auth_query) => {
+                    info!("Auth query: {}", auth_query);
+                    info!("Default pool size: {}", pool_config.default_pool_size);
+                    info!(
+                        "Auth query cache TTL: {}s, for users not found: {}s",
+                        pool_config.auth_query_cache_ttl, pool_config.auth_query_negative_cache_ttl
+                    );
+                }
+
+                None => info!("Auth query: disabled"),
+            };
             info!("Primary reads: {}", pool_config.primary_reads_enabled);
+            info!(
+                "Primary reads when the replicas are down: {}",
+                pool_config.primary_reads_fallback
+            );
+            match pool_config.primary_reads_after_write {
+                0 => info!("Primary reads after a write: disabled"),
+                window => info!("Primary reads after a write: {}ms", window),
+            };
+            match pool_config.load_balancing_mode.as_ref() {
+                "latency" => info!(
+                    "Load balancing: latency, sensitivity: {}",
+                    pool_config.latency_sensitivity
+                ),
+                load_balancing_mode => info!("Load balancing: {}", load_balancing_mode),
+            };
+            match pool_config.max_replica_lag {
+                0 => info!("Maximum replica lag: unlimited"),
+                max_replica_lag => info!("Maximum replica lag: {}ms", max_replica_lag),
+            };
+            match pool_config.slow_start {
+                0 => info!("Slow start: disabled"),
+                slow_start => info!("Slow start: {}ms", slow_start),
+            };
+            info!("Server reuse: {}", pool_config.server_reuse);
             info!("Query router: {}", pool_config.query_parser_enabled);
             info!("Number of shards: {}", pool_config.shards.len());
             info!("Number of users: {}", pool_config.users.len());
         }
     }
-}
 
-/// Get a read-only instance of the configuration
-/// from anywhere in the app.
-/// ArcSwap makes this cheap and quick.
-pub fn get_config() -> Config {
-    (*(*CONFIG.load())).clone()
-}
+    /// The pool of the tenant the client connected with, and the tenant.
+    pub fn tenant(&self, database: &str) -> Option<(String, Tenant)> {
+        self.pools.iter().find_map(|(pool_name, pool)| {
+            pool.tenants
+                .get(database)
+                .map(|tenant| (pool_name.clone(), tenant.clone()))
+        })
+    }
 
-/// Parse the configuration file located at the path.
-pub async fn parse(path: &str) -> Result<(), Error> {
-    let mut contents = String::new();
-    let mut file = match File::open(path).await {
-        Ok(file) => file,
-        Err(err) => {
-            error!("Could not open '{}': {}", path, err.to_string());
-            return Err(Error::BadConfig);
-        }
-    };
+    /// How we connect to the server at host:port of a pool's shard.
+    pub fn server_tls(
+        &self,
+        pool_name: &str,
+        shard: &str,
+        host: &str,
+        port: &str,
+    ) -> ServerTlsSettings {
+        let general = ServerTls::from(&self.general);
+        let pool = self.pools.get(pool_name);
+        let shard = pool.and_then(|pool| pool.shards.get(shard));
 
-    match file.read_to_string(&mut contents).await {
+        let mut levels = Vec::new();
+
+        if let Some(shard) = shard {
+            if let Some(server) = shard.server_tls.get(&format!("{}:{}", host, port)) {
+                levels.push(server.clone());
+            }
+            levels.push(ServerTls::from(shard));
+        }
+
+        if let Some(pool) = pool {
+            levels.push(ServerTls::from(pool));
+        }
+
+        levels.push(general);
+
+        ServerTlsSettings::new(&levels)
+    }
+
+    /// The settings of a pool's shard.
+    pub fn shard(&self, pool_name: &str, shard: usize) -> Option<&Shard> {
+        self.pools
+            .get(pool_name)
+            .and_then(|pool| pool.shards.get(&shard.to_string()))
+    }
+
+    /// How we connect to the server at host:port, in the first pool and shard
+    /// that has it, for when we only know its address.
+    pub fn find_server_tls(&self, host: &str, port: &str) -> ServerTlsSettings {
+        for (pool_name, pool) in &self.pools {
+            for (shard_name, shard) in &pool.shards {
+                if shard
+                    .servers
+                    .iter()
+                    .any(|server| server.0 == host && server.1.to_string() == port)
+                {
+                    return self.server_tls(pool_name, shard_name, host, port);
+                }
+            }
+        }
+
+        ServerTlsSettings::new(&[ServerTls::from(&self.general)])
+    }
+}
+
+/// Get a read-only instance of the configuration
+/// from anywhere in the app.
+/// ArcSwap makes this cheap and quick.
+pub fn get_config() -> Config {
+    (*(*CONFIG.load())).clone()
+}
+
+/// Parse the configuration file located at the path.
+pub async fn parse(path: &str) -> Result<(), Error> {
+    let mut contents = String::new();
+    let mut file = match File::open(path).await {
+        Ok(file) => file,
+        Err(err) => {
+            error!("Could not open '{}': {}", path, err.to_string());
+            return Err(Error::BadConfig);
+        }
+    };
+
+    match file.read_to_string(&mut contents).await {
         Ok(_) => (),
         Err(err) => {
             error!("Could not read config file: {}", err.to_string());
@@ -364,6 +1862,8 @@ pub async fn parse(path: &str) -> Result<(), Error> {
         }
     };
 
+    secrets::decrypt_config(&mut config).await?;
+
     // Validate TLS!
     match config.general.tls_certificate.clone() {
         Some(tls_certificate) => {
@@ -379,48 +1879,668 @@ pub async fn parse(path: &str) -> Result<(), Error> {
                             }
                         },
 
-                        None => {
-                            error!("tls_certificate is set, but the tls_private_key is not");
-                            return Err(Error::BadConfig);
-                        }
-                    };
-                }
+                        None => {
+                            error!("tls_certificate is set, but the tls_private_key is not");
+                            return Err(Error::BadConfig);
+                        }
+                    };
+                }
+
+                Err(err) => {
+                    error!("tls_certificate is incorrectly configured: {:?}", err);
+                    return Err(Error::BadConfig);
+                }
+            }
+        }
+        None => (),
+    };
+
+    tls::protocols(&config.general.tls_min_version, &config.general.tls_ciphers)?;
+    tls::protocols(
+        &config.general.server_tls_min_version,
+        &config.general.server_tls_ciphers,
+    )?;
+
+    let general_tls = ServerTls::from(&config.general);
+    validate_server_tls(std::slice::from_ref(&general_tls), "general settings")?;
+
+    for (pool_name, pool) in &config.pools {
+        let pool_tls = ServerTls::from(pool);
+        validate_server_tls(
+            &[pool_tls.clone(), general_tls.clone()],
+            &format!("pool {} settings", pool_name),
+        )?;
+
+        for (shard_name, shard) in &pool.shards {
+            let shard_tls = ServerTls::from(shard);
+            validate_server_tls(
+                &[shard_tls.clone(), pool_tls.clone(), general_tls.clone()],
+                &format!("shard {} of pool {} settings", shard_name, pool_name),
+            )?;
+
+            for (setting, server) in shard
+                .weights
+                .keys()
+                .map(|server| ("A weight", server))
+                .chain(shard.localities.keys().map(|server| ("A locality", server)))
+            {
+                if !shard
+                    .servers
+                    .iter()
+                    .any(|(host, port, _)| &format!("{}:{}", host, port) == server)
+                {
+                    error!(
+                        "{} is set for {}, which is not a server of shard {} of pool {}",
+                        setting, server, shard_name, pool_name
+                    );
+                    return Err(Error::BadConfig);
+                }
+            }
+
+            for (server, server_tls) in &shard.server_tls {
+                if !shard
+                    .servers
+                    .iter()
+                    .any(|(host, port, _)| &format!("{}:{}", host, port) == server)
+                {
+                    error!(
+                        "server_tls is set for {}, which is not a server of shard {} of pool {}",
+                        server, shard_name, pool_name
+                    );
+                    return Err(Error::BadConfig);
+                }
+
+                validate_server_tls(
+                    &[
+                        server_tls.clone(),
+                        shard_tls.clone(),
+                        pool_tls.clone(),
+                        general_tls.clone(),
+                    ],
+                    &format!(
+                        "server {} of shard {} of pool {} settings",
+                        server, shard_name, pool_name
+                    ),
+                )?;
+            }
+        }
+    }
+
+    if !["decline", "refuse"].contains(&config.general.gssenc_mode.as_str()) {
+        error!(
+            "gssenc_mode must be 'decline' or 'refuse', got: '{}'",
+            config.general.gssenc_mode
+        );
+        return Err(Error::BadConfig);
+    }
+
+    for listener in &config.general.listeners {
+        if !["decline", "refuse"].contains(&listener.gssenc_mode(&config.general)) {
+            error!(
+                "gssenc_mode of listener {}:{} must be 'decline' or 'refuse', got: '{}'",
+                listener.host,
+                listener.port,
+                listener.gssenc_mode(&config.general)
+            );
+            return Err(Error::BadConfig);
+        }
+
+        match (&listener.tls_certificate, &listener.tls_private_key) {
+            (Some(tls_certificate), Some(tls_private_key)) => {
+                if let Err(err) = load_certs(Path::new(tls_certificate)) {
+                    error!(
+                        "tls_certificate of listener {}:{} is incorrectly configured: {:?}",
+                        listener.host, listener.port, err
+                    );
+                    return Err(Error::BadConfig);
+                }
+
+                if let Err(err) = load_keys(Path::new(tls_private_key)) {
+                    error!(
+                        "tls_private_key of listener {}:{} is incorrectly configured: {:?}",
+                        listener.host, listener.port, err
+                    );
+                    return Err(Error::BadConfig);
+                }
+            }
+
+            (None, None) => (),
+
+            _ => {
+                error!(
+                    "Listener {}:{} must set both tls_certificate and tls_private_key, or neither",
+                    listener.host, listener.port
+                );
+                return Err(Error::BadConfig);
+            }
+        };
+    }
+
+    if let Some(ref tls_ca_certificate) = config.general.tls_ca_certificate {
+        if config.general.tls_certificate.is_none()
+            && config
+                .general
+                .listeners
+                .iter()
+                .all(|listener| listener.tls_certificate.is_none())
+        {
+            error!("tls_ca_certificate is set, but tls_certificate is not");
+            return Err(Error::BadConfig);
+        }
+
+        if let Err(err) = load_certs(Path::new(tls_ca_certificate)) {
+            error!("tls_ca_certificate is incorrectly configured: {:?}", err);
+            return Err(Error::BadConfig);
+        }
+    }
+
+    // Only client certificates signed by our CA are checked.
+    if config.general.tls_ca_certificate.is_none() {
+        if config.general.tls_crl_file.is_some() {
+            error!("tls_crl_file is set, but tls_ca_certificate is not");
+            return Err(Error::BadConfig);
+        }
+
+        if config.general.tls_ocsp {
+            error!("tls_ocsp is enabled, but tls_ca_certificate is not set");
+            return Err(Error::BadConfig);
+        }
+    }
+
+    for crl_file in [
+        &config.general.tls_crl_file,
+        &config.general.server_tls_crl_file,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        revocation::load(crl_file)?;
+    }
+
+    for rule in &config.hba {
+        match rule.connection_type.as_ref() {
+            "local" => (),
+            "host" => (),
+            "hostssl" => (),
+            "hostnossl" => (),
+            other => {
+                error!(
+                    "Supported HBA rule types are: 'local', 'host', 'hostssl', 'hostnossl', got: '{}'",
+                    other
+                );
+                return Err(Error::BadConfig);
+            }
+        };
+
+        match rule.method.as_ref() {
+            "trust" => (),
+            "reject" => (),
+            "md5" => (),
+            "scram-sha-256" => (),
+            "cert" => {
+                if config.general.tls_ca_certificate.is_none() {
+                    error!("HBA rule method is 'cert', but tls_ca_certificate is not set");
+                    return Err(Error::BadConfig);
+                }
+            }
+            "gss" => {
+                if config.general.krb_server_keyfile.is_none() {
+                    error!("HBA rule method is 'gss', but krb_server_keyfile is not set");
+                    return Err(Error::BadConfig);
+                }
+            }
+            "pam" => (),
+            other => {
+                error!(
+                    "Supported HBA rule methods are: 'trust', 'reject', 'md5', 'scram-sha-256', 'cert', 'gss', 'pam', got: '{}'",
+                    other
+                );
+                return Err(Error::BadConfig);
+            }
+        };
+
+        if rule.connection_type == "local" && rule.address != "all" {
+            error!(
+                "HBA rules of type 'local' are for the unix socket and have no address, got: '{}'",
+                rule.address
+            );
+            return Err(Error::BadConfig);
+        }
+
+        if rule.address != "all" && crate::hba::parse_address(&rule.address).is_none() {
+            error!(
+                "HBA rule address must be 'all' or an IP address with an optional prefix length, e.g. '10.0.0.0/8', got: '{}'",
+                rule.address
+            );
+            return Err(Error::BadConfig);
+        }
+    }
+
+    for (pool_name, pool) in &config.pools {
+        match pool.pool_mode.as_ref() {
+            "session" => (),
+            "transaction" => (),
+            _ => {
+                error!(
+                    "Supported pool modes are: 'session', 'transaction', got: '{}' in pool {} settings",
+                    pool.pool_mode,
+                    pool_name
+                );
+                return Err(Error::BadConfig);
+            }
+        };
+
+        for user in pool.users.values() {
+            let pool_size = user
+                .role_pool_size(Role::Primary)
+                .min(user.role_pool_size(Role::Replica));
+
+            if pool.min_pool_size > pool_size {
+                error!(
+                    "min_pool_size ({}) can't be more than the pool_size of user {} ({}) in pool {} settings",
+                    pool.min_pool_size, user.username, pool_size, pool_name
+                );
+                return Err(Error::BadConfig);
+            }
+
+            // Its queries would have nowhere to go.
+            if user.read_only {
+                if let Some((shard, _)) = pool
+                    .shards
+                    .iter()
+                    .find(|(_, shard)| !shard.servers.iter().any(|server| server.2 == "replica"))
+                {
+                    error!(
+                        "User {} is read_only, but shard {} of pool {} has no replica",
+                        user.username, shard, pool_name
+                    );
+                    return Err(Error::BadConfig);
+                }
+            }
+        }
+
+        if pool.auth_query.is_some() && pool.min_pool_size > pool.default_pool_size {
+            error!(
+                "min_pool_size ({}) can't be more than default_pool_size ({}) in pool {} settings",
+                pool.min_pool_size, pool.default_pool_size, pool_name
+            );
+            return Err(Error::BadConfig);
+        }
+
+        // The min_pool_size connections are opened when the pool is created.
+        if pool.lazy && pool.min_pool_size > 0 {
+            error!(
+                "min_pool_size ({}) can't be used with lazy in pool {} settings",
+                pool.min_pool_size, pool_name
+            );
+            return Err(Error::BadConfig);
+        }
+
+        match pool.sharding_function.as_ref() {
+            "pg_bigint_hash" => (),
+            "sha1" => (),
+            "murmur" => (),
+            _ => {
+                error!(
+                    "Supported sharding functions are: 'pg_bigint_hash', 'sha1', 'murmur', got: '{}' in pool {} settings",
+                    pool.sharding_function,
+                    pool_name
+                );
+                return Err(Error::BadConfig);
+            }
+        };
+
+        match pool.sharding_mode.as_ref() {
+            "modulo" => (),
+            "range" => (),
+            _ => {
+                error!(
+                    "Supported sharding modes are: 'modulo', 'range', got: '{}' in pool {} settings",
+                    pool.sharding_mode,
+                    pool_name
+                );
+                return Err(Error::BadConfig);
+            }
+        };
+
+        match pool.sharding_key_type.as_ref() {
+            "bigint" => (),
+            "date" => (),
+            "uuid" => (),
+            "text" => (),
+            _ => {
+                error!(
+                    "Supported sharding key types are: 'bigint', 'date', 'uuid', 'text', got: '{}' in pool {} settings",
+                    pool.sharding_key_type,
+                    pool_name
+                );
+                return Err(Error::BadConfig);
+            }
+        };
+
+        // SHA1 has no seed to change.
+        if pool.sharding_seed.is_some() && pool.sharding_function == "sha1" {
+            error!(
+                "sharding_seed can't be set with the 'sha1' sharding function in pool {} settings",
+                pool_name
+            );
+            return Err(Error::BadConfig);
+        }
+
+        match pool.load_balancing_mode.as_ref() {
+            "round_robin" => (),
+            "least_outstanding_requests" => (),
+            "latency" => (),
+            "power_of_two_choices" => (),
+            _ => {
+                error!(
+                    "Supported load balancing modes are: 'round_robin', 'least_outstanding_requests', 'latency', 'power_of_two_choices', got: '{}' in pool {} settings",
+                    pool.load_balancing_mode,
+                    pool_name
+                );
+                return Err(Error::BadConfig);
+            }
+        };
+
+        match pool.server_reuse.as_ref() {
+            "fifo" => (),
+            "lifo" => (),
+            _ => {
+                error!(
+                    "Supported server reuse orders are: 'fifo', 'lifo', got: '{}' in pool {} settings",
+                    pool.server_reuse,
+                    pool_name
+                );
+                return Err(Error::BadConfig);
+            }
+        };
+
+        // The servers aren't remembered for longer.
+        if pool.slow_start > 3_600_000 {
+            error!(
+                "slow_start can be at most 3600000 (an hour), got: {} in pool {} settings",
+                pool.slow_start, pool_name
+            );
+            return Err(Error::BadConfig);
+        }
+
+        match pool.auth_type.as_ref() {
+            "md5" => (),
+            "trust" => {
+                for address in &pool.trust_listen_addresses {
+                    if address != "unix" && address.parse::<std::net::IpAddr>().is_err() {
+                        error!(
+                            "trust_listen_addresses must be IP addresses or 'unix', got: '{}' in pool {} settings",
+                            address, pool_name
+                        );
+                        return Err(Error::BadConfig);
+                    }
+                }
+            }
+            "scram-sha-256" => (),
+            "cert" => {
+                if config.general.tls_ca_certificate.is_none() {
+                    error!(
+                        "auth_type is 'cert', but tls_ca_certificate is not set, in pool {} settings",
+                        pool_name
+                    );
+                    return Err(Error::BadConfig);
+                }
+            }
+            "gss" => {
+                if config.general.krb_server_keyfile.is_none() {
+                    error!(
+                        "auth_type is 'gss', but krb_server_keyfile is not set, in pool {} settings",
+                        pool_name
+                    );
+                    return Err(Error::BadConfig);
+                }
+            }
+            "pam" => (),
+            "jwt" => {
+                if pool.jwt.is_none() {
+                    error!(
+                        "auth_type is 'jwt', but the jwt section is missing in pool {} settings",
+                        pool_name
+                    );
+                    return Err(Error::BadConfig);
+                }
+            }
+            "ldap" => match pool.ldap {
+                Some(ref ldap) => {
+                    let simple_bind = ldap.prefix.is_some() || ldap.suffix.is_some();
+
+                    if simple_bind == ldap.base_dn.is_some() {
+                        error!(
+                            "LDAP needs either prefix/suffix or base_dn, but not both, in pool {} settings",
+                            pool_name
+                        );
+                        return Err(Error::BadConfig);
+                    }
+                }
+
+                None => {
+                    error!(
+                        "auth_type is 'ldap', but the ldap section is missing in pool {} settings",
+                        pool_name
+                    );
+                    return Err(Error::BadConfig);
+                }
+            },
+            other => {
+                error!(
+                    "Supported auth types are: 'md5', 'scram-sha-256', 'ldap', 'cert', 'jwt', 'gss', 'pam', 'trust', got: '{}' in pool {} settings",
+                    other, pool_name
+                );
+                return Err(Error::BadConfig);
+            }
+        };
+
+        // Nobody could connect if TLS isn't enabled on any listener.
+        let require_tls = pool.require_tls || pool.users.values().any(|user| user.require_tls);
+        let tls_enabled = config.general.tls().is_some()
+            || config
+                .general
+                .listeners
+                .iter()
+                .any(|listener| listener.tls(&config.general).is_some());
+
+        if require_tls && !tls_enabled {
+            error!(
+                "require_tls is set, but tls_certificate is not, in pool {} settings",
+                pool_name
+            );
+            return Err(Error::BadConfig);
+        }
+
+        if let Some(ref vault) = pool.vault {
+            match vault.auth_method.as_ref() {
+                "token" => (),
+                "approle" => {
+                    if vault.role_id.is_none() || vault.secret_id.is_none() {
+                        error!(
+                            "Vault auth_method is 'approle', but role_id or secret_id is not set in pool {} settings",
+                            pool_name
+                        );
+                        return Err(Error::BadConfig);
+                    }
+                }
+                other => {
+                    error!(
+                        "Supported Vault auth methods are: 'token', 'approle', got: '{}' in pool {} settings",
+                        other, pool_name
+                    );
+                    return Err(Error::BadConfig);
+                }
+            };
+        }
+
+        if pool.rds_iam_region.is_some() && pool.gcp_iam_credentials.is_some() {
+            error!(
+                "rds_iam_region and gcp_iam_credentials can't both be set in pool {} settings",
+                pool_name
+            );
+            return Err(Error::BadConfig);
+        }
+
+        if pool.auth_query.is_some()
+            && (pool.auth_query_user.is_none() || pool.auth_query_password.is_none())
+        {
+            error!(
+                "auth_query is set, but auth_query_user or auth_query_password is not in pool {} settings",
+                pool_name
+            );
+            return Err(Error::BadConfig);
+        }
+
+        match pool.default_role.as_ref() {
+            "any" => (),
+            "primary" => (),
+            "replica" => (),
+            other => {
+                error!(
+                    "Query router default_role must be 'primary', 'replica', or 'any', got: '{}'",
+                    other
+                );
+                return Err(Error::BadConfig);
+            }
+        };
+
+        for (name, tenant) in &pool.tenants {
+            if config.pools.contains_key(name) || name == "pgcat" || name == "pgbouncer" {
+                error!(
+                    "Tenant {} in pool {} settings has the name of a database",
+                    name, pool_name
+                );
+                return Err(Error::BadConfig);
+            }
+
+            if let Some((other, _)) = config.tenant(name).filter(|(other, _)| other != pool_name) {
+                error!(
+                    "Tenant {} is in both pool {} and pool {} settings",
+                    name, pool_name, other
+                );
+                return Err(Error::BadConfig);
+            }
+
+            if tenant.options().is_empty() {
+                error!(
+                    "Tenant {} needs a search_path or a role in pool {} settings",
+                    name, pool_name
+                );
+                return Err(Error::BadConfig);
+            }
+        }
+
+        // PgCat sets these itself.
+        for name in pool.server_startup_parameters.keys() {
+            match name.as_str() {
+                "user" | "database" | "replication" | "application_name" => {
+                    error!(
+                        "server_startup_parameters can't set {} in pool {} settings",
+                        name, pool_name
+                    );
+                    return Err(Error::BadConfig);
+                }
+                _ => (),
+            };
+        }
+
+        validate_sharding_ranges(
+            &pool.sharding_ranges,
+            pool.shards.len(),
+            &pool.sharding_key_type,
+            &format!("pool {} settings", pool_name),
+        )?;
+
+        if let Some(ref sharding_directory) = pool.sharding_directory {
+            if !pool.sharding_ranges.is_empty() {
+                error!(
+                    "sharding_directory and sharding_ranges can't both be set in pool {} settings",
+                    pool_name
+                );
+                return Err(Error::BadConfig);
+            }
+
+            if sharding_directory.file.is_some() == sharding_directory.query.is_some() {
+                error!(
+                    "sharding_directory needs either a file or a query in pool {} settings",
+                    pool_name
+                );
+                return Err(Error::BadConfig);
+            }
 
-                Err(err) => {
-                    error!("tls_certificate is incorrectly configured: {:?}", err);
+            match sharding_directory.pool {
+                Some(ref target) if !config.pools.contains_key(target) => {
+                    error!(
+                        "sharding_directory query is on pool {}, which doesn't exist, in pool {} settings",
+                        target, pool_name
+                    );
                     return Err(Error::BadConfig);
                 }
-            }
-        }
-        None => (),
-    };
+                _ => (),
+            };
 
-    for (pool_name, pool) in &config.pools {
-        match pool.sharding_function.as_ref() {
-            "pg_bigint_hash" => (),
-            "sha1" => (),
-            _ => {
+            if sharding_directory.refresh_interval == 0 {
                 error!(
-                    "Supported sharding functions are: 'pg_bigint_hash', 'sha1', got: '{}' in pool {} settings",
-                    pool.sharding_function,
+                    "sharding_directory refresh_interval can't be 0 in pool {} settings",
                     pool_name
                 );
                 return Err(Error::BadConfig);
             }
-        };
+        }
 
-        match pool.default_role.as_ref() {
-            "any" => (),
-            "primary" => (),
-            "replica" => (),
-            other => {
+        if let Some(ref resharding) = pool.resharding {
+            validate_resharding(resharding, pool, pool_name)?;
+        }
+
+        // In session mode, the client keeps the server of one shard.
+        if pool.scatter_gather && pool.pool_mode != "transaction" {
+            error!(
+                "scatter_gather needs pool_mode transaction in pool {} settings",
+                pool_name
+            );
+            return Err(Error::BadConfig);
+        }
+
+        for route in &pool.application_name_routes {
+            if let Err(err) = regex::Regex::new(&route.application_name) {
                 error!(
-                    "Query router default_role must be 'primary', 'replica', or 'any', got: '{}'",
-                    other
+                    "application_name route '{}' is not a valid regex in pool {} settings: {}",
+                    route.application_name, pool_name, err
                 );
                 return Err(Error::BadConfig);
             }
-        };
+
+            match route.pool {
+                Some(ref target) if !config.pools.contains_key(target) => {
+                    error!(
+                        "application_name route '{}' is to pool {}, which doesn't exist, in pool {} settings",
+                        route.application_name, target, pool_name
+                    );
+                    return Err(Error::BadConfig);
+                }
+                None if route.role.is_none() => {
+                    error!(
+                        "application_name route '{}' needs a pool or a role in pool {} settings",
+                        route.application_name, pool_name
+                    );
+                    return Err(Error::BadConfig);
+                }
+                _ => (),
+            };
+
+            match route.role.as_deref() {
+                None | Some("primary") | Some("replica") | Some("any") | Some("auto") => (),
+                Some(other) => {
+                    error!(
+                        "application_name route role must be 'primary', 'replica', 'any', or 'auto', got: '{}' in pool {} settings",
+                        other, pool_name
+                    );
+                    return Err(Error::BadConfig);
+                }
+            };
+        }
 
         for shard in &pool.shards {
             // We use addresses as unique identifiers,
@@ -487,7 +2607,264 @@ pub async fn parse(path: &str) -> Result<(), Error> {
     Ok(())
 }
 
+/// Change the configuration without reading the file, e.g. from the admin database.
+/// The file wins again on the next reload.
+pub fn set_config(config: Config) {
+    CONFIG.store(Arc::new(config));
+}
+
+/// Check the server TLS settings of one level, the first one,
+/// with the ones above it.
+fn validate_server_tls(levels: &[ServerTls], settings: &str) -> Result<(), Error> {
+    let level = &levels[0];
+    let tls = ServerTlsSettings::new(levels);
+
+    match tls.sslmode.as_ref() {
+        "disable" | "prefer" | "require" => (),
+        "verify-ca" | "verify-full" => {
+            if tls.ca_file.is_none() {
+                error!(
+                    "server_tls_sslmode is '{}', but server_tls_ca_file is not set, in {}",
+                    tls.sslmode, settings
+                );
+                return Err(Error::BadConfig);
+            }
+        }
+        other => {
+            error!(
+                "Supported server_tls_sslmode values are: 'disable', 'prefer', 'require', 'verify-ca', 'verify-full', got: '{}' in {}",
+                other, settings
+            );
+            return Err(Error::BadConfig);
+        }
+    };
+
+    if let Some(ref server_tls_ca_file) = level.server_tls_ca_file {
+        if let Err(err) = load_certs(Path::new(server_tls_ca_file)) {
+            error!(
+                "server_tls_ca_file is incorrectly configured: {:?}, in {}",
+                err, settings
+            );
+            return Err(Error::BadConfig);
+        }
+    }
+
+    validate_server_tls_certificate(
+        &level.server_tls_certificate,
+        &level.server_tls_private_key,
+        &tls.sslmode,
+        settings,
+    )
+}
+
+/// Check that the sharding ranges are all of bigints or all of dates, to existing
+/// shards, and that they don't overlap.
+fn validate_sharding_ranges(
+    ranges: &[ShardingRange],
+    shards: usize,
+    key_type: &str,
+    settings: &str,
+) -> Result<(), Error> {
+    let mut bounds = Vec::new();
+
+    for range in ranges {
+        if range.shard >= shards {
+            error!(
+                "sharding range to shard {}, but there are {} shards, in {}",
+                range.shard, shards, settings
+            );
+            return Err(Error::BadConfig);
+        }
+
+        for bound in range.from.iter().chain(range.to.iter()) {
+            if bound.key_type() != key_type {
+                error!(
+                    "sharding range bound {:?} is not a {}, the sharding_key_type, in {}",
+                    bound, key_type, settings
+                );
+                return Err(Error::BadConfig);
+            }
+
+            match bound.key() {
+                Some(key) => bounds.push(key),
+                None => {
+                    error!(
+                        "sharding range bound {:?} is not a bigint or a date like '2024-01-31', in {}",
+                        bound, settings
+                    );
+                    return Err(Error::BadConfig);
+                }
+            }
+        }
+
+        if let (Some(from), Some(to)) = (
+            range.from.as_ref().and_then(|from| from.key()),
+            range.to.as_ref().and_then(|to| to.key()),
+        ) {
+            if from >= to {
+                error!(
+                    "sharding range to shard {} ends before it starts, in {}",
+                    range.shard, settings
+                );
+                return Err(Error::BadConfig);
+            }
+        }
+    }
+
+    if bounds
+        .windows(2)
+        .any(|pair| std::mem::discriminant(&pair[0]) != std::mem::discriminant(&pair[1]))
+    {
+        error!("sharding ranges mix bigints and dates, in {}", settings);
+        return Err(Error::BadConfig);
+    }
+
+    // Without a bound, the range has no end on that side.
+    let overlap = |a: &ShardingRange, b: &ShardingRange| {
+        let starts_before_end = |from: &Option<RangeBound>, to: &Option<RangeBound>| match (
+            from.as_ref().and_then(|from| from.key()),
+            to.as_ref().and_then(|to| to.key()),
+        ) {
+            (Some(from), Some(to)) => from < to,
+            _ => true,
+        };
+
+        starts_before_end(&a.from, &b.to) && starts_before_end(&b.from, &a.to)
+    };
+
+    for (index, range) in ranges.iter().enumerate() {
+        if let Some(other) = ranges[index + 1..]
+            .iter()
+            .find(|other| overlap(range, other))
+        {
+            error!(
+                "sharding ranges to shards {} and {} overlap, in {}",
+                range.shard, other.shard, settings
+            );
+            return Err(Error::BadConfig);
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that the keys move from either some of the shards or ranges of keys, to
+/// existing shards, and that the phase is one of the four.
+fn validate_resharding(resharding: &Resharding, pool: &Pool, pool_name: &str) -> Result<(), Error> {
+    if pool.sharding_directory.is_some() {
+        error!(
+            "resharding and sharding_directory can't both be set in pool {} settings, move the keys in the directory instead",
+            pool_name
+        );
+        return Err(Error::BadConfig);
+    }
+
+    match resharding.from_shards {
+        Some(_) if !resharding.from_ranges.is_empty() => {
+            error!(
+                "resharding needs either from_shards or from_ranges in pool {} settings, not both",
+                pool_name
+            );
+            return Err(Error::BadConfig);
+        }
+
+        Some(from_shards) if from_shards == 0 || from_shards > pool.shards.len() => {
+            error!(
+                "resharding from {} shards, but there are {} shards, in pool {} settings",
+                from_shards,
+                pool.shards.len(),
+                pool_name
+            );
+            return Err(Error::BadConfig);
+        }
+
+        Some(_) => (),
+
+        None if resharding.from_ranges.is_empty() => {
+            error!(
+                "resharding needs either from_shards or from_ranges in pool {} settings",
+                pool_name
+            );
+            return Err(Error::BadConfig);
+        }
+
+        None => validate_sharding_ranges(
+            &resharding.from_ranges,
+            pool.shards.len(),
+            &pool.sharding_key_type,
+            &format!("pool {} resharding", pool_name),
+        )?,
+    };
+
+    if !["old", "dual_write", "cutover", "new"].contains(&resharding.phase.as_ref()) {
+        error!(
+            "resharding phase '{}' is not old, dual_write, cutover or new, in pool {} settings",
+            resharding.phase, pool_name
+        );
+        return Err(Error::BadConfig);
+    }
+
+    Ok(())
+}
+
+/// Check the client certificate and key we log into the servers with.
+fn validate_server_tls_certificate(
+    certificate: &Option<String>,
+    private_key: &Option<String>,
+    sslmode: &str,
+    settings: &str,
+) -> Result<(), Error> {
+    match (certificate, private_key) {
+        (Some(certificate), Some(private_key)) => {
+            if sslmode == "disable" {
+                error!(
+                    "server_tls_certificate is set, but server_tls_sslmode is 'disable', in {}",
+                    settings
+                );
+                return Err(Error::BadConfig);
+            }
+
+            if let Err(err) = load_certs(Path::new(certificate)) {
+                error!(
+                    "server_tls_certificate is incorrectly configured: {:?}, in {}",
+                    err, settings
+                );
+                return Err(Error::BadConfig);
+            }
+
+            match load_keys(Path::new(private_key)) {
+                Ok(keys) if !keys.is_empty() => Ok(()),
+                _ => {
+                    error!(
+                        "server_tls_private_key is incorrectly configured, in {}",
+                        settings
+                    );
+                    Err(Error::BadConfig)
+                }
+            }
+        }
+
+        (None, None) => Ok(()),
+
+        _ => {
+            error!(
+                "server_tls_certificate and server_tls_private_key must both be set, or neither, in {}",
+                settings
+            );
+            Err(Error::BadConfig)
+        }
+    }
+}
+
+/// Wait for the reload or the change of the config in progress, if any,
+/// and keep the others from starting until the guard is dropped.
+pub async fn reload_lock() -> tokio::sync::MutexGuard<'static, ()> {
+    RELOAD_LOCK.lock().await
+}
+
 pub async fn reload_config(client_server_map: ClientServerMap) -> Result<bool, Error> {
+    let _guard = reload_lock().await;
+
     let old_config = get_config();
     match parse(&old_config.path).await {
         Ok(()) => (),
@@ -498,11 +2875,15 @@ pub async fn reload_config(client_server_map: ClientServerMap) -> Result<bool, E
     };
     let new_config = get_config();
 
+    tls::reload();
+
     if old_config.pools != new_config.pools {
-        info!("Pool configuration changed, re-creating server pools");
+        info!("Pool configuration changed, re-creating the changed server pools");
         ConnectionPool::from_config(client_server_map).await?;
+        reload_routing();
         Ok(true)
     } else if old_config != new_config {
+        reload_routing();
         Ok(true)
     } else {
         Ok(false)
@@ -559,6 +2940,8 @@ mod test {
             "some_db"
         );
         assert_eq!(get_config().pools["simple_db"].default_role, "primary");
+        assert_eq!(get_config().pools["sharded"].auth_type, "md5");
+        assert_eq!(get_config().pools["simple_db"].auth_type, "scram-sha-256");
 
         assert_eq!(
             get_config().pools["simple_db"].users["0"].username,
@@ -571,6 +2954,288 @@ mod test {
         assert_eq!(get_config().pools["simple_db"].users["0"].pool_size, 5);
     }
 
+    #[test]
+    fn test_role_pool_size() {
+        let mut user = User {
+            pool_size: 15,
+            replica_pool_size: Some(80),
+            ..Default::default()
+        };
+
+        assert_eq!(user.role_pool_size(Role::Primary), 15);
+        assert_eq!(user.role_pool_size(Role::Replica), 80);
+
+        user.primary_pool_size = Some(30);
+        assert_eq!(user.role_pool_size(Role::Primary), 30);
+    }
+
+    #[test]
+    fn test_tenant() {
+        let tenant = Tenant {
+            search_path: Some(String::from("acme, public")),
+            role: Some(String::from("acme")),
+        };
+
+        let mut config = Config::default();
+        config.pools.insert(
+            String::from("app"),
+            Pool {
+                tenants: HashMap::from([(String::from("acme"), tenant.clone())]),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            config.tenant("acme"),
+            Some((String::from("app"), tenant.clone()))
+        );
+        assert_eq!(config.tenant("app"), None);
+
+        assert_eq!(
+            tenant.options(),
+            vec![
+                (String::from("search_path"), String::from("acme, public")),
+                (String::from("role"), String::from("acme")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_set_pool_size() {
+        let mut pool = Pool {
+            min_pool_size: 5,
+            users: HashMap::from([(
+                String::from("0"),
+                User {
+                    pool_size: 15,
+                    replica_pool_size: Some(80),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+
+        assert_eq!(pool.set_pool_size(0), Err(Error::BadConfig));
+        assert_eq!(pool.set_pool_size(4), Err(Error::BadConfig));
+        assert_eq!(pool.users["0"].pool_size, 15);
+
+        assert_eq!(pool.set_pool_size(40), Ok(()));
+        assert_eq!(pool.users["0"].role_pool_size(Role::Primary), 40);
+        assert_eq!(pool.users["0"].role_pool_size(Role::Replica), 40);
+        assert_eq!(pool.default_pool_size, 40);
+    }
+
+    #[test]
+    fn test_validate_sharding_ranges() {
+        let range = |shard: usize, from: Option<i64>, to: Option<i64>| ShardingRange {
+            shard,
+            from: from.map(RangeBound::Integer),
+            to: to.map(RangeBound::Integer),
+        };
+        let date = |date: &str| Some(RangeBound::Text(String::from(date)));
+
+        assert!(validate_sharding_ranges(
+            &[range(0, None, Some(100)), range(1, Some(100), None)],
+            2,
+            "bigint",
+            "test"
+        )
+        .is_ok());
+
+        // Overlapping, to a shard that doesn't exist, empty.
+        assert!(validate_sharding_ranges(
+            &[range(0, None, Some(100)), range(1, Some(50), Some(200))],
+            2,
+            "bigint",
+            "test"
+        )
+        .is_err());
+        assert!(
+            validate_sharding_ranges(&[range(2, Some(0), Some(100))], 2, "bigint", "test").is_err()
+        );
+        assert!(
+            validate_sharding_ranges(&[range(0, Some(100), Some(100))], 2, "bigint", "test")
+                .is_err()
+        );
+
+        let dates = ShardingRange {
+            shard: 0,
+            from: date("2024-01-01"),
+            to: date("2025-01-01"),
+        };
+        assert!(validate_sharding_ranges(std::slice::from_ref(&dates), 1, "date", "test").is_ok());
+        assert!(
+            validate_sharding_ranges(std::slice::from_ref(&dates), 1, "bigint", "test").is_err()
+        );
+
+        // Mixed with bigints, or not a date.
+        assert!(validate_sharding_ranges(
+            &[dates.clone(), range(0, Some(5), None)],
+            1,
+            "date",
+            "test"
+        )
+        .is_err());
+        let not_a_date = ShardingRange {
+            to: date("next year"),
+            ..dates
+        };
+        assert!(validate_sharding_ranges(&[not_a_date], 1, "date", "test").is_err());
+    }
+
+    #[test]
+    fn test_eq_but_routing() {
+        let pool = Pool::default();
+        let mut weighted = pool.clone();
+        let shard = weighted.shards.get_mut("1").unwrap();
+        shard.weights.insert(String::from("localhost:5432"), 3);
+        shard
+            .localities
+            .insert(String::from("localhost:5432"), String::from("us-east-1a"));
+
+        assert!(pool.eq_but_routing(&weighted));
+        assert!(pool != weighted);
+
+        weighted.shards.get_mut("1").unwrap().database = String::from("other");
+        assert!(!pool.eq_but_routing(&weighted));
+    }
+
+    #[test]
+    fn test_eq_but_shard_map() {
+        let pool = Pool::default();
+        let mut resharded = pool.clone();
+        resharded.shards.insert(String::from("2"), Shard::default());
+        resharded.sharding_ranges = vec![ShardingRange {
+            shard: 0,
+            from: None,
+            to: Some(RangeBound::Integer(100)),
+        }];
+
+        assert!(pool.eq_but_shard_map(&resharded));
+        assert!(!pool.eq_but_routing(&resharded));
+        assert!(pool.shards["1"].eq_but_routing(&resharded.shards["1"]));
+
+        resharded.pool_mode = String::from("session");
+        assert!(!pool.eq_but_shard_map(&resharded));
+    }
+
+    #[test]
+    fn test_listener_tls() {
+        let mut general = General::default();
+        let mut listener = Listener {
+            host: String::from("127.0.0.1"),
+            port: 6433,
+            tls_certificate: None,
+            tls_private_key: None,
+            gssenc_mode: None,
+        };
+
+        assert_eq!(listener.tls(&general), None);
+        assert_eq!(listener.gssenc_mode(&general), "decline");
+
+        listener.gssenc_mode = Some(String::from("refuse"));
+        assert_eq!(listener.gssenc_mode(&general), "refuse");
+
+        general.tls_certificate = Some(String::from("server.cert"));
+        general.tls_private_key = Some(String::from("server.key"));
+
+        assert_eq!(
+            listener.tls(&general),
+            Some((String::from("server.cert"), String::from("server.key")))
+        );
+
+        listener.tls_certificate = Some(String::from("internal.cert"));
+        listener.tls_private_key = Some(String::from("internal.key"));
+
+        assert_eq!(
+            listener.tls(&general),
+            Some((String::from("internal.cert"), String::from("internal.key")))
+        );
+    }
+
+    #[test]
+    fn test_sni_database() {
+        let mut general = General::default();
+
+        assert_eq!(general.sni_database("tenant1.db.example.com"), None);
+
+        general.sni_database_suffix = Some(String::from(".db.example.com"));
+
+        assert_eq!(
+            general.sni_database("tenant1.db.example.com"),
+            Some(String::from("tenant1"))
+        );
+        assert_eq!(
+            general.sni_database("Tenant1.DB.example.com"),
+            Some(String::from("tenant1"))
+        );
+        assert_eq!(general.sni_database(".db.example.com"), None);
+        assert_eq!(general.sni_database("tenant1.example.com"), None);
+    }
+
+    #[test]
+    fn test_server_tls() {
+        let mut config = Config::default();
+        config.general.server_tls_sslmode = String::from("require");
+        config.general.server_tls_certificate = Some(String::from("pgcat.crt"));
+        config.general.server_tls_private_key = Some(String::from("pgcat.key"));
+
+        let shard = Shard {
+            servers: vec![
+                (String::from("localhost"), 5432, String::from("primary")),
+                (
+                    String::from("db.example.com"),
+                    5432,
+                    String::from("replica"),
+                ),
+            ],
+            server_tls_sslmode: Some(String::from("verify-full")),
+            server_tls_ca_file: Some(String::from("root.crt")),
+            server_tls: HashMap::from([(
+                String::from("localhost:5432"),
+                ServerTls {
+                    server_tls_sslmode: Some(String::from("disable")),
+                    ..ServerTls::default()
+                },
+            )]),
+            ..Shard::default()
+        };
+
+        let pool = Pool {
+            server_tls_certificate: Some(String::from("sharded.crt")),
+            server_tls_private_key: Some(String::from("sharded.key")),
+            shards: HashMap::from([(String::from("0"), shard)]),
+            ..Pool::default()
+        };
+        config.pools.insert(String::from("sharded"), pool);
+
+        // The server's own settings, then its shard's, its pool's and the general ones.
+        let tls = config.server_tls("sharded", "0", "db.example.com", "5432");
+        assert_eq!(tls.sslmode, "verify-full");
+        assert_eq!(tls.ca_file, Some(String::from("root.crt")));
+        assert_eq!(
+            tls.client_certificate,
+            Some((String::from("sharded.crt"), String::from("sharded.key")))
+        );
+
+        let tls = config.server_tls("sharded", "0", "localhost", "5432");
+        assert_eq!(tls.sslmode, "disable");
+        assert_eq!(tls.ca_file, Some(String::from("root.crt")));
+
+        assert_eq!(
+            config.find_server_tls("db.example.com", "5432"),
+            config.server_tls("sharded", "0", "db.example.com", "5432")
+        );
+
+        let tls = config.find_server_tls("other.example.com", "5432");
+        assert_eq!(tls.sslmode, "require");
+        assert_eq!(tls.ca_file, None);
+        assert_eq!(
+            tls.client_certificate,
+            Some((String::from("pgcat.crt"), String::from("pgcat.key")))
+        );
+    }
+
     #[tokio::test]
     async fn test_serialize_configs() {
         parse("pgcat.toml").await.unwrap();
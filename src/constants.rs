@@ -8,18 +8,36 @@ pub const PROTOCOL_VERSION_NUMBER: i32 = 196608;
 // SSLRequest: used to indicate we want an SSL connection.
 pub const SSL_REQUEST_CODE: i32 = 80877103;
 
+// GSSENCRequest: used to indicate we want a GSSAPI encrypted connection.
+pub const GSSENC_REQUEST_CODE: i32 = 80877104;
+
 // CancelRequest: the cancel request code.
 pub const CANCEL_REQUEST_CODE: i32 = 80877102;
 
+// The largest startup packet Postgres accepts.
+pub const MAX_STARTUP_PACKET_LENGTH: i32 = 10000;
+
+// The largest password or SASL message Postgres accepts.
+pub const MAX_AUTH_TOKEN_LENGTH: i32 = 65535;
+
+// AuthenticationCleartextPassword
+pub const CLEARTEXT_PASSWORD: i32 = 3;
+
 // AuthenticationMD5Password
 pub const MD5_ENCRYPTED_PASSWORD: i32 = 5;
 
+// AuthenticationGSS and AuthenticationGSSContinue
+pub const GSS: i32 = 7;
+pub const GSS_CONTINUE: i32 = 8;
+
 // SASL
 pub const SASL: i32 = 10;
 pub const SASL_CONTINUE: i32 = 11;
 pub const SASL_FINAL: i32 = 12;
 pub const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+pub const SCRAM_SHA_256_PLUS: &str = "SCRAM-SHA-256-PLUS";
 pub const NONCE_LENGTH: usize = 24;
+pub const SCRAM_ITERATIONS: u32 = 4096;
 
 // AuthenticationOk
 pub const AUTHENTICATION_SUCCESSFUL: i32 = 0;
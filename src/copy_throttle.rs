@@ -0,0 +1,86 @@
+/// Limit the throughput of COPY, i.e. the CopyData going through each pool,
+/// so bulk loads don't starve the other clients of the pooler. The limit is
+/// shared by all the clients (and users) of the pool.
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// When the COPY data of each pool can go through next.
+static NEXT: Lazy<Mutex<HashMap<String, Arc<Mutex<Instant>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::default()));
+
+#[derive(Clone, Debug)]
+pub struct CopyThrottle {
+    bytes_per_second: u64,
+    next: Arc<Mutex<Instant>>,
+}
+
+impl CopyThrottle {
+    /// The throttle of the pool, if it has a limit.
+    pub fn new(pool_name: &str, bytes_per_second: u64) -> Option<CopyThrottle> {
+        if bytes_per_second == 0 {
+            return None;
+        }
+
+        let next = NEXT
+            .lock()
+            .entry(pool_name.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(Instant::now())))
+            .clone();
+
+        Some(CopyThrottle {
+            bytes_per_second,
+            next,
+        })
+    }
+
+    /// Wait until this much COPY data can go through.
+    pub async fn wait(&self, bytes: usize) {
+        let delay = reserve(
+            &mut self.next.lock(),
+            Instant::now(),
+            self.bytes_per_second,
+            bytes,
+        );
+
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Reserve the time it takes to send the bytes, after what's already reserved,
+/// and return how long to wait until then.
+fn reserve(next: &mut Instant, now: Instant, bytes_per_second: u64, bytes: usize) -> Duration {
+    let start = (*next).max(now);
+
+    *next = start + Duration::from_secs_f64(bytes as f64 / bytes_per_second as f64);
+
+    start - now
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reserve() {
+        let now = Instant::now();
+        let mut next = now;
+
+        // The first bytes go through right away, the next ones after them.
+        assert_eq!(reserve(&mut next, now, 1000, 500), Duration::ZERO);
+        assert_eq!(
+            reserve(&mut next, now, 1000, 500),
+            Duration::from_millis(500)
+        );
+        assert_eq!(next, now + Duration::from_secs(1));
+
+        // Time not used isn't saved for later.
+        let later = now + Duration::from_secs(5);
+        assert_eq!(reserve(&mut next, later, 1000, 1000), Duration::ZERO);
+        assert_eq!(next, later + Duration::from_secs(1));
+    }
+}
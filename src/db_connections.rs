@@ -0,0 +1,133 @@
+/// Cap the server connections of all the pools together, so the pools of many
+/// users and databases, each sized on its own, can't open more than the database's
+/// max_connections: max_db_connections for all the servers, and max_server_connections
+/// for each of them (host and port). The connections count until they're closed.
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::config::get_config;
+
+#[derive(Debug, Default)]
+struct Counts {
+    total: u32,
+
+    /// By host and port.
+    servers: HashMap<String, u32>,
+}
+
+static COUNTS: Lazy<Mutex<Counts>> = Lazy::new(|| Mutex::new(Counts::default()));
+
+/// Notified when a connection is closed.
+static CLOSED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// A server connection, counted until it's dropped.
+#[derive(Debug)]
+pub struct DbConnection {
+    server: String,
+}
+
+impl Drop for DbConnection {
+    fn drop(&mut self) {
+        let mut counts = COUNTS.lock();
+        counts.total -= 1;
+
+        if let Some(count) = counts.servers.get_mut(&self.server) {
+            *count -= 1;
+
+            if *count == 0 {
+                counts.servers.remove(&self.server);
+            }
+        }
+
+        CLOSED.notify_waiters();
+    }
+}
+
+/// The caps apply to all the connections of the server, whatever the database.
+pub fn server(host: &str, port: &str) -> String {
+    format!("{}:{}", host, port)
+}
+
+/// Count one more connection to the server, unless a cap is reached.
+pub fn open(server: &str) -> Option<DbConnection> {
+    let config = get_config();
+    let mut counts = COUNTS.lock();
+
+    if full(
+        &counts,
+        server,
+        config.general.max_db_connections,
+        config.general.max_server_connections,
+    ) {
+        return None;
+    }
+
+    counts.total += 1;
+    *counts.servers.entry(server.to_string()).or_insert(0) += 1;
+
+    Some(DbConnection {
+        server: server.to_string(),
+    })
+}
+
+/// Is a cap reached for the server?
+pub fn is_full(server: &str) -> bool {
+    let config = get_config();
+
+    full(
+        &COUNTS.lock(),
+        server,
+        config.general.max_db_connections,
+        config.general.max_server_connections,
+    )
+}
+
+/// Is max_server_connections reached for the server? Then only closing one of its
+/// connections makes room.
+pub fn is_server_full(server: &str) -> bool {
+    full(
+        &COUNTS.lock(),
+        server,
+        0,
+        get_config().general.max_server_connections,
+    )
+}
+
+fn full(
+    counts: &Counts,
+    server: &str,
+    max_db_connections: u32,
+    max_server_connections: u32,
+) -> bool {
+    (max_db_connections > 0 && counts.total >= max_db_connections)
+        || (max_server_connections > 0
+            && counts.servers.get(server).copied().unwrap_or(0) >= max_server_connections)
+}
+
+/// Wait for a connection to close, or the timeout.
+pub async fn wait(timeout: Duration) {
+    let _ = tokio::time::timeout(timeout, CLOSED.notified()).await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_full() {
+        let counts = Counts {
+            total: 3,
+            servers: HashMap::from([(server("a", "5432"), 2), (server("b", "5432"), 1)]),
+        };
+
+        assert!(!full(&counts, "a:5432", 0, 0));
+        assert!(full(&counts, "b:5432", 3, 0));
+        assert!(!full(&counts, "b:5432", 4, 0));
+        assert!(full(&counts, "a:5432", 0, 2));
+        assert!(!full(&counts, "b:5432", 0, 2));
+        assert!(!full(&counts, "c:5432", 4, 1));
+    }
+}
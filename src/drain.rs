@@ -0,0 +1,138 @@
+/// The servers (host and port) drained with DRAIN SERVER in the admin database:
+/// they don't get new transactions, and their connections are closed as soon as
+/// they're idle, e.g. to take a replica out for maintenance without errors.
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashSet;
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::config::get_config;
+use crate::db_connections;
+use crate::pool::get_all_pools;
+
+/// The servers drained, until they're resumed with RESUME SERVER.
+static DRAINED: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::default()));
+
+/// The server is drained.
+pub fn is_drained(server: &str) -> bool {
+    DRAINED.lock().contains(server)
+}
+
+/// How draining a server ended.
+#[derive(Debug, PartialEq)]
+pub enum Drain {
+    /// All its connections are closed, or it was resumed meanwhile.
+    Done,
+
+    /// No pool has the server.
+    Unknown,
+
+    /// Some connections were still in use after drain_timeout: the pools and shards
+    /// that have them. The server stays drained.
+    Busy(Vec<String>),
+}
+
+/// Stop routing transactions to the server and close its idle connections, then wait
+/// until the ones in use are released and closed too, for drain_timeout at most.
+pub async fn drain(server: &str) -> Drain {
+    if connections(server).is_none() {
+        return Drain::Unknown;
+    }
+
+    let timeout = match get_config().general.drain_timeout {
+        0 => None,
+        drain_timeout => Some(Instant::now() + Duration::from_secs(drain_timeout)),
+    };
+
+    if DRAINED.lock().insert(server.to_string()) {
+        info!("Draining server {}", server);
+    }
+
+    loop {
+        for pool in get_all_pools().values() {
+            for shard in 0..pool.shards() {
+                for index in 0..pool.servers(shard) {
+                    let address = pool.address(shard, index);
+
+                    if db_connections::server(&address.host, &address.port) == server {
+                        pool.close_all_idle(shard, index).await;
+                    }
+                }
+            }
+        }
+
+        // Resumed while it was draining.
+        if !is_drained(server) {
+            return Drain::Done;
+        }
+
+        let busy = connections(server).unwrap_or_default();
+
+        if busy.is_empty() {
+            break;
+        }
+
+        if timeout.is_some_and(|timeout| Instant::now() >= timeout) {
+            warn!(
+                "Server {} is still busy after the drain timeout: {}",
+                server,
+                busy.join(", ")
+            );
+
+            return Drain::Busy(busy);
+        }
+
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    info!("Server {} is drained", server);
+
+    Drain::Done
+}
+
+/// Route transactions to the server again. False if it wasn't drained.
+pub fn resume(server: &str) -> bool {
+    let resumed = DRAINED.lock().remove(server);
+
+    if resumed {
+        info!("Resuming server {}", server);
+    }
+
+    resumed
+}
+
+/// The pools and shards with connections to the server still open, and how many,
+/// none if no pool has it.
+fn connections(server: &str) -> Option<Vec<String>> {
+    let mut connections = None;
+
+    for ((pool_name, username), pool) in get_all_pools() {
+        for shard in 0..pool.shards() {
+            for index in 0..pool.servers(shard) {
+                let address = pool.address(shard, index);
+
+                if db_connections::server(&address.host, &address.port) != server {
+                    continue;
+                }
+
+                let connections = connections.get_or_insert_with(Vec::new);
+                let open = pool.pool_state(shard, index).connections
+                    + pool.reserve_connections(shard, index);
+
+                if open > 0 {
+                    connections.push(format!(
+                        "pool {}, user {}, shard {}, connections: {}",
+                        pool_name, username, shard, open
+                    ));
+                }
+            }
+        }
+    }
+
+    if let Some(ref mut connections) = connections {
+        connections.sort();
+    }
+
+    connections
+}
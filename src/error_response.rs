@@ -0,0 +1,154 @@
+/// The errors PgCat reports to clients itself, e.g. it couldn't get a server
+/// from the pool, as ErrorResponse messages with the fields Postgres sets:
+/// severity, SQLSTATE code, message, and optionally detail and hint.
+/// The routine field is always "pgcat", so clients can tell them from
+/// the errors of the servers.
+/// Docs on error codes: <https://www.postgresql.org/docs/current/errcodes-appendix.html>.
+use bytes::{BufMut, BytesMut};
+
+// Class 08: connection exception.
+pub const CONNECTION_FAILURE: &str = "08006";
+pub const PROTOCOL_VIOLATION: &str = "08P01";
+
+// Class 0A: feature not supported.
+pub const FEATURE_NOT_SUPPORTED: &str = "0A000";
+
+// Class 22: data exception.
+pub const INVALID_PARAMETER_VALUE: &str = "22023";
+
+// Class 28: invalid authorization specification.
+pub const INVALID_AUTHORIZATION_SPECIFICATION: &str = "28000";
+pub const INVALID_PASSWORD: &str = "28P01";
+
+// Class 3D: invalid catalog name, i.e. the database doesn't exist.
+pub const INVALID_CATALOG_NAME: &str = "3D000";
+
+// Class 53: insufficient resources.
+pub const TOO_MANY_CONNECTIONS: &str = "53300";
+
+// Class 55: object not in prerequisite state.
+pub const OBJECT_IN_USE: &str = "55006";
+
+// Class 57: operator intervention.
+pub const ADMIN_SHUTDOWN: &str = "57P01";
+pub const IDLE_SESSION_TIMEOUT: &str = "57P05";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorResponse {
+    severity: &'static str,
+    code: &'static str,
+    message: String,
+    detail: Option<String>,
+    hint: Option<String>,
+}
+
+impl ErrorResponse {
+    /// An error that ends the client's query; it can send the next one.
+    pub fn error(code: &'static str, message: &str) -> ErrorResponse {
+        ErrorResponse::new("ERROR", code, message)
+    }
+
+    /// An error that ends the client's connection.
+    pub fn fatal(code: &'static str, message: &str) -> ErrorResponse {
+        ErrorResponse::new("FATAL", code, message)
+    }
+
+    fn new(severity: &'static str, code: &'static str, message: &str) -> ErrorResponse {
+        ErrorResponse {
+            severity,
+            code,
+            message: message.to_string(),
+            detail: None,
+            hint: None,
+        }
+    }
+
+    /// More about the error, e.g. the values involved.
+    pub fn detail(mut self, detail: &str) -> ErrorResponse {
+        self.detail = Some(detail.to_string());
+        self
+    }
+
+    /// What the client could do about it.
+    pub fn hint(mut self, hint: &str) -> ErrorResponse {
+        self.hint = Some(hint.to_string());
+        self
+    }
+
+    /// The ErrorResponse message.
+    pub fn to_bytes(&self) -> BytesMut {
+        let mut fields = BytesMut::new();
+
+        // Severity, and the one that's never translated.
+        field(&mut fields, b'S', self.severity);
+        field(&mut fields, b'V', self.severity);
+
+        field(&mut fields, b'C', self.code);
+        field(&mut fields, b'M', &self.message);
+
+        if let Some(ref detail) = self.detail {
+            field(&mut fields, b'D', detail);
+        }
+
+        if let Some(ref hint) = self.hint {
+            field(&mut fields, b'H', hint);
+        }
+
+        field(&mut fields, b'R', "pgcat");
+
+        // No more fields follow.
+        fields.put_u8(0);
+
+        let mut res = BytesMut::with_capacity(fields.len() + 5);
+
+        res.put_u8(b'E');
+        res.put_i32(fields.len() as i32 + 4);
+        res.put(fields);
+
+        res
+    }
+}
+
+/// The SQLSTATE code of an ErrorResponse from a server, from its fields.
+pub fn code(fields: &[u8]) -> Option<&str> {
+    fields
+        .split(|byte| *byte == 0)
+        .find(|field| field.first() == Some(&b'C'))
+        .and_then(|field| std::str::from_utf8(&field[1..]).ok())
+}
+
+fn field(fields: &mut BytesMut, code: u8, value: &str) {
+    fields.put_u8(code);
+    fields.put_slice(value.as_bytes());
+    fields.put_u8(0);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_to_bytes() {
+        let error = ErrorResponse::error(TOO_MANY_CONNECTIONS, "no server")
+            .detail("pool timeout")
+            .hint("try again");
+
+        assert_eq!(
+            &error.to_bytes()[..],
+            b"E\0\0\0\x45SERROR\0VERROR\0C53300\0Mno server\0Dpool timeout\0Htry again\0Rpgcat\0\0"
+        );
+
+        assert_eq!(
+            &ErrorResponse::fatal(PROTOCOL_VIOLATION, "bad").to_bytes()[..],
+            b"E\0\0\0\x26SFATAL\0VFATAL\0C08P01\0Mbad\0Rpgcat\0\0"
+        );
+    }
+
+    #[test]
+    fn test_code() {
+        let error = ErrorResponse::fatal(INVALID_PASSWORD, "password authentication failed");
+        assert_eq!(code(&error.to_bytes()[5..]), Some(INVALID_PASSWORD));
+
+        assert_eq!(code(b"SFATAL\0Mno code\0\0"), None);
+    }
+}
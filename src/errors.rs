@@ -7,8 +7,14 @@ pub enum Error {
     ClientBadStartup,
     ProtocolSyncError,
     ServerError,
+
+    /// The server refused the password it was logged into with (28P01).
+    InvalidPassword,
     BadConfig,
     AllServersDown,
     ClientError,
     TlsError,
+    CheckoutTimeout,
+    QueueFull,
+    TooManyConnections,
 }
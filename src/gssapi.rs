@@ -0,0 +1,311 @@
+/// Kerberos client authentication with GSSAPI, like Postgres' gss method.
+/// The GSSAPI library (MIT Kerberos or Heimdal) is loaded the first time
+/// it's needed, so pgcat doesn't depend on it unless GSSAPI is used.
+use log::{debug, error, info};
+use once_cell::sync::Lazy;
+use std::ffi::{c_char, c_void, CString};
+use std::ptr::null_mut;
+
+use crate::errors::Error;
+
+/// Names of the GSSAPI library, MIT Kerberos first.
+const LIBRARY_NAMES: [&str; 4] = [
+    "libgssapi_krb5.so.2",
+    "libgssapi_krb5.so",
+    "libgssapi.so.3",
+    "libgssapi_krb5.dylib",
+];
+
+/// Major status codes, see RFC 2744.
+const GSS_S_COMPLETE: u32 = 0;
+const GSS_S_CONTINUE_NEEDED: u32 = 1;
+
+/// Calling and routine errors; the low bits are supplementary information.
+const GSS_S_ERROR_MASK: u32 = 0xffff0000;
+
+#[repr(C)]
+struct Buffer {
+    length: usize,
+    value: *mut c_void,
+}
+
+impl Buffer {
+    fn empty() -> Buffer {
+        Buffer {
+            length: 0,
+            value: null_mut(),
+        }
+    }
+
+    /// Copy the contents, the library owns the memory.
+    fn to_vec(&self) -> Vec<u8> {
+        if self.value.is_null() || self.length == 0 {
+            return Vec::new();
+        }
+
+        unsafe { std::slice::from_raw_parts(self.value as *const u8, self.length).to_vec() }
+    }
+}
+
+type AcceptSecContext = unsafe extern "C" fn(
+    minor_status: *mut u32,
+    context_handle: *mut *mut c_void,
+    acceptor_cred_handle: *mut c_void,
+    input_token: *mut Buffer,
+    input_chan_bindings: *mut c_void,
+    src_name: *mut *mut c_void,
+    mech_type: *mut *mut c_void,
+    output_token: *mut Buffer,
+    ret_flags: *mut u32,
+    time_rec: *mut u32,
+    delegated_cred_handle: *mut *mut c_void,
+) -> u32;
+
+type DisplayName = unsafe extern "C" fn(
+    minor_status: *mut u32,
+    name: *mut c_void,
+    output_name: *mut Buffer,
+    output_name_type: *mut *mut c_void,
+) -> u32;
+
+type ReleaseBuffer = unsafe extern "C" fn(minor_status: *mut u32, buffer: *mut Buffer) -> u32;
+
+type ReleaseName = unsafe extern "C" fn(minor_status: *mut u32, name: *mut *mut c_void) -> u32;
+
+type DeleteSecContext = unsafe extern "C" fn(
+    minor_status: *mut u32,
+    context_handle: *mut *mut c_void,
+    output_token: *mut Buffer,
+) -> u32;
+
+type RegisterAcceptorIdentity = unsafe extern "C" fn(identity: *const c_char) -> u32;
+
+/// The functions we use from the GSSAPI library.
+struct Library {
+    accept_sec_context: AcceptSecContext,
+    display_name: DisplayName,
+    release_buffer: ReleaseBuffer,
+    release_name: ReleaseName,
+    delete_sec_context: DeleteSecContext,
+    register_acceptor_identity: RegisterAcceptorIdentity,
+
+    // The functions above are valid as long as the library is loaded.
+    _library: libloading::Library,
+}
+
+static LIBRARY: Lazy<Option<Library>> = Lazy::new(|| match load() {
+    Ok(library) => Some(library),
+    Err(err) => {
+        error!("Could not load the GSSAPI library: {}", err);
+        None
+    }
+});
+
+fn load() -> Result<Library, libloading::Error> {
+    let mut result = None;
+
+    for name in LIBRARY_NAMES {
+        result = Some(unsafe { libloading::Library::new(name) });
+
+        if let Some(Ok(_)) = result {
+            info!("Loaded GSSAPI library {}", name);
+            break;
+        }
+    }
+
+    let library = result.unwrap()?;
+
+    unsafe {
+        // MIT Kerberos and Heimdal name it differently.
+        let register_acceptor_identity =
+            match library.get::<RegisterAcceptorIdentity>(b"krb5_gss_register_acceptor_identity\0")
+            {
+                Ok(symbol) => *symbol,
+                Err(_) => *library
+                    .get::<RegisterAcceptorIdentity>(b"gsskrb5_register_acceptor_identity\0")?,
+            };
+
+        Ok(Library {
+            accept_sec_context: *library.get(b"gss_accept_sec_context\0")?,
+            display_name: *library.get(b"gss_display_name\0")?,
+            release_buffer: *library.get(b"gss_release_buffer\0")?,
+            release_name: *library.get(b"gss_release_name\0")?,
+            delete_sec_context: *library.get(b"gss_delete_sec_context\0")?,
+            register_acceptor_identity,
+            _library: library,
+        })
+    }
+}
+
+/// The server side of a GSSAPI security context with one client.
+pub struct Context {
+    library: &'static Library,
+    handle: *mut c_void,
+}
+
+// The context is only used by the client that created it.
+unsafe impl Send for Context {}
+
+impl Context {
+    /// Start a new context, with the server keys in the keytab.
+    pub fn new(keytab: &str) -> Result<Context, Error> {
+        let library = match LIBRARY.as_ref() {
+            Some(library) => library,
+            None => return Err(Error::ClientError),
+        };
+
+        let keytab = match CString::new(keytab) {
+            Ok(keytab) => keytab,
+            Err(_) => return Err(Error::BadConfig),
+        };
+
+        // The keytab is global to the library, so this is set for every
+        // context in case it changed when the config was reloaded.
+        let major = unsafe { (library.register_acceptor_identity)(keytab.as_ptr()) };
+
+        if major & GSS_S_ERROR_MASK != 0 {
+            error!("Could not use keytab {:?}: {:#x}", keytab, major);
+            return Err(Error::BadConfig);
+        }
+
+        Ok(Context {
+            library,
+            handle: null_mut(),
+        })
+    }
+
+    /// Process a token from the client. Returns the token to send back (possibly empty),
+    /// and the client's principal, e.g. `user@EXAMPLE.COM`, once the context is established.
+    pub fn step(&mut self, token: &[u8]) -> Result<(Vec<u8>, Option<String>), Error> {
+        let mut minor = 0;
+        let mut release_minor = 0;
+        let mut input = Buffer {
+            length: token.len(),
+            value: token.as_ptr() as *mut c_void,
+        };
+        let mut output = Buffer::empty();
+        let mut name = null_mut();
+
+        let major = unsafe {
+            (self.library.accept_sec_context)(
+                &mut minor,
+                &mut self.handle,
+                null_mut(),
+                &mut input,
+                null_mut(),
+                &mut name,
+                null_mut(),
+                &mut output,
+                null_mut(),
+                null_mut(),
+                null_mut(),
+            )
+        };
+
+        let response = output.to_vec();
+
+        unsafe {
+            (self.library.release_buffer)(&mut release_minor, &mut output);
+        }
+
+        if major & GSS_S_ERROR_MASK != 0 {
+            debug!(
+                "GSSAPI accept failed, major: {:#x}, minor: {:#x}",
+                major, minor
+            );
+            return Err(Error::ClientError);
+        }
+
+        let principal = match major & GSS_S_CONTINUE_NEEDED {
+            GSS_S_CONTINUE_NEEDED => None,
+
+            _ => {
+                let principal = self.display_name(name);
+
+                unsafe {
+                    (self.library.release_name)(&mut release_minor, &mut name);
+                }
+
+                Some(principal?)
+            }
+        };
+
+        Ok((response, principal))
+    }
+
+    fn display_name(&self, name: *mut c_void) -> Result<String, Error> {
+        let mut minor = 0;
+        let mut output = Buffer::empty();
+
+        let major =
+            unsafe { (self.library.display_name)(&mut minor, name, &mut output, null_mut()) };
+
+        let principal = String::from_utf8(output.to_vec());
+
+        unsafe {
+            (self.library.release_buffer)(&mut minor, &mut output);
+        }
+
+        match (major, principal) {
+            (GSS_S_COMPLETE, Ok(principal)) => Ok(principal),
+            _ => Err(Error::ClientError),
+        }
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        if !self.handle.is_null() {
+            let mut minor = 0;
+
+            unsafe {
+                (self.library.delete_sec_context)(&mut minor, &mut self.handle, null_mut());
+            }
+        }
+    }
+}
+
+/// Is the client principal allowed to log in as the user. Principals are mapped to users
+/// in `ident_map`; without it, the principal without the realm must be the user name.
+pub fn principal_matches(
+    principal: &str,
+    user: &str,
+    ident_map: &std::collections::HashMap<String, String>,
+) -> bool {
+    if ident_map.is_empty() {
+        principal.split('@').next() == Some(user)
+    } else {
+        ident_map.get(principal).map(|mapped| mapped == user) == Some(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_principal_matches() {
+        assert!(principal_matches("app@EXAMPLE.COM", "app", &HashMap::new()));
+        assert!(!principal_matches(
+            "app@EXAMPLE.COM",
+            "other",
+            &HashMap::new()
+        ));
+
+        let ident_map = HashMap::from([("svc/host@EXAMPLE.COM".to_string(), "app".to_string())]);
+        assert!(principal_matches("svc/host@EXAMPLE.COM", "app", &ident_map));
+        assert!(!principal_matches("app@EXAMPLE.COM", "app", &ident_map));
+    }
+
+    #[test]
+    fn test_invalid_token() {
+        // The GSSAPI library isn't installed everywhere.
+        if LIBRARY.is_none() {
+            return;
+        }
+
+        let mut context = Context::new("/nonexistent.keytab").unwrap();
+        assert!(context.step(b"not a token").is_err());
+    }
+}
@@ -0,0 +1,170 @@
+/// Client access control with host rules, like Postgres' pg_hba.conf.
+use std::net::IpAddr;
+
+use crate::client::ClientAddress;
+use crate::config::Hba;
+
+/// Parse an address like `10.0.0.0/8`, `::1/128` or `127.0.0.1` (a single host)
+/// into the network address and the prefix length.
+pub fn parse_address(address: &str) -> Option<(IpAddr, u8)> {
+    let (ip, prefix) = match address.split_once('/') {
+        Some((ip, prefix)) => (ip.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+        None => (address.parse::<IpAddr>().ok()?, None),
+    };
+
+    let max = match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+
+    match prefix {
+        Some(prefix) if prefix > max => None,
+        Some(prefix) => Some((ip, prefix)),
+        None => Some((ip, max)),
+    }
+}
+
+/// Is the client address in the rule's address range.
+fn address_matches(address: &str, client: IpAddr) -> bool {
+    if address == "all" {
+        return true;
+    }
+
+    // IPv4 clients connecting to an IPv6 socket show up as ::ffff:a.b.c.d.
+    let client = match client {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => client,
+        },
+        client => client,
+    };
+
+    match (parse_address(address), client) {
+        (Some((IpAddr::V4(network), prefix)), IpAddr::V4(client)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(network) & mask == u32::from(client) & mask
+        }
+
+        (Some((IpAddr::V6(network), prefix)), IpAddr::V6(client)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(network) & mask == u128::from(client) & mask
+        }
+
+        _ => false,
+    }
+}
+
+/// Is the name in the comma-separated list, or is the list `all`.
+fn name_matches(names: &str, name: &str) -> bool {
+    names
+        .split(',')
+        .map(|n| n.trim())
+        .any(|n| n == "all" || n == name)
+}
+
+/// Find the first rule that matches the connection, like Postgres does.
+pub fn find_rule<'a>(
+    rules: &'a [Hba],
+    client: ClientAddress,
+    tls: bool,
+    database: &str,
+    user: &str,
+) -> Option<&'a Hba> {
+    rules.iter().find(|rule| {
+        // local rules are for the unix socket, the others for TCP clients.
+        let connection = match (rule.connection_type.as_ref(), client) {
+            ("local", ClientAddress::Unix) => true,
+            ("host", ClientAddress::Tcp(addr)) => address_matches(&rule.address, addr.ip()),
+            ("hostssl", ClientAddress::Tcp(addr)) => {
+                tls && address_matches(&rule.address, addr.ip())
+            }
+            ("hostnossl", ClientAddress::Tcp(addr)) => {
+                !tls && address_matches(&rule.address, addr.ip())
+            }
+            _ => false,
+        };
+
+        connection && name_matches(&rule.database, database) && name_matches(&rule.user, user)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rule(connection_type: &str, database: &str, user: &str, address: &str) -> Hba {
+        Hba {
+            connection_type: connection_type.to_string(),
+            database: database.to_string(),
+            user: user.to_string(),
+            address: address.to_string(),
+            method: "md5".to_string(),
+        }
+    }
+
+    fn tcp(ip: &str) -> ClientAddress {
+        ClientAddress::Tcp(std::net::SocketAddr::new(ip.parse().unwrap(), 5432))
+    }
+
+    #[test]
+    fn test_find_rule() {
+        let rules = vec![
+            rule("hostssl", "all", "admin", "all"),
+            rule("host", "shard0,shard1", "all", "10.0.0.0/8"),
+            rule("host", "all", "app", "192.168.1.10"),
+            rule("host", "all", "all", "fd00::/8"),
+        ];
+
+        let local = tcp("127.0.0.1");
+        let internal = tcp("10.1.2.3");
+
+        assert_eq!(
+            find_rule(&rules, local, true, "db", "admin"),
+            Some(&rules[0])
+        );
+        assert_eq!(find_rule(&rules, local, false, "db", "admin"), None);
+        assert_eq!(
+            find_rule(&rules, internal, false, "shard1", "app"),
+            Some(&rules[1])
+        );
+        assert_eq!(find_rule(&rules, internal, false, "shard2", "app"), None);
+        assert_eq!(
+            find_rule(&rules, tcp("::ffff:192.168.1.10"), false, "db", "app"),
+            Some(&rules[2])
+        );
+        assert_eq!(
+            find_rule(&rules, tcp("192.168.1.11"), false, "db", "app"),
+            None
+        );
+        assert_eq!(
+            find_rule(&rules, tcp("fd12::1"), false, "db", "app"),
+            Some(&rules[3])
+        );
+
+        // Unix socket clients only match local rules, and local rules only them.
+        assert_eq!(
+            find_rule(&rules, ClientAddress::Unix, false, "db", "admin"),
+            None
+        );
+
+        let rules = vec![
+            rule("local", "all", "all", "all"),
+            rule("host", "all", "all", "all"),
+        ];
+        assert_eq!(
+            find_rule(&rules, ClientAddress::Unix, false, "db", "app"),
+            Some(&rules[0])
+        );
+        assert_eq!(
+            find_rule(&rules, local, false, "db", "app"),
+            Some(&rules[1])
+        );
+
+        assert_eq!(parse_address("10.0.0.0/33"), None);
+        assert_eq!(
+            parse_address("0.0.0.0/0"),
+            Some(("0.0.0.0".parse().unwrap(), 0))
+        );
+        assert_eq!(parse_address("localhost"), None);
+    }
+}
@@ -0,0 +1,241 @@
+/// Authenticate clients with a JSON Web Token sent as the password,
+/// verified with the keys published by the identity provider (JWKS).
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use log::{debug, error, info};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config::{get_config, Jwt};
+use crate::errors::Error;
+
+/// Keys are fetched again after this long, so rotated keys are picked up.
+const JWKS_TTL: Duration = Duration::from_secs(300);
+
+/// Don't fetch the keys more often than this when a token is signed
+/// with a key we don't know, e.g. a key that was just added.
+const JWKS_MIN_REFRESH: Duration = Duration::from_secs(10);
+
+/// The keys of each JWKS URL and when we fetched them.
+static JWKS: Lazy<Mutex<HashMap<String, (Instant, JwkSet)>>> =
+    Lazy::new(|| Mutex::new(HashMap::default()));
+
+/// Fetch the key set, or use the cached one if it's fresh enough.
+async fn get_jwks(url: &str, max_age: Duration) -> Result<JwkSet, Error> {
+    if let Some((fetched_at, jwks)) = JWKS.lock().get(url) {
+        if fetched_at.elapsed() < max_age {
+            return Ok(jwks.clone());
+        }
+    }
+
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_millis(get_config().general.connect_timeout))
+        .build()
+    {
+        Ok(client) => client,
+        Err(err) => {
+            error!("Could not create HTTP client: {}", err);
+            return Err(Error::ClientError);
+        }
+    };
+
+    let jwks = match client.get(url).send().await {
+        Ok(response) => match response.error_for_status() {
+            Ok(response) => response.json::<JwkSet>().await,
+            Err(err) => Err(err),
+        },
+        Err(err) => Err(err),
+    };
+
+    match jwks {
+        Ok(jwks) => {
+            info!("Fetched {} keys from {}", jwks.keys.len(), url);
+            JWKS.lock()
+                .insert(url.to_string(), (Instant::now(), jwks.clone()));
+            Ok(jwks)
+        }
+
+        Err(err) => {
+            error!("Could not fetch JWKS from {}: {}", url, err);
+            Err(Error::ClientError)
+        }
+    }
+}
+
+/// Check the token signature, expiration, issuer and audience, and that
+/// the user claim is the user the client is logging in as.
+pub async fn authenticate(config: &Jwt, user: &str, token: &str) -> Result<(), Error> {
+    let header = match decode_header(token) {
+        Ok(header) => header,
+        Err(err) => {
+            debug!("Invalid JWT: {}", err);
+            return Err(Error::ClientError);
+        }
+    };
+
+    // Only public key signatures: the keys come from the JWKS, so a shared
+    // secret algorithm would let anyone who can read the JWKS sign tokens.
+    match header.alg {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+            debug!("JWT signed with unsupported algorithm {:?}", header.alg);
+            return Err(Error::ClientError);
+        }
+        _ => (),
+    };
+
+    let mut jwks = get_jwks(&config.jwks_url, JWKS_TTL).await?;
+
+    let find_key = |jwks: &JwkSet| match header.kid {
+        Some(ref kid) => jwks.find(kid).cloned(),
+        None => jwks.keys.first().cloned(),
+    };
+
+    let jwk = match find_key(&jwks) {
+        Some(jwk) => jwk,
+        None => {
+            jwks = get_jwks(&config.jwks_url, JWKS_MIN_REFRESH).await?;
+
+            match find_key(&jwks) {
+                Some(jwk) => jwk,
+                None => {
+                    debug!("JWT signed with unknown key {:?}", header.kid);
+                    return Err(Error::ClientError);
+                }
+            }
+        }
+    };
+
+    let key = match DecodingKey::from_jwk(&jwk) {
+        Ok(key) => key,
+        Err(err) => {
+            error!("Unsupported key {:?} in JWKS: {}", header.kid, err);
+            return Err(Error::ClientError);
+        }
+    };
+
+    let mut validation = Validation::new(header.alg);
+
+    if let Some(ref issuer) = config.issuer {
+        validation.set_issuer(&[issuer]);
+    }
+
+    if let Some(ref audience) = config.audience {
+        validation.set_audience(&[audience]);
+    }
+
+    let claims = match decode::<HashMap<String, serde_json::Value>>(token, &key, &validation) {
+        Ok(token) => token.claims,
+        Err(err) => {
+            debug!("JWT validation failed: {}", err);
+            return Err(Error::ClientError);
+        }
+    };
+
+    match claims.get(&config.user_claim) {
+        Some(serde_json::Value::String(claim)) if claim == user => Ok(()),
+        claim => {
+            debug!(
+                "JWT claim {} is {:?}, expected {:?}",
+                config.user_claim, claim, user
+            );
+            Err(Error::ClientError)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use x509_parser::prelude::{FromDer, X509Certificate};
+    use x509_parser::public_key::PublicKey;
+
+    /// The JWKS with the RSA key of the test client certificate, as if fetched from the URL.
+    fn jwks(url: &str) {
+        let der = crate::tls::load_certs(std::path::Path::new("tests/tls/client.cert")).unwrap();
+        let (_, cert) = X509Certificate::from_der(&der[0].0).unwrap();
+
+        let (n, e) = match cert.public_key().parsed().unwrap() {
+            PublicKey::RSA(key) => (key.modulus, key.exponent),
+            _ => panic!("expected an RSA key"),
+        };
+
+        let encode = |bytes: &[u8]| {
+            let start = bytes.iter().position(|byte| *byte != 0).unwrap_or(0);
+            base64::encode_config(&bytes[start..], base64::URL_SAFE_NO_PAD)
+        };
+
+        let jwks: JwkSet = serde_json::from_value(serde_json::json!({
+            "keys": [{ "kty": "RSA", "kid": "test", "alg": "RS256", "n": encode(n), "e": encode(e) }]
+        }))
+        .unwrap();
+
+        JWKS.lock().insert(url.to_string(), (Instant::now(), jwks));
+    }
+
+    fn token(key_file: &str, alg: Algorithm, claims: serde_json::Value) -> String {
+        let mut header = Header::new(alg);
+        header.kid = Some(String::from("test"));
+
+        let key = match alg {
+            Algorithm::HS256 => EncodingKey::from_secret(b"secret"),
+            _ => EncodingKey::from_rsa_pem(&std::fs::read(key_file).unwrap()).unwrap(),
+        };
+
+        encode(&header, &claims, &key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let config = Jwt {
+            jwks_url: String::from("http://jwks.test/test_authenticate"),
+            issuer: Some(String::from("https://issuer.test")),
+            audience: None,
+            user_claim: String::from("sub"),
+        };
+
+        jwks(&config.jwks_url);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let claims = |sub: &str, iss: &str, exp: u64| serde_json::json!({ "sub": sub, "iss": iss, "exp": exp });
+
+        let valid = claims("alice", "https://issuer.test", now + 60);
+        let key = "tests/tls/client.key";
+
+        let good = token(key, Algorithm::RS256, valid.clone());
+        assert!(authenticate(&config, "alice", &good).await.is_ok());
+
+        // Another user's token.
+        assert!(authenticate(&config, "bob", &good).await.is_err());
+
+        // Expired, or from another issuer.
+        let expired = token(
+            key,
+            Algorithm::RS256,
+            claims("alice", "https://issuer.test", now - 3600),
+        );
+        assert!(authenticate(&config, "alice", &expired).await.is_err());
+
+        let other_issuer = token(
+            key,
+            Algorithm::RS256,
+            claims("alice", "https://other.test", now + 60),
+        );
+        assert!(authenticate(&config, "alice", &other_issuer).await.is_err());
+
+        // Signed with a shared secret, or with another key.
+        let hmac = token(key, Algorithm::HS256, valid.clone());
+        assert!(authenticate(&config, "alice", &hmac).await.is_err());
+
+        let other_key = token("tests/tls/ca.key", Algorithm::RS256, valid);
+        assert!(authenticate(&config, "alice", &other_key).await.is_err());
+
+        assert!(authenticate(&config, "alice", "not a token").await.is_err());
+    }
+}
@@ -0,0 +1,166 @@
+/// Check client passwords against an LDAP server, the same way
+/// Postgres does it with the ldap method in pg_hba.conf.
+use ldap3::{drive, ldap_escape, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use log::{debug, error};
+
+use crate::config::{get_config, Ldap};
+use crate::errors::Error;
+
+/// Check the user's password with an LDAP bind. In simple bind mode, the DN is the
+/// user name between `prefix` and `suffix`. In search+bind mode, the DN is found
+/// by searching `base_dn` for an entry with `search_attribute` equal to the user name.
+pub async fn authenticate(config: &Ldap, user: &str, password: &str) -> Result<(), Error> {
+    // An empty password makes the bind unauthenticated, which would always succeed.
+    if password.is_empty() {
+        return Err(Error::ClientError);
+    }
+
+    let settings = LdapConnSettings::new().set_conn_timeout(std::time::Duration::from_millis(
+        get_config().general.connect_timeout,
+    ));
+
+    let (conn, mut ldap) = match LdapConnAsync::with_settings(settings, &config.url).await {
+        Ok(conn) => conn,
+        Err(err) => {
+            error!("Could not connect to LDAP server {}: {}", config.url, err);
+            return Err(Error::ClientError);
+        }
+    };
+
+    drive!(conn);
+
+    let dn = match config.base_dn {
+        Some(ref base_dn) => {
+            let bind_dn = config.bind_dn.clone().unwrap_or_default();
+            let bind_password = config.bind_password.clone().unwrap_or_default();
+
+            match ldap.simple_bind(&bind_dn, &bind_password).await {
+                Ok(result) if result.rc == 0 => (),
+                Ok(result) => {
+                    error!("LDAP search bind as {:?} failed: {}", bind_dn, result);
+                    return Err(Error::ClientError);
+                }
+                Err(err) => {
+                    error!("LDAP search bind failed: {}", err);
+                    return Err(Error::ClientError);
+                }
+            };
+
+            let filter = search_filter(config, user);
+
+            let entries = match ldap
+                .search(base_dn, Scope::Subtree, &filter, vec!["1.1"])
+                .await
+                .and_then(|result| result.success())
+            {
+                Ok((entries, _)) => entries,
+                Err(err) => {
+                    error!("LDAP search for {} failed: {}", filter, err);
+                    return Err(Error::ClientError);
+                }
+            };
+
+            // The user name must match exactly one entry, like Postgres requires.
+            if entries.len() != 1 {
+                debug!("LDAP search for {} found {} entries", filter, entries.len());
+                return Err(Error::ClientError);
+            }
+
+            SearchEntry::construct(entries.into_iter().next().unwrap()).dn
+        }
+
+        None => match simple_bind_dn(config, user) {
+            Some(dn) => dn,
+            None => {
+                debug!("Invalid character in user name {:?} for LDAP bind", user);
+                return Err(Error::ClientError);
+            }
+        },
+    };
+
+    let result = ldap.simple_bind(&dn, password).await;
+
+    let _ = ldap.unbind().await;
+
+    match result {
+        Ok(result) if result.rc == 0 => Ok(()),
+        Ok(result) => {
+            debug!("LDAP bind as {:?} failed: {}", dn, result);
+            Err(Error::ClientError)
+        }
+        Err(err) => {
+            error!("LDAP bind failed: {}", err);
+            Err(Error::ClientError)
+        }
+    }
+}
+
+/// The filter that finds the user in search+bind mode, with the user name escaped.
+fn search_filter(config: &Ldap, user: &str) -> String {
+    format!("({}={})", config.search_attribute, ldap_escape(user))
+}
+
+/// The DN of the user in simple bind mode. The user name can't have the characters
+/// that have a meaning in a DN, so it can't bind as another entry; Postgres refuses them too.
+fn simple_bind_dn(config: &Ldap, user: &str) -> Option<String> {
+    if user.contains([',', '+', '"', '\\', '<', '>', ';', '=']) {
+        return None;
+    }
+
+    Some(format!(
+        "{}{}{}",
+        config.prefix.clone().unwrap_or_default(),
+        user,
+        config.suffix.clone().unwrap_or_default()
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(base_dn: Option<&str>) -> Ldap {
+        Ldap {
+            url: String::from("ldap://localhost"),
+            prefix: Some(String::from("cn=")),
+            suffix: Some(String::from(",ou=people,dc=example,dc=com")),
+            base_dn: base_dn.map(String::from),
+            bind_dn: None,
+            bind_password: None,
+            search_attribute: String::from("uid"),
+        }
+    }
+
+    #[test]
+    fn test_simple_bind_dn() {
+        let config = config(None);
+
+        assert_eq!(
+            simple_bind_dn(&config, "alice").as_deref(),
+            Some("cn=alice,ou=people,dc=example,dc=com")
+        );
+
+        for user in [
+            "alice,ou=admins",
+            "alice+uid=root",
+            "\"alice\"",
+            "alice\\2c",
+            "<alice>",
+            "alice;",
+            "cn=alice",
+        ] {
+            assert_eq!(simple_bind_dn(&config, user), None, "{}", user);
+        }
+    }
+
+    #[test]
+    fn test_search_filter() {
+        let config = config(Some("dc=example,dc=com"));
+
+        assert_eq!(search_filter(&config, "alice"), "(uid=alice)");
+        assert_eq!(
+            search_filter(&config, "*)(uid=*"),
+            "(uid=\\2a\\29\\28uid=\\2a)"
+        );
+    }
+}
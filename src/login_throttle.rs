@@ -0,0 +1,65 @@
+/// Limit the new connections to each server (host and port), with server_login_rate,
+/// so the pools of all the users and databases reconnecting at once, e.g. after
+/// PgCat restarts or the server fails over, don't storm it with logins.
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config::get_config;
+
+/// When the next connection to each server can be opened.
+static NEXT: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(|| Mutex::new(HashMap::default()));
+
+/// Wait until one more connection to the server can be opened.
+pub async fn wait(server: &str) {
+    let logins_per_second = get_config().general.server_login_rate;
+
+    if logins_per_second == 0 {
+        return;
+    }
+
+    let delay = {
+        let now = Instant::now();
+        let mut next = NEXT.lock();
+        let next = next.entry(server.to_string()).or_insert(now);
+
+        reserve(next, now, logins_per_second)
+    };
+
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Reserve the next login after the ones already reserved, spread evenly over
+/// the second, and return how long to wait until then.
+fn reserve(next: &mut Instant, now: Instant, logins_per_second: u32) -> Duration {
+    let start = (*next).max(now);
+
+    *next = start + Duration::from_secs(1) / logins_per_second;
+
+    start - now
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reserve() {
+        let now = Instant::now();
+        let mut next = now;
+
+        // The first login goes right away, the next ones 250ms apart.
+        assert_eq!(reserve(&mut next, now, 4), Duration::ZERO);
+        assert_eq!(reserve(&mut next, now, 4), Duration::from_millis(250));
+        assert_eq!(reserve(&mut next, now, 4), Duration::from_millis(500));
+        assert_eq!(next, now + Duration::from_millis(750));
+
+        // Logins not used aren't saved for later.
+        let later = now + Duration::from_secs(5);
+        assert_eq!(reserve(&mut next, later, 4), Duration::ZERO);
+        assert_eq!(next, later + Duration::from_millis(250));
+    }
+}
@@ -38,7 +38,7 @@ extern crate toml;
 
 use log::{debug, error, info};
 use parking_lot::Mutex;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, UnixListener};
 use tokio::{
     signal,
     signal::unix::{signal as unix_signal, SignalKind},
@@ -49,20 +49,46 @@ use std::collections::HashMap;
 use std::sync::Arc;
 
 mod admin;
+mod auth_file;
+mod auth_passthrough;
+mod auth_throttle;
+mod autoscale;
 mod client;
 mod config;
 mod constants;
+mod copy_throttle;
+mod db_connections;
+mod drain;
+mod error_response;
 mod errors;
+mod gcp_iam;
+mod gssapi;
+mod hba;
+mod jwt;
+mod ldap;
+mod login_throttle;
 mod messages;
+mod pam;
+mod parameters;
 mod pool;
+mod prepared_statements;
 mod query_router;
+mod rds_iam;
+mod replica_lag;
+mod resharding;
+mod revocation;
+mod scatter_gather;
 mod scram;
+mod secrets;
 mod server;
 mod sharding;
+mod sharding_directory;
+mod slow_start;
 mod stats;
 mod tls;
+mod vault;
 
-use config::{get_config, reload_config};
+use config::{get_config, reload_config, Listener};
 use pool::{ClientServerMap, ConnectionPool};
 use stats::{Collector, Reporter, REPORTER};
 
@@ -80,6 +106,18 @@ async fn main() {
 
     let args = std::env::args().collect::<Vec<String>>();
 
+    // Encrypt a value for the config with the secret key, e.g. `pgcat --encrypt hunter2`.
+    if args.len() == 3 && args[1] == "--encrypt" {
+        match secrets::key()
+            .await
+            .and_then(|key| secrets::encrypt(&args[2], &key))
+        {
+            Ok(encrypted) => println!("{}", encrypted),
+            Err(err) => error!("Could not encrypt: {:?}", err),
+        };
+        return;
+    }
+
     let config_file = if args.len() == 2 {
         args[1].to_string()
     } else {
@@ -94,6 +132,14 @@ async fn main() {
         }
     };
 
+    match auth_file::reload().await {
+        Ok(_) => (),
+        Err(err) => {
+            error!("Auth file error: {:?}", err);
+            return;
+        }
+    };
+
     let config = get_config();
     let addr = format!("{}:{}", config.general.host, config.general.port);
 
@@ -107,6 +153,46 @@ async fn main() {
 
     info!("Running on {}", addr);
 
+    let mut extra_listeners = Vec::new();
+
+    for extra_listener in &config.general.listeners {
+        let addr = format!("{}:{}", extra_listener.host, extra_listener.port);
+
+        match TcpListener::bind(&addr).await {
+            Ok(sock) => {
+                info!("Running on {}", addr);
+                extra_listeners.push((sock, extra_listener.clone()));
+            }
+            Err(err) => {
+                error!("Listener socket error on {}: {:?}", addr, err);
+                return;
+            }
+        };
+    }
+
+    let unix_listener = match config.general.unix_socket_dir {
+        Some(ref unix_socket_dir) => {
+            // Same name as Postgres, so clients find it with the directory and port.
+            let path = format!("{}/.s.PGSQL.{}", unix_socket_dir, config.general.port);
+
+            // Remove the socket left behind by the last run.
+            let _ = std::fs::remove_file(&path);
+
+            match UnixListener::bind(&path) {
+                Ok(listener) => {
+                    info!("Running on {}", path);
+                    Some(listener)
+                }
+                Err(err) => {
+                    error!("Unix socket error: {:?}", err);
+                    return;
+                }
+            }
+        }
+
+        None => None,
+    };
+
     config.show();
 
     // Tracks which client is connected to which server for query cancellation.
@@ -131,6 +217,7 @@ async fn main() {
     // Save these for reloading
     let reload_client_server_map = client_server_map.clone();
     let autoreload_client_server_map = client_server_map.clone();
+    let unix_client_server_map = client_server_map.clone();
 
     tokio::task::spawn(async move {
         let mut stats_collector = Collector::new(rx, collector_tx);
@@ -139,41 +226,54 @@ async fn main() {
 
     info!("Waiting for clients");
 
-    // Client connection loop.
-    tokio::task::spawn(async move {
-        loop {
-            let client_server_map = client_server_map.clone();
-
-            let (socket, addr) = match listener.accept().await {
-                Ok((socket, addr)) => (socket, addr),
-                Err(err) => {
-                    error!("{:?}", err);
-                    continue;
-                }
-            };
+    // Client connection loops.
+    for (extra_listener, listener_config) in extra_listeners {
+        tokio::task::spawn(accept_clients(
+            extra_listener,
+            client_server_map.clone(),
+            Some(listener_config),
+        ));
+    }
 
-            // Handle client.
-            tokio::task::spawn(async move {
-                let start = chrono::offset::Utc::now().naive_utc();
+    tokio::task::spawn(accept_clients(listener, client_server_map, None));
 
-                match client::client_entrypoint(socket, client_server_map).await {
-                    Ok(_) => {
-                        let duration = chrono::offset::Utc::now().naive_utc() - start;
+    // Unix socket client connection loop.
+    if let Some(unix_listener) = unix_listener {
+        let client_server_map = unix_client_server_map;
 
-                        info!(
-                            "Client {:?} disconnected, session duration: {}",
-                            addr,
-                            format_duration(&duration)
-                        );
-                    }
+        tokio::task::spawn(async move {
+            loop {
+                let client_server_map = client_server_map.clone();
 
+                let socket = match unix_listener.accept().await {
+                    Ok((socket, _)) => socket,
                     Err(err) => {
-                        debug!("Client disconnected with error {:?}", err);
+                        error!("{:?}", err);
+                        continue;
                     }
                 };
-            });
-        }
-    });
+
+                tokio::task::spawn(async move {
+                    let start = chrono::offset::Utc::now().naive_utc();
+
+                    match client::unix_entrypoint(socket, client_server_map).await {
+                        Ok(_) => {
+                            let duration = chrono::offset::Utc::now().naive_utc() - start;
+
+                            info!(
+                                "Client disconnected (unix socket), session duration: {}",
+                                format_duration(&duration)
+                            );
+                        }
+
+                        Err(err) => {
+                            debug!("Client disconnected with error {:?}", err);
+                        }
+                    };
+                });
+            }
+        });
+    }
 
     // Reload config:
     // kill -SIGHUP $(pgrep pgcat)
@@ -214,6 +314,81 @@ async fn main() {
         });
     }
 
+    // Reload the auth file when it changes, or when the config
+    // points to another one.
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(1_000));
+
+        loop {
+            interval.tick().await;
+            let _ = auth_file::reload().await;
+        }
+    });
+
+    // Reload TLS certificates when they change, so they can be rotated
+    // without a restart.
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(1_000));
+
+        loop {
+            interval.tick().await;
+            tls::reload();
+        }
+    });
+
+    // Even out the server connections of the replicas, e.g. after one was banned.
+    tokio::task::spawn(async move {
+        loop {
+            match get_config().general.rebalance_interval {
+                0 => tokio::time::sleep(tokio::time::Duration::from_millis(1_000)).await,
+                rebalance_interval => {
+                    tokio::time::sleep(tokio::time::Duration::from_secs(rebalance_interval)).await;
+                    pool::rebalance().await;
+                }
+            }
+        }
+    });
+
+    // Measure the lag of the replicas, so the ones too far behind stop getting reads.
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(1_000));
+
+        loop {
+            interval.tick().await;
+            replica_lag::check().await;
+        }
+    });
+
+    // Reload the sharding directories of the pools that have one.
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(1_000));
+
+        loop {
+            interval.tick().await;
+            sharding_directory::refresh().await;
+        }
+    });
+
+    // Resize the autoscaled pools.
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(1_000));
+
+        loop {
+            interval.tick().await;
+            pool::autoscale().await;
+        }
+    });
+
+    // Renew Vault leases and rotate the credentials before they expire.
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(1_000));
+
+        loop {
+            interval.tick().await;
+            vault::renew().await;
+        }
+    });
+
     // Exit on Ctrl-C (SIGINT) and SIGTERM.
     let mut term_signal = unix_signal(SignalKind::terminate()).unwrap();
 
@@ -223,6 +398,49 @@ async fn main() {
     };
 
     info!("Shutting down...");
+
+    client::shutdown().await;
+}
+
+/// Accept clients on a TCP listener. `listener_config` is set for the extra listeners.
+async fn accept_clients(
+    listener: TcpListener,
+    client_server_map: ClientServerMap,
+    listener_config: Option<Listener>,
+) {
+    loop {
+        let client_server_map = client_server_map.clone();
+        let listener_config = listener_config.clone();
+
+        let (socket, addr) = match listener.accept().await {
+            Ok((socket, addr)) => (socket, addr),
+            Err(err) => {
+                error!("{:?}", err);
+                continue;
+            }
+        };
+
+        // Handle client.
+        tokio::task::spawn(async move {
+            let start = chrono::offset::Utc::now().naive_utc();
+
+            match client::client_entrypoint(socket, client_server_map, listener_config).await {
+                Ok(_) => {
+                    let duration = chrono::offset::Utc::now().naive_utc() - start;
+
+                    info!(
+                        "Client {:?} disconnected, session duration: {}",
+                        addr,
+                        format_duration(&duration)
+                    );
+                }
+
+                Err(err) => {
+                    debug!("Client disconnected with error {:?}", err);
+                }
+            };
+        });
+    }
 }
 
 /// Format chrono::Duration to be more human-friendly.
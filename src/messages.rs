@@ -1,10 +1,13 @@
 /// Helper functions to send one-off protocol messages
 /// and handle TcpStream (TCP socket).
 use bytes::{Buf, BufMut, BytesMut};
+use log::{debug, warn};
 use md5::{Digest, Md5};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
+use crate::constants::*;
+use crate::error_response::*;
 use crate::errors::Error;
 use std::collections::HashMap;
 use std::mem;
@@ -64,6 +67,141 @@ where
     Ok(salt)
 }
 
+/// Ask the client for its password in plain text (AuthenticationCleartextPassword).
+pub async fn cleartext_password_challenge<S>(stream: &mut S) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut res = BytesMut::new();
+    res.put_u8(b'R');
+    res.put_i32(8);
+    res.put_i32(CLEARTEXT_PASSWORD);
+
+    write_all(stream, res).await
+}
+
+/// Ask the client to authenticate with GSSAPI (AuthenticationGSS).
+pub async fn gss_challenge<S>(stream: &mut S) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut res = BytesMut::new();
+    res.put_u8(b'R');
+    res.put_i32(8);
+    res.put_i32(GSS);
+
+    write_all(stream, res).await
+}
+
+/// Send the server's GSSAPI token to the client (AuthenticationGSSContinue).
+pub async fn gss_continue<S>(stream: &mut S, token: &[u8]) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut res = BytesMut::new();
+    res.put_u8(b'R');
+    res.put_i32(4 + 4 + token.len() as i32);
+    res.put_i32(GSS_CONTINUE);
+    res.put_slice(token);
+
+    write_all(stream, res).await
+}
+
+/// Ask the client to authenticate with SCRAM-SHA-256 (AuthenticationSASL),
+/// or SCRAM-SHA-256-PLUS if we can do channel binding.
+pub async fn scram_start_challenge<S>(stream: &mut S, channel_binding: bool) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mechanisms = match channel_binding {
+        true => format!("{}\0{}\0\0", SCRAM_SHA_256_PLUS, SCRAM_SHA_256),
+        false => format!("{}\0\0", SCRAM_SHA_256),
+    };
+
+    let mut res = BytesMut::new();
+    res.put_u8(b'R');
+    res.put_i32(4 + 4 + mechanisms.len() as i32);
+    res.put_i32(SASL);
+    res.put_slice(mechanisms.as_bytes());
+
+    write_all(stream, res).await
+}
+
+/// Send a SCRAM server message to the client,
+/// either AuthenticationSASLContinue or AuthenticationSASLFinal.
+pub async fn scram_server_response<S>(stream: &mut S, code: i32, data: &[u8]) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut res = BytesMut::new();
+    res.put_u8(b'R');
+    res.put_i32(4 + 4 + data.len() as i32);
+    res.put_i32(code);
+    res.put_slice(data);
+
+    write_all(stream, res).await
+}
+
+/// Read the client's reply to an authentication challenge.
+/// This is the PasswordMessage, SASLInitialResponse or SASLResponse;
+/// they all share the same message code.
+pub async fn read_password<S>(stream: &mut S) -> Result<Vec<u8>, Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+{
+    let code = match stream.read_u8().await {
+        Ok(code) => code,
+        Err(_) => return Err(Error::SocketError),
+    };
+
+    if code as char != 'p' {
+        debug!("Expected p, got {}", code as char);
+        return Err(Error::ProtocolSyncError);
+    }
+
+    let len = match stream.read_i32().await {
+        Ok(len) => len,
+        Err(_) => return Err(Error::SocketError),
+    };
+
+    if !(4..=MAX_AUTH_TOKEN_LENGTH).contains(&len) {
+        warn!("Invalid password message length: {}", len);
+        return Err(Error::ProtocolSyncError);
+    }
+
+    let mut password = vec![0u8; (len - 4) as usize];
+
+    match stream.read_exact(&mut password).await {
+        Ok(_) => Ok(password),
+        Err(_) => Err(Error::SocketError),
+    }
+}
+
+/// Parse the SASLInitialResponse message body: the mechanism selected by the client
+/// and the client first message.
+pub fn parse_sasl_initial_response(mut bytes: BytesMut) -> Result<(String, BytesMut), Error> {
+    let mechanism = match bytes.iter().position(|c| *c == 0) {
+        Some(end) => {
+            let mechanism = String::from_utf8_lossy(&bytes[..end]).to_string();
+            bytes.advance(end + 1);
+            mechanism
+        }
+        None => return Err(Error::ProtocolSyncError),
+    };
+
+    if bytes.remaining() < 4 {
+        return Err(Error::ProtocolSyncError);
+    }
+
+    let len = bytes.get_i32();
+
+    if len < 0 || len as usize != bytes.remaining() {
+        return Err(Error::ProtocolSyncError);
+    }
+
+    Ok((mechanism, bytes))
+}
+
 /// Give the client the process_id and secret we generated
 /// used in query cancellation.
 pub async fn backend_key_data<S>(
@@ -82,6 +220,31 @@ where
     Ok(write_all(stream, key_data).await?)
 }
 
+/// Tell the client we only speak protocol 3.0, when it asked for a newer minor
+/// version or for protocol options (`_pq_.*`), which are listed as unsupported.
+pub async fn negotiate_protocol_version<S>(stream: &mut S, options: &[String]) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut negotiate = BytesMut::new();
+
+    // Newest minor version we support.
+    negotiate.put_i32(PROTOCOL_VERSION_NUMBER & 0xFFFF);
+
+    negotiate.put_i32(options.len() as i32);
+
+    for option in options {
+        negotiate.put_slice(option.as_bytes());
+        negotiate.put_u8(0);
+    }
+
+    let mut res = BytesMut::from(&b"v"[..]);
+    res.put_i32(negotiate.len() as i32 + 4);
+    res.put(negotiate);
+
+    write_all(stream, res).await
+}
+
 /// Construct a `Q`: Query message.
 pub fn simple_query(query: &str) -> BytesMut {
     let mut res = BytesMut::from(&b"Q"[..]);
@@ -93,6 +256,69 @@ pub fn simple_query(query: &str) -> BytesMut {
     res
 }
 
+/// An unnamed Parse, a Bind of the parameters as text, an Execute and a Sync: one
+/// query with parameters, whose values aren't part of the SQL like in a simple query.
+pub fn extended_query(query: &str, params: &[&str]) -> BytesMut {
+    let mut res = BytesMut::new();
+
+    // Parse, no parameter types: the server infers them.
+    res.put_u8(b'P');
+    res.put_i32(4 + 1 + query.len() as i32 + 1 + 2);
+    res.put_u8(0);
+    res.put_slice(query.as_bytes());
+    res.put_u8(0);
+    res.put_i16(0);
+
+    // Bind, the parameters and the columns as text.
+    let params_len: usize = params.iter().map(|param| 4 + param.len()).sum();
+    res.put_u8(b'B');
+    res.put_i32(4 + 1 + 1 + 2 + 2 + params_len as i32 + 2);
+    res.put_u8(0);
+    res.put_u8(0);
+    res.put_i16(0);
+    res.put_i16(params.len() as i16);
+    for param in params {
+        res.put_i32(param.len() as i32);
+        res.put_slice(param.as_bytes());
+    }
+    res.put_i16(0);
+
+    // Execute all the rows.
+    res.put_u8(b'E');
+    res.put_i32(4 + 1 + 4);
+    res.put_u8(0);
+    res.put_i32(0);
+
+    // Sync
+    res.put_u8(b'S');
+    res.put_i32(4);
+
+    res
+}
+
+/// Construct a `C`: Close message for a prepared statement.
+pub fn close_statement(name: &str) -> BytesMut {
+    let mut res = BytesMut::from(&b"C"[..]);
+
+    res.put_i32(name.len() as i32 + 6);
+    res.put_u8(b'S');
+    res.put_slice(name.as_bytes());
+    res.put_u8(0);
+
+    res
+}
+
+/// Construct an `f`: CopyFail message, aborting COPY FROM STDIN.
+pub fn copy_fail(reason: &str) -> BytesMut {
+    let mut res = BytesMut::from(&b"f"[..]);
+    let reason = format!("{}\0", reason);
+
+    res.put_i32(reason.len() as i32 + 4);
+    res.put_slice(reason.as_bytes());
+
+    res
+}
+
 /// Tell the client we're ready for another query.
 pub async fn ready_for_query<S>(stream: &mut S) -> Result<(), Error>
 where
@@ -108,8 +334,19 @@ where
 }
 
 /// Send the startup packet the server. We're pretending we're a Pg client.
-/// This tells the server which user we are and what database we want.
-pub async fn startup(stream: &mut TcpStream, user: &str, database: &str) -> Result<(), Error> {
+/// This tells the server which user we are and what database we want,
+/// and if it's a replication connection.
+pub async fn startup<S>(
+    stream: &mut S,
+    user: &str,
+    database: &str,
+    application_name: &str,
+    parameters: &[(String, String)],
+    replication: Option<&str>,
+) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
     let mut bytes = BytesMut::with_capacity(25);
 
     bytes.put_i32(196608); // Protocol number
@@ -123,6 +360,27 @@ pub async fn startup(stream: &mut TcpStream, user: &str, database: &str) -> Resu
     bytes.put(&b"database\0"[..]);
     bytes.put_slice(&database.as_bytes());
     bytes.put_u8(0);
+
+    // The session's default name, RESET and DISCARD ALL go back to it.
+    bytes.put(&b"application_name\0"[..]);
+    bytes.put_slice(application_name.as_bytes());
+    bytes.put_u8(0);
+
+    // The pool's settings are the session's defaults too.
+    for (name, value) in parameters {
+        bytes.put_slice(name.as_bytes());
+        bytes.put_u8(0);
+        bytes.put_slice(value.as_bytes());
+        bytes.put_u8(0);
+    }
+
+    // Replication
+    if let Some(replication) = replication {
+        bytes.put(&b"replication\0"[..]);
+        bytes.put_slice(replication.as_bytes());
+        bytes.put_u8(0);
+    }
+
     bytes.put_u8(0); // Null terminator
 
     let len = bytes.len() as i32 + 4i32;
@@ -138,6 +396,16 @@ pub async fn startup(stream: &mut TcpStream, user: &str, database: &str) -> Resu
     }
 }
 
+/// Ask the server to switch the connection to TLS before the startup packet.
+pub async fn ssl_request(stream: &mut TcpStream) -> Result<(), Error> {
+    let mut bytes = BytesMut::with_capacity(8);
+
+    bytes.put_i32(8);
+    bytes.put_i32(SSL_REQUEST_CODE);
+
+    write_all(stream, bytes).await
+}
+
 /// Parse the params the server sends as a key/value format.
 pub fn parse_params(mut bytes: BytesMut) -> Result<HashMap<String, String>, Error> {
     let mut result = HashMap::new();
@@ -190,18 +458,31 @@ pub fn parse_startup(bytes: BytesMut) -> Result<HashMap<String, String>, Error>
     Ok(result)
 }
 
+/// Check if the password is already an MD5 hash, as stored by Postgres in `pg_authid`,
+/// i.e. "md5" followed by 32 hex characters.
+pub fn is_md5_hash(password: &str) -> bool {
+    password.len() == 35
+        && password.starts_with("md5")
+        && password[3..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
 /// Create md5 password hash given a salt.
+/// If the password is already hashed, only the salt is applied.
 pub fn md5_hash_password(user: &str, password: &str, salt: &[u8]) -> Vec<u8> {
     let mut md5 = Md5::new();
 
     // First pass
-    md5.update(&password.as_bytes());
-    md5.update(&user.as_bytes());
+    let hash = if is_md5_hash(password) {
+        password[3..].to_string()
+    } else {
+        md5.update(&password.as_bytes());
+        md5.update(&user.as_bytes());
 
-    let output = md5.finalize_reset();
+        format!("{:x}", md5.finalize_reset())
+    };
 
     // Second pass
-    md5.update(format!("{:x}", output));
+    md5.update(hash);
     md5.update(salt);
 
     let mut password = format!("md5{:x}", md5.finalize())
@@ -235,6 +516,22 @@ where
     Ok(write_all(stream, message).await?)
 }
 
+/// Send the password in plain text to the server.
+/// This is the response to AuthenticationCleartextPassword.
+pub async fn cleartext_password_response<S>(stream: &mut S, password: &str) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut message = BytesMut::with_capacity(password.len() + 6);
+
+    message.put_u8(b'p');
+    message.put_i32(password.len() as i32 + 4 + 1);
+    message.put_slice(password.as_bytes());
+    message.put_u8(0);
+
+    write_all(stream, message).await
+}
+
 /// Implements a response to our custom `SET SHARDING KEY`
 /// and `SET SERVER ROLE` commands.
 /// This tells the client we're ready for the next query.
@@ -260,87 +557,68 @@ where
     write_all_half(stream, res).await
 }
 
-/// Send a custom error message to the client.
+/// Send an error to the client.
 /// Tell the client we are ready for the next query and no rollback is necessary.
-/// Docs on error codes: <https://www.postgresql.org/docs/12/errcodes-appendix.html>.
-pub async fn error_response<S>(stream: &mut S, message: &str) -> Result<(), Error>
+pub async fn error_response<S>(stream: &mut S, error: &ErrorResponse) -> Result<(), Error>
 where
     S: tokio::io::AsyncWrite + std::marker::Unpin,
 {
-    let mut error = BytesMut::new();
-
-    // Error level
-    error.put_u8(b'S');
-    error.put_slice(&b"FATAL\0"[..]);
-
-    // Error level (non-translatable)
-    error.put_u8(b'V');
-    error.put_slice(&b"FATAL\0"[..]);
-
-    // Error code: not sure how much this matters.
-    error.put_u8(b'C');
-    error.put_slice(&b"58000\0"[..]); // system_error, see Appendix A.
-
-    // The short error message.
-    error.put_u8(b'M');
-    error.put_slice(&format!("{}\0", message).as_bytes());
-
-    // No more fields follow.
-    error.put_u8(0);
-
-    // Ready for query, no rollback needed (I = idle).
-    let mut ready_for_query = BytesMut::new();
-
-    ready_for_query.put_u8(b'Z');
-    ready_for_query.put_i32(5);
-    ready_for_query.put_u8(b'I');
+    transaction_error_response(stream, error, b'I').await
+}
 
-    // Compose the two message reply.
-    let mut res = BytesMut::with_capacity(error.len() + ready_for_query.len() + 5);
+/// Send an error to a client using a server, followed by
+/// the transaction status of the server, e.g. it's still in a transaction.
+pub async fn transaction_error_response<S>(
+    stream: &mut S,
+    error: &ErrorResponse,
+    transaction_status: u8,
+) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut res = error.to_bytes();
 
-    res.put_u8(b'E');
-    res.put_i32(error.len() as i32 + 4);
+    // Ready for query, e.g. no rollback needed (I = idle).
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(transaction_status);
 
-    res.put(error);
-    res.put(ready_for_query);
+    write_all_half(stream, res).await
+}
 
-    Ok(write_all_half(stream, res).await?)
+/// Send an error to the client before disconnecting it, e.g. it couldn't log in.
+pub async fn fatal_error_response<S>(stream: &mut S, error: &ErrorResponse) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    write_all(stream, error.to_bytes()).await
 }
 
 pub async fn wrong_password<S>(stream: &mut S, user: &str) -> Result<(), Error>
 where
     S: tokio::io::AsyncWrite + std::marker::Unpin,
 {
-    let mut error = BytesMut::new();
-
-    // Error level
-    error.put_u8(b'S');
-    error.put_slice(&b"FATAL\0"[..]);
-
-    // Error level (non-translatable)
-    error.put_u8(b'V');
-    error.put_slice(&b"FATAL\0"[..]);
-
-    // Error code: not sure how much this matters.
-    error.put_u8(b'C');
-    error.put_slice(&b"28P01\0"[..]); // system_error, see Appendix A.
-
-    // The short error message.
-    error.put_u8(b'M');
-    error.put_slice(&format!("password authentication failed for user \"{}\"\0", user).as_bytes());
-
-    // No more fields follow.
-    error.put_u8(0);
-
-    // Compose the two message reply.
-    let mut res = BytesMut::new();
-
-    res.put_u8(b'E');
-    res.put_i32(error.len() as i32 + 4);
-
-    res.put(error);
+    fatal_error_response(
+        stream,
+        &ErrorResponse::fatal(
+            INVALID_PASSWORD,
+            &format!("password authentication failed for user \"{}\"", user),
+        ),
+    )
+    .await
+}
 
-    write_all(stream, res).await
+/// The client isn't allowed to log in as the user, e.g. its
+/// certificate doesn't match (invalid_authorization_specification).
+pub async fn invalid_authorization<S>(stream: &mut S, message: &str) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    fatal_error_response(
+        stream,
+        &ErrorResponse::fatal(INVALID_AUTHORIZATION_SPECIFICATION, message),
+    )
+    .await
 }
 
 /// Respond to a SHOW SHARD command.
@@ -471,6 +749,16 @@ where
 
 /// Read a complete message from the socket.
 pub async fn read_message<S>(stream: &mut S) -> Result<BytesMut, Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+{
+    let (code, len) = read_message_header(stream).await?;
+
+    read_message_body(stream, code, len).await
+}
+
+/// Read the code and the length of the next message from the socket.
+pub async fn read_message_header<S>(stream: &mut S) -> Result<(u8, i32), Error>
 where
     S: tokio::io::AsyncRead + std::marker::Unpin,
 {
@@ -480,10 +768,18 @@ where
     };
 
     let len = match stream.read_i32().await {
-        Ok(len) => len,
-        Err(_) => return Err(Error::SocketError),
+        Ok(len) if len >= 4 => len,
+        _ => return Err(Error::SocketError),
     };
 
+    Ok((code, len))
+}
+
+/// Read the rest of the message which header was read with `read_message_header()`.
+pub async fn read_message_body<S>(stream: &mut S, code: u8, len: i32) -> Result<BytesMut, Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+{
     let mut buf = vec![0u8; len as usize - 4];
 
     match stream.read_exact(&mut buf).await {
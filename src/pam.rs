@@ -0,0 +1,217 @@
+/// Check client passwords with the host's PAM stack, like Postgres' pam method.
+/// The PAM library is loaded the first time it's needed, so pgcat doesn't depend
+/// on it unless PAM is used. PAM modules block, so call this from a blocking thread.
+use log::{debug, error, info};
+use once_cell::sync::Lazy;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::ptr::null_mut;
+
+/// Names of the PAM library.
+const LIBRARY_NAMES: [&str; 3] = ["libpam.so.0", "libpam.so", "libpam.dylib"];
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_CONV_ERR: c_int = 19;
+
+/// Message styles, see pam_conv(3).
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+const PAM_PROMPT_ECHO_ON: c_int = 2;
+const PAM_ERROR_MSG: c_int = 3;
+const PAM_TEXT_INFO: c_int = 4;
+
+#[repr(C)]
+struct Message {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct Response {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+type Conversation = unsafe extern "C" fn(
+    num_msg: c_int,
+    msg: *mut *const Message,
+    resp: *mut *mut Response,
+    appdata_ptr: *mut c_void,
+) -> c_int;
+
+#[repr(C)]
+struct Conv {
+    conv: Conversation,
+    appdata_ptr: *mut c_void,
+}
+
+type Start = unsafe extern "C" fn(
+    service_name: *const c_char,
+    user: *const c_char,
+    pam_conversation: *const Conv,
+    pamh: *mut *mut c_void,
+) -> c_int;
+
+type Authenticate = unsafe extern "C" fn(pamh: *mut c_void, flags: c_int) -> c_int;
+
+type AcctMgmt = unsafe extern "C" fn(pamh: *mut c_void, flags: c_int) -> c_int;
+
+type End = unsafe extern "C" fn(pamh: *mut c_void, pam_status: c_int) -> c_int;
+
+type Strerror = unsafe extern "C" fn(pamh: *mut c_void, errnum: c_int) -> *const c_char;
+
+/// The functions we use from the PAM library.
+struct Library {
+    start: Start,
+    authenticate: Authenticate,
+    acct_mgmt: AcctMgmt,
+    end: End,
+    strerror: Strerror,
+
+    // The functions above are valid as long as the library is loaded.
+    _library: libloading::Library,
+}
+
+static LIBRARY: Lazy<Option<Library>> = Lazy::new(|| match load() {
+    Ok(library) => Some(library),
+    Err(err) => {
+        error!("Could not load the PAM library: {}", err);
+        None
+    }
+});
+
+fn load() -> Result<Library, libloading::Error> {
+    let mut result = None;
+
+    for name in LIBRARY_NAMES {
+        result = Some(unsafe { libloading::Library::new(name) });
+
+        if let Some(Ok(_)) = result {
+            info!("Loaded PAM library {}", name);
+            break;
+        }
+    }
+
+    let library = result.unwrap()?;
+
+    unsafe {
+        Ok(Library {
+            start: *library.get(b"pam_start\0")?,
+            authenticate: *library.get(b"pam_authenticate\0")?,
+            acct_mgmt: *library.get(b"pam_acct_mgmt\0")?,
+            end: *library.get(b"pam_end\0")?,
+            strerror: *library.get(b"pam_strerror\0")?,
+            _library: library,
+        })
+    }
+}
+
+/// Answer the PAM modules' prompts with the password, like Postgres does.
+/// The responses are freed by PAM, so they're allocated with malloc.
+unsafe extern "C" fn conversation(
+    num_msg: c_int,
+    msg: *mut *const Message,
+    resp: *mut *mut Response,
+    appdata_ptr: *mut c_void,
+) -> c_int {
+    if num_msg <= 0 || msg.is_null() || resp.is_null() || appdata_ptr.is_null() {
+        return PAM_CONV_ERR;
+    }
+
+    let password = appdata_ptr as *const c_char;
+    let responses =
+        libc::calloc(num_msg as usize, std::mem::size_of::<Response>()) as *mut Response;
+
+    if responses.is_null() {
+        return PAM_CONV_ERR;
+    }
+
+    for i in 0..num_msg as usize {
+        // Linux-PAM passes an array of pointers, so index the pointers.
+        let message = &**msg.add(i);
+
+        match message.msg_style {
+            PAM_PROMPT_ECHO_OFF | PAM_PROMPT_ECHO_ON => {
+                (*responses.add(i)).resp = libc::strdup(password);
+            }
+
+            PAM_ERROR_MSG | PAM_TEXT_INFO => {
+                if !message.msg.is_null() {
+                    debug!("PAM: {}", CStr::from_ptr(message.msg).to_string_lossy());
+                }
+            }
+
+            _ => {
+                libc::free(responses as *mut c_void);
+                return PAM_CONV_ERR;
+            }
+        }
+    }
+
+    *resp = responses;
+
+    PAM_SUCCESS
+}
+
+/// Check the user's password with the PAM service, and that the account can be used.
+pub fn authenticate(service: &str, user: &str, password: &str) -> bool {
+    let library = match LIBRARY.as_ref() {
+        Some(library) => library,
+        None => return false,
+    };
+
+    let (service, user, password) = match (
+        CString::new(service),
+        CString::new(user),
+        CString::new(password),
+    ) {
+        (Ok(service), Ok(user), Ok(password)) => (service, user, password),
+        _ => return false,
+    };
+
+    let conv = Conv {
+        conv: conversation,
+        appdata_ptr: password.as_ptr() as *mut c_void,
+    };
+
+    let mut handle = null_mut();
+
+    unsafe {
+        let mut status = (library.start)(service.as_ptr(), user.as_ptr(), &conv, &mut handle);
+
+        if status == PAM_SUCCESS {
+            status = (library.authenticate)(handle, 0);
+        }
+
+        if status == PAM_SUCCESS {
+            status = (library.acct_mgmt)(handle, 0);
+        }
+
+        if status != PAM_SUCCESS {
+            let message = (library.strerror)(handle, status);
+
+            if !message.is_null() {
+                debug!(
+                    "PAM authentication failed for {:?}: {}",
+                    user,
+                    CStr::from_ptr(message).to_string_lossy()
+                );
+            }
+        }
+
+        if !handle.is_null() {
+            (library.end)(handle, status);
+        }
+
+        status == PAM_SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unknown_user() {
+        assert!(!authenticate("pgcat", "pgcat-nonexistent-user", "password"));
+        assert!(!authenticate("pgcat", "user\0with nul", "password"));
+    }
+}
@@ -0,0 +1,353 @@
+/// Settings the servers report with ParameterStatus, e.g. TimeZone. Clients change
+/// them with SET, so in transaction mode the next client could get a server with
+/// someone else's. Each client remembers what it was told, and the servers it uses
+/// are set to that first. That includes application_name, so `pg_stat_activity`
+/// shows the name of the client using the server.
+///
+/// The settings clients ask for in the `options` startup parameter, or as startup
+/// parameters of their own, are set on the servers they use the same way.
+use bytes::{Buf, BufMut, BytesMut};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::messages::server_paramater_message;
+
+/// The settings set on the servers to what the client expects.
+const TRACKED: [&str; 6] = [
+    "application_name",
+    "client_encoding",
+    "DateStyle",
+    "IntervalStyle",
+    "TimeZone",
+    "standard_conforming_strings",
+];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServerParameters {
+    parameters: HashMap<String, String>,
+
+    /// Settings from the client's `options` that Postgres doesn't report.
+    options: BTreeMap<String, String>,
+}
+
+impl ServerParameters {
+    /// The parameters in ParameterStatus messages, e.g. the server info.
+    pub fn from_messages(messages: &BytesMut) -> ServerParameters {
+        let mut parameters = ServerParameters::default();
+        let mut messages = messages.clone();
+
+        while messages.len() >= 5 {
+            let code = messages.get_u8();
+            let len = messages.get_i32() as usize;
+
+            if len < 4 || messages.len() < len - 4 {
+                break;
+            }
+
+            let message = messages.split_to(len - 4);
+
+            if code == b'S' {
+                parameters.set(&message);
+            }
+        }
+
+        parameters
+    }
+
+    /// Remember the parameter in the ParameterStatus message (without its header).
+    pub fn set(&mut self, message: &[u8]) {
+        let mut strings = message.split(|byte| *byte == 0);
+
+        if let (Some(name), Some(value)) = (strings.next(), strings.next()) {
+            self.parameters.insert(
+                String::from_utf8_lossy(name).to_string(),
+                String::from_utf8_lossy(value).to_string(),
+            );
+        }
+    }
+
+    /// Use the values the client asked for in its startup packet, e.g. `TimeZone`.
+    /// Postgres would have reported them instead of its defaults. The other settings,
+    /// e.g. `statement_timeout`, are used like the ones in `options` if they're allowed,
+    /// and ignored otherwise.
+    pub fn startup(&mut self, startup: &HashMap<String, String>, allowed: &[String]) {
+        for (name, value) in startup {
+            if let Some(name) = TRACKED
+                .iter()
+                .find(|tracked| tracked.eq_ignore_ascii_case(name))
+            {
+                self.parameters.insert(name.to_string(), value.clone());
+            } else if let Some(name) = allowed
+                .iter()
+                .find(|allowed| allowed.eq_ignore_ascii_case(name))
+            {
+                self.options.insert(name.clone(), value.clone());
+            }
+        }
+    }
+
+    /// Use the settings of the client's `options`, e.g. `statement_timeout`.
+    pub fn startup_options(&mut self, options: Vec<(String, String)>) {
+        for (name, value) in options {
+            match TRACKED
+                .iter()
+                .find(|tracked| tracked.eq_ignore_ascii_case(&name))
+            {
+                Some(name) => self.parameters.insert(name.to_string(), value),
+                None => self.options.insert(name, value),
+            };
+        }
+    }
+
+    /// The server was set to the client's settings, or they were all reset.
+    pub fn set_options(&mut self, options: &ServerParameters) {
+        self.options = options.options.clone();
+    }
+
+    /// The settings of the options may have been reset, e.g. with RESET ALL, so
+    /// they're set again for the next client, and the ones it doesn't have reset.
+    pub fn forget_options(&mut self) {
+        for value in self.options.values_mut() {
+            // Not a value the options can have, they come from a C string.
+            *value = String::from("\0");
+        }
+    }
+
+    /// The ParameterStatus messages the client gets at startup.
+    pub fn messages(&self) -> BytesMut {
+        let mut messages = BytesMut::new();
+
+        for (name, value) in &self.parameters {
+            messages.put(server_paramater_message(name, value));
+        }
+
+        messages
+    }
+
+    /// The SET commands a server with the other parameters needs, if any.
+    pub fn set_query(&self, server: &ServerParameters) -> Option<String> {
+        let mut commands = TRACKED
+            .iter()
+            .filter_map(|name| match self.parameters.get(*name) {
+                Some(value) if server.parameters.get(*name) != Some(value) => {
+                    Some(format!("SET {} TO {}", name, literal(value)))
+                }
+                _ => None,
+            })
+            .collect::<Vec<String>>();
+
+        // set_config() takes the value like `options` does, e.g. a search_path list.
+        let options = self
+            .options
+            .iter()
+            .filter(|(name, value)| server.options.get(*name) != Some(value))
+            .map(|(name, value)| {
+                format!("set_config({}, {}, false)", literal(name), literal(value))
+            })
+            .collect::<Vec<String>>();
+
+        if !options.is_empty() {
+            commands.push(format!("SELECT {}", options.join(", ")));
+        }
+
+        // The settings of the server's previous client.
+        for name in server.options.keys() {
+            if !self.options.contains_key(name) {
+                commands.push(format!("RESET {}", name));
+            }
+        }
+
+        if commands.is_empty() {
+            None
+        } else {
+            Some(commands.join("; "))
+        }
+    }
+}
+
+/// A string literal with the value.
+fn literal(value: &str) -> String {
+    format!("E'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Parse the `options` startup parameter, e.g. `-c statement_timeout=5s --search_path=app`,
+/// into the settings it sets. Like Postgres, spaces are escaped with a backslash,
+/// and dashes in the names are underscores.
+pub fn parse_options(options: &str) -> Result<Vec<(String, String)>, String> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut escaped = false;
+
+    for c in options.chars() {
+        if escaped {
+            arg.push(c);
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c.is_ascii_whitespace() {
+            if !arg.is_empty() {
+                args.push(std::mem::take(&mut arg));
+            }
+        } else {
+            arg.push(c);
+        }
+    }
+
+    if !arg.is_empty() {
+        args.push(arg);
+    }
+
+    let mut settings = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        let setting = if arg == "-c" {
+            match args.next() {
+                Some(setting) => setting,
+                None => return Err(String::from("-c requires a setting")),
+            }
+        } else if let Some(setting) = arg.strip_prefix("--").or_else(|| arg.strip_prefix("-c")) {
+            setting.to_string()
+        } else {
+            return Err(format!("unsupported option \"{}\"", arg));
+        };
+
+        match setting.split_once('=') {
+            Some((name, value)) => settings.push((name.replace('-', "_"), value.to_string())),
+            None => return Err(format!("setting \"{}\" requires a value", setting)),
+        }
+    }
+
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_messages() {
+        let mut messages = server_paramater_message("TimeZone", "UTC");
+        messages.put(server_paramater_message("application_name", ""));
+        messages.put(server_paramater_message("server_version", "15.1"));
+
+        let parameters = ServerParameters::from_messages(&messages);
+
+        assert_eq!(parameters.parameters["TimeZone"], "UTC");
+        assert_eq!(parameters.parameters["application_name"], "");
+        assert_eq!(parameters.parameters["server_version"], "15.1");
+        assert_eq!(
+            ServerParameters::from_messages(&parameters.messages()),
+            parameters
+        );
+    }
+
+    #[test]
+    fn test_set_query() {
+        let server = ServerParameters::from_messages(&server_paramater_message("TimeZone", "UTC"));
+        let mut client = server.clone();
+
+        assert_eq!(client.set_query(&server), None);
+
+        client.startup(
+            &HashMap::from([
+                (String::from("timezone"), String::from("Europe/Paris")),
+                (String::from("datestyle"), String::from("it's")),
+                (String::from("search_path"), String::from("app")),
+                (String::from("statement_timeout"), String::from("5s")),
+            ]),
+            &[String::from("statement_timeout")],
+        );
+
+        assert_eq!(
+            client.set_query(&server),
+            Some(String::from(
+                "SET DateStyle TO E'it\\'s'; SET TimeZone TO E'Europe/Paris'; \
+                 SELECT set_config(E'statement_timeout', E'5s', false)"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_application_name() {
+        let mut server =
+            ServerParameters::from_messages(&server_paramater_message("application_name", "pgcat"));
+        let mut client = server.clone();
+
+        client.startup(
+            &HashMap::from([(String::from("application_name"), String::from("app"))]),
+            &[],
+        );
+
+        assert_eq!(
+            client.set_query(&server),
+            Some(String::from("SET application_name TO E'app'"))
+        );
+
+        // The next client without a name doesn't get the previous one's.
+        server.set(b"application_name\0app\0");
+        let client =
+            ServerParameters::from_messages(&server_paramater_message("application_name", "pgcat"));
+
+        assert_eq!(
+            client.set_query(&server),
+            Some(String::from("SET application_name TO E'pgcat'"))
+        );
+    }
+    #[test]
+    fn test_options() {
+        let server = ServerParameters::from_messages(&server_paramater_message("TimeZone", "UTC"));
+        let mut client = server.clone();
+
+        client.startup_options(vec![
+            (String::from("timezone"), String::from("UTC")),
+            (String::from("search_path"), String::from("app, public")),
+        ]);
+
+        assert_eq!(
+            client.set_query(&server),
+            Some(String::from(
+                "SELECT set_config(E'search_path', E'app, public', false)"
+            ))
+        );
+
+        let mut server = client.clone();
+        assert_eq!(client.set_query(&server), None);
+
+        // The next client doesn't have the options of the previous one.
+        server.startup_options(vec![(
+            String::from("statement_timeout"),
+            String::from("5s"),
+        )]);
+        assert_eq!(
+            client.set_query(&server),
+            Some(String::from("RESET statement_timeout"))
+        );
+
+        // The server was reset, the options may or may not be set anymore.
+        server.forget_options();
+        assert_eq!(
+            client.set_query(&server),
+            Some(String::from(
+                "SELECT set_config(E'search_path', E'app, public', false); RESET statement_timeout"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(
+            parse_options("-c statement_timeout=5s  -csearch_path=app,public --lock-timeout=1s -c application_name=my\\ app"),
+            Ok(vec![
+                (String::from("statement_timeout"), String::from("5s")),
+                (String::from("search_path"), String::from("app,public")),
+                (String::from("lock_timeout"), String::from("1s")),
+                (String::from("application_name"), String::from("my app")),
+            ])
+        );
+
+        assert_eq!(parse_options(""), Ok(vec![]));
+        assert!(parse_options("-c").is_err());
+        assert!(parse_options("-c statement_timeout").is_err());
+        assert!(parse_options("-d 5").is_err());
+    }
+}
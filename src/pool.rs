@@ -1,17 +1,34 @@
 use arc_swap::ArcSwap;
 use async_trait::async_trait;
-use bb8::{ManageConnection, Pool, PooledConnection};
+use bb8::{ManageConnection, Pool, PooledConnection, QueueStrategy, RunError};
 use bytes::BytesMut;
 use chrono::naive::NaiveDateTime;
 use log::{debug, error, info, warn};
 use once_cell::sync::Lazy;
 use parking_lot::{Mutex, RwLock};
-use std::collections::HashMap;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
-
-use crate::config::{get_config, Address, Role, Shard, User};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+use crate::auth_passthrough::{AuthPassthrough, AuthPassthroughMap, AUTH_PASSTHROUGHS};
+use crate::autoscale::Autoscaler;
+use crate::config::{
+    get_config, reload_lock, set_config, Address, ApplicationNameRoute, Jwt, Ldap,
+    Pool as PoolConfig, Resharding, Role, Shard, ShardingRange, User, Vault,
+};
+use crate::copy_throttle::CopyThrottle;
+use crate::db_connections::{self, DbConnection};
+use crate::drain;
 use crate::errors::Error;
+use crate::gcp_iam;
+use crate::login_throttle;
+use crate::rds_iam;
+use crate::replica_lag;
+use crate::slow_start;
+use crate::vault;
 
 use crate::server::Server;
 use crate::stats::{get_reporter, Reporter};
@@ -24,27 +41,166 @@ pub type PoolMap = HashMap<(String, String), ConnectionPool>;
 /// The pool is recreated dynamically when the config is reloaded.
 pub static POOLS: Lazy<ArcSwap<PoolMap>> = Lazy::new(|| ArcSwap::from_pointee(HashMap::default()));
 
+/// Serializes creation of pools for users found with `auth_query`, and resizing them.
+static ADD_USER_LOCK: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()));
+
 #[derive(Clone, Debug)]
 pub struct PoolSettings {
+    pub pool_name: String,
     pub pool_mode: String,
+    pub min_pool_size: u32,
+    pub reserve_pool_size: u32,
+    pub reserve_pool_timeout: u64,
     pub shards: HashMap<String, Shard>,
     pub user: User,
     pub default_role: String,
     pub query_parser_enabled: bool,
     pub primary_reads_enabled: bool,
+    pub load_balancing_mode: String,
+    pub latency_sensitivity: u32,
+    pub max_replica_lag: u64,
+    pub slow_start: u64,
+    pub primary_reads_fallback: bool,
+    pub primary_reads_after_write: u64,
     pub sharding_function: String,
+    pub sharding_mode: String,
+    pub sharding_seed: Option<u64>,
+    pub sharding_key_type: String,
+    pub sharding_ranges: Vec<ShardingRange>,
+    pub sharding_directory: bool,
+    pub scatter_gather: bool,
+    pub resharding: Option<Resharding>,
+    pub auth_type: String,
+    pub trust_listen_addresses: Vec<String>,
+    pub ldap: Option<Ldap>,
+    pub jwt: Option<Jwt>,
+    pub pam_service: String,
+    pub require_tls: bool,
+    pub max_prepared_statements: usize,
+    pub reject_pipelining: bool,
+    pub copy_throttle: Option<CopyThrottle>,
+    pub allowed_options: Vec<String>,
+    pub server_reset_query: String,
+    pub server_reset_query_always: bool,
+    pub ident_map: HashMap<String, String>,
+    pub application_name_routes: Vec<(Regex, ApplicationNameRoute)>,
+
+    /// The weights and localities of the servers, replaced when the config is reloaded.
+    pub routing: Arc<ArcSwap<Routing>>,
 }
 impl Default for PoolSettings {
     fn default() -> PoolSettings {
         PoolSettings {
+            pool_name: String::new(),
             pool_mode: String::from("transaction"),
+            min_pool_size: 0,
+            reserve_pool_size: 0,
+            reserve_pool_timeout: 5000,
             shards: HashMap::from([(String::from("1"), Shard::default())]),
             user: User::default(),
             default_role: String::from("any"),
             query_parser_enabled: false,
             primary_reads_enabled: true,
+            load_balancing_mode: String::from("round_robin"),
+            latency_sensitivity: 1,
+            max_replica_lag: 0,
+            slow_start: 0,
+            primary_reads_fallback: false,
+            primary_reads_after_write: 0,
             sharding_function: "pg_bigint_hash".to_string(),
+            sharding_mode: String::from("modulo"),
+            sharding_seed: None,
+            sharding_key_type: String::from("bigint"),
+            sharding_ranges: Vec::new(),
+            sharding_directory: false,
+            scatter_gather: false,
+            resharding: None,
+            auth_type: String::from("md5"),
+            trust_listen_addresses: Vec::new(),
+            ldap: None,
+            jwt: None,
+            pam_service: String::from("pgcat"),
+            require_tls: false,
+            max_prepared_statements: 0,
+            reject_pipelining: false,
+            copy_throttle: None,
+            allowed_options: Vec::new(),
+            server_reset_query: String::from("DISCARD ALL"),
+            server_reset_query_always: false,
+            ident_map: HashMap::default(),
+            application_name_routes: Vec::new(),
+            routing: Arc::new(ArcSwap::from_pointee(Routing::default())),
+        }
+    }
+}
+
+/// The weights of the servers of each shard and if they're local, read from the config
+/// when the pool is built, and again when it's reloaded, without re-creating the pool.
+#[derive(Debug, Default)]
+pub struct Routing {
+    /// The weight of each server of the shard, if it has weights.
+    weights: Vec<Option<Vec<u32>>>,
+
+    /// If each server of the shard is in PgCat's locality, if it has one
+    /// and the shard has localities.
+    local: Vec<Option<Vec<bool>>>,
+}
+
+impl Routing {
+    fn new(
+        pool_config: &PoolConfig,
+        locality: &Option<String>,
+        addresses: &[Vec<Address>],
+    ) -> Routing {
+        let server = |address: &Address| format!("{}:{}", address.host, address.port);
+        let mut routing = Routing::default();
+
+        for (shard, addresses) in addresses.iter().enumerate() {
+            let shard_config = pool_config.shards.get(&shard.to_string());
+
+            routing.weights.push(
+                shard_config
+                    .filter(|shard_config| !shard_config.weights.is_empty())
+                    .map(|shard_config| {
+                        addresses
+                            .iter()
+                            .map(|address| {
+                                shard_config
+                                    .weights
+                                    .get(&server(address))
+                                    .copied()
+                                    .unwrap_or(1)
+                            })
+                            .collect()
+                    }),
+            );
+
+            routing.local.push(match (locality, shard_config) {
+                (Some(locality), Some(shard_config)) if !shard_config.localities.is_empty() => {
+                    Some(
+                        addresses
+                            .iter()
+                            .map(|address| {
+                                shard_config.localities.get(&server(address)) == Some(locality)
+                            })
+                            .collect(),
+                    )
+                }
+                _ => None,
+            });
         }
+
+        routing
+    }
+}
+
+impl PoolSettings {
+    /// The first route that matches the client's application_name, if any.
+    pub fn application_name_route(&self, application_name: &str) -> Option<&ApplicationNameRoute> {
+        self.application_name_routes
+            .iter()
+            .find(|(regex, _)| regex.is_match(application_name))
+            .map(|(_, route)| route)
     }
 }
 
@@ -54,6 +210,23 @@ pub struct ConnectionPool {
     /// The pools handled internally by bb8.
     databases: Vec<Vec<Pool<ServerPool>>>,
 
+    /// The reserve pools of the servers, if reserve_pool_size is set. They're used
+    /// by the clients that waited for more than reserve_pool_timeout.
+    reserves: Vec<Vec<Option<Pool<ServerPool>>>>,
+
+    /// The pool sizes of the servers, if autoscale is enabled.
+    autoscalers: Vec<Vec<Option<Arc<Autoscaler>>>>,
+
+    /// Notified when the servers can't be connected to, even after connect_retries,
+    /// so the clients waiting for them try the next ones.
+    connect_failed: Vec<Vec<Arc<Notify>>>,
+
+    /// The servers of each address in use by a client, i.e. its outstanding requests.
+    in_flight: Vec<Vec<Arc<AtomicUsize>>>,
+
+    /// The moving average of the health check round trips to each address, in microseconds.
+    latencies: Vec<Vec<Arc<AtomicU64>>>,
+
     /// The addresses (host, port, role) to handle
     /// failover and load balancing deterministically.
     addresses: Vec<Vec<Address>>,
@@ -68,128 +241,591 @@ pub struct ConnectionPool {
 
     /// The server information (K messages) have to be passed to the
     /// clients on startup. We pre-connect to all shards and replicas
-    /// on pool creation and save the K messages here.
-    server_info: BytesMut,
+    /// on pool creation and save the K messages here, or when the first
+    /// client connects if the pool is lazy.
+    server_info: Arc<tokio::sync::OnceCell<BytesMut>>,
+
+    /// The clients waiting for a server, i.e. in the queue.
+    waiting: Arc<AtomicUsize>,
+
+    /// The pool was replaced by a config reload. Its clients move to the new one
+    /// between transactions, and its servers are closed as they're released.
+    retired: Arc<AtomicBool>,
+
+    /// The shards, by number, whose servers the pool that replaced this one kept,
+    /// because only other shards changed. They stay open when they're released.
+    kept_shards: Arc<RwLock<HashSet<usize>>>,
+
+    /// The config of the pool it was created from, so a reload only replaces it
+    /// if it changed.
+    config: Arc<PoolConfig>,
 
     pub settings: PoolSettings,
 }
 
 impl ConnectionPool {
-    /// Construct the connection pool from the configuration.
+    /// Construct the connection pool from the configuration. After a reload, the pools
+    /// whose config didn't change are kept as they are, with their servers and clients.
     pub async fn from_config(client_server_map: ClientServerMap) -> Result<(), Error> {
         let config = get_config();
+        let old_pools = get_all_pools();
+        let old_auth_passthroughs = AUTH_PASSTHROUGHS.load();
         let mut new_pools = PoolMap::default();
+        let mut auth_passthroughs = AuthPassthroughMap::default();
+
+        // The pools of the users found with auth_query too.
+        let unchanged = |pool_name: &String, pool_config: &PoolConfig| {
+            let old_pools = old_pools
+                .iter()
+                .filter(|((name, _), _)| name == pool_name)
+                .collect::<Vec<_>>();
+
+            !old_pools.is_empty()
+                && old_pools
+                    .iter()
+                    .all(|(_, pool)| pool.config.eq_but_routing(pool_config))
+        };
+
+        for (pool_name, pool_config) in &config.pools {
+            if unchanged(pool_name, pool_config) {
+                for ((name, username), pool) in &old_pools {
+                    if name == pool_name {
+                        new_pools.insert((name.clone(), username.clone()), pool.clone());
+                    }
+                }
+
+                if let Some(auth_passthrough) = old_auth_passthroughs.get(pool_name) {
+                    auth_passthroughs.insert(pool_name.clone(), auth_passthrough.clone());
+                }
+            }
+        }
+
+        let mut used_address_ids = new_pools
+            .values()
+            .flat_map(|pool| pool.address_ids())
+            .collect::<HashSet<usize>>();
 
-        let mut address_id = 0;
         for (pool_name, pool_config) in &config.pools {
+            if unchanged(pool_name, pool_config) {
+                continue;
+            }
+
             for (_user_index, user_info) in &pool_config.users {
-                let mut shards = Vec::new();
-                let mut addresses = Vec::new();
-                let mut banlist = Vec::new();
-                let mut shard_ids = pool_config
-                    .shards
-                    .clone()
-                    .into_keys()
-                    .map(|x| x.to_string())
-                    .collect::<Vec<String>>();
-
-                // Sort by shard number to ensure consistency.
-                shard_ids.sort_by_key(|k| k.parse::<i64>().unwrap());
-
-                for shard_idx in shard_ids {
-                    let shard = &pool_config.shards[&shard_idx];
-                    let mut pools = Vec::new();
-                    let mut servers = Vec::new();
-                    let mut replica_number = 0;
-
-                    for server in shard.servers.iter() {
-                        let role = match server.2.as_ref() {
-                            "primary" => Role::Primary,
-                            "replica" => Role::Replica,
-                            _ => {
-                                error!("Config error: server role can be 'primary' or 'replica', have: '{}'. Defaulting to 'replica'.", server.2);
-                                Role::Replica
-                            }
-                        };
-
-                        let address = Address {
-                            id: address_id,
-                            database: pool_name.clone(),
-                            host: server.0.clone(),
-                            port: server.1.to_string(),
-                            role: role,
-                            replica_number,
-                            shard: shard_idx.parse::<usize>().unwrap(),
-                        };
-
-                        address_id += 1;
-
-                        if role == Role::Replica {
-                            replica_number += 1;
-                        }
+                // Only the shard map changed: the shards that didn't keep their servers,
+                // and their addresses.
+                let old_pool = old_pools
+                    .get(&(pool_name.clone(), user_info.username.clone()))
+                    .filter(|old_pool| old_pool.config.eq_but_shard_map(pool_config));
+
+                if let Some(old_pool) = old_pool {
+                    used_address_ids.extend(old_pool.address_ids());
+                }
+
+                let mut address_id = free_address_ids(&used_address_ids, pool_config);
+                let pool = Self::from_user_config(
+                    pool_name,
+                    pool_config,
+                    user_info,
+                    &mut address_id,
+                    client_server_map.clone(),
+                    old_pool,
+                )
+                .await?;
+
+                used_address_ids.extend(pool.address_ids());
+                new_pools.insert((pool_name.clone(), user_info.username.clone()), pool);
+            }
+
+            if let Some(auth_passthrough) =
+                AuthPassthrough::from_pool_config(pool_config, client_server_map.clone())
+            {
+                auth_passthroughs.insert(pool_name.clone(), auth_passthrough);
+            }
+        }
+
+        // The replicas added by the reload warm up, with slow_start. All the servers
+        // are new when the pooler starts, there's nothing to warm up instead of them.
+        if !old_pools.is_empty() {
+            let old_servers = old_pools
+                .values()
+                .flat_map(|pool| pool.addresses.iter().flatten())
+                .map(|address| db_connections::server(&address.host, &address.port))
+                .collect::<HashSet<String>>();
+            let mut added = HashSet::new();
+
+            for pool in new_pools.values() {
+                if pool.settings.slow_start == 0 {
+                    continue;
+                }
+
+                for address in pool.addresses.iter().flatten() {
+                    let server = db_connections::server(&address.host, &address.port);
+
+                    if address.role == Role::Replica
+                        && !old_servers.contains(&server)
+                        && added.insert(server.clone())
+                    {
+                        slow_start::start(&server);
+                    }
+                }
+            }
+        }
+
+        // The servers of the old pools are in use by clients, possibly in the middle
+        // of a transaction. They're closed when they're released instead, but the ones
+        // of the shards the new pools kept.
+        for (key, pool) in POOLS.swap(Arc::new(new_pools.clone())).iter() {
+            match new_pools.get(key) {
+                Some(new_pool) if Arc::ptr_eq(&new_pool.retired, &pool.retired) => (),
 
-                        let manager = ServerPool::new(
-                            address.clone(),
-                            user_info.clone(),
-                            &shard.database,
-                            client_server_map.clone(),
-                            get_reporter(),
+                Some(new_pool) => {
+                    let kept_shards = pool.shards_kept_by(new_pool);
+
+                    if !kept_shards.is_empty() {
+                        info!(
+                            "Pool {} keeps the servers of the shards that didn't change: {:?}",
+                            key.0, kept_shards
                         );
+                    }
 
-                        let pool = Pool::builder()
-                            .max_size(user_info.pool_size)
-                            .connection_timeout(std::time::Duration::from_millis(
-                                config.general.connect_timeout,
-                            ))
-                            .test_on_check_out(false)
-                            .build(manager)
-                            .await
-                            .unwrap();
+                    pool.retire_but(kept_shards);
+                }
 
-                        pools.push(pool);
-                        servers.push(address);
+                None => pool.retire(),
+            };
+        }
+        AUTH_PASSTHROUGHS.store(Arc::new(auth_passthroughs));
+
+        Ok(())
+    }
+
+    /// Create a pool for a user that was found with `auth_query`,
+    /// i.e. it's not in the config, and make it available to clients.
+    pub async fn add_user(
+        pool_name: &str,
+        user: User,
+        client_server_map: ClientServerMap,
+    ) -> Result<ConnectionPool, Error> {
+        // Two clients of the same new user can race here,
+        // the first one creates the pool for both.
+        let _guard = ADD_USER_LOCK.lock().await;
+
+        if let Some(pool) = get_pool(pool_name.to_string(), user.username.clone()) {
+            return Ok(pool);
+        }
+
+        let config = get_config();
+        let pool_config = match config.pools.get(pool_name) {
+            Some(pool_config) => pool_config,
+            None => return Err(Error::BadConfig),
+        };
+
+        let used_address_ids = get_all_pools()
+            .values()
+            .flat_map(|pool| pool.address_ids())
+            .collect::<HashSet<usize>>();
+
+        let mut address_id = free_address_ids(&used_address_ids, pool_config);
+        let pool = Self::from_user_config(
+            pool_name,
+            pool_config,
+            &user,
+            &mut address_id,
+            client_server_map,
+            None,
+        )
+        .await?;
+
+        POOLS.rcu(|pools| {
+            let mut pools = HashMap::clone(pools);
+            pools.insert((pool_name.to_string(), user.username.clone()), pool.clone());
+            pools
+        });
+
+        info!(
+            "Created pool for user {} in pool {} with auth_query",
+            user.username, pool_name
+        );
+
+        Ok(pool)
+    }
+
+    /// Resize the pools of the database, for all its users, without a reload: the new pools
+    /// can use all their server connections right away, and the servers of the old ones
+    /// are closed as they're released, like after a reload. It lasts until the next one.
+    pub async fn resize(
+        pool_name: &str,
+        pool_size: u32,
+        client_server_map: ClientServerMap,
+    ) -> Result<(), Error> {
+        // A reload in the middle would re-create the pools with the old size.
+        let _reload_guard = reload_lock().await;
+        let _guard = ADD_USER_LOCK.lock().await;
+
+        let mut config = get_config();
+        let pool_config = match config.pools.get_mut(pool_name) {
+            Some(pool_config) => pool_config,
+            None => return Err(Error::BadConfig),
+        };
+
+        pool_config.set_pool_size(pool_size)?;
+
+        let pool_config = pool_config.clone();
+        let mut resized = Vec::new();
+
+        for ((name, username), pool) in get_all_pools() {
+            if name != pool_name {
+                continue;
+            }
+
+            let mut user = pool.settings.user.clone();
+            user.pool_size = pool_size;
+            user.primary_pool_size = None;
+            user.replica_pool_size = None;
+
+            // The new pool takes over the addresses of the old one, and their stats.
+            let mut address_id = pool.address_ids().min().unwrap_or(0);
+
+            let new_pool = Self::from_user_config(
+                pool_name,
+                &pool_config,
+                &user,
+                &mut address_id,
+                client_server_map.clone(),
+                None,
+            )
+            .await?;
+
+            resized.push(((name, username), pool, new_pool));
+        }
+
+        // Only once all of them are, so a failure leaves the pool as it was.
+        set_config(config);
+
+        POOLS.rcu(|pools| {
+            let mut pools = HashMap::clone(pools);
+
+            for (key, _, new_pool) in &resized {
+                pools.insert(key.clone(), new_pool.clone());
+            }
+
+            pools
+        });
+
+        for (_, pool, _) in resized {
+            pool.retire();
+        }
+
+        info!("Resized pool {} to pool_size {}", pool_name, pool_size);
+
+        Ok(())
+    }
+
+    /// Remove the pools of a user that isn't in the config, e.g. because their
+    /// password changed in the auth file. They'll be recreated when the user connects again.
+    pub fn remove_user(username: &str) {
+        let config = get_config();
+
+        POOLS.rcu(|pools| {
+            let mut pools = HashMap::clone(pools);
+            pools.retain(|(pool_name, user), pool| {
+                let keep = user != username
+                    || match config.pools.get(pool_name) {
+                        Some(pool_config) => {
+                            pool_config.users.values().any(|u| u.username == *user)
+                        }
+                        None => false,
+                    };
+
+                if !keep {
+                    pool.retire();
+                }
+
+                keep
+            });
+            pools
+        });
+    }
+
+    /// Create the pool for one user of a pool in the config.
+    async fn from_user_config(
+        pool_name: &str,
+        pool_config: &PoolConfig,
+        user_info: &User,
+        address_id: &mut usize,
+        client_server_map: ClientServerMap,
+        old_pool: Option<&ConnectionPool>,
+    ) -> Result<ConnectionPool, Error> {
+        let config = get_config();
+        let mut shards = Vec::new();
+        let mut reserve_shards = Vec::new();
+        let mut autoscaler_shards = Vec::new();
+        let mut connect_failed = Vec::new();
+        let mut in_flight = Vec::new();
+        let mut latencies = Vec::new();
+        let mut addresses = Vec::new();
+        let mut banlist = Vec::new();
+        let mut shard_ids = pool_config
+            .shards
+            .clone()
+            .into_keys()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>();
+
+        // Sort by shard number to ensure consistency.
+        shard_ids.sort_by_key(|k| k.parse::<i64>().unwrap());
+
+        for shard_idx in shard_ids {
+            let shard = &pool_config.shards[&shard_idx];
+
+            // The shard didn't change since the pool this one replaces, e.g. another one
+            // was added: it keeps the servers, so its clients don't notice.
+            let kept = old_pool.and_then(|old_pool| {
+                let unchanged = old_pool
+                    .config
+                    .shards
+                    .get(&shard_idx)
+                    .is_some_and(|old_shard| old_shard.eq_but_routing(shard));
+
+                old_pool
+                    .addresses
+                    .iter()
+                    .position(|servers| {
+                        servers.first().map(|address| address.shard.to_string())
+                            == Some(shard_idx.clone())
+                    })
+                    .filter(|_| unchanged)
+                    .map(|index| (old_pool, index))
+            });
+
+            if let Some((old_pool, index)) = kept {
+                debug!(
+                    "Keeping the servers of shard {} of pool {}",
+                    shard_idx, pool_name
+                );
+
+                shards.push(old_pool.databases[index].clone());
+                reserve_shards.push(old_pool.reserves[index].clone());
+                autoscaler_shards.push(old_pool.autoscalers[index].clone());
+                connect_failed.push(old_pool.connect_failed[index].clone());
+                in_flight.push(old_pool.in_flight[index].clone());
+                latencies.push(old_pool.latencies[index].clone());
+                addresses.push(old_pool.addresses[index].clone());
+                banlist.push(old_pool.banlist.read()[index].clone());
+                continue;
+            }
+            let mut pools = Vec::new();
+            let mut reserves = Vec::new();
+            let mut autoscalers = Vec::new();
+            let mut connect_failures = Vec::new();
+            let mut in_flight_requests = Vec::new();
+            let mut round_trips = Vec::new();
+            let mut servers = Vec::new();
+            let mut replica_number = 0;
+
+            for server in shard.servers.iter() {
+                let role = match server.2.as_ref() {
+                    "primary" => Role::Primary,
+                    "replica" => Role::Replica,
+                    _ => {
+                        error!("Config error: server role can be 'primary' or 'replica', have: '{}'. Defaulting to 'replica'.", server.2);
+                        Role::Replica
                     }
+                };
+
+                let address = Address {
+                    id: *address_id,
+                    database: pool_name.to_string(),
+                    host: server.0.clone(),
+                    port: server.1.to_string(),
+                    role: role,
+                    replica_number,
+                    shard: shard_idx.parse::<usize>().unwrap(),
+                };
+
+                *address_id += 1;
 
-                    shards.push(pools);
-                    addresses.push(servers);
-                    banlist.push(HashMap::new());
+                if role == Role::Replica {
+                    replica_number += 1;
                 }
 
-                assert_eq!(shards.len(), addresses.len());
-
-                let mut pool = ConnectionPool {
-                    databases: shards,
-                    addresses: addresses,
-                    banlist: Arc::new(RwLock::new(banlist)),
-                    stats: get_reporter(),
-                    server_info: BytesMut::new(),
-                    settings: PoolSettings {
-                        pool_mode: pool_config.pool_mode.clone(),
-                        shards: pool_config.shards.clone(),
-                        user: user_info.clone(),
-                        default_role: pool_config.default_role.clone(),
-                        query_parser_enabled: pool_config.query_parser_enabled.clone(),
-                        primary_reads_enabled: pool_config.primary_reads_enabled,
-                        sharding_function: pool_config.sharding_function.clone(),
-                    },
+                let connect_failed = Arc::new(Notify::new());
+
+                let manager = || {
+                    let mut manager = ServerPool::new(
+                        address.clone(),
+                        user_info.clone(),
+                        &shard.database,
+                        client_server_map.clone(),
+                        get_reporter(),
+                        pool_config.rds_iam_region.clone(),
+                        pool_config.gcp_iam_credentials.clone(),
+                        pool_config.vault.clone(),
+                    );
+
+                    manager.connect_failed = connect_failed.clone();
+                    manager.startup_parameters = pool_config.server_startup_parameters();
+                    manager
                 };
 
-                // Connect to the servers to make sure pool configuration is valid
-                // before setting it globally.
-                match pool.validate().await {
-                    Ok(_) => (),
-                    Err(err) => {
-                        error!("Could not validate connection pool: {:?}", err);
-                        return Err(err);
+                let max_lifetime = match config.general.server_lifetime {
+                    0 => None,
+                    server_lifetime => Some(std::time::Duration::from_secs(server_lifetime)),
+                };
+                let idle_timeout = match config.general.server_idle_timeout {
+                    0 => None,
+                    server_idle_timeout => {
+                        Some(std::time::Duration::from_secs(server_idle_timeout))
                     }
                 };
-                new_pools.insert((pool_name.clone(), user_info.username.clone()), pool);
+                let connection_timeout =
+                    std::time::Duration::from_millis(config.general.checkout_timeout);
+
+                // The idle servers the most recently used first keep their caches hot,
+                // and let the others reach server_idle_timeout. The least recently used
+                // first spread the load over all of them.
+                let queue_strategy = match pool_config.server_reuse.as_ref() {
+                    "lifo" => QueueStrategy::Lifo,
+                    _ => QueueStrategy::Fifo,
+                };
+
+                // The pool opens the min_pool_size connections in the background, and
+                // opens more when some are closed or used, every 30 seconds. Idle ones older
+                // than server_lifetime, or idle for longer than server_idle_timeout, are
+                // closed then too.
+                let pool = Pool::builder()
+                    .max_size(user_info.role_pool_size(role))
+                    .min_idle(Some(pool_config.min_pool_size))
+                    .max_lifetime(max_lifetime)
+                    .idle_timeout(idle_timeout)
+                    .connection_timeout(connection_timeout)
+                    .queue_strategy(queue_strategy)
+                    .test_on_check_out(false)
+                    .build_unchecked(manager());
+
+                // The reserve starts empty. Its connections are closed like the others,
+                // so it's empty again server_idle_timeout after the burst.
+                let reserve = match pool_config.reserve_pool_size {
+                    0 => None,
+                    reserve_pool_size => Some(
+                        Pool::builder()
+                            .max_size(reserve_pool_size)
+                            .min_idle(None)
+                            .max_lifetime(max_lifetime)
+                            .idle_timeout(idle_timeout)
+                            .connection_timeout(connection_timeout)
+                            .queue_strategy(queue_strategy)
+                            .test_on_check_out(false)
+                            .build_unchecked(manager()),
+                    ),
+                };
+
+                let autoscaler = match pool_config.autoscale {
+                    true => Some(Arc::new(Autoscaler::new(
+                        pool_config.min_pool_size,
+                        user_info.role_pool_size(role),
+                    ))),
+                    false => None,
+                };
+
+                pools.push(pool);
+                reserves.push(reserve);
+                autoscalers.push(autoscaler);
+                connect_failures.push(connect_failed);
+                in_flight_requests.push(Arc::new(AtomicUsize::new(0)));
+                round_trips.push(Arc::new(AtomicU64::new(0)));
+                servers.push(address);
             }
+
+            shards.push(pools);
+            reserve_shards.push(reserves);
+            autoscaler_shards.push(autoscalers);
+            connect_failed.push(connect_failures);
+            in_flight.push(in_flight_requests);
+            latencies.push(round_trips);
+            addresses.push(servers);
+            banlist.push(HashMap::new());
         }
 
-        POOLS.store(Arc::new(new_pools.clone()));
+        assert_eq!(shards.len(), addresses.len());
+
+        let pool = ConnectionPool {
+            databases: shards,
+            reserves: reserve_shards,
+            autoscalers: autoscaler_shards,
+            connect_failed,
+            in_flight,
+            latencies,
+            addresses: addresses.clone(),
+            banlist: Arc::new(RwLock::new(banlist)),
+            stats: get_reporter(),
+            server_info: Arc::new(tokio::sync::OnceCell::new()),
+            waiting: Arc::new(AtomicUsize::new(0)),
+            retired: Arc::new(AtomicBool::new(false)),
+            kept_shards: Arc::new(RwLock::new(HashSet::new())),
+            config: Arc::new(pool_config.clone()),
+            settings: PoolSettings {
+                pool_name: pool_name.to_string(),
+                pool_mode: pool_config.pool_mode.clone(),
+                min_pool_size: pool_config.min_pool_size,
+                reserve_pool_size: pool_config.reserve_pool_size,
+                reserve_pool_timeout: pool_config.reserve_pool_timeout,
+                shards: pool_config.shards.clone(),
+                user: user_info.clone(),
+                default_role: pool_config.default_role.clone(),
+                query_parser_enabled: pool_config.query_parser_enabled.clone(),
+                primary_reads_enabled: pool_config.primary_reads_enabled,
+                load_balancing_mode: pool_config.load_balancing_mode.clone(),
+                latency_sensitivity: pool_config.latency_sensitivity,
+                max_replica_lag: pool_config.max_replica_lag,
+                slow_start: pool_config.slow_start,
+                primary_reads_fallback: pool_config.primary_reads_fallback,
+                primary_reads_after_write: pool_config.primary_reads_after_write,
+                sharding_function: pool_config.sharding_function.clone(),
+                sharding_mode: pool_config.sharding_mode.clone(),
+                sharding_seed: pool_config.sharding_seed,
+                sharding_key_type: pool_config.sharding_key_type.clone(),
+                sharding_ranges: pool_config.sharding_ranges.clone(),
+                sharding_directory: pool_config.sharding_directory.is_some(),
+                scatter_gather: pool_config.scatter_gather,
+                resharding: pool_config.resharding.clone(),
+                auth_type: pool_config.auth_type.clone(),
+                trust_listen_addresses: pool_config.trust_listen_addresses.clone(),
+                ldap: pool_config.ldap.clone(),
+                jwt: pool_config.jwt.clone(),
+                pam_service: pool_config.pam_service.clone(),
+                require_tls: pool_config.require_tls || user_info.require_tls,
+                max_prepared_statements: pool_config.max_prepared_statements,
+                reject_pipelining: pool_config.reject_pipelining,
+                copy_throttle: CopyThrottle::new(pool_name, pool_config.copy_bytes_per_second),
+                allowed_options: pool_config.allowed_options.clone(),
+                server_reset_query: pool_config.server_reset_query.clone(),
+                server_reset_query_always: pool_config.server_reset_query_always,
+                ident_map: pool_config.ident_map.clone(),
+                application_name_routes: pool_config
+                    .application_name_routes
+                    .iter()
+                    .filter_map(|route| {
+                        Regex::new(&route.application_name)
+                            .ok()
+                            .map(|regex| (regex, route.clone()))
+                    })
+                    .collect(),
+                routing: Arc::new(ArcSwap::from_pointee(Routing::new(
+                    pool_config,
+                    &config.general.locality,
+                    &addresses,
+                ))),
+            },
+        };
 
-        Ok(())
+        // Connect to the servers to make sure pool configuration is valid
+        // before setting it globally. Lazy pools do it for their first client.
+        if !pool_config.lazy {
+            pool.server_info().await?;
+        }
+
+        Ok(pool)
     }
 
     /// Connect to all shards and grab server information.
@@ -197,7 +833,7 @@ impl ConnectionPool {
     /// when they connect.
     /// This also warms up the pool for clients that connect when
     /// the pooler starts up.
-    async fn validate(&mut self) -> Result<(), Error> {
+    async fn validate(&mut self) -> Result<BytesMut, Error> {
         let mut server_infos = Vec::new();
         let stats = self.stats.clone();
 
@@ -216,10 +852,15 @@ impl ConnectionPool {
                     }
                 };
 
-                let proxy = connection.0;
+                let mut proxy = connection.0;
                 let address = connection.1;
-                let server = &*proxy;
-                let server_info = server.server_info();
+                let server_info = proxy.server_info();
+
+                // The pools aren't used yet, so they couldn't close it to make room
+                // for the connections of the next ones.
+                if db_connections::is_full(&db_connections::server(&address.host, &address.port)) {
+                    proxy.retire();
+                }
 
                 stats.client_disconnecting(fake_process_id, address.id);
 
@@ -244,23 +885,21 @@ impl ConnectionPool {
             return Err(Error::AllServersDown);
         }
 
-        self.server_info = server_infos[0].clone();
-
-        Ok(())
+        Ok(server_infos.swap_remove(0))
     }
 
     /// Get a connection from the pool.
     pub async fn get(
-        &mut self,
-        shard: usize,           // shard number
-        role: Option<Role>,     // primary or replica
-        process_id: i32,        // client id
-        mut round_robin: usize, // round robin offset
+        &self,
+        shard: usize,       // shard number
+        role: Option<Role>, // primary or replica
+        process_id: i32,    // client id
+        round_robin: usize, // round robin offset
     ) -> Result<(PooledConnection<'_, ServerPool>, Address), Error> {
         let now = Instant::now();
         let addresses = &self.addresses[shard];
 
-        let mut allowed_attempts = match role {
+        let allowed_attempts = match role {
             // Primary-specific queries get one attempt, if the primary is down,
             // nothing we should do about it I think. It's dangerous to retry
             // write queries.
@@ -283,33 +922,165 @@ impl ConnectionPool {
             return Err(Error::BadConfig);
         }
 
-        while allowed_attempts > 0 {
-            // Round-robin replicas.
-            round_robin += 1;
-
-            let index = round_robin % addresses.len();
+        for index in self
+            .servers_to_try(shard, role, round_robin)
+            .into_iter()
+            .take(allowed_attempts)
+        {
             let address = &addresses[index];
 
-            // Make sure you're getting a primary or a replica
-            // as per request. If no specific role is requested, the first
-            // available will be chosen.
-            if address.role != role {
-                continue;
-            }
+            // All the replicas are down, the reads go to the primary instead.
+            let fallback = role == Some(Role::Replica) && address.role == Role::Primary;
 
-            allowed_attempts -= 1;
+            if fallback {
+                warn!(
+                    "All the replicas of shard {} are down, sending the reads to the primary",
+                    shard
+                );
+            }
 
             // Don't attempt to connect to banned servers.
-            if self.is_banned(address, shard, role) {
+            if !fallback && self.is_banned(address, shard, role) {
                 continue;
             }
 
+            let pool = &self.databases[shard][index];
+            let max_waiting_clients = get_config().general.max_waiting_clients;
+
+            // Without an idle server, the client would wait behind the others. The queue is first in, first out.
+            if max_waiting_clients > 0
+                && pool.state().idle_connections == 0
+                && self.waiting.load(Ordering::Relaxed) >= max_waiting_clients
+            {
+                warn!(
+                    "Pool {:?} has {} clients waiting for a server already",
+                    address, max_waiting_clients
+                );
+                return Err(Error::QueueFull);
+            }
+
             // Indicate we're waiting on a server connection from a pool.
             self.stats.client_waiting(process_id, address.id);
 
+            let connect_failed = self.connect_failed[shard][index].notified();
+
+            self.waiting.fetch_add(1, Ordering::Relaxed);
+            let checkout = async {
+                // The autoscaled pool may be smaller than pool_size for now.
+                let permit = match &self.autoscalers[shard][index] {
+                    Some(autoscaler) => {
+                        let checkout_timeout =
+                            Duration::from_millis(get_config().general.checkout_timeout);
+
+                        match tokio::time::timeout(checkout_timeout, autoscaler.acquire()).await {
+                            Ok(permit) => Some(permit),
+                            Err(_) => return Err(RunError::User(Error::CheckoutTimeout)),
+                        }
+                    }
+                    None => None,
+                };
+
+                let conn = match &self.reserves[shard][index] {
+                    // The client keeps its place in the queue while it waits for the reserve too.
+                    Some(reserve) => {
+                        let get = pool.get();
+                        tokio::pin!(get);
+
+                        tokio::select! {
+                            conn = &mut get => conn,
+                            _ = tokio::time::sleep(tokio::time::Duration::from_millis(
+                                self.settings.reserve_pool_timeout,
+                            )) => {
+                                debug!(
+                                    "Client waited for a server of {:?} for more than reserve_pool_timeout, using the reserve pool",
+                                    address
+                                );
+
+                                tokio::select! {
+                                    conn = &mut get => conn,
+                                    conn = reserve.get() => conn,
+                                }
+                            }
+                        }
+                    }
+                    None => pool.get().await,
+                };
+
+                conn.map(|conn| (conn, permit))
+            };
+            let conn = tokio::select! {
+                conn = checkout => conn,
+                _ = connect_failed => Err(RunError::User(Error::SocketError)),
+            };
+            self.waiting.fetch_sub(1, Ordering::Relaxed);
+
             // Check if we can connect
-            let mut conn = match self.databases[shard][index].get().await {
-                Ok(conn) => conn,
+            let mut conn = match conn {
+                Ok((mut conn, permit)) => {
+                    if let Some(permit) = permit {
+                        conn.hold(permit);
+                    }
+                    conn.track(InFlight::new(self.in_flight[shard][index].clone()));
+                    conn
+                }
+
+                // The autoscaled pool didn't grow in time, all its servers are in use.
+                Err(RunError::User(Error::CheckoutTimeout)) => {
+                    warn!(
+                        "Client waited for a server of {:?} for more than checkout_timeout, all {} are in use",
+                        address,
+                        self.pool_size(shard, index)
+                    );
+                    self.stats.client_disconnecting(process_id, address.id);
+                    self.stats
+                        .checkout_time(now.elapsed().as_micros(), process_id, address.id);
+                    return Err(Error::CheckoutTimeout);
+                }
+
+                // The server is down, no need to wait for checkout_timeout.
+                Err(RunError::User(err)) => {
+                    error!("Banning replica {}, could not connect: {:?}", index, err);
+                    self.ban(address, shard);
+                    self.stats.client_disconnecting(process_id, address.id);
+                    self.stats
+                        .checkout_time(now.elapsed().as_micros(), process_id, address.id);
+                    continue;
+                }
+
+                // The pools have all the connections max_db_connections allows.
+                Err(RunError::TimedOut)
+                    if db_connections::is_full(&db_connections::server(
+                        &address.host,
+                        &address.port,
+                    )) =>
+                {
+                    warn!(
+                        "Client waited for a server of {:?} for more than checkout_timeout, max_db_connections or max_server_connections is reached",
+                        address
+                    );
+                    self.stats.client_disconnecting(process_id, address.id);
+                    self.stats
+                        .checkout_time(now.elapsed().as_micros(), process_id, address.id);
+                    return Err(Error::TooManyConnections);
+                }
+
+                // All the servers are in use, the clients before this one got them.
+                // Nothing's wrong with the server, it shouldn't be banned.
+                Err(_)
+                    if pool.state().connections
+                        >= self.settings.user.role_pool_size(address.role) =>
+                {
+                    warn!(
+                        "Client waited for a server of {:?} for more than checkout_timeout, all {} are in use",
+                        address,
+                        self.pool_size(shard, index) + self.settings.reserve_pool_size
+                    );
+                    self.stats.client_disconnecting(process_id, address.id);
+                    self.stats
+                        .checkout_time(now.elapsed().as_micros(), process_id, address.id);
+                    return Err(Error::CheckoutTimeout);
+                }
+
                 Err(err) => {
                     error!("Banning replica {}, error: {:?}", index, err);
                     self.ban(address, shard);
@@ -326,6 +1097,8 @@ impl ConnectionPool {
 
             self.stats.server_tested(server.process_id(), address.id);
 
+            let healthcheck_started = Instant::now();
+
             match tokio::time::timeout(
                 tokio::time::Duration::from_millis(healthcheck_timeout),
                 server.query(";"),
@@ -335,9 +1108,18 @@ impl ConnectionPool {
                 // Check if health check succeeded.
                 Ok(res) => match res {
                     Ok(_) => {
+                        measure(
+                            &self.latencies[shard][index],
+                            healthcheck_started.elapsed().as_micros() as u64,
+                        );
                         self.stats
                             .checkout_time(now.elapsed().as_micros(), process_id, address.id);
                         self.stats.server_idle(conn.process_id(), address.id);
+
+                        if fallback {
+                            self.stats.read_fallback(process_id, address.id);
+                        }
+
                         return Ok((conn, address.clone()));
                     }
 
@@ -374,6 +1156,140 @@ impl ConnectionPool {
         return Err(Error::AllServersDown);
     }
 
+    /// The servers of the shard with the role requested, in the order they're tried.
+    /// They're tried tier by tier: the ones drained (with a weight of 0) last, before them
+    /// the ones too far behind the primary, and with a locality, the ones that are full
+    /// and the remote ones. Within a tier, they're ordered by load_balancing_mode, and the
+    /// ones warming up with slow_start are often tried last.
+    fn servers_to_try(&self, shard: usize, role: Option<Role>, round_robin: usize) -> Vec<usize> {
+        let addresses = &self.addresses[shard];
+        let server = |address: &Address| format!("{}:{}", address.host, address.port);
+
+        // Replaced when the config is reloaded, so a reload changes them right away.
+        let routing = self.settings.routing.load();
+        let weights = routing
+            .weights
+            .get(shard)
+            .and_then(|weights| weights.as_ref());
+        let local = routing.local.get(shard).and_then(|local| local.as_ref());
+
+        let tiers = addresses
+            .iter()
+            .enumerate()
+            .map(|(index, address)| {
+                let drained = weights.is_some_and(|weights| weights[index] == 0);
+                let lagging = self.settings.max_replica_lag > 0
+                    && address.role == Role::Replica
+                    && replica_lag::get(&db_connections::server(&address.host, &address.port))
+                        .unwrap_or(0)
+                        > self.settings.max_replica_lag;
+                let (full, remote) = match local {
+                    Some(local) => (self.is_full(shard, index), !local[index]),
+                    None => (false, false),
+                };
+
+                (drained, lagging, full, remote)
+            })
+            .collect::<Vec<(bool, bool, bool, bool)>>();
+
+        // The servers drained in the admin database don't get any transaction.
+        let routable = addresses
+            .iter()
+            .map(|address| address.role == role && !drain::is_drained(&server(address)))
+            .collect::<Vec<bool>>();
+
+        let mut order = (0..addresses.len())
+            .filter(|&index| routable[index])
+            .map(|index| tiers[index])
+            .collect::<Vec<(bool, bool, bool, bool)>>();
+        order.sort();
+        order.dedup();
+
+        let in_flight = match self.settings.load_balancing_mode.as_ref() {
+            "least_outstanding_requests" => Some(self.in_flight[shard].as_slice()),
+            _ => None,
+        };
+
+        // The replicas warming up, with slow_start.
+        let shares = match self.settings.slow_start {
+            0 => None,
+            slow_start => Some(
+                addresses
+                    .iter()
+                    .map(|address| {
+                        slow_start::share(&server(address), Duration::from_millis(slow_start))
+                    })
+                    .collect::<Vec<f64>>(),
+            ),
+        };
+
+        let mut servers = Vec::new();
+
+        for tier in order {
+            let mut tier_servers = candidates(
+                addresses.len(),
+                |index| routable[index] && tiers[index] == tier,
+                round_robin,
+                in_flight,
+            );
+
+            match self.settings.load_balancing_mode.as_ref() {
+                "latency" => by_latency(
+                    &mut tier_servers,
+                    &self.latencies[shard],
+                    self.settings.latency_sensitivity,
+                    rand::random,
+                ),
+                "power_of_two_choices" => {
+                    two_choices(&mut tier_servers, &self.in_flight[shard], |servers| {
+                        rand::random::<usize>() % servers
+                    })
+                }
+                _ => (),
+            };
+
+            if let Some(weights) = weights {
+                by_weight(
+                    &mut tier_servers,
+                    weights,
+                    round_robin,
+                    self.settings.load_balancing_mode == "round_robin",
+                );
+            }
+
+            if let Some(ref shares) = shares {
+                by_share(&mut tier_servers, shares, rand::random);
+            }
+
+            servers.extend(tier_servers);
+        }
+
+        // When all the replicas are down, the reads go to the primary.
+        if self.reads_fall_back(role) {
+            servers.extend((0..addresses.len()).filter(|&index| {
+                addresses[index].role == Role::Primary
+                    && !drain::is_drained(&server(&addresses[index]))
+            }));
+        }
+
+        servers
+    }
+
+    /// The reads go to the primary when the replicas are down, with primary_reads_fallback.
+    /// Not the ones of the read-only users.
+    fn reads_fall_back(&self, role: Option<Role>) -> bool {
+        role == Some(Role::Replica)
+            && self.settings.primary_reads_fallback
+            && !self.settings.user.read_only
+    }
+
+    /// All the connections to the server are in use, and the pool can't open more.
+    fn is_full(&self, shard: usize, server: usize) -> bool {
+        let state = self.databases[shard][server].state();
+
+        state.idle_connections == 0 && state.connections >= self.pool_size(shard, server)
+    }
+
     /// Ban an address (i.e. replica). It no longer will serve
     /// traffic for any new transactions. Existing transactions on that replica
     /// will finish successfully or error out to the clients.
@@ -407,8 +1323,9 @@ impl ConnectionPool {
 
         let guard = self.banlist.read();
 
-        // Everything is banned = nothing is banned.
-        if guard[shard].len() == replicas_available {
+        // Everything is banned = nothing is banned. Unless the reads can go
+        // to the primary instead.
+        if guard[shard].len() == replicas_available && !self.reads_fall_back(role) {
             drop(guard);
             let mut guard = self.banlist.write();
             guard[shard].clear();
@@ -429,6 +1346,11 @@ impl ConnectionPool {
                     warn!("Unbanning {:?}", address);
                     let mut guard = self.banlist.write();
                     guard[shard].remove(address);
+
+                    if self.settings.slow_start > 0 {
+                        slow_start::start(&db_connections::server(&address.host, &address.port));
+                    }
+
                     false
                 } else {
                     debug!("{:?} is banned", address);
@@ -443,6 +1365,19 @@ impl ConnectionPool {
         }
     }
 
+    /// The servers of the shard banned, for SHOW SHARDS. Unlike `is_banned()`,
+    /// the expired bans are skipped but not lifted.
+    pub fn banned(&self, shard: usize) -> Vec<Address> {
+        let now = chrono::offset::Utc::now().naive_utc();
+        let ban_time = get_config().general.ban_time;
+
+        self.banlist.read()[shard]
+            .iter()
+            .filter(|(_, timestamp)| now.timestamp() - timestamp.timestamp() <= ban_time)
+            .map(|(address, _)| address.clone())
+            .collect()
+    }
+
     /// Get the number of configured shards.
     pub fn shards(&self) -> usize {
         self.databases.len()
@@ -468,32 +1403,339 @@ impl ConnectionPool {
         self.databases[shard][server].state()
     }
 
+    /// The connections of the server's reserve pool, open or being opened.
+    pub fn reserve_connections(&self, shard: usize, server: usize) -> u32 {
+        match &self.reserves[shard][server] {
+            Some(reserve) => reserve.state().connections,
+            None => 0,
+        }
+    }
+
     /// Get the address information for a shard server.
     pub fn address(&self, shard: usize, server: usize) -> &Address {
         &self.addresses[shard][server]
     }
 
-    pub fn server_info(&self) -> BytesMut {
-        self.server_info.clone()
+    /// Even out the server connections of the replicas of each shard, one at a time.
+    /// The clients use them in turn, so the ones that got more while another replica
+    /// was banned keep them idle. One of those is closed, and one is opened on the
+    /// replicas with less if they're all in use.
+    pub async fn rebalance(&self) {
+        for shard in 0..self.shards() {
+            let replicas = (0..self.servers(shard))
+                .filter(|&server| {
+                    let address = &self.addresses[shard][server];
+                    address.role == Role::Replica
+                        && !self.is_banned(address, shard, Some(Role::Replica))
+                })
+                .collect::<Vec<usize>>();
+
+            if replicas.len() < 2 {
+                continue;
+            }
+
+            let connections = replicas
+                .iter()
+                .map(|&server| self.databases[shard][server].state().connections)
+                .sum::<u32>();
+            let share = connections.div_ceil(replicas.len() as u32);
+
+            for server in replicas {
+                let pool = &self.databases[shard][server];
+                let state = pool.state();
+
+                if state.connections > share && state.idle_connections > 0 {
+                    debug!(
+                        "Rebalancing {:?}: {} connections, {} is enough",
+                        self.addresses[shard][server], state.connections, share
+                    );
+                    self.close_idle(shard, server).await;
+                } else if state.connections + 1 < share && state.idle_connections == 0 {
+                    // None are idle, so one is opened, without waiting for it:
+                    // it can take until connect_timeout, or the pool's checkout timeout.
+                    debug!(
+                        "Rebalancing {:?}: {} connections, opening one more",
+                        self.addresses[shard][server], state.connections
+                    );
+                    let pool = pool.clone();
+                    tokio::task::spawn(async move {
+                        let _ = pool.get().await;
+                    });
+                }
+            }
+        }
     }
-}
 
-/// Wrapper for the bb8 connection pool.
-pub struct ServerPool {
-    address: Address,
-    user: User,
-    database: String,
-    client_server_map: ClientServerMap,
-    stats: Reporter,
-}
+    /// Resize the autoscaled pools of the servers, and close an idle connection
+    /// of those that have more than their size now.
+    pub async fn autoscale(&self) {
+        for shard in 0..self.shards() {
+            for server in 0..self.servers(shard) {
+                let autoscaler = match &self.autoscalers[shard][server] {
+                    Some(autoscaler) => autoscaler,
+                    None => continue,
+                };
 
-impl ServerPool {
-    pub fn new(
-        address: Address,
+                let size = autoscaler.tick();
+                let state = self.databases[shard][server].state();
+
+                if state.connections > size && state.idle_connections > 0 {
+                    debug!(
+                        "Autoscaling {:?}: {} connections, {} is enough",
+                        self.addresses[shard][server], state.connections, size
+                    );
+                    self.close_idle(shard, server).await;
+                }
+            }
+        }
+    }
+
+    /// Run a query on one of the servers, e.g. to check how far behind the primary a replica is.
+    pub async fn fetch(
+        &self,
+        shard: usize,
+        server: usize,
+        query: &str,
+    ) -> Result<Vec<Vec<Option<String>>>, Error> {
+        let healthcheck_timeout = Duration::from_millis(get_config().general.healthcheck_timeout);
+
+        let mut conn =
+            match tokio::time::timeout(healthcheck_timeout, self.databases[shard][server].get())
+                .await
+            {
+                Ok(Ok(conn)) => conn,
+                Ok(Err(RunError::User(err))) => return Err(err),
+                Ok(Err(RunError::TimedOut)) | Err(_) => return Err(Error::CheckoutTimeout),
+            };
+
+        match tokio::time::timeout(healthcheck_timeout, conn.fetch(query)).await {
+            Ok(rows) => rows,
+            Err(_) => {
+                // Don't leave a server in the middle of a query in the pool.
+                conn.mark_bad();
+                Err(Error::SocketError)
+            }
+        }
+    }
+
+    /// Close one of the idle connections of the server.
+    async fn close_idle(&self, shard: usize, server: usize) {
+        // An idle one is handed out right away.
+        if let Ok(Ok(mut conn)) = tokio::time::timeout(
+            Duration::from_millis(100),
+            self.databases[shard][server].get(),
+        )
+        .await
+        {
+            conn.retire();
+        }
+    }
+
+    /// Close all the idle connections to the server, in the reserve pool too.
+    pub async fn close_all_idle(&self, shard: usize, server: usize) {
+        let pools = std::iter::once(&self.databases[shard][server])
+            .chain(self.reserves[shard][server].as_ref());
+
+        for pool in pools {
+            while pool.state().idle_connections > 0 {
+                match tokio::time::timeout(Duration::from_millis(100), pool.get()).await {
+                    Ok(Ok(mut conn)) => conn.retire(),
+                    _ => break,
+                }
+            }
+        }
+    }
+
+    /// The servers of the pool that can be in use at once, autoscaled or the
+    /// pool_size of the server's role.
+    pub fn pool_size(&self, shard: usize, server: usize) -> u32 {
+        match &self.autoscalers[shard][server] {
+            Some(autoscaler) => autoscaler.size(),
+            None => self
+                .settings
+                .user
+                .role_pool_size(self.addresses[shard][server].role),
+        }
+    }
+
+    /// Close the servers as they're released, the pool isn't used by new clients anymore.
+    pub fn retire(&self) {
+        self.retired.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_retired(&self) -> bool {
+        self.retired.load(Ordering::Relaxed)
+    }
+
+    /// Retire the pool, but the servers of the shards the pool that replaced it kept.
+    fn retire_but(&self, kept_shards: HashSet<usize>) {
+        *self.kept_shards.write() = kept_shards;
+        self.retire();
+    }
+
+    /// The server of the shard is closed when it's released: the pool was retired,
+    /// and the one that replaced it didn't keep the shard.
+    pub fn retires(&self, shard: usize) -> bool {
+        self.is_retired() && !self.kept_shards.read().contains(&shard)
+    }
+
+    /// The shards, by number, whose servers the other pool uses too.
+    fn shards_kept_by(&self, other: &ConnectionPool) -> HashSet<usize> {
+        self.in_flight
+            .iter()
+            .zip(&self.addresses)
+            .filter(|(in_flight, _)| {
+                in_flight.first().is_some_and(|in_flight| {
+                    other
+                        .in_flight
+                        .iter()
+                        .flatten()
+                        .any(|other| Arc::ptr_eq(in_flight, other))
+                })
+            })
+            .filter_map(|(_, addresses)| addresses.first().map(|address| address.shard))
+            .collect()
+    }
+
+    /// The IDs of all the addresses, e.g. for the stats.
+    pub fn address_ids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.addresses
+            .iter()
+            .flat_map(|shard| shard.iter().map(|address| address.id))
+    }
+
+    /// The server information, connecting to the servers to get it the first time.
+    pub async fn server_info(&self) -> Result<BytesMut, Error> {
+        let server_info = self
+            .server_info
+            .get_or_try_init(|| async {
+                match self.clone().validate().await {
+                    Ok(server_info) => Ok(server_info),
+                    Err(err) => {
+                        error!("Could not validate connection pool: {:?}", err);
+                        Err(err)
+                    }
+                }
+            })
+            .await?;
+
+        Ok(server_info.clone())
+    }
+
+    /// Connect to the shard's primary outside of the pool for a client in
+    /// replication mode, e.g. `replication=database`. The connection is the
+    /// client's until it disconnects, so it has the client's name, e.g. the standby's
+    /// for `synchronous_standby_names`.
+    pub async fn replication(
+        &self,
+        shard: usize,
+        replication: &str,
+        application_name: Option<&str>,
+        client_server_map: ClientServerMap,
+    ) -> Result<Server, Error> {
+        let address = match self.addresses[shard]
+            .iter()
+            .find(|address| address.role == Role::Primary)
+        {
+            Some(address) => address,
+            None => {
+                error!("Shard {} has no primary for replication", shard);
+                return Err(Error::AllServersDown);
+            }
+        };
+
+        let config = get_config();
+        let pool_config = match config.pools.get(&address.database) {
+            Some(pool_config) => pool_config,
+            None => return Err(Error::AllServersDown),
+        };
+
+        let mut manager = ServerPool::new(
+            address.clone(),
+            self.settings.user.clone(),
+            &self.settings.shards[&shard.to_string()].database,
+            client_server_map,
+            get_reporter(),
+            pool_config.rds_iam_region.clone(),
+            pool_config.gcp_iam_credentials.clone(),
+            pool_config.vault.clone(),
+        );
+
+        manager.replication = Some(replication.to_string());
+        manager.startup_parameters = pool_config.server_startup_parameters();
+
+        if let Some(application_name) = application_name {
+            manager.application_name = application_name.to_string();
+        }
+
+        manager.connect().await
+    }
+}
+
+/// A server in use by a client, counted toward the requests in flight
+/// of its address until it's back in the pool.
+#[derive(Debug)]
+pub struct InFlight {
+    count: Arc<AtomicUsize>,
+}
+
+impl InFlight {
+    pub fn new(count: Arc<AtomicUsize>) -> InFlight {
+        count.fetch_add(1, Ordering::Relaxed);
+        InFlight { count }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Wrapper for the bb8 connection pool.
+pub struct ServerPool {
+    address: Address,
+    user: User,
+    database: String,
+    client_server_map: ClientServerMap,
+    stats: Reporter,
+
+    /// Log into the server with an RDS IAM token instead of the user's password.
+    rds_iam_region: Option<String>,
+
+    /// Log into the server with a Google access token instead of the user's password.
+    gcp_iam_credentials: Option<String>,
+
+    /// Log into the server with credentials leased from Vault instead of the user's.
+    vault: Option<Vault>,
+
+    /// The secondary password worked last time, so try it first.
+    secondary_password_first: AtomicBool,
+
+    /// Connect in replication mode, e.g. `database` for logical replication.
+    replication: Option<String>,
+
+    /// The name the server shows in `pg_stat_activity` when no client is using it.
+    application_name: String,
+
+    /// The settings the server starts the session with, i.e. server_startup_parameters.
+    startup_parameters: Vec<(String, String)>,
+
+    /// Notified when the server can't be connected to, even after connect_retries.
+    connect_failed: Arc<Notify>,
+}
+
+impl ServerPool {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        address: Address,
         user: User,
         database: &str,
         client_server_map: ClientServerMap,
         stats: Reporter,
+        rds_iam_region: Option<String>,
+        gcp_iam_credentials: Option<String>,
+        vault: Option<Vault>,
     ) -> ServerPool {
         ServerPool {
             address: address,
@@ -501,8 +1743,112 @@ impl ServerPool {
             database: database.to_string(),
             client_server_map: client_server_map,
             stats: stats,
+            rds_iam_region,
+            gcp_iam_credentials,
+            vault,
+            secondary_password_first: AtomicBool::new(false),
+            replication: None,
+            application_name: String::from("pgcat"),
+            startup_parameters: Vec::new(),
+            connect_failed: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Count the connection toward max_db_connections and max_server_connections.
+    /// If one is reached, an idle connection of another pool is closed, or the
+    /// connection waits for one to close until checkout_timeout.
+    async fn db_connection(&self) -> Result<DbConnection, Error> {
+        let server = db_connections::server(&self.address.host, &self.address.port);
+        let checkout_timeout = Duration::from_millis(get_config().general.checkout_timeout);
+        let start = Instant::now();
+
+        loop {
+            if let Some(db_connection) = db_connections::open(&server) {
+                return Ok(db_connection);
+            }
+
+            if start.elapsed() >= checkout_timeout {
+                warn!(
+                    "Could not connect to {:?}, max_db_connections or max_server_connections is reached",
+                    self.address.name()
+                );
+                return Err(Error::TooManyConnections);
+            }
+
+            if !self.close_idle_elsewhere(&server).await {
+                db_connections::wait(Duration::from_millis(100)).await;
+            }
+        }
+    }
+
+    /// Close an idle connection of another pool, to the same server if its
+    /// max_server_connections is reached. Returns whether one was closed.
+    async fn close_idle_elsewhere(&self, server: &str) -> bool {
+        let same_server = db_connections::is_server_full(server);
+
+        for pool in POOLS.load().values() {
+            for shard in 0..pool.shards() {
+                for index in 0..pool.servers(shard) {
+                    let address = pool.address(shard, index);
+
+                    if pool.settings.user.username == self.user.username && *address == self.address
+                    {
+                        continue;
+                    }
+
+                    if same_server && db_connections::server(&address.host, &address.port) != server
+                    {
+                        continue;
+                    }
+
+                    if pool.pool_state(shard, index).idle_connections > 0 {
+                        debug!(
+                            "Closing an idle connection of {:?} to make room for {:?}",
+                            address.name(),
+                            self.address.name()
+                        );
+                        pool.close_idle(shard, index).await;
+                        return true;
+                    }
+                }
+            }
         }
+
+        false
+    }
+}
+
+/// The user to log into the server as: the client-facing user can log in as another one.
+fn server_user(user: &User) -> User {
+    let mut server_user = user.clone();
+
+    if let Some(ref server_username) = user.server_username {
+        server_user.username = server_username.clone();
+    }
+
+    if let Some(ref server_password) = user.server_password {
+        server_user.password = server_password.clone();
     }
+
+    server_user
+}
+
+/// The password to try if the server refuses the user's, while it's rotated. Not with a
+/// server_password or credentials from Vault or IAM (`generated`), they're not the user's.
+fn secondary_password(user: &User, generated: bool) -> Option<String> {
+    match (&user.server_password, generated) {
+        (None, false) => user.secondary_password.clone(),
+        _ => None,
+    }
+}
+
+/// How long to wait before retrying a server connection: twice as long each time,
+/// from 100ms up to 5s, and randomly up to half less, so the pools don't all retry at once.
+fn backoff(retry: u32) -> Duration {
+    let max = 100u64
+        .saturating_mul(1 << retry.saturating_sub(1).min(6))
+        .min(5000);
+    Duration::from_millis(max - rand::random::<u64>() % (max / 2 + 1))
 }
 
 #[async_trait]
@@ -512,28 +1858,166 @@ impl ManageConnection for ServerPool {
 
     /// Attempts to create a new connection.
     async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        // Not even to keep min_pool_size.
+        if drain::is_drained(&db_connections::server(
+            &self.address.host,
+            &self.address.port,
+        )) {
+            debug!("Not connecting to {:?}, it's drained", self.address.name());
+            return Err(Error::AllServersDown);
+        }
+
         info!(
             "Creating a new connection to {:?} using user {:?}",
             self.address.name(),
             self.user.username
         );
 
+        let mut user = server_user(&self.user);
+
+        if let Some(ref vault) = self.vault {
+            (user.username, user.password) = vault::credentials(vault).await?;
+        }
+
+        if let Some(ref region) = self.rds_iam_region {
+            user.password = rds_iam::auth_token(
+                &self.address.host,
+                &self.address.port,
+                &user.username,
+                region,
+            )?;
+        }
+
+        if let Some(ref gcp_iam_credentials) = self.gcp_iam_credentials {
+            user.password = gcp_iam::auth_token(gcp_iam_credentials).await?;
+        }
+
+        // While passwords are rotated, the server may only accept the secondary
+        // password. Start with the one that worked last time.
+        let mut passwords = vec![user.password.clone()];
+
+        let secondary_password = secondary_password(
+            &self.user,
+            self.vault.is_some()
+                || self.rds_iam_region.is_some()
+                || self.gcp_iam_credentials.is_some(),
+        );
+
+        if let Some(ref secondary_password) = secondary_password {
+            if self.secondary_password_first.load(Ordering::Relaxed) {
+                passwords.insert(0, secondary_password.clone());
+            } else {
+                passwords.push(secondary_password.clone());
+            }
+        }
+
         // Put a temporary process_id into the stats
         // for server login.
         let process_id = rand::random::<i32>();
         self.stats.server_login(process_id, self.address.id);
 
-        // Connect to the PostgreSQL server.
-        match Server::startup(
-            &self.address,
-            &self.user,
-            &self.database,
-            self.client_server_map.clone(),
-            self.stats.clone(),
-        )
-        .await
-        {
-            Ok(conn) => {
+        let db_connection = match self.db_connection().await {
+            Ok(db_connection) => db_connection,
+            Err(err) => {
+                self.stats.server_disconnecting(process_id, self.address.id);
+                return Err(err);
+            }
+        };
+
+        let config = get_config();
+        let connect_timeout = Duration::from_millis(config.general.connect_timeout);
+        let mut result = Err(Error::ServerError);
+        let mut retry = 0;
+
+        loop {
+            for (attempt, password) in passwords.iter().enumerate() {
+                user.password = password.clone();
+
+                // Each attempt is a new connection to the server.
+                login_throttle::wait(&db_connections::server(
+                    &self.address.host,
+                    &self.address.port,
+                ))
+                .await;
+
+                // Connect to the PostgreSQL server.
+                let startup = Server::startup(
+                    &self.address,
+                    &user,
+                    &self.database,
+                    &self.application_name,
+                    &self.startup_parameters,
+                    self.replication.as_deref(),
+                    self.client_server_map.clone(),
+                    self.stats.clone(),
+                );
+
+                result = match tokio::time::timeout(connect_timeout, startup).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        error!(
+                            "Could not connect to {:?} within connect_timeout ({}ms)",
+                            self.address.name(),
+                            config.general.connect_timeout
+                        );
+                        Err(Error::SocketError)
+                    }
+                };
+
+                match result {
+                    Ok(_) => {
+                        self.secondary_password_first.store(
+                            secondary_password.as_ref() == Some(password),
+                            Ordering::Relaxed,
+                        );
+                        break;
+                    }
+
+                    // The server rejected the password, try the other one.
+                    Err(Error::InvalidPassword) if attempt + 1 < passwords.len() => {
+                        warn!(
+                            "Login to {:?} as {:?} failed, trying the other password",
+                            self.address.name(),
+                            user.username
+                        );
+                    }
+
+                    Err(_) => break,
+                }
+            }
+
+            // The server couldn't be reached, e.g. it's restarting. It rejecting
+            // the login wouldn't change by trying again.
+            match result {
+                Err(Error::SocketError) if retry < config.general.connect_retries => {
+                    retry += 1;
+                    let backoff = backoff(retry);
+
+                    warn!(
+                        "Retrying the connection to {:?} in {}ms ({}/{})",
+                        self.address.name(),
+                        backoff.as_millis(),
+                        retry,
+                        config.general.connect_retries
+                    );
+
+                    tokio::time::sleep(backoff).await;
+                }
+
+                _ => break,
+            }
+        }
+
+        // bb8 keeps trying until checkout_timeout, the clients waiting for
+        // this server try the next one instead.
+        if let Err(Error::SocketError) = result {
+            self.connect_failed.notify_waiters();
+        }
+
+        match result {
+            Ok(mut conn) => {
+                conn.count(db_connection);
+
                 // Remove the temporary process_id from the stats.
                 self.stats.server_disconnecting(process_id, self.address.id);
                 Ok(conn)
@@ -547,13 +2031,238 @@ impl ManageConnection for ServerPool {
     }
 
     /// Determines if the connection is still connected to the database.
-    async fn is_valid(&self, _conn: &mut PooledConnection<'_, Self>) -> Result<(), Self::Error> {
+    async fn is_valid(&self, _conn: &mut Self::Connection) -> Result<(), Self::Error> {
         Ok(())
     }
 
     /// Synchronously determine if the connection is no longer usable, if possible.
     fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+        // It's called when the server is returned to the pool.
+        conn.release_permit();
+
+        // A server returned inside a transaction wasn't cleaned up,
+        // the next client would end up in it.
+        // Connections made with credentials Vault rotated since are recycled.
+        // The ones to a drained server are closed.
         conn.is_bad()
+            || conn.in_transaction()
+            || drain::is_drained(&db_connections::server(
+                &self.address.host,
+                &self.address.port,
+            ))
+            || self
+                .vault
+                .as_ref()
+                .is_some_and(|vault| vault::rotated_since(vault, conn.connected_at()))
+    }
+}
+
+/// The servers to try, the ones the filter keeps, in round-robin order. With the requests
+/// in flight of each server, the ones with the fewest go first, so a slow one gets fewer
+/// clients. The sort is stable: the ones as busy stay in round-robin order.
+fn candidates(
+    servers: usize,
+    filter: impl Fn(usize) -> bool,
+    round_robin: usize,
+    in_flight: Option<&[Arc<AtomicUsize>]>,
+) -> Vec<usize> {
+    let mut candidates = (0..servers)
+        .filter(|&index| filter(index))
+        .collect::<Vec<usize>>();
+
+    // In turn between the servers kept, so each one comes first as often.
+    if !candidates.is_empty() {
+        let first = round_robin % candidates.len();
+        candidates.rotate_left(first);
+    }
+
+    if let Some(in_flight) = in_flight {
+        candidates.sort_by_key(|&index| in_flight[index].load(Ordering::Relaxed));
+    }
+
+    candidates
+}
+
+/// Weighted round-robin: the transactions go to the servers in turn, each one as many
+/// times in a row as its weight. Whatever the load balancing mode, the servers with a
+/// weight of 0 go last, so they're only used when the others are down.
+fn by_weight(candidates: &mut [usize], weights: &[u32], round_robin: usize, in_turn: bool) {
+    let total = candidates
+        .iter()
+        .map(|&index| weights[index] as usize)
+        .sum::<usize>();
+
+    if in_turn && total > 0 {
+        candidates.sort();
+
+        let mut position = round_robin % total;
+        let first = candidates
+            .iter()
+            .position(
+                |&index| match position.checked_sub(weights[index] as usize) {
+                    Some(rest) => {
+                        position = rest;
+                        false
+                    }
+                    None => true,
+                },
+            )
+            .unwrap_or(0);
+
+        candidates.rotate_left(first);
+    }
+
+    candidates.sort_by_key(|&index| weights[index] == 0);
+}
+
+/// Slow start: each server warming up stays where it is with a chance of its share,
+/// else it goes after the others, so it comes first, and gets the transaction, that
+/// much less often. The others keep their order.
+fn by_share(candidates: &mut Vec<usize>, shares: &[f64], mut random: impl FnMut() -> f64) {
+    let (first, last): (Vec<usize>, Vec<usize>) = candidates
+        .iter()
+        .partition(|&&index| shares[index] >= 1.0 || random() < shares[index]);
+
+    *candidates = first;
+    candidates.extend(last);
+}
+
+/// Add a health check round trip, in microseconds, to the moving average of the server.
+/// Each one counts for a tenth, so a server getting slower is soon seen as such.
+fn measure(latency: &AtomicU64, round_trip: u64) {
+    let _ = latency.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+        Some(match average {
+            // The first one, 0 is for the servers not measured yet.
+            0 => round_trip.max(1),
+            average => (average * 9 + round_trip) / 10,
+        })
+    });
+}
+
+/// Shuffle the servers so the faster ones tend to go first: each one comes before the
+/// others with a chance relative to (the fastest's latency / its latency) to the power
+/// of latency_sensitivity, e.g. half as often as the fastest when it's twice as slow,
+/// with a sensitivity of 1. The servers not measured yet go first.
+fn by_latency(
+    candidates: &mut [usize],
+    latencies: &[Arc<AtomicU64>],
+    sensitivity: u32,
+    mut random: impl FnMut() -> f64,
+) {
+    let latency = |index: usize| latencies[index].load(Ordering::Relaxed);
+    let fastest = match candidates
+        .iter()
+        .map(|&index| latency(index))
+        .filter(|&latency| latency > 0)
+        .min()
+    {
+        Some(fastest) => fastest,
+        None => return,
+    };
+
+    // A weighted random order: the server with the largest ln(random) / weight goes first.
+    let mut keys = candidates
+        .iter()
+        .map(|&index| {
+            let key = match latency(index) {
+                0 => f64::INFINITY,
+                latency => {
+                    let weight = (fastest as f64 / latency as f64).powi(sensitivity as i32);
+                    random().ln() / weight
+                }
+            };
+
+            (key, index)
+        })
+        .collect::<Vec<(f64, usize)>>();
+
+    keys.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    for (candidate, (_, index)) in candidates.iter_mut().zip(keys) {
+        *candidate = index;
+    }
+}
+
+/// Power of two choices: of two servers picked at random, the one with fewer requests
+/// in flight goes first, then the other one. Almost as good as the least busy of all,
+/// without all the servers' clients piling up on the same one. The others stay in
+/// round-robin order, for the retries.
+fn two_choices(
+    candidates: &mut Vec<usize>,
+    in_flight: &[Arc<AtomicUsize>],
+    mut random: impl FnMut(usize) -> usize,
+) {
+    if candidates.len() < 2 {
+        return;
+    }
+
+    let first = candidates[random(candidates.len())];
+    let second = {
+        let others = candidates
+            .iter()
+            .copied()
+            .filter(|&index| index != first)
+            .collect::<Vec<usize>>();
+        others[random(others.len())]
+    };
+
+    let load = |index: usize| in_flight[index].load(Ordering::Relaxed);
+    let picked = match load(second) < load(first) {
+        true => [second, first],
+        false => [first, second],
+    };
+
+    candidates.retain(|index| !picked.contains(index));
+    candidates.splice(0..0, picked);
+}
+
+/// Resize the autoscaled pools.
+pub async fn autoscale() {
+    for pool in get_all_pools().values() {
+        pool.autoscale().await;
+    }
+}
+
+/// Rebalance the server connections of all the pools.
+pub async fn rebalance() {
+    for pool in get_all_pools().values() {
+        pool.rebalance().await;
+    }
+}
+
+/// The pool the route of the client's application_name sends it to, e.g. the one with the
+/// analytics replicas, or the same pool, and the server role the route sends it to, if any.
+pub fn route_application_name(
+    pool: ConnectionPool,
+    application_name: Option<&String>,
+) -> (ConnectionPool, Option<String>) {
+    let route = match application_name
+        .and_then(|application_name| pool.settings.application_name_route(application_name))
+    {
+        Some(route) => route.clone(),
+        None => return (pool, None),
+    };
+
+    let target = match route.pool {
+        Some(ref target) => target,
+        None => return (pool, route.role),
+    };
+
+    match get_pool(target.clone(), pool.settings.user.username.clone()) {
+        Some(target_pool) => {
+            debug!(
+                "Client with application_name {:?} routed to pool {}",
+                application_name, target
+            );
+            (target_pool, route.role)
+        }
+        None => {
+            warn!(
+                "Pool {} of the application_name route '{}' has no user {}",
+                target, route.application_name, pool.settings.user.username
+            );
+            (pool, route.role)
+        }
     }
 }
 
@@ -565,13 +2274,372 @@ pub fn get_pool(db: String, user: String) -> Option<ConnectionPool> {
     }
 }
 
-pub fn get_number_of_addresses() -> usize {
+/// The IDs of the addresses of all the pools.
+pub fn get_address_ids() -> HashSet<usize> {
     get_all_pools()
-        .iter()
-        .map(|(_, pool)| pool.databases())
-        .sum()
+        .values()
+        .flat_map(|pool| pool.address_ids())
+        .collect()
+}
+
+/// The first ID of the addresses of a new pool with the config: the lowest ones in a row
+/// no other pool uses, so they stay low when pools are replaced.
+fn free_address_ids(used: &HashSet<usize>, pool_config: &PoolConfig) -> usize {
+    let count = pool_config
+        .shards
+        .values()
+        .map(|shard| shard.servers.len())
+        .sum::<usize>();
+    let mut start = 0;
+
+    while let Some(id) = (start..start + count).find(|id| used.contains(id)) {
+        start = id + 1;
+    }
+
+    start
+}
+
+/// Read the weights and localities of the servers from the config again, after a reload:
+/// they change without re-creating the pools.
+pub fn reload_routing() {
+    let config = get_config();
+
+    for ((pool_name, _), pool) in get_all_pools() {
+        if let Some(pool_config) = config.pools.get(&pool_name) {
+            pool.settings.routing.store(Arc::new(Routing::new(
+                pool_config,
+                &config.general.locality,
+                &pool.addresses,
+            )));
+        }
+    }
 }
 
 pub fn get_all_pools() -> HashMap<(String, String), ConnectionPool> {
     return (*(*POOLS.load())).clone();
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_application_name_route() {
+        let route = |application_name: &str, pool: Option<&str>, role: Option<&str>| {
+            (
+                Regex::new(application_name).unwrap(),
+                ApplicationNameRoute {
+                    application_name: application_name.to_string(),
+                    pool: pool.map(|pool| pool.to_string()),
+                    role: role.map(|role| role.to_string()),
+                },
+            )
+        };
+
+        let settings = PoolSettings {
+            application_name_routes: vec![
+                route("^analytics-", Some("analytics"), None),
+                route("^reports$", None, Some("replica")),
+                route("report", Some("other"), None),
+            ],
+            ..Default::default()
+        };
+
+        let pool = |application_name| {
+            settings
+                .application_name_route(application_name)
+                .and_then(|route| route.pool.clone())
+        };
+
+        assert_eq!(pool("analytics-daily"), Some(String::from("analytics")));
+        assert_eq!(pool("reports"), None);
+        assert_eq!(
+            settings.application_name_route("reports").unwrap().role,
+            Some(String::from("replica"))
+        );
+        assert_eq!(pool("daily-reports"), Some(String::from("other")));
+        assert!(settings.application_name_route("app").is_none());
+    }
+
+    #[test]
+    fn test_server_user() {
+        let user = User {
+            username: String::from("app"),
+            password: String::from("app_password"),
+            secondary_password: Some(String::from("new_password")),
+            ..Default::default()
+        };
+
+        let login = server_user(&user);
+        assert_eq!(login.username, "app");
+        assert_eq!(login.password, "app_password");
+        assert_eq!(
+            secondary_password(&user, false),
+            Some(String::from("new_password"))
+        );
+        assert_eq!(secondary_password(&user, true), None);
+
+        // Logs in as another user, with that user's password.
+        let user = User {
+            server_username: Some(String::from("postgres")),
+            server_password: Some(String::from("postgres_password")),
+            ..user
+        };
+
+        let login = server_user(&user);
+        assert_eq!(login.username, "postgres");
+        assert_eq!(login.password, "postgres_password");
+        assert_eq!(secondary_password(&user, false), None);
+
+        // With its own password.
+        let user = User {
+            server_password: None,
+            ..user
+        };
+
+        assert_eq!(server_user(&user).password, "app_password");
+        assert_eq!(
+            secondary_password(&user, false),
+            Some(String::from("new_password"))
+        );
+    }
+
+    #[test]
+    fn test_backoff() {
+        for _ in 0..100 {
+            let first = backoff(1).as_millis();
+            assert!((50..=100).contains(&first));
+
+            let second = backoff(2).as_millis();
+            assert!((100..=200).contains(&second));
+
+            let last = backoff(20).as_millis();
+            assert!((2500..=5000).contains(&last));
+        }
+    }
+
+    #[test]
+    fn test_free_address_ids() {
+        // Two servers.
+        let pool_config = PoolConfig {
+            shards: HashMap::from([(
+                String::from("0"),
+                Shard {
+                    servers: vec![
+                        (String::from("a"), 5432, String::from("primary")),
+                        (String::from("b"), 5432, String::from("replica")),
+                    ],
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+
+        assert_eq!(free_address_ids(&HashSet::new(), &pool_config), 0);
+        assert_eq!(free_address_ids(&HashSet::from([0, 1]), &pool_config), 2);
+        assert_eq!(free_address_ids(&HashSet::from([1, 4]), &pool_config), 2);
+        assert_eq!(free_address_ids(&HashSet::from([0, 3, 6]), &pool_config), 1);
+    }
+
+    #[test]
+    fn test_candidates() {
+        // A primary and three replicas.
+        let addresses = (0..4)
+            .map(|id| Address {
+                id,
+                role: match id {
+                    0 => Role::Primary,
+                    _ => Role::Replica,
+                },
+                ..Default::default()
+            })
+            .collect::<Vec<Address>>();
+        let addresses = &addresses;
+        let role = |role: Option<Role>| move |index: usize| addresses[index].role == role;
+
+        assert_eq!(candidates(4, role(None), 0, None), vec![0, 1, 2, 3]);
+        assert_eq!(candidates(4, role(None), 5, None), vec![1, 2, 3, 0]);
+        assert_eq!(candidates(4, role(Some(Role::Primary)), 2, None), vec![0]);
+
+        // Each replica comes first as often.
+        for (round_robin, first) in [(0, 1), (1, 2), (2, 3), (3, 1)] {
+            assert_eq!(
+                candidates(4, role(Some(Role::Replica)), round_robin, None)[0],
+                first
+            );
+        }
+
+        // Replica 2 is the busiest, the others stay in round-robin order.
+        let in_flight = (0..4)
+            .map(|_| Arc::new(AtomicUsize::new(0)))
+            .collect::<Vec<Arc<AtomicUsize>>>();
+        let busy = [
+            InFlight::new(in_flight[2].clone()),
+            InFlight::new(in_flight[2].clone()),
+            InFlight::new(in_flight[3].clone()),
+        ];
+
+        assert_eq!(
+            candidates(4, role(Some(Role::Replica)), 1, Some(&in_flight)),
+            vec![1, 3, 2]
+        );
+
+        // Back in the pool.
+        drop(busy);
+        assert_eq!(
+            candidates(4, role(Some(Role::Replica)), 1, Some(&in_flight)),
+            vec![2, 3, 1]
+        );
+    }
+
+    #[test]
+    fn test_by_weight() {
+        // Three replicas: twice the share for the second one, none for the third one.
+        let weights = [1, 2, 0];
+        let order = |round_robin: usize, in_turn: bool| {
+            let mut candidates = vec![1, 2, 0];
+            by_weight(&mut candidates, &weights, round_robin, in_turn);
+            candidates
+        };
+
+        assert_eq!(order(0, true), vec![0, 1, 2]);
+        assert_eq!(order(1, true), vec![1, 0, 2]);
+        assert_eq!(order(2, true), vec![1, 0, 2]);
+        assert_eq!(order(3, true), vec![0, 1, 2]);
+
+        // The other modes keep their order, but for the servers with a weight of 0.
+        assert_eq!(order(0, false), vec![1, 0, 2]);
+
+        // Only the servers with a weight of 0 are left.
+        let mut candidates = vec![2];
+        by_weight(&mut candidates, &weights, 5, true);
+        assert_eq!(candidates, vec![2]);
+    }
+
+    #[test]
+    fn test_by_share() {
+        // The second and fourth servers are warming up, a quarter and three quarters of the way.
+        let shares = [1.0, 0.25, 1.0, 0.75];
+
+        let mut candidates = vec![0, 1, 2, 3];
+        by_share(&mut candidates, &shares, || 0.5);
+        assert_eq!(candidates, vec![0, 2, 3, 1]);
+
+        let mut candidates = vec![3, 2, 1, 0];
+        by_share(&mut candidates, &shares, || 0.9);
+        assert_eq!(candidates, vec![2, 0, 3, 1]);
+
+        // A quarter of the way, first a quarter as often.
+        let mut first = 0;
+        for _ in 0..10000 {
+            let mut candidates = vec![1, 0];
+            by_share(&mut candidates, &shares, rand::random);
+            if candidates == vec![1, 0] {
+                first += 1;
+            }
+        }
+        assert!((2200..=2800).contains(&first));
+    }
+
+    #[test]
+    fn test_measure() {
+        let latency = AtomicU64::new(0);
+
+        measure(&latency, 1000);
+        assert_eq!(latency.load(Ordering::Relaxed), 1000);
+
+        measure(&latency, 2000);
+        assert_eq!(latency.load(Ordering::Relaxed), 1100);
+
+        for _ in 0..50 {
+            measure(&latency, 2000);
+        }
+        assert!((1990..=2000).contains(&latency.load(Ordering::Relaxed)));
+    }
+
+    #[test]
+    fn test_by_latency() {
+        // 100us, 200us, 2ms, and not measured yet.
+        let latencies = [100, 200, 2000, 0]
+            .iter()
+            .map(|&latency| Arc::new(AtomicU64::new(latency)))
+            .collect::<Vec<Arc<AtomicU64>>>();
+
+        // The one not measured yet goes first, the others by weight.
+        let mut candidates = vec![0, 1, 2, 3];
+        by_latency(&mut candidates, &latencies, 1, || 0.5);
+        assert_eq!(candidates, vec![3, 0, 1, 2]);
+
+        // Twice as slow, half as often first.
+        let mut first = 0;
+        for _ in 0..10000 {
+            let mut candidates = vec![0, 1];
+            by_latency(&mut candidates, &latencies, 1, rand::random);
+            if candidates == vec![0, 1] {
+                first += 1;
+            }
+        }
+        assert!((6300..=7000).contains(&first));
+
+        // With more sensitivity, the slow ones are hardly used.
+        let mut first = 0;
+        for _ in 0..10000 {
+            let mut candidates = vec![0, 2];
+            by_latency(&mut candidates, &latencies, 2, rand::random);
+            if candidates == vec![0, 2] {
+                first += 1;
+            }
+        }
+        assert!(first >= 9800);
+
+        // No sensitivity, the same share.
+        let mut first = 0;
+        for _ in 0..10000 {
+            let mut candidates = vec![0, 2];
+            by_latency(&mut candidates, &latencies, 0, rand::random);
+            if candidates == vec![0, 2] {
+                first += 1;
+            }
+        }
+        assert!((4700..=5300).contains(&first));
+    }
+
+    #[test]
+    fn test_two_choices() {
+        let in_flight = [3, 1, 0, 2]
+            .iter()
+            .map(|&count| Arc::new(AtomicUsize::new(count)))
+            .collect::<Vec<Arc<AtomicUsize>>>();
+
+        // Servers 0 and 3 are picked, 3 is less busy. The others stay in order.
+        let mut picks = [0, 2].into_iter();
+        let mut candidates = vec![0, 1, 2, 3];
+        two_choices(&mut candidates, &in_flight, |_| picks.next().unwrap());
+        assert_eq!(candidates, vec![3, 0, 1, 2]);
+
+        // The busiest one never goes first, the least busy one whenever it's picked,
+        // i.e. with half of the pairs.
+        let mut first = [0; 4];
+        for _ in 0..10000 {
+            let mut candidates = vec![0, 1, 2, 3];
+            two_choices(&mut candidates, &in_flight, |servers| {
+                rand::random::<usize>() % servers
+            });
+            first[candidates[0]] += 1;
+        }
+        assert_eq!(first[0], 0);
+        assert!((4700..=5300).contains(&first[2]));
+        assert!((3100..=3600).contains(&first[1]));
+
+        // As busy, the first one picked goes first.
+        in_flight[0].store(2, Ordering::Relaxed);
+        let mut picks = [0, 2].into_iter();
+        let mut candidates = vec![0, 1, 2, 3];
+        two_choices(&mut candidates, &in_flight, |_| picks.next().unwrap());
+        assert_eq!(candidates, vec![0, 3, 1, 2]);
+
+        let mut candidates = vec![1];
+        two_choices(&mut candidates, &in_flight, |_| unreachable!());
+        assert_eq!(candidates, vec![1]);
+    }
+}
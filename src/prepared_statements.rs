@@ -0,0 +1,316 @@
+/// Named prepared statements of the clients, prepared on whichever server
+/// they're using, like pgbouncer's max_prepared_statements. In transaction mode,
+/// each transaction can get a different server, which doesn't have the statements
+/// the client prepared on the others.
+///
+/// The statements are renamed after their query, so clients preparing the same
+/// one share it, and each server prepares it once.
+use bytes::{BufMut, BytesMut};
+use log::debug;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet};
+
+use crate::server::{Response, Server};
+
+/// Queries we named, at most this many. Then they're forgotten and named again,
+/// with new numbers, so a name is never used for another query.
+const STATEMENT_NAMES_MAX: usize = 100_000;
+
+/// The names of the statements on the servers, by their query and parameter types.
+struct StatementNames {
+    names: HashMap<Vec<u8>, String>,
+
+    /// The number of the last one.
+    last: u64,
+}
+
+static STATEMENT_NAMES: Lazy<Mutex<StatementNames>> = Lazy::new(|| {
+    Mutex::new(StatementNames {
+        names: HashMap::new(),
+        last: 0,
+    })
+});
+
+/// A statement the client prepared.
+struct Statement {
+    /// Name of the statement on the servers.
+    name: String,
+
+    /// Parse message to prepare it on a server.
+    parse: BytesMut,
+}
+
+/// The prepared statements of a client.
+pub struct PreparedStatements {
+    /// Maximum number of statements prepared on each server, 0 doesn't rename them.
+    max: usize,
+
+    /// The client's statements, by the name it gave them.
+    statements: HashMap<String, Statement>,
+
+    /// What to do with the server's answers to the messages buffered until the Sync or Flush.
+    responses: Vec<Response>,
+
+    /// Statements the buffered messages prepare.
+    preparing: HashSet<String>,
+}
+
+impl PreparedStatements {
+    pub fn new(max: usize) -> PreparedStatements {
+        PreparedStatements {
+            max,
+            statements: HashMap::new(),
+            responses: Vec::new(),
+            preparing: HashSet::new(),
+        }
+    }
+
+    /// Maximum number of statements prepared on each server.
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Handle a Parse, Bind, Describe, Execute or Close message the client sent,
+    /// and return the messages to send the server instead.
+    pub fn message(&mut self, message: BytesMut, server: &mut Server) -> BytesMut {
+        if self.max == 0 {
+            // The client's named statements stay on the server.
+            if message[0] == b'P' && message.get(5).is_some_and(|name| *name != 0) {
+                server.mark_needs_cleanup();
+            }
+
+            self.responses.push(Response::Forward);
+            return message;
+        }
+
+        match message[0] as char {
+            'P' => self.parse(message, server),
+            'B' => self.bind(message, server),
+            'D' | 'C' => self.describe_or_close(message, server),
+            _ => {
+                self.responses.push(Response::Forward);
+                message
+            }
+        }
+    }
+
+    /// What to do with the server's answers to the messages returned
+    /// by `self.message()` since the last call.
+    pub fn responses(&mut self) -> Vec<Response> {
+        self.preparing.clear();
+        std::mem::take(&mut self.responses)
+    }
+
+    /// If the statement is prepared on the server, or will be by the buffered messages.
+    fn prepared(&self, name: &str, server: &mut Server) -> bool {
+        self.preparing.contains(name) || server.has_prepared_statement(name)
+    }
+
+    /// Prepare the statement on the server before it's used, if it doesn't have it.
+    fn prepare(&mut self, name: &str, server: &mut Server, messages: &mut BytesMut) {
+        let statement = &self.statements[name];
+
+        if self.prepared(&statement.name, server) {
+            return;
+        }
+
+        debug!("Preparing statement {} on server", statement.name);
+
+        messages.put(&statement.parse[..]);
+
+        self.preparing.insert(statement.name.clone());
+        self.responses.push(Response::Prepare {
+            name: statement.name.clone(),
+            forward: false,
+        });
+    }
+
+    fn parse(&mut self, message: BytesMut, server: &mut Server) -> BytesMut {
+        let (name, rest) = match cstr(&message[5..]) {
+            Some((name, rest)) if !name.is_empty() => (name, rest),
+
+            // The unnamed statement only lasts until the next one.
+            _ => {
+                self.responses.push(Response::Forward);
+                return message;
+            }
+        };
+
+        let server_name = statement_name(rest);
+        let parse = parse(&server_name, rest);
+
+        self.statements.insert(
+            name,
+            Statement {
+                name: server_name.clone(),
+                parse: parse.clone(),
+            },
+        );
+
+        if self.prepared(&server_name, server) {
+            self.responses.push(Response::Answer(b'1'));
+            BytesMut::new()
+        } else {
+            self.preparing.insert(server_name.clone());
+            self.responses.push(Response::Prepare {
+                name: server_name,
+                forward: true,
+            });
+            parse
+        }
+    }
+
+    fn bind(&mut self, message: BytesMut, server: &mut Server) -> BytesMut {
+        let statement = cstr(&message[5..])
+            .and_then(|(portal, rest)| cstr(rest).map(|(name, rest)| (portal, name, rest)));
+
+        let (portal, name, rest) = match statement {
+            Some((portal, name, rest)) if self.statements.contains_key(&name) => {
+                (portal, name, rest)
+            }
+
+            // The unnamed statement, and statements the client prepared with SQL.
+            _ => {
+                self.responses.push(Response::Forward);
+                return message;
+            }
+        };
+
+        let mut messages = BytesMut::new();
+
+        self.prepare(&name, server, &mut messages);
+        self.responses.push(Response::Forward);
+
+        let server_name = &self.statements[&name].name;
+
+        messages.put_u8(b'B');
+        messages.put_i32((4 + portal.len() + 1 + server_name.len() + 1 + rest.len()) as i32);
+        messages.put_slice(portal.as_bytes());
+        messages.put_u8(0);
+        messages.put_slice(server_name.as_bytes());
+        messages.put_u8(0);
+        messages.put_slice(rest);
+
+        messages
+    }
+
+    fn describe_or_close(&mut self, message: BytesMut, server: &mut Server) -> BytesMut {
+        let code = message[0];
+
+        let name = match (message.get(5), cstr(message.get(6..).unwrap_or_default())) {
+            (Some(b'S'), Some((name, _))) if self.statements.contains_key(&name) => name,
+
+            // Portals, and statements the client prepared with SQL.
+            _ => {
+                self.responses.push(Response::Forward);
+                return message;
+            }
+        };
+
+        // Other clients may still use it, so it stays on the servers.
+        if code == b'C' {
+            self.statements.remove(&name);
+            self.responses.push(Response::Answer(b'3'));
+            return BytesMut::new();
+        }
+
+        let mut messages = BytesMut::new();
+
+        self.prepare(&name, server, &mut messages);
+        self.responses.push(Response::Forward);
+
+        let server_name = &self.statements[&name].name;
+
+        messages.put_u8(code);
+        messages.put_i32((4 + 1 + server_name.len() + 1) as i32);
+        messages.put_u8(b'S');
+        messages.put_slice(server_name.as_bytes());
+        messages.put_u8(0);
+
+        messages
+    }
+}
+
+/// Read a null-terminated string, and return the rest.
+fn cstr(bytes: &[u8]) -> Option<(String, &[u8])> {
+    let end = bytes.iter().position(|byte| *byte == 0)?;
+
+    Some((
+        String::from_utf8_lossy(&bytes[..end]).to_string(),
+        &bytes[end + 1..],
+    ))
+}
+
+/// Name of a statement on the servers, from its query and parameter types.
+fn statement_name(query: &[u8]) -> String {
+    let mut statement_names = STATEMENT_NAMES.lock();
+
+    if let Some(name) = statement_names.names.get(query) {
+        return name.clone();
+    }
+
+    if statement_names.names.len() >= STATEMENT_NAMES_MAX {
+        statement_names.names.clear();
+    }
+
+    statement_names.last += 1;
+
+    let name = format!("pgcat_{}", statement_names.last);
+    statement_names.names.insert(query.to_vec(), name.clone());
+
+    name
+}
+
+/// Parse message preparing the query (and parameter types) with this name.
+fn parse(name: &str, query: &[u8]) -> BytesMut {
+    let mut parse = BytesMut::from(&b"P"[..]);
+
+    parse.put_i32((4 + name.len() + 1 + query.len()) as i32);
+    parse.put_slice(name.as_bytes());
+    parse.put_u8(0);
+    parse.put_slice(query);
+
+    parse
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_statement_name() {
+        let query = b"select $1\0\0\0";
+
+        assert_eq!(statement_name(query), statement_name(query));
+        assert_ne!(statement_name(query), statement_name(b"select $2\0\0\0"));
+        assert!(statement_name(query).starts_with("pgcat_"));
+
+        // Same query, other parameter types.
+        assert_ne!(
+            statement_name(b"select $1\0\0\x01\0\0\0\x17"),
+            statement_name(b"select $1\0\0\x01\0\0\0\x19")
+        );
+
+        // Different queries never share a name, even once they're forgotten.
+        let first = statement_name(b"select 'first'\0\0\0");
+        let mut names = (0..=STATEMENT_NAMES_MAX)
+            .map(|i| statement_name(format!("select {}\0\0\0", i).as_bytes()))
+            .collect::<HashSet<String>>();
+        names.insert(first);
+        names.insert(statement_name(b"select 'first'\0\0\0"));
+        assert_eq!(names.len(), STATEMENT_NAMES_MAX + 3);
+    }
+
+    #[test]
+    fn test_parse() {
+        let message = parse("pgcat_1", b"select 1\0\0\0");
+
+        assert_eq!(&message[..], b"P\0\0\0\x17pgcat_1\0select 1\0\0\0");
+        assert_eq!(
+            cstr(&message[5..]),
+            Some((String::from("pgcat_1"), &b"select 1\0\0\0"[..]))
+        );
+        assert_eq!(cstr(b"no end"), None);
+    }
+}
@@ -1,20 +1,24 @@
 /// Route queries automatically based on explicitely requested
 /// or implied query characteristics.
 use bytes::{Buf, BytesMut};
-use log::{debug, error};
-use once_cell::sync::OnceCell;
+use log::{debug, error, warn};
+use once_cell::sync::{Lazy, OnceCell};
 use regex::{Regex, RegexSet};
 use sqlparser::ast::Statement::{Query, StartTransaction};
 use sqlparser::dialect::PostgreSqlDialect;
 use sqlparser::parser::Parser;
+use std::time::{Duration, Instant};
 
 use crate::config::Role;
 use crate::pool::{ConnectionPool, PoolSettings};
-use crate::sharding::{Sharder, ShardingFunction};
+use crate::resharding::{self, Phase};
+use crate::scatter_gather::{self, Merge};
+use crate::sharding::{range_shard, Sharder, ShardingKey};
+use crate::sharding_directory;
 
 /// Regexes used to parse custom commands.
 const CUSTOM_SQL_REGEXES: [&str; 7] = [
-    r"(?i)^ *SET SHARDING KEY TO '?([0-9]+)'? *;? *$",
+    r"(?i)^ *SET SHARDING KEY TO (?:'([^']+)'|([0-9]+)) *;? *$",
     r"(?i)^ *SET SHARD TO '?([0-9]+|ANY)'? *;? *$",
     r"(?i)^ *SHOW SHARD *;? *$",
     r"(?i)^ *SET SERVER ROLE TO '(PRIMARY|REPLICA|ANY|AUTO|DEFAULT)' *;? *$",
@@ -23,6 +27,15 @@ const CUSTOM_SQL_REGEXES: [&str; 7] = [
     r"(?i)^ *SHOW PRIMARY READS *;? *$",
 ];
 
+/// Routing hints in a comment of the query, e.g. `/* pgcat: replica, shard=2 */`.
+static HINTS_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"/\*\s*pgcat:([^*]*)\*/").unwrap());
+
+/// One routing hint in a comment of its own, e.g. `/* sharding_key: 1234 */`, like
+/// the ORMs that tag the queries add them.
+static HINT_COMMENT_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)/\*\s*(sharding_key|shard|role)\s*[:=]\s*'?([^'*\s]+)'?\s*\*/").unwrap()
+});
+
 /// Custom commands.
 #[derive(PartialEq, Debug)]
 pub enum Command {
@@ -49,12 +62,31 @@ pub struct QueryRouter {
     /// Which server should we be talking to.
     active_role: Option<Role>,
 
+    /// The role and shard the hints of the query asked for, for it only.
+    role_hint: Option<Role>,
+    shard_hint: Option<usize>,
+
+    /// The shard the writes of the sharding key (or the hint's) go to too, while
+    /// it moves there or from there with the pool's resharding.
+    mirror_shard: Option<usize>,
+    mirror_hint: Option<usize>,
+
     /// Should we try to parse queries to route them to replicas or primary automatically
     query_parser_enabled: bool,
 
     /// Include the primary into the replica pool for reads.
     primary_reads_enabled: bool,
 
+    /// The transaction the client is in wrote.
+    writing: bool,
+
+    /// The query parser couldn't tell if the transaction the client is in writes,
+    /// e.g. sqlparser doesn't know INSERT ... ON CONFLICT.
+    unparsed: bool,
+
+    /// When the client released the server after its last write.
+    last_write: Option<Instant>,
+
     pool_settings: PoolSettings,
 }
 
@@ -95,12 +127,43 @@ impl QueryRouter {
         QueryRouter {
             active_shard: None,
             active_role: None,
+            role_hint: None,
+            shard_hint: None,
+            mirror_shard: None,
+            mirror_hint: None,
             query_parser_enabled: target_pool.settings.query_parser_enabled,
             primary_reads_enabled: target_pool.settings.primary_reads_enabled,
+            writing: false,
+            unparsed: false,
+            last_write: None,
             pool_settings: target_pool.settings,
         }
     }
 
+    /// Use the settings of the pool the client moved to after a config reload.
+    pub fn update_pool_settings(&mut self, pool_settings: PoolSettings) {
+        // The shard the client picked may not exist anymore.
+        if self
+            .active_shard
+            .map(|shard| shard >= pool_settings.shards.len())
+            == Some(true)
+        {
+            self.active_shard = None;
+        }
+
+        // And the resharding may be over.
+        if pool_settings.resharding.is_none()
+            || self
+                .mirror_shard
+                .map(|shard| shard >= pool_settings.shards.len())
+                == Some(true)
+        {
+            self.mirror_shard = None;
+        }
+
+        self.pool_settings = pool_settings;
+    }
+
     /// Try to parse a command and execute it.
     pub fn try_execute_command(&mut self, mut buf: BytesMut) -> Option<(Command, String)> {
         let code = buf.get_u8() as char;
@@ -110,8 +173,10 @@ impl QueryRouter {
             return None;
         }
 
-        let len = buf.get_i32() as usize;
-        let query = String::from_utf8_lossy(&buf[..len - 5]).to_string(); // Ignore the terminating NULL.
+        let _len = buf.get_i32();
+
+        // Not a command, unless the message is well formed.
+        let query = String::from_utf8_lossy(c_string(&buf)?).to_string();
 
         let regex_set = match CUSTOM_SQL_REGEX_SET.get() {
             Some(regex_set) => regex_set,
@@ -132,19 +197,6 @@ impl QueryRouter {
             return None;
         }
 
-        let sharding_function = match self.pool_settings.sharding_function.as_ref() {
-            "pg_bigint_hash" => ShardingFunction::PgBigintHash,
-            "sha1" => ShardingFunction::Sha1,
-            _ => unreachable!(),
-        };
-
-        let default_server_role = match self.pool_settings.default_role.as_ref() {
-            "any" => None,
-            "primary" => Some(Role::Primary),
-            "replica" => Some(Role::Replica),
-            _ => unreachable!(),
-        };
-
         let command = match matches[0] {
             0 => Command::SetShardingKey,
             1 => Command::SetShard,
@@ -166,8 +218,9 @@ impl QueryRouter {
                 // that matches all 5 custom SQL patterns, but maybe that's not very legible?
                 //
                 // I think this is faster than running the Regex engine 5 times.
+                // The first group that matched, e.g. the quoted or unquoted sharding key.
                 match regex_list[matches[0]].captures(&query) {
-                    Some(captures) => match captures.get(1) {
+                    Some(captures) => match captures.iter().skip(1).flatten().next() {
                         Some(value) => value.as_str().to_string(),
                         None => return None,
                     },
@@ -195,51 +248,27 @@ impl QueryRouter {
         };
 
         match command {
+            // The value is the shard, empty if no shard has the key.
             Command::SetShardingKey => {
-                let sharder = Sharder::new(self.pool_settings.shards.len(), sharding_function);
-                let shard = sharder.shard(value.parse::<i64>().unwrap());
-                self.active_shard = Some(shard);
-                value = shard.to_string();
+                value = match self.shards_of_key(&value) {
+                    Some((shard, mirror)) => {
+                        self.active_shard = Some(shard);
+                        self.mirror_shard = mirror;
+                        shard.to_string()
+                    }
+                    None => String::new(),
+                };
             }
 
             Command::SetShard => {
+                self.mirror_shard = None;
                 self.active_shard = match value.to_ascii_uppercase().as_ref() {
                     "ANY" => Some(rand::random::<usize>() % self.pool_settings.shards.len()),
                     _ => Some(value.parse::<usize>().unwrap()),
                 };
             }
 
-            Command::SetServerRole => {
-                self.active_role = match value.to_ascii_lowercase().as_ref() {
-                    "primary" => {
-                        self.query_parser_enabled = false;
-                        Some(Role::Primary)
-                    }
-
-                    "replica" => {
-                        self.query_parser_enabled = false;
-                        Some(Role::Replica)
-                    }
-
-                    "any" => {
-                        self.query_parser_enabled = false;
-                        None
-                    }
-
-                    "auto" => {
-                        self.query_parser_enabled = true;
-                        None
-                    }
-
-                    "default" => {
-                        self.active_role = default_server_role;
-                        self.query_parser_enabled = self.query_parser_enabled;
-                        self.active_role
-                    }
-
-                    _ => unreachable!(),
-                };
-            }
+            Command::SetServerRole => self.set_server_role(&value),
 
             Command::SetPrimaryReads => {
                 if value == "on" {
@@ -260,43 +289,215 @@ impl QueryRouter {
         Some((command, value))
     }
 
-    /// Try to infer which server to connect to based on the contents of the query.
-    pub fn infer_role(&mut self, mut buf: BytesMut) -> bool {
-        debug!("Inferring role");
+    /// The shard of the sharding key, by the pool's directory, ranges or sharding function.
+    /// None if no shard has it.
+    fn shard_of_key(&self, value: &str) -> Option<usize> {
+        match ShardingKey::parse(value, &self.pool_settings.sharding_key_type) {
+            _ if self.pool_settings.sharding_directory => {
+                sharding_directory::shard(&self.pool_settings.pool_name, value)
+            }
 
-        let code = buf.get_u8() as char;
-        let len = buf.get_i32() as usize;
+            Some(key) if !self.pool_settings.sharding_ranges.is_empty() => {
+                range_shard(&self.pool_settings.sharding_ranges, &key)
+            }
 
-        let query = match code {
-            // Query
-            'Q' => {
-                let query = String::from_utf8_lossy(&buf[..len - 5]).to_string();
-                debug!("Query: '{}'", query);
-                query
+            Some(key) => Some(self.sharder(self.pool_settings.shards.len()).shard(&key)),
+
+            None => None,
+        }
+    }
+
+    /// The shard of the sharding key, and the shard its writes are mirrored to while
+    /// it moves with the pool's resharding, depending on the phase. None if no shard has it.
+    fn shards_of_key(&self, value: &str) -> Option<(usize, Option<usize>)> {
+        let new = self.shard_of_key(value);
+
+        let resharding = match self.pool_settings.resharding {
+            Some(ref resharding) => resharding,
+            None => return new.map(|shard| (shard, None)),
+        };
+
+        let old = match (
+            ShardingKey::parse(value, &self.pool_settings.sharding_key_type),
+            resharding.from_shards,
+        ) {
+            (Some(key), Some(from_shards)) => Some(self.sharder(from_shards).shard(&key)),
+            (Some(key), None) => range_shard(&resharding.from_ranges, &key),
+            (None, _) => None,
+        };
+
+        match (
+            resharding::phase(&self.pool_settings.pool_name, resharding),
+            old,
+            new,
+        ) {
+            // The key doesn't move.
+            (_, Some(old), Some(new)) if old == new => Some((new, None)),
+
+            (Phase::Old, Some(old), _) => Some((old, None)),
+            (Phase::DualWrite, Some(old), new) => Some((old, new)),
+            (Phase::Cutover, old, Some(new)) => Some((new, old)),
+            (Phase::New, _, Some(new)) => Some((new, None)),
+
+            // Only one of the shard maps has the key.
+            (_, old, new) => old.or(new).map(|shard| (shard, None)),
+        }
+    }
+
+    /// The pool's sharding function over the first shards.
+    fn sharder(&self, shards: usize) -> Sharder {
+        Sharder::from_settings(
+            shards,
+            &self.pool_settings.sharding_function,
+            &self.pool_settings.sharding_mode,
+            self.pool_settings.sharding_seed,
+        )
+    }
+
+    /// Use the servers with the role: primary, replica, any, auto (the query parser
+    /// decides) or default, like `SET SERVER ROLE`.
+    pub fn set_server_role(&mut self, role: &str) {
+        let default_server_role = match self.pool_settings.default_role.as_ref() {
+            "any" => None,
+            "primary" => Some(Role::Primary),
+            "replica" => Some(Role::Replica),
+            _ => unreachable!(),
+        };
+
+        self.active_role = match role.to_ascii_lowercase().as_ref() {
+            "primary" => {
+                self.query_parser_enabled = false;
+                Some(Role::Primary)
             }
 
-            // Parse (prepared statement)
-            'P' => {
-                let mut start = 0;
-                let mut end;
+            "replica" => {
+                self.query_parser_enabled = false;
+                Some(Role::Replica)
+            }
 
-                // Skip the name of the prepared statement.
-                while buf[start] != 0 && start < buf.len() {
-                    start += 1;
-                }
-                start += 1; // Skip terminating null
+            "any" => {
+                self.query_parser_enabled = false;
+                None
+            }
+
+            "auto" => {
+                self.query_parser_enabled = true;
+                None
+            }
 
-                // Find the end of the prepared stmt (\0)
-                end = start;
-                while buf[end] != 0 && end < buf.len() {
-                    end += 1;
+            "default" => {
+                self.active_role = default_server_role;
+                self.query_parser_enabled = self.query_parser_enabled;
+                self.active_role
+            }
+
+            _ => unreachable!(),
+        };
+    }
+
+    /// Use the routing hints in a comment of the query, for it only, e.g.
+    /// `/* pgcat: primary */`, `/* pgcat: replica */`, `/* pgcat: shard=2 */` or
+    /// `/* pgcat: sharding_key=1234 */`, or in comments of their own, e.g.
+    /// `/* sharding_key: 1234 */` or `/* role: replica */`.
+    /// The ones of the previous query are forgotten.
+    pub fn use_hints(&mut self, buf: &BytesMut) {
+        self.role_hint = None;
+        self.shard_hint = None;
+        self.mirror_hint = None;
+
+        let query = match statement(buf) {
+            Some(query) if query.contains("/*") => query,
+            _ => return,
+        };
+
+        let mut hints = Vec::new();
+
+        if let Some(captures) = HINTS_REGEX.captures(&query) {
+            hints.extend(
+                captures[1]
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|hint| !hint.is_empty())
+                    .map(|hint| hint.to_string()),
+            );
+        }
+
+        for captures in HINT_COMMENT_REGEX.captures_iter(&query) {
+            hints.push(match captures[1].to_ascii_lowercase().as_ref() {
+                "role" => captures[2].to_string(),
+                name => format!("{}={}", name, &captures[2]),
+            });
+        }
+
+        for hint in hints {
+            // The names aren't case sensitive, the sharding keys are.
+            let (name, value) = match hint.split_once('=') {
+                Some((name, value)) => (name.to_ascii_lowercase(), Some(value)),
+                None => (hint.to_ascii_lowercase(), None),
+            };
+
+            match (name.as_ref(), value) {
+                ("primary", None) => self.role_hint = Some(Role::Primary),
+                ("replica", None) => self.role_hint = Some(Role::Replica),
+
+                ("shard", Some(shard)) => match shard
+                    .trim_matches(|c| c == '\'' || c == '"')
+                    .parse::<usize>()
+                {
+                    Ok(shard) if shard < self.pool_settings.shards.len() => {
+                        self.shard_hint = Some(shard);
+                        self.mirror_hint = None;
+                    }
+                    _ => warn!("Ignoring the routing hint '{}'", hint),
+                },
+
+                ("sharding_key", Some(key)) => {
+                    match self.shards_of_key(key.trim_matches(|c| c == '\'' || c == '"')) {
+                        Some((shard, mirror)) => {
+                            self.shard_hint = Some(shard);
+                            self.mirror_hint = mirror;
+                        }
+                        None => warn!("Ignoring the routing hint '{}', no shard has the key", hint),
+                    }
                 }
 
-                let query = String::from_utf8_lossy(&buf[start..end]).to_string();
+                _ => warn!("Ignoring the routing hint '{}'", hint),
+            };
+        }
 
-                debug!("Prepared statement: '{}'", query);
+        debug!(
+            "Routing hints: role {:?}, shard {:?}",
+            self.role_hint, self.shard_hint
+        );
+    }
 
-                query.replace("$", "") // Remove placeholders turning them into "values"
+    /// Try to infer which server to connect to based on the contents of the query.
+    pub fn infer_role(&mut self, buf: BytesMut) -> bool {
+        debug!("Inferring role");
+
+        let query = match buf[0] as char {
+            // Query
+            'Q' => match statement(&buf) {
+                Some(query) => {
+                    debug!("Query: '{}'", query);
+                    query
+                }
+                None => return false,
+            },
+
+            // Parse (prepared statement)
+            'P' => match statement(&buf) {
+                Some(query) => {
+                    debug!("Prepared statement: '{}'", query);
+                    query.replace("$", "") // Remove placeholders turning them into "values"
+                }
+                None => return false,
+            },
+
+            // FunctionCall, e.g. lo_write(), the function may write.
+            'F' => {
+                self.active_role = Some(Role::Primary);
+                self.writing = true;
+                return true;
             }
 
             _ => return false,
@@ -306,6 +507,7 @@ impl QueryRouter {
             Ok(ast) => ast,
             Err(err) => {
                 debug!("{}", err.to_string());
+                self.unparsed = true;
                 return false;
             }
         };
@@ -314,44 +516,141 @@ impl QueryRouter {
             return false;
         }
 
-        match ast[0] {
-            // All transactions go to the primary, probably a write.
-            StartTransaction { .. } => {
-                self.active_role = Some(Role::Primary);
-            }
+        // The statements of a simple query all go to the same server, so if any
+        // of them writes (or starts a transaction), the whole query goes to the primary.
+        let mut role = None;
 
-            // Likely a read-only query
-            Query { .. } => {
-                self.active_role = match self.primary_reads_enabled {
-                    false => Some(Role::Replica), // If primary should not be receiving reads, use a replica.
-                    true => None,                 // Any server role is fine in this case.
+        for statement in &ast {
+            match statement {
+                // All transactions go to the primary, probably a write.
+                StartTransaction { .. } => {
+                    role = Some(Role::Primary);
                 }
-            }
 
-            // Likely a write
-            _ => {
-                self.active_role = Some(Role::Primary);
+                // Likely a read-only query
+                Query { .. } => {
+                    if role.is_none() {
+                        role = Some(Role::Replica);
+                    }
+                }
+
+                // Likely a write
+                _ => {
+                    role = Some(Role::Primary);
+                }
+            };
+        }
+
+        self.active_role = match role {
+            Some(Role::Primary) => {
+                self.writing = true;
+                role
             }
+
+            // The client reads what it just wrote, even if the replicas
+            // didn't replay it yet.
+            Some(Role::Replica) if self.wrote_recently() => Some(Role::Primary),
+
+            // If primary should not be receiving reads, use a replica,
+            // otherwise any server role is fine.
+            Some(Role::Replica) if self.primary_reads_enabled => None,
+            role => role,
         };
 
         true
     }
 
+    /// The client released its server at the end of a transaction. If it wrote,
+    /// its reads go to the primary for primary_reads_after_write from now on.
+    pub fn server_released(&mut self) {
+        self.unparsed = false;
+
+        if self.writing {
+            self.writing = false;
+            self.last_write = Some(Instant::now());
+        }
+    }
+
+    /// The client wrote less than primary_reads_after_write ago, or is writing.
+    fn wrote_recently(&self) -> bool {
+        let window = Duration::from_millis(self.pool_settings.primary_reads_after_write);
+
+        !window.is_zero()
+            && (self.writing
+                || self
+                    .last_write
+                    .is_some_and(|last_write| last_write.elapsed() < window))
+    }
+
     /// Get the current desired server role we should be talking to.
     pub fn role(&self) -> Option<Role> {
-        self.active_role
+        // Even the writes: the replicas reject them.
+        if self.pool_settings.user.read_only {
+            return Some(Role::Replica);
+        }
+
+        self.role_hint.or(self.active_role)
+    }
+
+    /// How to merge the results of the query, if it goes to all the shards: a SELECT
+    /// of a client that didn't pick a shard, with the pool's scatter_gather.
+    pub fn scatter_gather(&self, message: &BytesMut) -> Option<Merge> {
+        if !self.pool_settings.scatter_gather
+            || self.pool_settings.shards.len() < 2
+            || self.active_shard.is_some()
+            || self.shard_hint.is_some()
+            || message[0] as char != 'Q'
+        {
+            return None;
+        }
+
+        statement(message).and_then(|query| scatter_gather::plan(&query))
     }
 
     /// Get desired shard we should be talking to.
     pub fn shard(&self) -> usize {
-        match self.active_shard {
-            Some(shard) => shard,
-            None => 0,
-        }
+        self.shard_hint.or(self.active_shard).unwrap_or_default()
     }
 
     pub fn set_shard(&mut self, shard: usize) {
         self.active_shard = Some(shard);
+        self.mirror_shard = None;
+    }
+
+    /// The shard the transaction is mirrored to, while its sharding key moves with the
+    /// pool's resharding: if it may write, unless the query parser or the role tells
+    /// it doesn't.
+    pub fn mirror_shard(&self) -> Option<usize> {
+        let mirror = match self.shard_hint {
+            Some(_) => self.mirror_hint,
+            None => self.mirror_shard,
+        }?;
+
+        if (self.query_parser_enabled && !self.writing && !self.unparsed)
+            || self.role() == Some(Role::Replica)
+        {
+            return None;
+        }
+
+        Some(mirror)
+    }
+
+    /// The shard the transaction is mirrored to from this statement on, if it's the first
+    /// one that may write: a session, or a transaction, may read first. The role of the
+    /// next transaction is still inferred from its first statement.
+    pub fn mirror_write(&mut self, message: &BytesMut) -> Option<usize> {
+        match self.shard_hint {
+            Some(_) => self.mirror_hint,
+            None => self.mirror_shard,
+        }?;
+
+        let active_role = self.active_role;
+        self.infer_role(message.clone());
+
+        let mirror = self.mirror_shard();
+        self.active_role = active_role;
+
+        mirror
     }
 
     /// Should we attempt to parse queries?
@@ -361,11 +660,38 @@ impl QueryRouter {
     }
 }
 
+/// The SQL of a Query or a Parse (prepared statement) message.
+/// None if the message is malformed, e.g. its string isn't terminated.
+fn statement(message: &BytesMut) -> Option<String> {
+    // Past the code and the length.
+    let buf = message.get(5..)?;
+
+    let query = match message[0] as char {
+        'Q' => c_string(buf)?,
+
+        // Past the name of the prepared statement.
+        'P' => c_string(&buf[c_string(buf)?.len() + 1..])?,
+
+        _ => return None,
+    };
+
+    Some(String::from_utf8_lossy(query).to_string())
+}
+
+/// The string at the start of the buffer, without its terminating null.
+fn c_string(buf: &[u8]) -> Option<&[u8]> {
+    let end = buf.iter().position(|byte| *byte == 0)?;
+
+    Some(&buf[..end])
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::config::{RangeBound, Resharding, Shard, ShardingRange, User};
     use crate::messages::simple_query;
     use bytes::BufMut;
+    use std::collections::HashMap;
 
     #[test]
     fn test_defaults() {
@@ -429,6 +755,30 @@ mod test {
         assert_eq!(qr.role(), None);
     }
 
+    #[test]
+    fn test_infer_role_multiple_statements() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new(ConnectionPool::default());
+        qr.try_execute_command(simple_query("SET SERVER ROLE TO 'auto'"));
+        assert!(qr
+            .try_execute_command(simple_query("SET PRIMARY READS TO off"))
+            .is_some());
+
+        let queries = vec![
+            simple_query("SELECT * FROM items; UPDATE items SET name = 'pumpkin' WHERE id = 5"),
+            simple_query("BEGIN; SELECT * FROM items WHERE id = 5; COMMIT;"),
+            simple_query("DELETE FROM items WHERE id = 5; SELECT * FROM items"),
+        ];
+
+        for query in queries {
+            assert!(qr.infer_role(query));
+            assert_eq!(qr.role(), Some(Role::Primary));
+        }
+
+        assert!(qr.infer_role(simple_query("SELECT 1; SELECT * FROM items WHERE id = 5")));
+        assert_eq!(qr.role(), Some(Role::Replica));
+    }
+
     #[test]
     fn test_infer_role_parse_prepared() {
         QueryRouter::setup();
@@ -449,6 +799,62 @@ mod test {
         assert_eq!(qr.role(), Some(Role::Replica));
     }
 
+    #[test]
+    fn test_malformed_messages() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new(ConnectionPool::default());
+        qr.query_parser_enabled = true;
+
+        // An empty query, and a query and a prepared statement without their terminating null.
+        let mut empty = BytesMut::from(&b"Q"[..]);
+        empty.put_i32(4);
+
+        let mut query = BytesMut::from(&b"Q"[..]);
+        query.put_i32(4 + 8);
+        query.put(&b"SELECT 1"[..]);
+
+        let mut prepared = BytesMut::from(&b"P"[..]);
+        prepared.put_i32(4 + 1 + 8);
+        prepared.put_u8(0);
+        prepared.put(&b"SELECT 1"[..]);
+
+        let mut unnamed = BytesMut::from(&b"P"[..]);
+        unnamed.put_i32(4 + 4);
+        unnamed.put(&b"stmt"[..]);
+
+        for message in [empty, query, prepared, unnamed] {
+            assert_eq!(qr.try_execute_command(message.clone()), None);
+            assert!(!qr.infer_role(message.clone()));
+            qr.use_hints(&message);
+        }
+    }
+
+    #[test]
+    fn test_infer_role_function_call() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new(ConnectionPool::default());
+        qr.try_execute_command(simple_query("SET SERVER ROLE TO 'auto'"));
+        assert!(qr
+            .try_execute_command(simple_query("SET PRIMARY READS TO off"))
+            .is_some());
+
+        // lo_open(16384, 131072), i.e. INV_WRITE.
+        let mut function_call = BytesMut::from(&b"F"[..]);
+        function_call.put_i32(4 + 4 + 2 + 2 + 2 * (4 + 4) + 2);
+        function_call.put_i32(952);
+        function_call.put_i16(1);
+        function_call.put_i16(1);
+        function_call.put_i16(2);
+        function_call.put_i32(4);
+        function_call.put_i32(16384);
+        function_call.put_i32(4);
+        function_call.put_i32(131072);
+        function_call.put_i16(1);
+
+        assert!(qr.infer_role(function_call));
+        assert_eq!(qr.role(), Some(Role::Primary));
+    }
+
     #[test]
     fn test_regex_set() {
         QueryRouter::setup();
@@ -483,6 +889,9 @@ mod test {
             "SET SHARDING KEY TO 11235",
             "SET SHARD TO 15",
             "SET PRIMARY READS TO off",
+            // Text and UUID keys
+            "SET SHARDING KEY TO 'acme corp'",
+            "SET SHARDING KEY TO 'a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11';",
             // Spaces and semicolon
             "  SET SHARDING KEY TO 11235  ; ",
             "  SET SHARD TO 15;   ",
@@ -495,8 +904,8 @@ mod test {
 
         // Which regexes it'll match to in the list
         let matches = [
-            0, 1, 2, 3, 3, 3, 3, 4, 5, 5, 5, 6, 0, 1, 2, 3, 3, 3, 3, 4, 5, 5, 5, 0, 1, 5, 0, 1, 0,
-            3, 3, 3, 5,
+            0, 1, 2, 3, 3, 3, 3, 4, 5, 5, 5, 6, 0, 1, 2, 3, 3, 3, 3, 4, 5, 5, 5, 0, 1, 5, 0, 0, 0,
+            1, 0, 3, 3, 3, 5,
         ];
 
         let list = CUSTOM_SQL_REGEX_LIST.get().unwrap();
@@ -513,6 +922,8 @@ mod test {
         let bad = [
             "SELECT * FROM table",
             "SELECT * FROM table WHERE value = 'set sharding key to 5'", // Don't capture things in the middle of the query
+            "SET SHARDING KEY TO 5; SELECT 1",
+            "SET SHARDING KEY TO ''",
         ];
 
         for query in &bad {
@@ -621,4 +1032,350 @@ mod test {
         assert!(qr.try_execute_command(query) != None);
         assert!(qr.query_parser_enabled());
     }
+
+    #[test]
+    fn test_primary_reads_after_write() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new(ConnectionPool::default());
+        assert!(qr
+            .try_execute_command(simple_query("SET PRIMARY READS TO off"))
+            .is_some());
+
+        let read = simple_query("SELECT * FROM items WHERE id = 5");
+        let write = simple_query("INSERT INTO items (id, name) VALUES (5, 'pumpkin')");
+
+        // Disabled.
+        assert!(qr.infer_role(write.clone()));
+        qr.server_released();
+        assert!(qr.infer_role(read.clone()));
+        assert_eq!(qr.role(), Some(Role::Replica));
+
+        qr.update_pool_settings(PoolSettings {
+            primary_reads_after_write: 60_000,
+            ..PoolSettings::default()
+        });
+
+        // After the write, the reads follow it to the primary.
+        assert!(qr.infer_role(write));
+        assert_eq!(qr.role(), Some(Role::Primary));
+        qr.server_released();
+        assert!(qr.infer_role(read.clone()));
+        assert_eq!(qr.role(), Some(Role::Primary));
+
+        // Reads alone don't extend the window.
+        qr.server_released();
+        qr.last_write = Some(Instant::now() - Duration::from_secs(61));
+        assert!(qr.infer_role(read));
+        assert_eq!(qr.role(), Some(Role::Replica));
+    }
+
+    #[test]
+    fn test_use_hints() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new(ConnectionPool::default());
+        qr.update_pool_settings(PoolSettings {
+            shards: HashMap::from([
+                (String::from("0"), Shard::default()),
+                (String::from("1"), Shard::default()),
+            ]),
+            ..PoolSettings::default()
+        });
+        qr.set_server_role("replica");
+
+        qr.use_hints(&simple_query("/* pgcat: primary */ SELECT * FROM items"));
+        assert_eq!(qr.role(), Some(Role::Primary));
+        assert_eq!(qr.shard(), 0);
+
+        // At the end, like sqlcommenter does.
+        qr.use_hints(&simple_query(
+            "SELECT * FROM items /* pgcat: Replica, shard='1' */",
+        ));
+        assert_eq!(qr.role(), Some(Role::Replica));
+        assert_eq!(qr.shard(), 1);
+
+        let mut parse = BytesMut::from(&b"P"[..]);
+        let prepared_stmt = &b"UPDATE items SET name = $1 /*pgcat:shard=1*/\0"[..];
+        parse.put_i32(prepared_stmt.len() as i32 + 4 + 1 + 2);
+        parse.put_u8(0);
+        parse.put(prepared_stmt);
+        parse.put_i16(0);
+        qr.use_hints(&parse);
+        assert_eq!(qr.shard(), 1);
+
+        // For that query only.
+        qr.use_hints(&simple_query("SELECT * FROM items"));
+        assert_eq!(qr.role(), Some(Role::Replica));
+        assert_eq!(qr.shard(), 0);
+
+        // No such shard.
+        qr.use_hints(&simple_query("/* pgcat: shard=2 */ SELECT 1"));
+        assert_eq!(qr.shard(), 0);
+        qr.use_hints(&simple_query("/* not pgcat: primary */ SELECT 1"));
+        assert_eq!(qr.role(), Some(Role::Replica));
+    }
+
+    #[test]
+    fn test_sharding_key_hints() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new(ConnectionPool::default());
+        qr.update_pool_settings(PoolSettings {
+            shards: HashMap::from([
+                (String::from("0"), Shard::default()),
+                (String::from("1"), Shard::default()),
+            ]),
+            sharding_ranges: vec![
+                ShardingRange {
+                    shard: 0,
+                    from: None,
+                    to: Some(RangeBound::Integer(1000)),
+                },
+                ShardingRange {
+                    shard: 1,
+                    from: Some(RangeBound::Integer(1000)),
+                    to: None,
+                },
+            ],
+            ..PoolSettings::default()
+        });
+
+        qr.use_hints(&simple_query(
+            "/* sharding_key: 1234 */ SELECT * FROM items",
+        ));
+        assert_eq!(qr.shard(), 1);
+
+        qr.use_hints(&simple_query(
+            "SELECT * FROM items /* pgcat: sharding_key=5 */",
+        ));
+        assert_eq!(qr.shard(), 0);
+
+        qr.use_hints(&simple_query(
+            "SELECT 1 /* Sharding_Key: '2000' */ /* role: primary */",
+        ));
+        assert_eq!(qr.shard(), 1);
+        assert_eq!(qr.role(), Some(Role::Primary));
+
+        qr.use_hints(&simple_query("/* shard=1 */ /* role: replica */ SELECT 1"));
+        assert_eq!(qr.shard(), 1);
+        assert_eq!(qr.role(), Some(Role::Replica));
+
+        // Not a key, for the query only.
+        qr.use_hints(&simple_query("/* sharding_key: abc */ SELECT 1"));
+        assert_eq!(qr.shard(), 0);
+    }
+
+    #[test]
+    fn test_read_only() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new(ConnectionPool::default());
+        qr.update_pool_settings(PoolSettings {
+            user: User {
+                read_only: true,
+                ..User::default()
+            },
+            ..PoolSettings::default()
+        });
+
+        assert_eq!(qr.role(), Some(Role::Replica));
+
+        qr.set_server_role("auto");
+        assert!(qr.infer_role(simple_query("DELETE FROM items WHERE id = 5")));
+        assert_eq!(qr.role(), Some(Role::Replica));
+
+        qr.set_server_role("primary");
+        qr.use_hints(&simple_query("/* pgcat: primary */ SELECT 1"));
+        assert_eq!(qr.role(), Some(Role::Replica));
+    }
+
+    #[test]
+    fn test_sharding_ranges() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new(ConnectionPool::default());
+        qr.update_pool_settings(PoolSettings {
+            shards: HashMap::from([
+                (String::from("0"), Shard::default()),
+                (String::from("1"), Shard::default()),
+            ]),
+            sharding_key_type: String::from("date"),
+            sharding_ranges: vec![
+                ShardingRange {
+                    shard: 0,
+                    from: None,
+                    to: Some(RangeBound::Text(String::from("2024-01-01"))),
+                },
+                ShardingRange {
+                    shard: 1,
+                    from: Some(RangeBound::Text(String::from("2024-01-01"))),
+                    to: Some(RangeBound::Text(String::from("2025-01-01"))),
+                },
+            ],
+            ..PoolSettings::default()
+        });
+
+        assert_eq!(
+            qr.try_execute_command(simple_query("SET SHARDING KEY TO '2024-06-30'")),
+            Some((Command::SetShardingKey, String::from("1")))
+        );
+        assert_eq!(qr.shard(), 1);
+
+        // Out of the ranges, the shard stays the same.
+        assert_eq!(
+            qr.try_execute_command(simple_query("SET SHARDING KEY TO '2025-06-30'")),
+            Some((Command::SetShardingKey, String::new()))
+        );
+        assert_eq!(qr.shard(), 1);
+
+        assert_eq!(
+            qr.try_execute_command(simple_query("SET SHARDING KEY TO '1999-12-31'")),
+            Some((Command::SetShardingKey, String::from("0")))
+        );
+        assert_eq!(qr.shard(), 0);
+
+        // Not a date, like a key out of the ranges.
+        assert_eq!(
+            qr.try_execute_command(simple_query("SET SHARDING KEY TO '20240630'")),
+            Some((Command::SetShardingKey, String::new()))
+        );
+        assert_eq!(qr.shard(), 0);
+
+        // A bigint by default, so not a date either.
+        qr.update_pool_settings(PoolSettings::default());
+        assert_eq!(
+            qr.try_execute_command(simple_query("SET SHARDING KEY TO '2024-06-30'")),
+            Some((Command::SetShardingKey, String::new()))
+        );
+
+        // Hashed as text.
+        qr.update_pool_settings(PoolSettings {
+            sharding_key_type: String::from("text"),
+            ..PoolSettings::default()
+        });
+        assert_eq!(
+            qr.try_execute_command(simple_query("SET SHARDING KEY TO '2024-06-30'")),
+            Some((Command::SetShardingKey, String::from("0")))
+        );
+    }
+
+    #[test]
+    fn test_update_pool_settings() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new(ConnectionPool::default());
+
+        qr.set_shard(1);
+        qr.update_pool_settings(PoolSettings {
+            shards: HashMap::from([
+                (String::from("0"), Shard::default()),
+                (String::from("1"), Shard::default()),
+            ]),
+            ..PoolSettings::default()
+        });
+        assert_eq!(qr.shard(), 1);
+
+        // The shard was removed from the config.
+        qr.update_pool_settings(PoolSettings::default());
+        assert_eq!(qr.shard(), 0);
+    }
+
+    #[test]
+    fn test_resharding() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new(ConnectionPool::default());
+
+        // The keys from 100 move from shard 0 to shard 1.
+        let pool_settings = |phase: &str| PoolSettings {
+            pool_name: String::from("test_resharding"),
+            shards: HashMap::from([
+                (String::from("0"), Shard::default()),
+                (String::from("1"), Shard::default()),
+            ]),
+            sharding_ranges: vec![
+                ShardingRange {
+                    shard: 0,
+                    from: None,
+                    to: Some(RangeBound::Integer(100)),
+                },
+                ShardingRange {
+                    shard: 1,
+                    from: Some(RangeBound::Integer(100)),
+                    to: None,
+                },
+            ],
+            resharding: Some(Resharding {
+                from_shards: None,
+                from_ranges: vec![ShardingRange {
+                    shard: 0,
+                    from: None,
+                    to: None,
+                }],
+                phase: phase.to_string(),
+            }),
+            ..PoolSettings::default()
+        };
+
+        for (phase, shard, mirror) in [
+            ("old", "0", None),
+            ("dual_write", "0", Some(1)),
+            ("cutover", "1", Some(0)),
+            ("new", "1", None),
+        ] {
+            qr.update_pool_settings(pool_settings(phase));
+
+            assert_eq!(
+                qr.try_execute_command(simple_query("SET SHARDING KEY TO '150'")),
+                Some((Command::SetShardingKey, String::from(shard)))
+            );
+            assert_eq!(qr.mirror_shard(), mirror);
+
+            // The key that doesn't move isn't mirrored.
+            assert_eq!(
+                qr.try_execute_command(simple_query("SET SHARDING KEY TO '50'")),
+                Some((Command::SetShardingKey, String::from("0")))
+            );
+            assert_eq!(qr.mirror_shard(), None);
+        }
+
+        qr.update_pool_settings(pool_settings("dual_write"));
+        qr.try_execute_command(simple_query("SET SHARDING KEY TO '150'"));
+        assert_eq!(qr.mirror_shard(), Some(1));
+
+        // The reads aren't mirrored.
+        qr.set_server_role("replica");
+        assert_eq!(qr.mirror_shard(), None);
+        qr.set_server_role("any");
+
+        qr.use_hints(&simple_query(
+            "/* sharding_key: 50 */ INSERT INTO t VALUES (1)",
+        ));
+        assert_eq!(qr.mirror_shard(), None);
+        qr.use_hints(&simple_query("INSERT INTO t VALUES (1)"));
+        assert_eq!(qr.mirror_shard(), Some(1));
+
+        qr.try_execute_command(simple_query("SET SHARD TO '1'"));
+        assert_eq!(qr.mirror_shard(), None);
+
+        // With the query parser, the reads aren't mirrored, but for the ones it can't parse.
+        qr.query_parser_enabled = true;
+        qr.try_execute_command(simple_query("SET SHARDING KEY TO '150'"));
+        assert!(qr.infer_role(simple_query("SELECT * FROM t")));
+        assert_eq!(qr.mirror_shard(), None);
+
+        assert!(!qr.infer_role(simple_query(
+            "INSERT INTO t VALUES (1) ON CONFLICT (id) DO UPDATE SET n = t.n + 1"
+        )));
+        assert_eq!(qr.mirror_shard(), Some(1));
+
+        qr.server_released();
+        assert!(qr.infer_role(simple_query("INSERT INTO t VALUES (1)")));
+        assert_eq!(qr.mirror_shard(), Some(1));
+
+        // The transaction that read first is mirrored from its first write on.
+        qr.server_released();
+        assert!(qr.infer_role(simple_query("SELECT * FROM t")));
+        let role = qr.role();
+        assert_eq!(qr.mirror_write(&simple_query("SELECT * FROM t")), None);
+        assert_eq!(
+            qr.mirror_write(&simple_query("UPDATE t SET n = n + 1")),
+            Some(1)
+        );
+        assert_eq!(qr.role(), role);
+    }
 }
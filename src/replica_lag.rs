@@ -0,0 +1,106 @@
+/// The replication lag of the replicas, measured every second for the pools with
+/// max_replica_lag, so the ones too far behind the primary stop getting reads
+/// until they catch up.
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet};
+
+use crate::config::Role;
+use crate::db_connections;
+use crate::drain;
+use crate::pool::get_all_pools;
+
+/// How far behind the primary the replica is, in milliseconds: since the last
+/// transaction it replayed, unless it replayed all the WAL it received.
+const QUERY: &str = "SELECT CASE \
+     WHEN NOT pg_is_in_recovery() OR pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() THEN 0 \
+     ELSE (EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp()) * 1000)::bigint \
+     END";
+
+/// The lag of each replica (host and port), in milliseconds.
+static LAGS: Lazy<Mutex<HashMap<String, u64>>> = Lazy::new(|| Mutex::new(HashMap::default()));
+
+/// The lag of the replica, if it's measured.
+pub fn get(server: &str) -> Option<u64> {
+    LAGS.lock().get(server).copied()
+}
+
+/// Measure the lag of the replicas of the pools with max_replica_lag, once for each server.
+/// The ones that can't be reached aren't measured, banning them is up to the health checks.
+pub async fn check() {
+    let mut checked = HashSet::new();
+    let mut lags = HashMap::new();
+
+    for pool in get_all_pools().values() {
+        let max_replica_lag = pool.settings.max_replica_lag;
+
+        if max_replica_lag == 0 {
+            continue;
+        }
+
+        for shard in 0..pool.shards() {
+            for server in 0..pool.servers(shard) {
+                let address = pool.address(shard, server);
+                let key = db_connections::server(&address.host, &address.port);
+
+                if address.role != Role::Replica
+                    || drain::is_drained(&key)
+                    || !checked.insert(key.clone())
+                {
+                    continue;
+                }
+
+                let lag = match pool.fetch(shard, server, QUERY).await {
+                    Ok(rows) => lag(&rows),
+                    Err(err) => {
+                        warn!("Could not check the lag of replica {}: {:?}", key, err);
+                        continue;
+                    }
+                };
+
+                let was_lagging = get(&key).is_some_and(|lag| lag > max_replica_lag);
+
+                match (was_lagging, lag > max_replica_lag) {
+                    (false, true) => warn!(
+                        "Replica {} is {}ms behind the primary, more than max_replica_lag ({}ms)",
+                        key, lag, max_replica_lag
+                    ),
+                    (true, false) => info!("Replica {} caught up with the primary", key),
+                    _ => (),
+                };
+
+                lags.insert(key, lag);
+            }
+        }
+    }
+
+    *LAGS.lock() = lags;
+}
+
+/// The lag the query returned, 0 if the replica didn't replay any transaction yet.
+fn lag(rows: &[Vec<Option<String>>]) -> u64 {
+    rows.first()
+        .and_then(|row| row.first())
+        .and_then(|lag| lag.as_ref())
+        .and_then(|lag| lag.parse::<i64>().ok())
+        .unwrap_or(0)
+        .max(0) as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lag() {
+        assert_eq!(lag(&[vec![Some(String::from("1500"))]]), 1500);
+        assert_eq!(lag(&[vec![Some(String::from("0"))]]), 0);
+
+        // Clocks a bit off.
+        assert_eq!(lag(&[vec![Some(String::from("-3"))]]), 0);
+
+        assert_eq!(lag(&[vec![None]]), 0);
+        assert_eq!(lag(&[]), 0);
+    }
+}
@@ -0,0 +1,285 @@
+/// Resharding, e.g. splitting the shards: the keys of a pool move from the shard map of
+/// its resharding to the pool's in phases, set in its config or with SET resharding_phase
+/// in the admin database. While they're copied, the writes of the keys that move go to
+/// both their old and new shard, so the reads can switch to the new one, and back.
+use bytes::Buf;
+use log::{error, info};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::config::{get_config, Resharding};
+
+/// Where the keys that move are read and written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    /// On their old shard, e.g. until the copy starts.
+    Old,
+
+    /// On their old shard, and their writes are mirrored to the new one.
+    DualWrite,
+
+    /// On their new shard, and their writes are mirrored to the old one, to go back to it.
+    Cutover,
+
+    /// On their new shard only.
+    New,
+}
+
+impl Phase {
+    pub fn parse(phase: &str) -> Option<Phase> {
+        match phase.to_ascii_lowercase().as_ref() {
+            "old" => Some(Phase::Old),
+            "dual_write" => Some(Phase::DualWrite),
+            "cutover" => Some(Phase::Cutover),
+            "new" => Some(Phase::New),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Old => write!(f, "old"),
+            Phase::DualWrite => write!(f, "dual_write"),
+            Phase::Cutover => write!(f, "cutover"),
+            Phase::New => write!(f, "new"),
+        }
+    }
+}
+
+/// The resharding of a pool.
+struct State {
+    /// The phase of the config when the phase was set: the config's wins once it changes.
+    config_phase: String,
+    phase: Phase,
+
+    /// The queries mirrored to the other shard of their key, and the ones it failed.
+    mirrored: u64,
+    mirror_errors: u64,
+
+    /// The mirrored queries that failed since the phase was set: the writes the other
+    /// shard missed, so the keys can't be read there.
+    missed: u64,
+}
+
+impl State {
+    fn new(config_phase: &str) -> State {
+        State {
+            config_phase: config_phase.to_string(),
+            phase: Phase::parse(config_phase).unwrap_or(Phase::Old),
+            mirrored: 0,
+            mirror_errors: 0,
+            missed: 0,
+        }
+    }
+
+    /// The writes the shard the keys would be read from in the phase missed, if any:
+    /// the new shard's in dual_write, and the old shard's in cutover.
+    fn missed(&self, phase: Phase) -> Option<u64> {
+        let reads_mirror = match self.phase {
+            Phase::DualWrite => matches!(phase, Phase::Cutover | Phase::New),
+            Phase::Cutover => matches!(phase, Phase::DualWrite | Phase::Old),
+            Phase::Old | Phase::New => false,
+        };
+
+        match reads_mirror && self.missed > 0 {
+            true => Some(self.missed),
+            false => None,
+        }
+    }
+
+    /// Move to the phase. Setting the same one again, e.g. after copying the keys
+    /// again, forgets the writes missed.
+    fn enter(&mut self, phase: Phase) {
+        self.phase = phase;
+        self.missed = 0;
+    }
+}
+
+/// Why the phase of a pool's resharding can't be changed.
+#[derive(Debug, PartialEq)]
+pub enum PhaseError {
+    /// The pool doesn't reshard.
+    NotResharding,
+
+    /// The shard the keys would be read from missed that many writes, which failed there.
+    Missed(u64),
+}
+
+/// The resharding of the pools, by name.
+static STATES: Lazy<Mutex<HashMap<String, State>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The phase of the pool's resharding: the one set in the admin database, unless
+/// the phase of its config changed since.
+pub fn phase(pool_name: &str, resharding: &Resharding) -> Phase {
+    let mut states = STATES.lock();
+    let state = states
+        .entry(pool_name.to_string())
+        .or_insert_with(|| State::new(&resharding.phase));
+
+    if state.config_phase != resharding.phase {
+        state.config_phase = resharding.phase.clone();
+        let phase = Phase::parse(&resharding.phase).unwrap_or(Phase::Old);
+
+        match state.missed(phase) {
+            Some(missed) => error!(
+                "Resharding pool {}: staying in phase {}, not {}: the shard of the keys in {} missed {} writes",
+                pool_name, state.phase, phase, phase, missed
+            ),
+            None => state.enter(phase),
+        }
+    }
+
+    state.phase
+}
+
+/// Move the keys of the pool to the phase, with `SET resharding_phase = 'cutover' FOR POOL
+/// mydb`, until it's restarted or the phase of its config changes. Not if the shard the keys
+/// would be read from missed writes mirrored there in this phase.
+pub fn set_phase(pool_name: &str, phase: Phase) -> Result<(), PhaseError> {
+    let config = get_config();
+
+    match config
+        .pools
+        .get(pool_name)
+        .and_then(|pool| pool.resharding.as_ref())
+    {
+        Some(resharding) => set(pool_name, &resharding.phase, phase),
+        None => Err(PhaseError::NotResharding),
+    }
+}
+
+fn set(pool_name: &str, config_phase: &str, phase: Phase) -> Result<(), PhaseError> {
+    let mut states = STATES.lock();
+    let state = states
+        .entry(pool_name.to_string())
+        .or_insert_with(|| State::new(config_phase));
+
+    if let Some(missed) = state.missed(phase) {
+        return Err(PhaseError::Missed(missed));
+    }
+
+    info!(
+        "Resharding pool {}: phase {} (was {})",
+        pool_name, phase, state.phase
+    );
+
+    state.config_phase = config_phase.to_string();
+    state.enter(phase);
+
+    Ok(())
+}
+
+/// A query of the pool was mirrored to the other shard of its key, and failed there or not.
+pub fn mirrored(pool_name: &str, failed: bool) {
+    if let Some(state) = STATES.lock().get_mut(pool_name) {
+        state.mirrored += 1;
+
+        if failed {
+            state.mirror_errors += 1;
+            state.missed += 1;
+        }
+    }
+}
+
+/// The queries of the pool mirrored so far, the ones that failed, and the ones that failed
+/// since the phase was set, for SHOW RESHARDING.
+pub fn mirror_stats(pool_name: &str) -> (u64, u64, u64) {
+    match STATES.lock().get(pool_name) {
+        Some(state) => (state.mirrored, state.mirror_errors, state.missed),
+        None => (0, 0, 0),
+    }
+}
+
+/// The messages of a server have an ErrorResponse.
+pub fn has_error(mut messages: &[u8]) -> bool {
+    while messages.len() >= 5 {
+        let code = messages[0] as char;
+        let len = (&messages[1..5]).get_i32() as usize;
+
+        if code == 'E' {
+            return true;
+        }
+
+        if len < 4 || messages.len() < len + 1 {
+            break;
+        }
+
+        messages.advance(len + 1);
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error_response::ErrorResponse;
+    use crate::messages::command_complete;
+
+    #[test]
+    fn test_phase() {
+        let mut resharding = Resharding {
+            from_shards: Some(2),
+            from_ranges: Vec::new(),
+            phase: String::from("dual_write"),
+        };
+
+        assert_eq!(phase("test_phase", &resharding), Phase::DualWrite);
+
+        // Set in the admin database, until the config changes.
+        assert_eq!(set("test_phase", "dual_write", Phase::Cutover), Ok(()));
+        assert_eq!(phase("test_phase", &resharding), Phase::Cutover);
+
+        resharding.phase = String::from("new");
+        assert_eq!(phase("test_phase", &resharding), Phase::New);
+
+        mirrored("test_phase", false);
+        mirrored("test_phase", true);
+        assert_eq!(mirror_stats("test_phase"), (2, 1, 1));
+
+        // The old shard is done with, it doesn't matter what it missed.
+        assert_eq!(set("test_phase", "new", Phase::DualWrite), Ok(()));
+        assert_eq!(mirror_stats("test_phase"), (2, 1, 0));
+
+        // The new shard missed a write, the keys can't be read there.
+        mirrored("test_phase", true);
+        assert_eq!(
+            set("test_phase", "new", Phase::Cutover),
+            Err(PhaseError::Missed(1))
+        );
+        resharding.phase = String::from("cutover");
+        assert_eq!(phase("test_phase", &resharding), Phase::DualWrite);
+
+        // Until it's set again, after copying the keys again.
+        assert_eq!(set("test_phase", "cutover", Phase::DualWrite), Ok(()));
+        assert_eq!(set("test_phase", "cutover", Phase::Cutover), Ok(()));
+
+        // Nor read from the old shard after it missed one.
+        mirrored("test_phase", true);
+        assert_eq!(
+            set("test_phase", "cutover", Phase::Old),
+            Err(PhaseError::Missed(1))
+        );
+        assert_eq!(set("test_phase", "cutover", Phase::New), Ok(()));
+
+        assert_eq!(Phase::parse("DUAL_WRITE"), Some(Phase::DualWrite));
+        assert_eq!(Phase::parse("both"), None);
+        assert_eq!(Phase::Cutover.to_string(), "cutover");
+    }
+
+    #[test]
+    fn test_has_error() {
+        let mut messages = command_complete("INSERT 0 1");
+        assert!(!has_error(&messages));
+
+        messages.extend(
+            ErrorResponse::error("23505", "duplicate key value violates unique constraint")
+                .to_bytes(),
+        );
+        assert!(has_error(&messages));
+    }
+}
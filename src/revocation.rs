@@ -0,0 +1,990 @@
+/// Check TLS certificates for revocation, with certificate revocation lists (CRLs)
+/// and OCSP responders. CRL files are reloaded when they change. OCSP is soft-fail,
+/// like in browsers: a certificate is refused if its responder says it's revoked,
+/// not when the responder can't be reached or doesn't know it.
+use arc_swap::ArcSwap;
+use chrono::naive::NaiveDateTime;
+use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use ring::signature;
+use sha1::{Digest, Sha1};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_rustls::rustls::Certificate;
+use x509_parser::der_parser::oid::Oid;
+use x509_parser::extensions::{GeneralName, ParsedExtension};
+use x509_parser::oid_registry::*;
+use x509_parser::pem::Pem;
+use x509_parser::prelude::{
+    CertificateRevocationList, FromDer, SubjectPublicKeyInfo, X509Certificate,
+};
+use x509_parser::time::ASN1Time;
+
+use crate::config::get_config;
+use crate::errors::Error;
+
+/// OCSP answers are reused for this long, so clients that reconnect
+/// often don't each wait for the responder. Answers without a next update
+/// are only good for this long after their responder made them.
+const OCSP_CACHE_SECONDS: u64 = 300;
+
+/// How far the responder's clock can be from ours.
+const OCSP_CLOCK_SKEW_SECONDS: u64 = 300;
+
+/// The OCSP nonce extension, 1.3.6.1.5.5.7.48.1.2.
+const OCSP_NONCE: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x02];
+
+/// DER tags.
+const SEQUENCE: u8 = 0x30;
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const NULL: u8 = 0x05;
+const GENERALIZED_TIME: u8 = 0x18;
+const REQUEST_EXTENSIONS: u8 = 0xa2;
+const RESPONSE_EXTENSIONS: u8 = 0xa1;
+const NEXT_UPDATE: u8 = 0xa0;
+
+/// OCSP certificate statuses.
+const OCSP_GOOD: u8 = 0x80;
+const OCSP_REVOKED: u8 = 0xa1;
+
+/// A CRL: who issued it, the serial numbers of the certificates it revokes,
+/// and its signature, checked with the issuer's certificate when it's used.
+#[derive(Clone)]
+struct Crl {
+    issuer: Vec<u8>,
+    revoked: HashSet<Vec<u8>>,
+    tbs: Vec<u8>,
+    algorithm: Oid<'static>,
+    signature: Vec<u8>,
+}
+
+/// The CRLs of a file.
+pub struct CrlFile {
+    crls: Vec<Crl>,
+
+    /// When the file was last modified when we loaded it.
+    modified: Option<SystemTime>,
+}
+
+/// CRL files, by path.
+static CRL_FILES: Lazy<ArcSwap<HashMap<String, Arc<CrlFile>>>> =
+    Lazy::new(|| ArcSwap::from_pointee(HashMap::default()));
+
+/// An OCSP answer: if the certificate is revoked, and until when we reuse it.
+type OcspAnswer = (bool, Instant);
+
+/// Recent OCSP answers, by certificate ID.
+static OCSP_ANSWERS: Lazy<Mutex<HashMap<Vec<u8>, OcspAnswer>>> =
+    Lazy::new(|| Mutex::new(HashMap::default()));
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Split the DER element at the start of the input into its tag,
+/// its contents, and the rest of the input.
+fn parse_der(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, input) = input.split_first()?;
+
+    let (len, input) = match first {
+        0..=0x7f => (first as usize, input),
+        0x81..=0x84 => {
+            let bytes = (first & 0x7f) as usize;
+
+            if input.len() < bytes {
+                return None;
+            }
+
+            let len = input[..bytes]
+                .iter()
+                .fold(0, |len, byte| len << 8 | *byte as usize);
+
+            (len, &input[bytes..])
+        }
+        _ => return None,
+    };
+
+    if input.len() < len {
+        return None;
+    }
+
+    Some((tag, &input[..len], &input[len..]))
+}
+
+/// Encode a DER element.
+fn encode_der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut der = vec![tag];
+    let len = contents.len();
+
+    if len < 0x80 {
+        der.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let bytes = &bytes[len.leading_zeros() as usize / 8..];
+
+        der.push(0x80 | bytes.len() as u8);
+        der.extend_from_slice(bytes);
+    }
+
+    der.extend_from_slice(contents);
+    der
+}
+
+/// A GeneralizedTime, e.g. 20261015094500Z, to the second.
+fn parse_time(contents: &[u8]) -> Option<SystemTime> {
+    let time = std::str::from_utf8(contents.get(..14)?).ok()?;
+    let time = NaiveDateTime::parse_from_str(time, "%Y%m%d%H%M%S").ok()?;
+
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(time.timestamp()).ok()?))
+}
+
+/// The signed part of a certificate or CRL, its first element.
+fn signed_part(der: &[u8]) -> Option<&[u8]> {
+    let (_, contents, _) = parse_der(der)?;
+    let (_, _, rest) = parse_der(contents)?;
+
+    Some(&contents[..contents.len() - rest.len()])
+}
+
+/// Check a signature made with the private key of this public key.
+fn verify_signature(
+    key: &SubjectPublicKeyInfo,
+    algorithm: &Oid,
+    message: &[u8],
+    signature: &[u8],
+) -> bool {
+    let curve = key
+        .algorithm
+        .parameters
+        .as_ref()
+        .and_then(|parameters| parameters.as_oid().ok());
+
+    let algorithm: &dyn signature::VerificationAlgorithm = if *algorithm == OID_PKCS1_SHA256WITHRSA
+    {
+        &signature::RSA_PKCS1_2048_8192_SHA256
+    } else if *algorithm == OID_PKCS1_SHA384WITHRSA {
+        &signature::RSA_PKCS1_2048_8192_SHA384
+    } else if *algorithm == OID_PKCS1_SHA512WITHRSA {
+        &signature::RSA_PKCS1_2048_8192_SHA512
+    } else if *algorithm == OID_SIG_ED25519 {
+        &signature::ED25519
+    } else if *algorithm == OID_SIG_ECDSA_WITH_SHA256 {
+        match curve {
+            Some(curve) if curve == OID_EC_P256 => &signature::ECDSA_P256_SHA256_ASN1,
+            Some(curve) if curve == OID_NIST_EC_P384 => &signature::ECDSA_P384_SHA256_ASN1,
+            _ => return false,
+        }
+    } else if *algorithm == OID_SIG_ECDSA_WITH_SHA384 {
+        match curve {
+            Some(curve) if curve == OID_EC_P256 => &signature::ECDSA_P256_SHA384_ASN1,
+            Some(curve) if curve == OID_NIST_EC_P384 => &signature::ECDSA_P384_SHA384_ASN1,
+            _ => return false,
+        }
+    } else {
+        return false;
+    };
+
+    signature::UnparsedPublicKey::new(algorithm, &key.subject_public_key.data)
+        .verify(message, signature)
+        .is_ok()
+}
+
+/// Parse a DER CRL.
+fn parse_crl(der: &[u8]) -> Option<Crl> {
+    let (_, crl) = CertificateRevocationList::from_der(der).ok()?;
+
+    if let Some(next_update) = crl.next_update() {
+        if next_update < ASN1Time::now() {
+            warn!("The CRL of {} expired on {}", crl.issuer(), next_update);
+        }
+    }
+
+    Some(Crl {
+        issuer: crl.issuer().as_raw().to_vec(),
+        revoked: crl
+            .iter_revoked_certificates()
+            .map(|revoked| revoked.raw_serial().to_vec())
+            .collect(),
+        tbs: signed_part(der)?.to_vec(),
+        algorithm: crl.signature_algorithm.algorithm.to_owned(),
+        signature: crl.signature_value.data.to_vec(),
+    })
+}
+
+/// Load the CRLs of a PEM file, which can have many, or of a DER file.
+pub fn load(path: &str) -> Result<CrlFile, Error> {
+    // Before reading it, so changes made while we read are picked up next time.
+    let modified = modified(path);
+
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(err) => {
+            error!("Could not read CRL file {}: {}", path, err);
+            return Err(Error::BadConfig);
+        }
+    };
+
+    let ders = if data.windows(11).any(|bytes| bytes == b"-----BEGIN ") {
+        Pem::iter_from_buffer(&data)
+            .filter_map(|pem| pem.ok())
+            // The label is cut at the first space, "X509 CRL" is read as "X509".
+            .filter(|pem| pem.label == "X509" || pem.label == "X509 CRL")
+            .map(|pem| pem.contents)
+            .collect()
+    } else {
+        vec![data]
+    };
+
+    match ders
+        .iter()
+        .map(|der| parse_crl(der))
+        .collect::<Option<Vec<Crl>>>()
+    {
+        Some(crls) if !crls.is_empty() => Ok(CrlFile { crls, modified }),
+        _ => {
+            error!("CRL file {} is not valid", path);
+            Err(Error::BadConfig)
+        }
+    }
+}
+
+/// The CRLs of the file, loaded the first time they're needed.
+fn crl_file(path: &str) -> Result<Arc<CrlFile>, Error> {
+    if let Some(crl_file) = CRL_FILES.load().get(path) {
+        return Ok(crl_file.clone());
+    }
+
+    let crl_file = Arc::new(load(path)?);
+
+    CRL_FILES.rcu(|crl_files| {
+        let mut crl_files = HashMap::clone(crl_files);
+        crl_files.insert(path.to_string(), crl_file.clone());
+        crl_files
+    });
+
+    Ok(crl_file)
+}
+
+/// Reload the CRL files that changed, and forget the ones no longer configured.
+/// If a file can't be loaded, e.g. because it's being replaced, the old CRLs
+/// are used until it can.
+pub fn reload() {
+    let config = get_config();
+    let current = CRL_FILES.load();
+
+    let mut crl_files = HashMap::new();
+    let mut changed = false;
+
+    for path in [
+        config.general.tls_crl_file,
+        config.general.server_tls_crl_file,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        if crl_files.contains_key(&path) {
+            continue;
+        }
+
+        let old = current.get(&path);
+
+        if let Some(crl_file) = old {
+            if crl_file.modified == modified(&path) {
+                crl_files.insert(path, crl_file.clone());
+                continue;
+            }
+        }
+
+        match load(&path) {
+            Ok(crl_file) => {
+                info!("Loaded CRL file {}", path);
+                crl_files.insert(path, Arc::new(crl_file));
+                changed = true;
+            }
+
+            // Keep the old one, and don't try again until the file changes.
+            Err(_) => {
+                if let Some(crl_file) = old {
+                    let crl_file = CrlFile {
+                        crls: crl_file.crls.clone(),
+                        modified: modified(&path),
+                    };
+
+                    crl_files.insert(path, Arc::new(crl_file));
+                    changed = true;
+                }
+            }
+        };
+    }
+
+    if changed || crl_files.len() != current.len() {
+        CRL_FILES.store(Arc::new(crl_files));
+    }
+}
+
+/// Each certificate of the chain and its issuer, the next certificate
+/// in the chain or one of our CAs. Self-signed CAs have no issuer.
+fn issuers(
+    chain: &[Certificate],
+    ca_certificates: &[Certificate],
+) -> Vec<(Certificate, Certificate)> {
+    let parse = |cert: &Certificate| {
+        X509Certificate::from_der(&cert.0).ok().map(|(_, parsed)| {
+            (
+                cert.clone(),
+                parsed.subject().as_raw().to_vec(),
+                parsed.issuer().as_raw().to_vec(),
+            )
+        })
+    };
+
+    let chain = chain.iter().filter_map(parse).collect::<Vec<_>>();
+    let ca_certificates = ca_certificates.iter().filter_map(parse).collect::<Vec<_>>();
+
+    chain
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, subject, issuer))| subject != issuer)
+        .filter_map(|(i, (cert, _, issuer))| {
+            chain[i + 1..]
+                .iter()
+                .chain(ca_certificates.iter())
+                .find(|(_, subject, _)| subject == issuer)
+                .map(|(issuer, _, _)| (cert.clone(), issuer.clone()))
+        })
+        .collect()
+}
+
+/// Check the certificate isn't revoked by a CRL of its issuer.
+/// CRLs that aren't signed by the issuer revoke everything it issued.
+fn crl_revoked(crl_file: &CrlFile, cert: &Certificate, issuer: &Certificate) -> bool {
+    let (cert, issuer) = match (
+        X509Certificate::from_der(&cert.0),
+        X509Certificate::from_der(&issuer.0),
+    ) {
+        (Ok((_, cert)), Ok((_, issuer))) => (cert, issuer),
+        _ => return true,
+    };
+
+    for crl in crl_file
+        .crls
+        .iter()
+        .filter(|crl| crl.issuer == cert.issuer().as_raw())
+    {
+        if !verify_signature(
+            issuer.public_key(),
+            &crl.algorithm,
+            &crl.tbs,
+            &crl.signature,
+        ) {
+            error!(
+                "The CRL of {} is not signed by its certificate",
+                cert.issuer()
+            );
+            return true;
+        }
+
+        if crl.revoked.contains(cert.raw_serial()) {
+            warn!(
+                "Certificate {} with serial {} is revoked by its CRL",
+                cert.subject(),
+                cert.raw_serial_as_string()
+            );
+            return true;
+        }
+    }
+
+    false
+}
+
+/// The URL of the certificate's OCSP responder, if it has one.
+fn ocsp_url(cert: &X509Certificate) -> Option<String> {
+    cert.extensions()
+        .iter()
+        .find_map(|extension| match extension.parsed_extension() {
+            ParsedExtension::AuthorityInfoAccess(access) => access
+                .accessdescs
+                .iter()
+                .filter(|description| description.access_method == OID_PKIX_ACCESS_DESCRIPTOR_OCSP)
+                .find_map(|description| match description.access_location {
+                    GeneralName::URI(uri) => Some(uri.to_string()),
+                    _ => None,
+                }),
+            _ => None,
+        })
+}
+
+/// The ID OCSP responders know the certificate by: the SHA-1 hashes
+/// of its issuer's name and public key, and its serial number.
+fn cert_id(cert: &X509Certificate, issuer: &X509Certificate) -> Vec<u8> {
+    let sha1 = encode_der(
+        SEQUENCE,
+        &[
+            encode_der(OBJECT_IDENTIFIER, OID_HASH_SHA1.as_bytes()),
+            encode_der(NULL, &[]),
+        ]
+        .concat(),
+    );
+
+    encode_der(
+        SEQUENCE,
+        &[
+            sha1,
+            encode_der(OCTET_STRING, &Sha1::digest(cert.issuer().as_raw())),
+            encode_der(
+                OCTET_STRING,
+                &Sha1::digest(&issuer.public_key().subject_public_key.data),
+            ),
+            encode_der(INTEGER, cert.raw_serial()),
+        ]
+        .concat(),
+    )
+}
+
+/// The nonce extension of an OCSP request, the responder sends it back
+/// so its answer can't be replayed.
+fn nonce_extension(nonce: &[u8]) -> Vec<u8> {
+    encode_der(
+        SEQUENCE,
+        &[
+            encode_der(OBJECT_IDENTIFIER, OCSP_NONCE),
+            encode_der(OCTET_STRING, &encode_der(OCTET_STRING, nonce)),
+        ]
+        .concat(),
+    )
+}
+
+/// An OCSP request for one certificate, with a nonce.
+fn ocsp_request(cert_id: &[u8], nonce: &[u8]) -> Vec<u8> {
+    // requestList, Request.
+    let requests = encode_der(SEQUENCE, &encode_der(SEQUENCE, cert_id));
+    let extensions = encode_der(
+        REQUEST_EXTENSIONS,
+        &encode_der(SEQUENCE, &nonce_extension(nonce)),
+    );
+
+    // OCSPRequest, TBSRequest.
+    encode_der(
+        SEQUENCE,
+        &encode_der(SEQUENCE, &[requests, extensions].concat()),
+    )
+}
+
+/// The nonce of the OCSP response's extensions, if it has one.
+fn response_nonce(mut extensions: &[u8]) -> Option<&[u8]> {
+    let (_, contents, _) = parse_der(extensions)?;
+    extensions = contents;
+
+    // Extension: extnID, critical, extnValue.
+    while let Some((_, extension, rest)) = parse_der(extensions) {
+        extensions = rest;
+
+        let (_, id, rest) = parse_der(extension)?;
+
+        if id == OCSP_NONCE {
+            let (_, mut value, rest) = parse_der(rest)?;
+
+            if !rest.is_empty() {
+                (_, value, _) = parse_der(rest)?;
+            }
+
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// Certificate IDs are the same if their hashes and serial numbers are,
+/// the hash algorithm can be encoded with or without parameters.
+fn same_cert_id(a: &[u8], b: &[u8]) -> bool {
+    let hashes = |id| parse_der(id).map(|(_, _, rest)| rest);
+
+    match (hashes(a), hashes(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Check the OCSP response is signed by a responder certificate
+/// the issuer delegated OCSP signing to.
+fn signed_by_responder(
+    responder: &[u8],
+    issuer: &X509Certificate,
+    algorithm: &Oid,
+    message: &[u8],
+    signature: &[u8],
+) -> bool {
+    let responder = match X509Certificate::from_der(responder) {
+        Ok((_, responder)) => responder,
+        Err(_) => return false,
+    };
+
+    let ocsp_signing = matches!(
+        responder.extended_key_usage(),
+        Ok(Some(usage)) if usage.value.ocsp_signing
+    );
+
+    ocsp_signing
+        && responder.issuer().as_raw() == issuer.subject().as_raw()
+        && verify_signature(
+            issuer.public_key(),
+            &responder.signature_algorithm.algorithm,
+            responder.tbs_certificate.as_ref(),
+            &responder.signature_value.data,
+        )
+        && verify_signature(responder.public_key(), algorithm, message, signature)
+}
+
+/// What the OCSP response says about the certificate: revoked or not, and until when
+/// that's true. Only if it's signed by the issuer or a responder it delegated to, it's
+/// still valid, and it answers our nonce. Responders that pre-sign their answers don't
+/// send one back, their answers are trusted until their next update.
+fn ocsp_answer(
+    response: &[u8],
+    cert_id: &[u8],
+    nonce: &[u8],
+    issuer: &X509Certificate,
+    now: SystemTime,
+) -> Option<(bool, SystemTime)> {
+    // OCSPResponse: responseStatus, responseBytes.
+    let (_, response, _) = parse_der(response)?;
+    let (_, status, rest) = parse_der(response)?;
+
+    if status != [0] {
+        debug!("OCSP response status: {:?}", status);
+        return None;
+    }
+
+    // ResponseBytes: responseType, response, a BasicOCSPResponse.
+    let (_, bytes, _) = parse_der(rest)?;
+    let (_, bytes, _) = parse_der(bytes)?;
+    let (_, _, rest) = parse_der(bytes)?;
+    let (_, basic, _) = parse_der(rest)?;
+
+    // BasicOCSPResponse: tbsResponseData, signatureAlgorithm, signature, certs.
+    let (_, basic, _) = parse_der(basic)?;
+    let (_, data, rest) = parse_der(basic)?;
+    let tbs = &basic[..basic.len() - rest.len()];
+    let (_, algorithm, rest) = parse_der(rest)?;
+    let (_, algorithm, _) = parse_der(algorithm)?;
+    let (_, signature, rest) = parse_der(rest)?;
+
+    // The bit string starts with the number of unused bits.
+    let signature = signature.get(1..)?;
+    let algorithm = Oid::new(Cow::Borrowed(algorithm));
+
+    let mut responders = Vec::new();
+
+    if let Some((_, certs, _)) = parse_der(rest) {
+        let (_, mut certs, _) = parse_der(certs)?;
+
+        while let Some((_, _, rest)) = parse_der(certs) {
+            responders.push(&certs[..certs.len() - rest.len()]);
+            certs = rest;
+        }
+    }
+
+    let signed = verify_signature(issuer.public_key(), &algorithm, tbs, signature)
+        || responders
+            .iter()
+            .any(|responder| signed_by_responder(responder, issuer, &algorithm, tbs, signature));
+
+    if !signed {
+        warn!("OCSP response is not signed by {}", issuer.subject());
+        return None;
+    }
+
+    // ResponseData: version, responderID, producedAt, responses, responseExtensions.
+    let mut data = data;
+
+    let (mut responses, extensions) = loop {
+        let (tag, contents, rest) = parse_der(data)?;
+
+        if tag == SEQUENCE {
+            break (contents, rest);
+        }
+
+        data = rest;
+    };
+
+    if let Some((RESPONSE_EXTENSIONS, extensions, _)) = parse_der(extensions) {
+        // Some responders send the nonce as is, not as an OCTET STRING.
+        if let Some(response_nonce) = response_nonce(extensions) {
+            if response_nonce != encode_der(OCTET_STRING, nonce) && response_nonce != nonce {
+                warn!("OCSP response is for another request");
+                return None;
+            }
+        }
+    }
+
+    let (_, cert_id, _) = parse_der(cert_id)?;
+
+    // SingleResponse: certID, certStatus, thisUpdate, nextUpdate.
+    while let Some((_, single, rest)) = parse_der(responses) {
+        responses = rest;
+
+        let (_, id, rest) = parse_der(single)?;
+
+        if !same_cert_id(id, cert_id) {
+            continue;
+        }
+
+        let (status, _, rest) = parse_der(rest)?;
+        let (tag, this_update, rest) = parse_der(rest)?;
+
+        if tag != GENERALIZED_TIME {
+            return None;
+        }
+
+        let this_update = parse_time(this_update)?;
+
+        let valid_until = match parse_der(rest) {
+            Some((NEXT_UPDATE, next_update, _)) => {
+                let (_, next_update, _) = parse_der(next_update)?;
+                parse_time(next_update)?
+            }
+            _ => this_update + Duration::from_secs(OCSP_CACHE_SECONDS),
+        };
+
+        let skew = Duration::from_secs(OCSP_CLOCK_SKEW_SECONDS);
+
+        if this_update > now + skew || valid_until + skew < now {
+            warn!("OCSP response for {} is not current", issuer.subject());
+            return None;
+        }
+
+        return match status {
+            OCSP_GOOD => Some((false, valid_until)),
+            OCSP_REVOKED => Some((true, valid_until)),
+            _ => None,
+        };
+    }
+
+    None
+}
+
+/// Ask the certificate's OCSP responder if it's revoked.
+async fn ocsp_revoked(cert: &Certificate, issuer: &Certificate) -> bool {
+    let (url, cert_id, subject) = match (
+        X509Certificate::from_der(&cert.0),
+        X509Certificate::from_der(&issuer.0),
+    ) {
+        (Ok((_, cert)), Ok((_, issuer))) => match ocsp_url(&cert) {
+            Some(url) => (url, cert_id(&cert, &issuer), cert.subject().to_string()),
+            None => {
+                debug!("Certificate {} has no OCSP responder", cert.subject());
+                return false;
+            }
+        },
+        _ => return true,
+    };
+
+    let cached = OCSP_ANSWERS.lock().get(&cert_id).copied();
+
+    if let Some((revoked, expires_at)) = cached {
+        if expires_at > Instant::now() {
+            return revoked;
+        }
+    }
+
+    let nonce: [u8; 16] = rand::random();
+
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_millis(get_config().general.connect_timeout))
+        .build()
+    {
+        Ok(client) => client,
+        Err(_) => return false,
+    };
+
+    let response = match client
+        .post(&url)
+        .header("Content-Type", "application/ocsp-request")
+        .body(ocsp_request(&cert_id, &nonce))
+        .send()
+        .await
+    {
+        Ok(response) => response.error_for_status(),
+        Err(err) => Err(err),
+    };
+
+    let response = match response {
+        Ok(response) => response.bytes().await,
+        Err(err) => Err(err),
+    };
+
+    let response = match response {
+        Ok(response) => response,
+        Err(err) => {
+            warn!(
+                "Could not check {} with OCSP responder {}: {}",
+                subject, url, err
+            );
+            return false;
+        }
+    };
+
+    let answer = match X509Certificate::from_der(&issuer.0) {
+        Ok((_, issuer)) => ocsp_answer(&response, &cert_id, &nonce, &issuer, SystemTime::now()),
+        Err(_) => None,
+    };
+
+    match answer {
+        Some((revoked, valid_until)) => {
+            if revoked {
+                warn!(
+                    "Certificate {} is revoked by OCSP responder {}",
+                    subject, url
+                );
+            }
+
+            // Until the answer's next update, if it's sooner.
+            let valid_for = valid_until
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+                .min(Duration::from_secs(OCSP_CACHE_SECONDS));
+
+            let mut answers = OCSP_ANSWERS.lock();
+            answers.retain(|_, (_, expires_at)| *expires_at > Instant::now());
+            answers.insert(cert_id, (revoked, Instant::now() + valid_for));
+
+            revoked
+        }
+
+        None => {
+            warn!(
+                "OCSP responder {} doesn't know if {} is revoked",
+                url, subject
+            );
+            false
+        }
+    }
+}
+
+/// Check the certificate chain a client or server sent, already verified, isn't revoked:
+/// every certificate with the CRLs of its issuer, and the peer's own certificate
+/// with its OCSP responder too.
+pub async fn revoked(
+    chain: &[Certificate],
+    ca_certificates: &[Certificate],
+    crl_file: &Option<String>,
+    ocsp: bool,
+) -> bool {
+    let issuers = issuers(chain, ca_certificates);
+
+    if let Some(path) = crl_file {
+        let crl_file = match self::crl_file(path) {
+            Ok(crl_file) => crl_file,
+            Err(_) => return true,
+        };
+
+        if issuers
+            .iter()
+            .any(|(cert, issuer)| crl_revoked(&crl_file, cert, issuer))
+        {
+            return true;
+        }
+    }
+
+    // Only the peer's own certificate, the first one.
+    if ocsp {
+        if let Some((cert, issuer)) = issuers.first() {
+            if Some(cert) == chain.first() {
+                return ocsp_revoked(cert, issuer).await;
+            }
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tls::load_certs;
+    use std::path::Path;
+
+    fn cert(name: &str) -> Certificate {
+        load_certs(Path::new(&format!("tests/tls/{}.cert", name)))
+            .unwrap()
+            .remove(0)
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let (ca, client, server) = (cert("ca"), cert("client"), cert("server"));
+        let (_, ca) = X509Certificate::from_der(&ca.0).unwrap();
+        let (_, server) = X509Certificate::from_der(&server.0).unwrap();
+        let (_, client) = X509Certificate::from_der(&client.0).unwrap();
+
+        let algorithm = &client.signature_algorithm.algorithm;
+        let tbs = client.tbs_certificate.as_ref();
+        let signature = &client.signature_value.data;
+
+        assert!(verify_signature(ca.public_key(), algorithm, tbs, signature));
+
+        // Not its issuer.
+        assert!(!verify_signature(
+            server.public_key(),
+            algorithm,
+            tbs,
+            signature
+        ));
+
+        let mut tampered = tbs.to_vec();
+        tampered[10] ^= 1;
+        assert!(!verify_signature(
+            ca.public_key(),
+            algorithm,
+            &tampered,
+            signature
+        ));
+
+        assert!(!verify_signature(
+            ca.public_key(),
+            &OID_HASH_SHA1,
+            tbs,
+            signature
+        ));
+    }
+
+    #[test]
+    fn test_crl_revoked() {
+        let (ca, client, server) = (cert("ca"), cert("client"), cert("server"));
+
+        let crl_file = load("tests/tls/crl.pem").unwrap();
+        assert!(crl_revoked(&crl_file, &client, &ca));
+        assert!(!crl_revoked(&crl_file, &server, &ca));
+
+        // Expired, still used.
+        let expired = load("tests/tls/crl_expired.pem").unwrap();
+        assert!(crl_revoked(&expired, &client, &ca));
+        assert!(!crl_revoked(&expired, &server, &ca));
+
+        // Not signed by the CA: everything it issued is revoked.
+        let mut crls = crl_file.crls.clone();
+        crls[0].signature[0] ^= 1;
+        let bad_signature = CrlFile {
+            crls,
+            modified: None,
+        };
+        assert!(crl_revoked(&bad_signature, &server, &ca));
+
+        // Another issuer's.
+        let other = load_certs(Path::new(".circleci/server.cert"))
+            .unwrap()
+            .remove(0);
+        assert!(!crl_revoked(&crl_file, &other, &other));
+
+        assert!(load("tests/tls/ca.key").is_err());
+    }
+
+    #[test]
+    fn test_ocsp_answer() {
+        let (ca, client, server) = (cert("ca"), cert("client"), cert("server"));
+        let (_, ca) = X509Certificate::from_der(&ca.0).unwrap();
+        let (_, client) = X509Certificate::from_der(&client.0).unwrap();
+        let (_, server) = X509Certificate::from_der(&server.0).unwrap();
+
+        let response = |name| std::fs::read(format!("tests/tls/{}.ocsp", name)).unwrap();
+        let (good, revoked) = (response("server"), response("client"));
+        let (server_id, client_id) = (cert_id(&server, &ca), cert_id(&client, &ca));
+
+        let nonce = b"pgcat test nonce";
+        let now = SystemTime::now();
+        let answer = |response: &[u8], cert_id: &[u8], nonce: &[u8], now| {
+            ocsp_answer(response, cert_id, nonce, &ca, now).map(|(revoked, _)| revoked)
+        };
+
+        assert_eq!(answer(&good, &server_id, nonce, now), Some(false));
+        assert_eq!(answer(&revoked, &client_id, nonce, now), Some(true));
+
+        // Valid until its next update.
+        let (_, valid_until) = ocsp_answer(&good, &server_id, nonce, &ca, now).unwrap();
+        assert!(valid_until > now + Duration::from_secs(86400 * 365));
+
+        // For another certificate or request.
+        assert_eq!(answer(&good, &client_id, nonce, now), None);
+        assert_eq!(answer(&good, &server_id, b"other test nonce", now), None);
+
+        // Expired, or not valid yet.
+        let years = |years: u64| UNIX_EPOCH + Duration::from_secs(86400 * 365 * years);
+        assert_eq!(answer(&good, &server_id, nonce, years(300)), None);
+        assert_eq!(answer(&good, &server_id, nonce, years(30)), None);
+
+        // Signed by the server's key, which can't answer for the CA.
+        let other = response("server_other");
+        assert_eq!(answer(&other, &server_id, nonce, now), None);
+
+        // The responder's name changed, the first one, before its certificate's.
+        let mut bad_signature = good.clone();
+        let name = bad_signature
+            .windows(13)
+            .position(|bytes| bytes == b"pgcat test CA")
+            .unwrap();
+        bad_signature[name] ^= 1;
+        assert_eq!(answer(&bad_signature, &server_id, nonce, now), None);
+
+        let request = ocsp_request(&server_id, nonce);
+        assert!(request.windows(nonce.len()).any(|bytes| bytes == nonce));
+    }
+
+    #[test]
+    fn test_der() {
+        let short = encode_der(OCTET_STRING, b"pgcat");
+        assert_eq!(short, b"\x04\x05pgcat");
+        assert_eq!(
+            parse_der(&short),
+            Some((OCTET_STRING, &b"pgcat"[..], &b""[..]))
+        );
+
+        let long = encode_der(SEQUENCE, &[0; 300]);
+        assert_eq!(&long[..4], &[SEQUENCE, 0x82, 0x01, 0x2c]);
+
+        let both = [long.clone(), short.clone()].concat();
+        let (tag, contents, rest) = parse_der(&both).unwrap();
+        assert_eq!((tag, contents.len(), rest), (SEQUENCE, 300, &short[..]));
+
+        // Truncated.
+        assert_eq!(parse_der(&long[..100]), None);
+        assert_eq!(parse_der(&[SEQUENCE]), None);
+    }
+
+    #[test]
+    fn test_same_cert_id() {
+        let hashes = [
+            encode_der(OCTET_STRING, &[1; 20]),
+            encode_der(OCTET_STRING, &[2; 20]),
+            encode_der(INTEGER, &[3]),
+        ]
+        .concat();
+        let sha1 = encode_der(OBJECT_IDENTIFIER, OID_HASH_SHA1.as_bytes());
+
+        // With and without NULL parameters.
+        let a = [
+            encode_der(SEQUENCE, &[sha1.clone(), encode_der(NULL, &[])].concat()),
+            hashes.clone(),
+        ]
+        .concat();
+        let b = [encode_der(SEQUENCE, &sha1), hashes].concat();
+        assert!(same_cert_id(&a, &b));
+
+        let c = [
+            encode_der(SEQUENCE, &sha1),
+            encode_der(OCTET_STRING, &[1; 20]),
+            encode_der(OCTET_STRING, &[2; 20]),
+            encode_der(INTEGER, &[4]),
+        ]
+        .concat();
+        assert!(!same_cert_id(&a, &c));
+    }
+}
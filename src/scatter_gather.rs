@@ -0,0 +1,654 @@
+/// Cross-shard queries: with the pools' scatter_gather, the SELECTs of the clients that
+/// didn't pick a shard go to all the shards at once, e.g. for reports, and the client gets
+/// their results merged, the rows of all the shards or the sums of their COUNTs and SUMs.
+use bytes::{Buf, BufMut, BytesMut};
+use log::{debug, error};
+use sqlparser::ast::{
+    Expr, FunctionArg, FunctionArgExpr, SelectItem, SetExpr, Statement, TableFactor,
+};
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::parser::Parser;
+
+use crate::config::Role;
+use crate::error_response::{
+    ErrorResponse, CONNECTION_FAILURE, FEATURE_NOT_SUPPORTED, INVALID_PARAMETER_VALUE,
+    TOO_MANY_CONNECTIONS,
+};
+use crate::errors::Error;
+use crate::parameters::ServerParameters;
+use crate::pool::ConnectionPool;
+
+/// The functions computed row by row, from their arguments only, so the same on any shard.
+/// The others, e.g. the aggregates, now(), random() or nextval(), aren't.
+const PER_ROW: [&str; 46] = [
+    "abs",
+    "btrim",
+    "ceil",
+    "ceiling",
+    "char_length",
+    "character_length",
+    "coalesce",
+    "concat",
+    "concat_ws",
+    "date_part",
+    "date_trunc",
+    "floor",
+    "greatest",
+    "initcap",
+    "jsonb_build_object",
+    "json_build_object",
+    "least",
+    "left",
+    "length",
+    "lower",
+    "lpad",
+    "ltrim",
+    "md5",
+    "mod",
+    "nullif",
+    "octet_length",
+    "position",
+    "power",
+    "regexp_replace",
+    "repeat",
+    "replace",
+    "reverse",
+    "right",
+    "round",
+    "rpad",
+    "rtrim",
+    "sign",
+    "split_part",
+    "sqrt",
+    "strpos",
+    "substr",
+    "substring",
+    "to_char",
+    "translate",
+    "trunc",
+    "upper",
+];
+
+/// How the results of the shards are merged.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Merge {
+    /// The rows of all the shards, one after the other.
+    Concat,
+
+    /// The row of each shard, its columns added up: COUNTs and SUMs only.
+    Sum,
+}
+
+/// How the results of the query are merged, if it can go to all the shards: one SELECT
+/// from tables, of columns and expressions of them, or of COUNTs and SUMs only. Without
+/// ORDER BY, LIMIT, DISTINCT, GROUP BY, subqueries or other aggregates, which the shards
+/// would apply to their own rows only.
+pub fn plan(query: &str) -> Option<Merge> {
+    let ast = Parser::parse_sql(&PostgreSqlDialect {}, query).ok()?;
+
+    let query = match ast.as_slice() {
+        [Statement::Query(query)] => query,
+        _ => return None,
+    };
+
+    if query.with.is_some()
+        || !query.order_by.is_empty()
+        || query.limit.is_some()
+        || query.offset.is_some()
+        || query.fetch.is_some()
+    {
+        return None;
+    }
+
+    let select = match query.body {
+        SetExpr::Select(ref select) => select,
+        _ => return None,
+    };
+
+    // Without tables, e.g. `SELECT now()`, the shard doesn't matter.
+    if select.distinct
+        || select.top.is_some()
+        || !select.group_by.is_empty()
+        || select.having.is_some()
+        || select.from.is_empty()
+    {
+        return None;
+    }
+
+    let tables = select.from.iter().all(|table| {
+        std::iter::once(&table.relation)
+            .chain(table.joins.iter().map(|join| &join.relation))
+            .all(|relation| matches!(relation, TableFactor::Table { .. }))
+    });
+
+    if !tables || !select.selection.as_ref().is_none_or(per_row) {
+        return None;
+    }
+
+    let columns = select
+        .projection
+        .iter()
+        .map(|item| match item {
+            SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => Some(expr),
+            SelectItem::QualifiedWildcard(_) | SelectItem::Wildcard => None,
+        })
+        .collect::<Vec<Option<&Expr>>>();
+
+    if columns.iter().all(|column| column.is_none_or(per_row)) {
+        return Some(Merge::Concat);
+    }
+
+    let sums = columns.iter().all(|column| match column {
+        Some(Expr::Function(function)) => {
+            let name = function.name.to_string().to_ascii_lowercase();
+
+            (name == "count" || name == "sum")
+                && !function.distinct
+                && function.over.is_none()
+                && function.args.iter().all(|arg| match arg {
+                    FunctionArg::Named { arg, .. } | FunctionArg::Unnamed(arg) => match arg {
+                        FunctionArgExpr::Expr(expr) => per_row(expr),
+                        _ => true,
+                    },
+                })
+        }
+        _ => false,
+    });
+
+    match sums {
+        true => Some(Merge::Sum),
+        false => None,
+    }
+}
+
+/// The expression is computed row by row, so the same on any shard: no aggregates,
+/// window functions, subqueries, or functions not known to be computed row by row.
+/// The ones we don't know aren't.
+fn per_row(expr: &Expr) -> bool {
+    match expr {
+        Expr::Identifier(_)
+        | Expr::CompoundIdentifier(_)
+        | Expr::Value(_)
+        | Expr::TypedString { .. } => true,
+
+        Expr::IsNull(expr)
+        | Expr::IsNotNull(expr)
+        | Expr::Nested(expr)
+        | Expr::UnaryOp { expr, .. }
+        | Expr::Cast { expr, .. }
+        | Expr::TryCast { expr, .. }
+        | Expr::Extract { expr, .. }
+        | Expr::Collate { expr, .. } => per_row(expr),
+
+        Expr::IsDistinctFrom(left, right)
+        | Expr::IsNotDistinctFrom(left, right)
+        | Expr::BinaryOp { left, right, .. } => per_row(left) && per_row(right),
+
+        Expr::Between {
+            expr, low, high, ..
+        } => per_row(expr) && per_row(low) && per_row(high),
+
+        Expr::InList { expr, list, .. } => per_row(expr) && list.iter().all(per_row),
+        Expr::Tuple(list) => list.iter().all(per_row),
+
+        Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            operand.as_deref().is_none_or(per_row)
+                && conditions.iter().all(per_row)
+                && results.iter().all(per_row)
+                && else_result.as_deref().is_none_or(per_row)
+        }
+
+        Expr::Function(function) => {
+            let name = function.name.to_string().to_ascii_lowercase();
+
+            PER_ROW.contains(&name.as_ref())
+                && function.over.is_none()
+                && function.args.iter().all(|arg| match arg {
+                    FunctionArg::Named { arg, .. } | FunctionArg::Unnamed(arg) => match arg {
+                        FunctionArgExpr::Expr(expr) => per_row(expr),
+                        _ => true,
+                    },
+                })
+        }
+
+        _ => false,
+    }
+}
+
+/// Send the query, a Query message, to all the shards of the pool at once, each to a server
+/// with the role, and merge their responses into the one the client gets. The servers go
+/// back to the pool when their shard answered.
+pub async fn execute(
+    pool: &ConnectionPool,
+    role: Option<Role>,
+    process_id: i32,
+    round_robin: usize,
+    query: BytesMut,
+    server_parameters: &ServerParameters,
+    merge: Merge,
+) -> Result<BytesMut, ErrorResponse> {
+    debug!("Sending the query to all {} shards", pool.shards());
+
+    let queries = (0..pool.shards())
+        .map(|shard| {
+            let pool = pool.clone();
+            let query = query.clone();
+            let server_parameters = server_parameters.clone();
+
+            tokio::spawn(async move {
+                let (mut server, address) = pool.get(shard, role, process_id, round_robin).await?;
+
+                server.set_parameters(&server_parameters).await?;
+                server.send(query).await?;
+
+                let mut response = BytesMut::new();
+
+                loop {
+                    response.put(server.recv().await?);
+
+                    if !server.is_data_available() {
+                        break;
+                    }
+                }
+
+                if pool.retires(address.shard) {
+                    server.retire();
+                } else {
+                    server
+                        .checkin_cleanup(
+                            &pool.settings.server_reset_query,
+                            pool.settings.server_reset_query_always,
+                        )
+                        .await?;
+                }
+
+                Ok::<BytesMut, Error>(response)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut responses = Vec::with_capacity(queries.len());
+
+    for (shard, query) in queries.into_iter().enumerate() {
+        let err = match query.await {
+            Ok(Ok(response)) => {
+                responses.push(response);
+                continue;
+            }
+            Ok(Err(err)) => err,
+            Err(_) => Error::SocketError,
+        };
+
+        error!("Shard {} could not run the query: {:?}", shard, err);
+
+        let error = match err {
+            Error::CheckoutTimeout | Error::QueueFull | Error::TooManyConnections => {
+                ErrorResponse::error(TOO_MANY_CONNECTIONS, "no server connection available")
+            }
+
+            // The values in the client's options are invalid.
+            Error::ServerError => {
+                ErrorResponse::error(INVALID_PARAMETER_VALUE, "invalid value in options")
+            }
+
+            _ => ErrorResponse::error(CONNECTION_FAILURE, "server connection failed"),
+        };
+
+        return Err(error.detail(&format!("On shard {}.", shard)));
+    }
+
+    self::merge(responses, merge)
+}
+
+/// Merge the responses of the shards, each up to its ReadyForQuery, into one. If a shard
+/// answered with an error, the client gets its response instead.
+pub fn merge(responses: Vec<BytesMut>, merge: Merge) -> Result<BytesMut, ErrorResponse> {
+    let mut row_description = None;
+    let mut notices = BytesMut::new();
+    let mut rows = Vec::new();
+
+    for response in responses {
+        let mut messages = response.clone();
+
+        while messages.len() >= 5 {
+            let len = i32::from_be_bytes([messages[1], messages[2], messages[3], messages[4]]);
+            let message = messages.split_to(len as usize + 1);
+
+            match message[0] as char {
+                // ErrorResponse
+                'E' => return Ok(response),
+
+                // RowDescription, the same on all the shards.
+                'T' => {
+                    row_description.get_or_insert(message);
+                }
+
+                // NoticeResponse
+                'N' => notices.put(message),
+
+                // DataRow, forwarded as it is: only the sums read its columns.
+                'D' => rows.push(message),
+
+                // CommandComplete and ReadyForQuery are the merged ones,
+                // ParameterStatus the server's.
+                _ => (),
+            };
+        }
+    }
+
+    if merge == Merge::Sum && !rows.is_empty() {
+        let rows_columns = rows
+            .iter()
+            .map(columns)
+            .collect::<Vec<Vec<Option<&[u8]>>>>();
+        let mut sums = Vec::with_capacity(rows_columns[0].len());
+
+        for column in 0..rows_columns[0].len() {
+            // The counts and sums are numbers, in ASCII.
+            let values = rows_columns
+                .iter()
+                .map(|row| match row[column] {
+                    Some(value) => std::str::from_utf8(value).ok().map(Some),
+                    None => Some(None),
+                })
+                .collect::<Option<Vec<Option<&str>>>>();
+
+            match values.and_then(|values| sum(values.into_iter())) {
+                Some(value) => sums.push(value),
+                None => {
+                    return Err(ErrorResponse::error(
+                        FEATURE_NOT_SUPPORTED,
+                        "could not add up the results of the shards",
+                    )
+                    .detail(&format!("Column {} is not a number.", column + 1)))
+                }
+            };
+        }
+
+        rows = vec![data_row(&sums)];
+    }
+
+    let mut merged = notices;
+
+    if let Some(row_description) = row_description {
+        merged.put(row_description);
+    }
+
+    for row in &rows {
+        merged.put_slice(row);
+    }
+
+    let command = format!("SELECT {}\0", rows.len());
+
+    merged.put_u8(b'C');
+    merged.put_i32(command.len() as i32 + 4);
+    merged.put_slice(command.as_bytes());
+
+    // Ready for query, not in a transaction.
+    merged.put_u8(b'Z');
+    merged.put_i32(5);
+    merged.put_u8(b'I');
+
+    Ok(merged)
+}
+
+/// The columns of a DataRow message, None for the NULLs.
+fn columns(row: &BytesMut) -> Vec<Option<&[u8]>> {
+    let mut message = &row[5..];
+    let count = message.get_i16();
+    let mut columns = Vec::with_capacity(count as usize);
+
+    for _ in 0..count {
+        let column_len = message.get_i32();
+
+        // -1 indicates a NULL column value.
+        if column_len < 0 {
+            columns.push(None);
+        } else {
+            let (column, rest) = message.split_at(column_len as usize);
+            columns.push(Some(column));
+            message = rest;
+        }
+    }
+
+    columns
+}
+
+/// A DataRow message with the columns, None for the NULLs.
+fn data_row(columns: &[Option<String>]) -> BytesMut {
+    let mut data_row = BytesMut::new();
+
+    data_row.put_i16(columns.len() as i16);
+
+    for column in columns {
+        match column {
+            Some(column) => {
+                data_row.put_i32(column.len() as i32);
+                data_row.put_slice(column.as_bytes());
+            }
+            None => data_row.put_i32(-1),
+        };
+    }
+
+    let mut message = BytesMut::with_capacity(data_row.len() + 5);
+
+    message.put_u8(b'D');
+    message.put_i32(data_row.len() as i32 + 4);
+    message.put(data_row);
+
+    message
+}
+
+/// The sum of the values, the NULLs aside like SUM does: NULL if they all are. Exact for
+/// integers and decimals, e.g. the numeric of SUM(price). None if one isn't a number.
+fn sum<'a>(values: impl Iterator<Item = Option<&'a str>>) -> Option<Option<String>> {
+    let values = values.flatten().collect::<Vec<&str>>();
+
+    if values.is_empty() {
+        return Some(None);
+    }
+
+    // The digits, without the point, and how many are after it.
+    let decimals = values
+        .iter()
+        .map(|value| {
+            let (integer, fraction) = value.split_once('.').unwrap_or((value, ""));
+            let digits = format!("{}{}", integer, fraction);
+
+            match fraction.chars().all(|c| c.is_ascii_digit()) {
+                true => digits
+                    .parse::<i128>()
+                    .ok()
+                    .map(|digits| (digits, fraction.len())),
+                false => None,
+            }
+        })
+        .collect::<Option<Vec<(i128, usize)>>>();
+
+    let decimals = match decimals {
+        Some(decimals) => decimals,
+
+        // Floats, e.g. 1.5e+20 or NaN.
+        None => {
+            return values
+                .iter()
+                .map(|value| value.parse::<f64>().ok())
+                .sum::<Option<f64>>()
+                .map(|sum| Some(sum.to_string()))
+        }
+    };
+
+    let scale = decimals.iter().map(|(_, scale)| *scale).max().unwrap_or(0);
+
+    let mut sum: i128 = 0;
+
+    for (digits, digits_scale) in decimals {
+        let digits = 10i128
+            .checked_pow((scale - digits_scale) as u32)
+            .and_then(|factor| digits.checked_mul(factor))?;
+        sum = sum.checked_add(digits)?;
+    }
+
+    if scale == 0 {
+        return Some(Some(sum.to_string()));
+    }
+
+    let digits = format!("{:0>width$}", sum.unsigned_abs(), width = scale + 1);
+    let (integer, fraction) = digits.split_at(digits.len() - scale);
+
+    Some(Some(format!(
+        "{}{}.{}",
+        if sum < 0 { "-" } else { "" },
+        integer,
+        fraction
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        assert_eq!(plan("SELECT * FROM users"), Some(Merge::Concat));
+        assert_eq!(
+            plan("SELECT id, lower(name) AS name FROM users u JOIN orders o ON o.user_id = u.id WHERE o.total > 10"),
+            Some(Merge::Concat)
+        );
+        assert_eq!(
+            plan("SELECT count(*), SUM(total) AS total FROM orders WHERE total > 10"),
+            Some(Merge::Sum)
+        );
+
+        for query in [
+            "SELECT now()",
+            "SELECT id, now() FROM users",
+            "SELECT id, nextval('ids') FROM users",
+            "SELECT * FROM users WHERE random() < 0.1",
+            "SELECT id, my_function(id) FROM users",
+            "SELECT * FROM users ORDER BY id",
+            "SELECT * FROM users LIMIT 10",
+            "SELECT DISTINCT name FROM users",
+            "SELECT name, count(*) FROM users GROUP BY name",
+            "SELECT count(DISTINCT name) FROM users",
+            "SELECT count(*), max(id) FROM users",
+            "SELECT count(*) + 1 FROM users",
+            "SELECT id, row_number() OVER () FROM users",
+            "SELECT * FROM (SELECT count(*) FROM users) counts",
+            "SELECT * FROM users WHERE id IN (SELECT user_id FROM orders)",
+            "WITH u AS (SELECT * FROM users) SELECT * FROM u",
+            "SELECT * FROM users UNION SELECT * FROM admins",
+            "SELECT * FROM users; SELECT * FROM orders",
+            "UPDATE users SET name = 'a'",
+        ] {
+            assert_eq!(plan(query), None, "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_sum() {
+        let sum = |values: &[Option<&str>]| sum(values.iter().copied());
+
+        assert_eq!(
+            sum(&[Some("1"), Some("41")]),
+            Some(Some(String::from("42")))
+        );
+        assert_eq!(sum(&[Some("1"), None]), Some(Some(String::from("1"))));
+        assert_eq!(sum(&[None, None]), Some(None));
+        assert_eq!(
+            sum(&[Some("10.5"), Some("0.25"), Some("-11")]),
+            Some(Some(String::from("-0.25")))
+        );
+        assert_eq!(
+            sum(&[Some("99999999999999999999.99"), Some("0.01")]),
+            Some(Some(String::from("100000000000000000000.00")))
+        );
+        assert_eq!(
+            sum(&[Some("1.5e+20"), Some("0.5e+20")]),
+            Some(Some(String::from("200000000000000000000")))
+        );
+        assert_eq!(sum(&[Some("1 day"), Some("1")]), None);
+    }
+
+    #[test]
+    fn test_merge() {
+        let response = |messages: &[BytesMut]| {
+            let mut response = BytesMut::new();
+            for message in messages {
+                response.put(message.clone());
+            }
+            response
+        };
+
+        let row_description =
+            crate::messages::row_description(&vec![("count", crate::messages::DataType::Numeric)]);
+        let row = |value: &str| crate::messages::data_row(&vec![value.to_string()]);
+        let end = |rows: usize| {
+            let mut end = crate::messages::command_complete(&format!("SELECT {}", rows));
+            end.put_u8(b'Z');
+            end.put_i32(5);
+            end.put_u8(b'I');
+            end
+        };
+
+        let shards = vec![
+            response(&[row_description.clone(), row("1"), row("2"), end(2)]),
+            response(&[row_description.clone(), end(0)]),
+            response(&[row_description.clone(), row("3"), end(1)]),
+        ];
+
+        assert_eq!(
+            merge(shards.clone(), Merge::Concat).unwrap(),
+            response(&[
+                row_description.clone(),
+                row("1"),
+                row("2"),
+                row("3"),
+                end(3)
+            ])
+        );
+        assert_eq!(
+            merge(shards, Merge::Sum).unwrap(),
+            response(&[row_description.clone(), row("6"), end(1)])
+        );
+
+        // The rows are forwarded as they are, e.g. in another encoding than UTF-8.
+        let mut latin1 = BytesMut::new();
+        latin1.put_u8(b'D');
+        latin1.put_i32(12);
+        latin1.put_i16(1);
+        latin1.put_i32(2);
+        latin1.put_slice(&[0xe9, 0xe8]);
+        assert_eq!(
+            merge(
+                vec![response(&[row_description.clone(), latin1.clone(), end(1)])],
+                Merge::Concat
+            )
+            .unwrap(),
+            response(&[row_description.clone(), latin1.clone(), end(1)])
+        );
+        assert!(merge(
+            vec![response(&[row_description.clone(), latin1, end(1)])],
+            Merge::Sum
+        )
+        .is_err());
+
+        let error = response(&[
+            ErrorResponse::error(FEATURE_NOT_SUPPORTED, "no").to_bytes(),
+            end(0).split_off(14),
+        ]);
+        assert_eq!(
+            merge(
+                vec![response(&[row_description.clone(), end(0)]), error.clone()],
+                Merge::Concat
+            )
+            .unwrap(),
+            error
+        );
+    }
+}
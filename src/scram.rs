@@ -5,7 +5,6 @@
 use bytes::BytesMut;
 use hmac::{Hmac, Mac};
 use rand::{self, Rng};
-use sha2::digest::FixedOutput;
 use sha2::{Digest, Sha256};
 
 use std::fmt::Write;
@@ -27,11 +26,23 @@ fn normalize(pass: &[u8]) -> Vec<u8> {
     }
 }
 
+/// Parse the list of SASL mechanisms sent by the server
+/// in AuthenticationSASL. The list is made of null-terminated
+/// strings and ends with an empty string.
+pub fn sasl_mechanisms(message: &[u8]) -> Vec<String> {
+    message
+        .split(|c| *c == 0)
+        .take_while(|mechanism| !mechanism.is_empty())
+        .map(|mechanism| String::from_utf8_lossy(mechanism).to_string())
+        .collect()
+}
+
 /// Keep the SASL state through the exchange.
 /// It takes 3 messages to complete the authentication.
 pub struct ScramSha256 {
     password: String,
-    salted_password: [u8; 32],
+    keys: Option<(Vec<u8>, Vec<u8>)>,
+    server_key: Vec<u8>,
     auth_message: String,
     message: BytesMut,
     nonce: String,
@@ -41,18 +52,7 @@ impl ScramSha256 {
     /// Create the Scram state from a password. It'll automatically
     /// generate a nonce.
     pub fn new(password: &str) -> ScramSha256 {
-        let mut rng = rand::thread_rng();
-        let nonce = (0..NONCE_LENGTH)
-            .map(|_| {
-                let mut v = rng.gen_range(0x21u8..0x7e);
-                if v == 0x2c {
-                    v = 0x7e
-                }
-                v as char
-            })
-            .collect::<String>();
-
-        Self::from_nonce(password, &nonce)
+        Self::from_nonce(password, &generate_nonce())
     }
 
     /// Used for testing.
@@ -63,11 +63,21 @@ impl ScramSha256 {
             password: password.to_string(),
             nonce: String::from(nonce),
             message,
-            salted_password: [0u8; 32],
+            keys: None,
+            server_key: Vec::new(),
             auth_message: String::new(),
         }
     }
 
+    /// Create the Scram state from the client and server keys
+    /// instead of the plain text password. Used when the password
+    /// is only known to us as a SCRAM verifier.
+    pub fn from_keys(client_key: &[u8], server_key: &[u8]) -> ScramSha256 {
+        let mut scram = Self::new("");
+        scram.keys = Some((client_key.to_vec(), server_key.to_vec()));
+        scram
+    }
+
     /// Get the current state of the SASL authentication.
     pub fn message(&mut self) -> BytesMut {
         self.message.clone()
@@ -86,28 +96,26 @@ impl ScramSha256 {
             Err(_) => return Err(Error::ProtocolSyncError),
         };
 
-        let salted_password = Self::hi(
-            &normalize(&self.password.as_bytes()[..]),
-            &salt,
-            server_message.iterations,
-        );
-
-        // Save for verification of final server message.
-        self.salted_password = salted_password;
-
-        let mut hmac = match Hmac::<Sha256>::new_from_slice(&salted_password) {
-            Ok(hmac) => hmac,
-            Err(_) => return Err(Error::ServerError),
+        let (client_key, server_key) = match &self.keys {
+            Some(keys) => keys.clone(),
+            None => {
+                let salted_password = Self::hi(
+                    &normalize(&self.password.as_bytes()[..]),
+                    &salt,
+                    server_message.iterations,
+                );
+
+                (
+                    hmac_sha256(&salted_password, b"Client Key"),
+                    hmac_sha256(&salted_password, b"Server Key"),
+                )
+            }
         };
 
-        hmac.update(b"Client Key");
-
-        let client_key = hmac.finalize().into_bytes();
-
-        let mut hash = Sha256::default();
-        hash.update(client_key.as_slice());
+        // Save for verification of final server message.
+        self.server_key = server_key;
 
-        let stored_key = hash.finalize_fixed();
+        let stored_key = Sha256::digest(&client_key);
         let mut cbind_input = vec![];
         cbind_input.extend("n,,".as_bytes());
 
@@ -149,7 +157,7 @@ impl ScramSha256 {
             *proof ^= signature;
         }
 
-        match write!(&mut self.message, ",p={}", base64::encode(&*client_proof)) {
+        match write!(&mut self.message, ",p={}", base64::encode(&client_proof)) {
             Ok(_) => (),
             Err(_) => return Err(Error::ServerError),
         };
@@ -166,14 +174,7 @@ impl ScramSha256 {
             Err(_) => return Err(Error::ProtocolSyncError),
         };
 
-        let mut hmac = match Hmac::<Sha256>::new_from_slice(&self.salted_password) {
-            Ok(hmac) => hmac,
-            Err(_) => return Err(Error::ServerError),
-        };
-        hmac.update(b"Server Key");
-        let server_key = hmac.finalize().into_bytes();
-
-        let mut hmac = match Hmac::<Sha256>::new_from_slice(&server_key) {
+        let mut hmac = match Hmac::<Sha256>::new_from_slice(&self.server_key) {
             Ok(hmac) => hmac,
             Err(_) => return Err(Error::ServerError),
         };
@@ -209,6 +210,316 @@ impl ScramSha256 {
     }
 }
 
+/// Server side of the SCRAM exchange, used to authenticate clients.
+/// Same 3 messages as the client side, but we're the one verifying the proof.
+pub struct ScramSha256Server {
+    salt: Vec<u8>,
+    client_key: Option<Vec<u8>>,
+    iterations: u32,
+    stored_key: Vec<u8>,
+    server_key: Vec<u8>,
+    secondary_keys: Option<(Vec<u8>, Vec<u8>)>,
+    channel_binding: Option<Vec<u8>>,
+    channel_bound: bool,
+    nonce: String,
+    gs2_header: String,
+    client_first_bare: String,
+    server_first: String,
+}
+
+impl ScramSha256Server {
+    /// Create the server state from the plain text password. It'll automatically
+    /// generate a salt and a nonce.
+    pub fn new(password: &str) -> ScramSha256Server {
+        let salt: [u8; 16] = rand::random();
+
+        Self::from_salt(password, &salt, SCRAM_ITERATIONS, &generate_nonce())
+    }
+
+    /// Used for testing.
+    pub fn from_salt(
+        password: &str,
+        salt: &[u8],
+        iterations: u32,
+        nonce: &str,
+    ) -> ScramSha256Server {
+        let (stored_key, server_key) = Self::keys(password, salt, iterations);
+
+        Self::from_keys(salt, iterations, &stored_key, &server_key, nonce)
+    }
+
+    /// Also accept a second password, e.g. the new one while passwords are rotated.
+    /// It's salted the same way as the first one.
+    pub fn add_secondary_password(&mut self, password: &str) {
+        self.secondary_keys = Some(Self::keys(password, &self.salt, self.iterations));
+    }
+
+    /// Offer SCRAM-SHA-256-PLUS, binding the exchange to the TLS connection
+    /// with the tls-server-end-point data (RFC 5929).
+    pub fn set_channel_binding(&mut self, data: Vec<u8>) {
+        self.channel_binding = Some(data);
+    }
+
+    /// Did the client bind the exchange to the TLS connection.
+    pub fn channel_bound(&self) -> bool {
+        self.channel_bound
+    }
+
+    /// The stored key and the server key of a plain text password.
+    fn keys(password: &str, salt: &[u8], iterations: u32) -> (Vec<u8>, Vec<u8>) {
+        let salted_password = ScramSha256::hi(&normalize(password.as_bytes()), salt, iterations);
+
+        let client_key = hmac_sha256(&salted_password, b"Client Key");
+        let stored_key = Sha256::digest(&client_key).to_vec();
+        let server_key = hmac_sha256(&salted_password, b"Server Key");
+
+        (stored_key, server_key)
+    }
+
+    /// Create the server state from a SCRAM verifier, as stored
+    /// by Postgres in pg_authid, e.g.
+    /// `SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>`.
+    pub fn from_verifier(verifier: &str) -> Result<ScramSha256Server, Error> {
+        let verifier = ScramVerifier::parse(verifier)?;
+
+        Ok(Self::from_keys(
+            &verifier.salt,
+            verifier.iterations,
+            &verifier.stored_key,
+            &verifier.server_key,
+            &generate_nonce(),
+        ))
+    }
+
+    fn from_keys(
+        salt: &[u8],
+        iterations: u32,
+        stored_key: &[u8],
+        server_key: &[u8],
+        nonce: &str,
+    ) -> ScramSha256Server {
+        ScramSha256Server {
+            salt: salt.to_vec(),
+            client_key: None,
+            iterations,
+            stored_key: stored_key.to_vec(),
+            server_key: server_key.to_vec(),
+            secondary_keys: None,
+            channel_binding: None,
+            channel_bound: false,
+            nonce: String::from(nonce),
+            gs2_header: String::new(),
+            client_first_bare: String::new(),
+            server_first: String::new(),
+        }
+    }
+
+    /// Parse the client first message and generate the server challenge.
+    pub fn server_first(&mut self, client_first: &[u8]) -> Result<BytesMut, Error> {
+        let client_first = String::from_utf8_lossy(client_first).to_string();
+        let parts = client_first.splitn(3, ',').collect::<Vec<&str>>();
+
+        if parts.len() != 3 {
+            return Err(Error::ProtocolSyncError);
+        }
+
+        // Channel binding flag. "y" means the client supports channel binding
+        // but thinks we don't; if we offered it, someone removed it along the way.
+        self.channel_bound = match (parts[0], &self.channel_binding) {
+            ("n", _) | ("y", None) => false,
+            ("p=tls-server-end-point", Some(_)) => true,
+            _ => return Err(Error::ProtocolSyncError),
+        };
+
+        let client_nonce = match parts[2].split(',').find(|part| part.starts_with("r=")) {
+            Some(nonce) => &nonce[2..],
+            None => return Err(Error::ProtocolSyncError),
+        };
+
+        self.gs2_header = format!("{},{},", parts[0], parts[1]);
+        self.client_first_bare = parts[2].to_string();
+        self.nonce = format!("{}{}", client_nonce, self.nonce);
+        self.server_first = format!(
+            "r={},s={},i={}",
+            self.nonce,
+            base64::encode(&self.salt),
+            self.iterations
+        );
+
+        Ok(BytesMut::from(self.server_first.as_bytes()))
+    }
+
+    /// Verify the client proof and generate the server final message.
+    pub fn server_final(&mut self, client_final: &[u8]) -> Result<BytesMut, Error> {
+        let client_final = String::from_utf8_lossy(client_final).to_string();
+
+        let (without_proof, proof) = match client_final.rsplit_once(",p=") {
+            Some(parts) => parts,
+            None => return Err(Error::ProtocolSyncError),
+        };
+
+        let mut channel_binding = None;
+        let mut nonce = None;
+
+        for part in without_proof.split(',') {
+            if let Some(value) = part.strip_prefix("c=") {
+                channel_binding = Some(value);
+            } else if let Some(value) = part.strip_prefix("r=") {
+                nonce = Some(value);
+            }
+        }
+
+        // The GS2 header and, with channel binding, the data the client
+        // sees on its side of the TLS connection.
+        let mut expected = self.gs2_header.as_bytes().to_vec();
+
+        if self.channel_bound {
+            expected.extend(self.channel_binding.clone().unwrap_or_default());
+        }
+
+        match channel_binding {
+            Some(value) if value == base64::encode(&expected) => (),
+            _ => return Err(Error::ProtocolSyncError),
+        };
+
+        if nonce != Some(self.nonce.as_str()) {
+            return Err(Error::ProtocolSyncError);
+        }
+
+        let proof = match base64::decode(proof) {
+            Ok(proof) => proof,
+            Err(_) => return Err(Error::ProtocolSyncError),
+        };
+
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, self.server_first, without_proof
+        );
+
+        let mut keys = vec![(self.stored_key.clone(), self.server_key.clone())];
+        keys.extend(self.secondary_keys.clone());
+
+        // Recover the client key from the proof and check it against our stored key.
+        let (client_key, server_key) =
+            match keys.into_iter().find_map(|(stored_key, server_key)| {
+                let client_signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+
+                if proof.len() != client_signature.len() {
+                    return None;
+                }
+
+                let client_key = proof
+                    .iter()
+                    .zip(client_signature)
+                    .map(|(proof, signature)| proof ^ signature)
+                    .collect::<Vec<u8>>();
+
+                match Sha256::digest(&client_key).as_slice() == stored_key.as_slice() {
+                    true => Some((client_key, server_key)),
+                    false => None,
+                }
+            }) {
+                Some(keys) => keys,
+                None => return Err(Error::ClientError),
+            };
+
+        let server_signature = hmac_sha256(&server_key, auth_message.as_bytes());
+
+        // The server key of the password the client used.
+        self.server_key = server_key;
+
+        // The client proved it knows the password, so we can use its key
+        // to authenticate with the server on its behalf.
+        self.client_key = Some(client_key);
+
+        Ok(BytesMut::from(
+            format!("v={}", base64::encode(server_signature)).as_bytes(),
+        ))
+    }
+
+    /// The client key recovered from the client proof, available
+    /// once the exchange succeeded.
+    pub fn client_key(&self) -> Option<Vec<u8>> {
+        self.client_key.clone()
+    }
+
+    /// The server key, from the password or the verifier.
+    pub fn server_key(&self) -> Vec<u8> {
+        self.server_key.clone()
+    }
+}
+
+/// A SCRAM verifier, the way Postgres stores passwords hashed with SCRAM-SHA-256.
+struct ScramVerifier {
+    iterations: u32,
+    salt: Vec<u8>,
+    stored_key: Vec<u8>,
+    server_key: Vec<u8>,
+}
+
+impl ScramVerifier {
+    /// Parse `SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>`.
+    fn parse(verifier: &str) -> Result<ScramVerifier, Error> {
+        let parts = verifier.split('$').collect::<Vec<&str>>();
+
+        if parts.len() != 3 || parts[0] != SCRAM_SHA_256 {
+            return Err(Error::BadConfig);
+        }
+
+        let (iterations, salt) = match parts[1].split_once(':') {
+            Some(parts) => parts,
+            None => return Err(Error::BadConfig),
+        };
+
+        let (stored_key, server_key) = match parts[2].split_once(':') {
+            Some(parts) => parts,
+            None => return Err(Error::BadConfig),
+        };
+
+        match (
+            iterations.parse::<u32>(),
+            base64::decode(salt),
+            base64::decode(stored_key),
+            base64::decode(server_key),
+        ) {
+            (Ok(iterations), Ok(salt), Ok(stored_key), Ok(server_key)) => Ok(ScramVerifier {
+                iterations,
+                salt,
+                stored_key,
+                server_key,
+            }),
+            _ => Err(Error::BadConfig),
+        }
+    }
+}
+
+/// Check if the password is a SCRAM verifier instead of plain text.
+pub fn is_scram_verifier(password: &str) -> bool {
+    ScramVerifier::parse(password).is_ok()
+}
+
+/// Generate a random printable nonce, without commas.
+fn generate_nonce() -> String {
+    let mut rng = rand::thread_rng();
+    (0..NONCE_LENGTH)
+        .map(|_| {
+            let mut v = rng.gen_range(0x21u8..0x7e);
+            if v == 0x2c {
+                v = 0x7e
+            }
+            v as char
+        })
+        .collect::<String>()
+}
+
+/// HMAC-SHA-256 of the message with the key.
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut hmac =
+        Hmac::<Sha256>::new_from_slice(key).expect("HMAC is able to accept all key sizes");
+    hmac.update(message);
+    hmac.finalize().into_bytes().to_vec()
+}
+
 /// Parse the server challenge.
 struct Message {
     nonce: String,
@@ -228,16 +539,19 @@ impl Message {
             return Err(Error::ProtocolSyncError);
         }
 
-        let nonce = str::replace(&parts[0], "r=", "");
-        let salt = str::replace(&parts[1], "s=", "");
-        let iterations = match str::replace(&parts[2], "i=", "").parse::<u32>() {
-            Ok(iterations) => iterations,
-            Err(_) => return Err(Error::ProtocolSyncError),
+        // The nonce and the salt can contain "r=" and "s=", only strip the prefix.
+        let (nonce, salt, iterations) = match (
+            parts[0].strip_prefix("r="),
+            parts[1].strip_prefix("s="),
+            parts[2].strip_prefix("i=").map(|i| i.parse::<u32>()),
+        ) {
+            (Some(nonce), Some(salt), Some(Ok(iterations))) => (nonce, salt, iterations),
+            _ => return Err(Error::ProtocolSyncError),
         };
 
         Ok(Message {
-            nonce,
-            salt,
+            nonce: nonce.to_string(),
+            salt: salt.to_string(),
             iterations,
         })
     }
@@ -288,6 +602,16 @@ mod test {
         );
     }
 
+    #[test]
+    fn parse_sasl_mechanisms() {
+        assert_eq!(sasl_mechanisms(b"SCRAM-SHA-256\0\0"), vec!["SCRAM-SHA-256"]);
+        assert_eq!(
+            sasl_mechanisms(b"SCRAM-SHA-256-PLUS\0SCRAM-SHA-256\0\0"),
+            vec!["SCRAM-SHA-256-PLUS", "SCRAM-SHA-256"]
+        );
+        assert!(sasl_mechanisms(b"\0").is_empty());
+    }
+
     // recorded auth exchange from psql
     #[test]
     fn exchange() {
@@ -317,4 +641,162 @@ mod test {
             .finish(&BytesMut::from(&server_final.as_bytes()[..]))
             .unwrap();
     }
+
+    // same exchange, but we're the server this time
+    #[test]
+    fn server_exchange() {
+        let password = "foobar";
+        let salt = base64::decode("fs3IXBy7U7+IvVjZ").unwrap();
+        let nonce = "jx/oIRLs02gGSHcw1KEty3eY";
+
+        let client_first = "n,,n=,r=9IZ2O01zb9IgiIZ1WJ/zgpJB";
+        let server_first =
+            "r=9IZ2O01zb9IgiIZ1WJ/zgpJBjx/oIRLs02gGSHcw1KEty3eY,s=fs3IXBy7U7+IvVjZ,i\
+             =4096";
+        let client_final =
+            "c=biws,r=9IZ2O01zb9IgiIZ1WJ/zgpJBjx/oIRLs02gGSHcw1KEty3eY,p=AmNKosjJzS3\
+             1NTlQYNs5BTeQjdHdk7lOflDo5re2an8=";
+        let server_final = "v=U+ppxD5XUKtradnv8e2MkeupiA8FU87Sg8CXzXHDAzw=";
+
+        let mut scram = ScramSha256Server::from_salt(password, &salt, 4096, nonce);
+
+        let result = scram.server_first(client_first.as_bytes()).unwrap();
+        assert_eq!(std::str::from_utf8(&result).unwrap(), server_first);
+
+        let result = scram.server_final(client_final.as_bytes()).unwrap();
+        assert_eq!(std::str::from_utf8(&result).unwrap(), server_final);
+
+        // Wrong password.
+        let mut scram = ScramSha256Server::from_salt("hunter2", &salt, 4096, nonce);
+        scram.server_first(client_first.as_bytes()).unwrap();
+        assert_eq!(
+            scram.server_final(client_final.as_bytes()),
+            Err(Error::ClientError)
+        );
+
+        // Right password, as the secondary one.
+        let mut scram = ScramSha256Server::from_salt("hunter2", &salt, 4096, nonce);
+        scram.add_secondary_password(password);
+        scram.server_first(client_first.as_bytes()).unwrap();
+        let result = scram.server_final(client_final.as_bytes()).unwrap();
+        assert_eq!(std::str::from_utf8(&result).unwrap(), server_final);
+    }
+
+    // the client binds the exchange to the TLS connection
+    #[test]
+    fn server_exchange_channel_binding() {
+        let salt = base64::decode("fs3IXBy7U7+IvVjZ").unwrap();
+        let client_first_bare = "n=,r=clientnonce";
+        let gs2_header = "p=tls-server-end-point,,";
+
+        // What the client would send, with the channel binding data it sees.
+        let client_final = |server_first: &str, data: &[u8]| {
+            let without_proof = format!(
+                "c={},r=clientnonceservernonce",
+                base64::encode([gs2_header.as_bytes(), data].concat())
+            );
+            let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+
+            let salted_password = ScramSha256::hi(b"hunter2", &salt, 4096);
+            let client_key = hmac_sha256(&salted_password, b"Client Key");
+            let signature = hmac_sha256(&Sha256::digest(&client_key), auth_message.as_bytes());
+            let proof = client_key
+                .iter()
+                .zip(signature)
+                .map(|(key, signature)| key ^ signature)
+                .collect::<Vec<u8>>();
+
+            format!("{},p={}", without_proof, base64::encode(proof))
+        };
+
+        let client_first = format!("{}{}", gs2_header, client_first_bare);
+
+        let mut scram = ScramSha256Server::from_salt("hunter2", &salt, 4096, "servernonce");
+        scram.set_channel_binding(b"certificate hash".to_vec());
+        let server_first = scram.server_first(client_first.as_bytes()).unwrap();
+        let server_first = std::str::from_utf8(&server_first).unwrap().to_string();
+        assert!(scram.channel_bound());
+        assert!(scram
+            .server_final(client_final(&server_first, b"certificate hash").as_bytes())
+            .is_ok());
+
+        // The client sees another certificate, e.g. because of a man in the middle.
+        let mut scram = ScramSha256Server::from_salt("hunter2", &salt, 4096, "servernonce");
+        scram.set_channel_binding(b"certificate hash".to_vec());
+        scram.server_first(client_first.as_bytes()).unwrap();
+        assert_eq!(
+            scram.server_final(client_final(&server_first, b"other hash").as_bytes()),
+            Err(Error::ProtocolSyncError)
+        );
+
+        // Channel binding without TLS.
+        let mut scram = ScramSha256Server::from_salt("hunter2", &salt, 4096, "servernonce");
+        assert!(scram.server_first(client_first.as_bytes()).is_err());
+
+        // The client thinks we don't support channel binding, but we do.
+        let mut scram = ScramSha256Server::from_salt("hunter2", &salt, 4096, "servernonce");
+        scram.set_channel_binding(b"certificate hash".to_vec());
+        assert!(scram.server_first(b"y,,n=,r=clientnonce").is_err());
+    }
+
+    // the client and the server agree with each other
+    #[test]
+    fn parse_server_first_message_nonce_prefix() {
+        let message =
+            Message::parse(&BytesMut::from("r=abcr=def,s=c2FsdA==,i=4096".as_bytes())).unwrap();
+
+        assert_eq!(message.nonce, "abcr=def");
+        assert_eq!(message.salt, "c2FsdA==");
+        assert_eq!(message.iterations, 4096);
+
+        assert!(Message::parse(&BytesMut::from("abc,s=c2FsdA==,i=4096".as_bytes())).is_err());
+    }
+
+    #[test]
+    fn client_server_exchange() {
+        let mut client = ScramSha256::new("hunter2");
+        let mut server = ScramSha256Server::new("hunter2");
+
+        let server_first = server.server_first(&client.message()).unwrap();
+        let client_final = client.update(&server_first).unwrap();
+        let server_final = server.server_final(&client_final).unwrap();
+
+        client.finish(&server_final).unwrap();
+    }
+
+    // the client key captured from one exchange can be reused
+    // to authenticate with a server that only has the verifier
+    #[test]
+    fn verifier_passthrough() {
+        let salt = base64::decode("fs3IXBy7U7+IvVjZ").unwrap();
+        let salted_password = ScramSha256::hi(b"hunter2", &salt, 4096);
+        let client_key = hmac_sha256(&salted_password, b"Client Key");
+        let verifier = format!(
+            "SCRAM-SHA-256$4096:fs3IXBy7U7+IvVjZ${}:{}",
+            base64::encode(Sha256::digest(&client_key)),
+            base64::encode(hmac_sha256(&salted_password, b"Server Key")),
+        );
+
+        assert!(is_scram_verifier(&verifier));
+        assert!(!is_scram_verifier("hunter2"));
+        assert!(!is_scram_verifier("SCRAM-SHA-256$4096:fs3IXBy7U7+IvVjZ"));
+
+        let mut client = ScramSha256::new("hunter2");
+        let mut server = ScramSha256Server::from_verifier(&verifier).unwrap();
+        assert_eq!(server.client_key(), None);
+
+        let server_first = server.server_first(&client.message()).unwrap();
+        let client_final = client.update(&server_first).unwrap();
+        let server_final = server.server_final(&client_final).unwrap();
+        client.finish(&server_final).unwrap();
+
+        let client_key = server.client_key().unwrap();
+        let mut client = ScramSha256::from_keys(&client_key, &server.server_key());
+        let mut server = ScramSha256Server::from_verifier(&verifier).unwrap();
+
+        let server_first = server.server_first(&client.message()).unwrap();
+        let client_final = client.update(&server_first).unwrap();
+        let server_final = server.server_final(&client_final).unwrap();
+        client.finish(&server_final).unwrap();
+    }
 }
@@ -0,0 +1,391 @@
+/// Encrypted secrets in the config, e.g. `password = "enc:..."`, so configs can be
+/// checked into git. Values are encrypted with AES-256-GCM, with a key from
+/// `PGCAT_SECRET_KEY`, or a data key encrypted with AWS KMS in `PGCAT_SECRET_KEY_KMS`.
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::errors::Error;
+use crate::rds_iam::{self, Credentials};
+
+/// Encrypted values start with this.
+pub const PREFIX: &str = "enc:";
+
+/// Base64 of the 32 byte key.
+const KEY_ENV: &str = "PGCAT_SECRET_KEY";
+
+/// Base64 of the key encrypted with AWS KMS, e.g. the `CiphertextBlob`
+/// of `aws kms generate-data-key --key-spec AES_256`.
+const KMS_KEY_ENV: &str = "PGCAT_SECRET_KEY_KMS";
+
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(PREFIX)
+}
+
+fn aead_key(key: &[u8]) -> Result<LessSafeKey, Error> {
+    match UnboundKey::new(&AES_256_GCM, key) {
+        Ok(key) => Ok(LessSafeKey::new(key)),
+        Err(_) => {
+            error!("The secret key must be 32 bytes");
+            Err(Error::BadConfig)
+        }
+    }
+}
+
+/// Encrypt the value, with a random nonce in front of the ciphertext.
+pub fn encrypt(value: &str, key: &[u8]) -> Result<String, Error> {
+    let nonce = rand::random::<[u8; NONCE_LEN]>();
+    let mut ciphertext = value.as_bytes().to_vec();
+
+    if aead_key(key)?
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::empty(),
+            &mut ciphertext,
+        )
+        .is_err()
+    {
+        return Err(Error::BadConfig);
+    }
+
+    Ok(format!(
+        "{}{}",
+        PREFIX,
+        base64::encode([nonce.to_vec(), ciphertext].concat())
+    ))
+}
+
+/// Decrypt a value created by `encrypt`.
+pub fn decrypt(value: &str, key: &[u8]) -> Result<String, Error> {
+    let key = aead_key(key)?;
+
+    let mut data = match base64::decode(value.trim_start_matches(PREFIX)) {
+        Ok(data) if data.len() > NONCE_LEN => data,
+        _ => return Err(Error::BadConfig),
+    };
+
+    let mut ciphertext = data.split_off(NONCE_LEN);
+    let nonce = match Nonce::try_assume_unique_for_key(&data) {
+        Ok(nonce) => nonce,
+        Err(_) => return Err(Error::BadConfig),
+    };
+
+    match key.open_in_place(nonce, Aad::empty(), &mut ciphertext) {
+        Ok(plaintext) => match String::from_utf8(plaintext.to_vec()) {
+            Ok(plaintext) => Ok(plaintext),
+            Err(_) => Err(Error::BadConfig),
+        },
+        Err(_) => Err(Error::BadConfig),
+    }
+}
+
+/// Get the key from the environment, decrypting it with KMS if needed.
+pub async fn key() -> Result<Vec<u8>, Error> {
+    if let Ok(key) = std::env::var(KEY_ENV) {
+        return match base64::decode(key.trim()) {
+            Ok(key) => Ok(key),
+            Err(err) => {
+                error!("{} is not valid base64: {}", KEY_ENV, err);
+                Err(Error::BadConfig)
+            }
+        };
+    }
+
+    if let Ok(ciphertext) = std::env::var(KMS_KEY_ENV) {
+        return kms_decrypt(ciphertext.trim()).await;
+    }
+
+    error!(
+        "The config has encrypted values, but neither {} nor {} is set",
+        KEY_ENV, KMS_KEY_ENV
+    );
+    Err(Error::BadConfig)
+}
+
+#[derive(Deserialize)]
+struct KmsResponse {
+    #[serde(rename = "Plaintext")]
+    plaintext: String,
+}
+
+/// Headers of a signed KMS Decrypt request with the body.
+fn kms_decrypt_headers(
+    host: &str,
+    region: &str,
+    body: &str,
+    credentials: &Credentials,
+    now: DateTime<Utc>,
+) -> Vec<(String, String)> {
+    let date = now.format("%Y%m%d").to_string();
+    let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let scope = format!("{}/{}/kms/aws4_request", date, region);
+
+    let mut headers = vec![
+        (
+            "content-type".to_string(),
+            "application/x-amz-json-1.1".to_string(),
+        ),
+        ("host".to_string(), host.to_string()),
+        ("x-amz-date".to_string(), timestamp.clone()),
+        (
+            "x-amz-target".to_string(),
+            "TrentService.Decrypt".to_string(),
+        ),
+    ];
+
+    if let Some(ref session_token) = credentials.session_token {
+        headers.push(("x-amz-security-token".to_string(), session_token.clone()));
+    }
+
+    headers.sort();
+
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<&str>>()
+        .join(";");
+
+    let canonical_request = format!(
+        "POST\n/\n\n{}\n{}\n{:x}",
+        headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect::<String>(),
+        signed_headers,
+        Sha256::digest(body.as_bytes())
+    );
+
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+        timestamp,
+        scope,
+        Sha256::digest(canonical_request.as_bytes())
+    );
+
+    let signature = rds_iam::sign(credentials, &date, region, "kms", &string_to_sign);
+
+    headers.push((
+        "authorization".to_string(),
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key_id, scope, signed_headers, signature
+        ),
+    ));
+
+    // reqwest sets the host itself.
+    headers.retain(|(name, _)| name != "host");
+
+    headers
+}
+
+/// Decrypt the key with AWS KMS, using the credentials and region from the environment.
+async fn kms_decrypt(ciphertext: &str) -> Result<Vec<u8>, Error> {
+    let credentials = Credentials::from_env();
+    let region = std::env::var("AWS_REGION").or_else(|_| std::env::var("AWS_DEFAULT_REGION"));
+
+    let (credentials, region) = match (credentials, region) {
+        (Some(credentials), Ok(region)) => (credentials, region),
+        _ => {
+            error!("AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_REGION must be set to decrypt {} with KMS", KMS_KEY_ENV);
+            return Err(Error::BadConfig);
+        }
+    };
+
+    let endpoint = std::env::var("AWS_ENDPOINT_URL_KMS")
+        .unwrap_or_else(|_| format!("https://kms.{}.amazonaws.com", region));
+
+    let host = match reqwest::Url::parse(&endpoint) {
+        Ok(url) => match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            _ => return Err(Error::BadConfig),
+        },
+        Err(err) => {
+            error!("Invalid KMS endpoint {}: {}", endpoint, err);
+            return Err(Error::BadConfig);
+        }
+    };
+
+    let body = serde_json::json!({ "CiphertextBlob": ciphertext }).to_string();
+
+    let mut request = reqwest::Client::new().post(&endpoint).body(body.clone());
+
+    for (name, value) in kms_decrypt_headers(&host, &region, &body, &credentials, Utc::now()) {
+        request = request.header(name, value);
+    }
+
+    let response = match request.send().await {
+        Ok(response) => response.error_for_status(),
+        Err(err) => Err(err),
+    };
+
+    let response = match response {
+        Ok(response) => response.json::<KmsResponse>().await,
+        Err(err) => Err(err),
+    };
+
+    match response {
+        Ok(response) => {
+            info!("Decrypted the secret key with KMS");
+
+            match base64::decode(response.plaintext) {
+                Ok(key) => Ok(key),
+                Err(_) => Err(Error::BadConfig),
+            }
+        }
+
+        Err(err) => {
+            error!("KMS decrypt failed: {}", err);
+            Err(Error::BadConfig)
+        }
+    }
+}
+
+/// The config values that can be encrypted.
+fn secrets(config: &mut Config) -> Vec<&mut String> {
+    let mut secrets = vec![&mut config.general.admin_password];
+
+    for pool in config.pools.values_mut() {
+        secrets.extend(pool.auth_query_password.as_mut());
+
+        if let Some(ref mut ldap) = pool.ldap {
+            secrets.extend(ldap.bind_password.as_mut());
+        }
+
+        if let Some(ref mut vault) = pool.vault {
+            secrets.extend(vault.token.as_mut());
+            secrets.extend(vault.secret_id.as_mut());
+        }
+
+        for user in pool.users.values_mut() {
+            secrets.push(&mut user.password);
+            secrets.extend(user.secondary_password.as_mut());
+            secrets.extend(user.server_password.as_mut());
+        }
+    }
+
+    secrets
+}
+
+/// Replace the encrypted values in the config with the plaintext.
+pub async fn decrypt_config(config: &mut Config) -> Result<(), Error> {
+    if !secrets(config).iter().any(|secret| is_encrypted(secret)) {
+        return Ok(());
+    }
+
+    decrypt_secrets(config, &key().await?)
+}
+
+fn decrypt_secrets(config: &mut Config, key: &[u8]) -> Result<(), Error> {
+    for secret in secrets(config) {
+        if !is_encrypted(secret) {
+            continue;
+        }
+
+        match decrypt(secret, key) {
+            Ok(plaintext) => *secret = plaintext,
+            Err(err) => {
+                error!("Could not decrypt a secret in the config, is the key right?");
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let key = [7u8; 32];
+        let encrypted = encrypt("hunter2", &key).unwrap();
+
+        assert!(is_encrypted(&encrypted));
+        assert_ne!(encrypted, encrypt("hunter2", &key).unwrap());
+        assert_eq!(decrypt(&encrypted, &key).unwrap(), "hunter2");
+        assert!(decrypt(&encrypted, &[8u8; 32]).is_err());
+        assert!(decrypt("enc:aGVsbG8=", &key).is_err());
+        assert!(encrypt("hunter2", &[7u8; 16]).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_config() {
+        let key = [7u8; 32];
+        let mut config = Config::default();
+        config.general.admin_password = encrypt("admin password", &key).unwrap();
+        config.general.admin_username = encrypt("not a secret", &key).unwrap();
+
+        let mut pool = crate::config::Pool::default();
+        pool.users.insert(
+            "0".to_string(),
+            crate::config::User {
+                password: "plaintext".to_string(),
+                server_password: Some(encrypt("server password", &key).unwrap()),
+                ..Default::default()
+            },
+        );
+        config.pools.insert("db".to_string(), pool);
+
+        assert!(decrypt_secrets(&mut config.clone(), &[8u8; 32]).is_err());
+
+        decrypt_secrets(&mut config, &key).unwrap();
+
+        assert_eq!(config.general.admin_password, "admin password");
+        assert!(is_encrypted(&config.general.admin_username));
+        assert_eq!(config.pools["db"].users["0"].password, "plaintext");
+        assert_eq!(
+            config.pools["db"].users["0"].server_password.as_deref(),
+            Some("server password")
+        );
+    }
+
+    // Signed by botocore's SigV4Auth with the same request.
+    #[test]
+    fn test_kms_decrypt_headers() {
+        let now = Utc.ymd(2022, 6, 1).and_hms(12, 30, 45);
+        let credentials = Credentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        };
+
+        let headers = kms_decrypt_headers(
+            "kms.us-east-1.amazonaws.com",
+            "us-east-1",
+            r#"{"CiphertextBlob":"AQIDBA=="}"#,
+            &credentials,
+            now,
+        );
+
+        assert_eq!(
+            headers.last().unwrap().1,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20220601/us-east-1/kms/aws4_request, \
+             SignedHeaders=content-type;host;x-amz-date;x-amz-target, \
+             Signature=d1e8fcfd4921c05d50e7b6d83d562fb2dd38bf903619c530371e88100d74f857"
+        );
+
+        let credentials = Credentials {
+            session_token: Some("session/token+=".to_string()),
+            ..credentials
+        };
+
+        let headers = kms_decrypt_headers(
+            "kms.us-east-1.amazonaws.com",
+            "us-east-1",
+            r#"{"CiphertextBlob":"AQIDBA=="}"#,
+            &credentials,
+            now,
+        );
+
+        assert!(headers.last().unwrap().1.ends_with(
+            "Signature=9b301ecac3ae73becbfebdb08f2ac3888088a7209b703fac76f3b3f3a04c6a57"
+        ));
+    }
+}
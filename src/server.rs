@@ -1,21 +1,237 @@
 /// Implementation of the PostgreSQL server (database) protocol.
 /// Here we are pretending to the a Postgres client.
 use bytes::{Buf, BufMut, BytesMut};
-use log::{debug, error, info, trace};
-use tokio::io::{AsyncReadExt, BufReader};
-use tokio::net::{
-    tcp::{OwnedReadHalf, OwnedWriteHalf},
-    TcpStream,
+use log::{debug, error, info, trace, warn};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{
+    split, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, BufReader, ReadBuf, ReadHalf,
+    WriteHalf,
 };
-
-use crate::config::{Address, User};
+use tokio::net::TcpStream;
+use tokio::sync::{watch, OwnedSemaphorePermit};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::{Certificate, ServerName};
+use tokio_rustls::TlsConnector;
+
+use crate::auth_passthrough::get_scram_keys;
+use crate::config::{get_config, Address, ServerTlsSettings, User};
 use crate::constants::*;
+use crate::copy_throttle::CopyThrottle;
+use crate::db_connections::DbConnection;
+use crate::error_response::{self, INVALID_PASSWORD};
 use crate::errors::Error;
 use crate::messages::*;
-use crate::scram::ScramSha256;
+use crate::parameters::ServerParameters;
+use crate::pool::InFlight;
+use crate::revocation;
+use crate::scram::{sasl_mechanisms, ScramSha256};
 use crate::stats::Reporter;
+use crate::tls::server_tls_config;
 use crate::ClientServerMap;
 
+/// What to do with the server's answer to an extended protocol message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    /// Forward it to the client.
+    Forward,
+
+    /// The message wasn't sent to the server, give the client this answer instead,
+    /// e.g. ParseComplete for a statement the server already has.
+    Answer(u8),
+
+    /// Parse of a statement shared by the clients. Its answer is only forwarded
+    /// if the client sent the Parse, not if we did to use the statement here.
+    Prepare { name: String, forward: bool },
+
+    /// Close of a statement we evicted, the client doesn't get the answer.
+    Close(String),
+
+    /// The Sync ending a sequence, answered with ReadyForQuery. The client can
+    /// pipeline more sequences before it gets the answer.
+    Sync,
+}
+
+/// The cancel requests sent to the servers, by their process ID and secret key, until
+/// the clients using them are done. The value is true once the server closed the
+/// connection of the request, i.e. the backend was signalled.
+static CANCELS: Lazy<Mutex<CancelMap>> = Lazy::new(|| Mutex::new(HashMap::default()));
+
+type CancelMap = HashMap<(i32, i32), watch::Receiver<bool>>;
+
+/// The words in the queries that may create temporary tables or take session advisory
+/// locks (not `pg_advisory_xact_lock`). Their command tags don't tell us.
+const PINNING: [&str; 6] = [
+    "temp",
+    "temporary",
+    "pg_advisory_lock",
+    "pg_advisory_lock_shared",
+    "pg_try_advisory_lock",
+    "pg_try_advisory_lock_shared",
+];
+
+/// The words in the queries that change settings like SET does, e.g.
+/// `SELECT set_config('search_path', 'app', false)`. Their command tag is SELECT.
+const SETTING: [&str; 1] = ["set_config"];
+
+/// Connection to the server, encrypted or not.
+pub enum ServerStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for ServerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            ServerStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ServerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            ServerStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            ServerStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            ServerStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Check the server certificate against the CRL and OCSP responder, if the
+/// certificate is verified at all.
+async fn server_revoked(
+    host: &str,
+    port: &str,
+    tls: &ServerTlsSettings,
+    ca_certificates: &[Certificate],
+    stream: &TlsStream<TcpStream>,
+) -> Result<(), Error> {
+    let config = get_config();
+
+    if config.general.server_tls_crl_file.is_none() && !config.general.server_tls_ocsp {
+        return Ok(());
+    }
+
+    match (tls.sslmode.as_str(), &tls.ca_file) {
+        ("verify-ca" | "verify-full", Some(_)) => (),
+        _ => return Ok(()),
+    };
+
+    let certs = match stream.get_ref().1.peer_certificates() {
+        Some(certs) => certs.to_vec(),
+        None => return Ok(()),
+    };
+
+    if revocation::revoked(
+        &certs,
+        ca_certificates,
+        &config.general.server_tls_crl_file,
+        config.general.server_tls_ocsp,
+    )
+    .await
+    {
+        error!("Server {}:{} certificate has been revoked", host, port);
+        return Err(Error::TlsError);
+    }
+
+    Ok(())
+}
+
+/// Connect to the server, and switch to TLS as required by its sslmode,
+/// with the client certificate and key, if any.
+async fn connect(host: &str, port: &str, tls: ServerTlsSettings) -> Result<ServerStream, Error> {
+    let mut stream = match TcpStream::connect(&format!("{}:{}", host, port)).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            error!("Could not connect to server: {}", err);
+            return Err(Error::SocketError);
+        }
+    };
+
+    let sslmode = tls.sslmode.as_str();
+
+    if sslmode == "disable" {
+        return Ok(ServerStream::Plain(stream));
+    }
+
+    ssl_request(&mut stream).await?;
+
+    let response = match stream.read_u8().await {
+        Ok(response) => response,
+        Err(_) => return Err(Error::SocketError),
+    };
+
+    match response {
+        b'S' => {
+            let (tls_config, ca_certificates) = server_tls_config(&tls)?;
+
+            let server_name = match ServerName::try_from(host) {
+                Ok(server_name) => server_name,
+                Err(_) => {
+                    error!("Server host {} is not a valid TLS server name", host);
+                    return Err(Error::TlsError);
+                }
+            };
+
+            match TlsConnector::from(tls_config)
+                .connect(server_name, stream)
+                .await
+            {
+                Ok(stream) => {
+                    server_revoked(host, port, &tls, &ca_certificates, &stream).await?;
+                    Ok(ServerStream::Tls(Box::new(stream)))
+                }
+                Err(err) => {
+                    error!("TLS connection to server {}:{} failed: {}", host, port, err);
+                    Err(Error::TlsError)
+                }
+            }
+        }
+
+        // The server doesn't support TLS.
+        b'N' => {
+            if sslmode == "prefer" {
+                Ok(ServerStream::Plain(stream))
+            } else {
+                error!(
+                    "Server {}:{} doesn't support TLS, required by server_tls_sslmode = {}",
+                    host, port, sslmode
+                );
+                Err(Error::TlsError)
+            }
+        }
+
+        _ => Err(Error::ProtocolSyncError),
+    }
+}
+
 /// Server state.
 pub struct Server {
     /// Server host, e.g. localhost,
@@ -23,10 +239,10 @@ pub struct Server {
     address: Address,
 
     /// Buffered read socket.
-    read: BufReader<OwnedReadHalf>,
+    read: BufReader<ReadHalf<ServerStream>>,
 
     /// Unbuffered write socket (our client code buffers).
-    write: OwnedWriteHalf,
+    write: WriteHalf<ServerStream>,
 
     /// Our server response buffer. We buffer data before we give it to the client.
     buffer: BytesMut,
@@ -34,19 +250,83 @@ pub struct Server {
     /// Server information the server sent us over on startup.
     server_info: BytesMut,
 
+    /// The server's settings, from its ParameterStatus messages.
+    parameters: ServerParameters,
+
     /// Backend id and secret key used for query cancellation.
     process_id: i32,
     secret_key: i32,
 
-    /// Is the server inside a transaction or idle.
-    in_transaction: bool,
+    /// The transaction status in the last ReadyForQuery: idle (I),
+    /// inside a transaction (T) or inside a failed transaction (E).
+    transaction_status: u8,
 
     /// Is there more data for the client to read.
     data_available: bool,
 
+    /// DataRow and CopyData messages larger than this are streamed to the client.
+    streaming_threshold: usize,
+
+    /// Bytes left to stream of the message the client got the start of.
+    streaming: usize,
+
+    /// Is the server in COPY FROM STDIN, until ReadyForQuery.
+    in_copy_mode: bool,
+
+    /// Is the server in COPY TO STDOUT, until CopyDone.
+    in_copy_out: bool,
+
+    /// Limit of the pool on the COPY data going through, if any.
+    copy_throttle: Option<CopyThrottle>,
+
+    /// The COPY was started by the extended protocol: the server ignored the Sync
+    /// sent with it, and answers after the client's next one.
+    extended_copy: bool,
+
+    /// What to do with the answers to the extended protocol messages we sent, in order.
+    responses: VecDeque<Response>,
+
+    /// The server returned an error, so it ignores all messages until the Sync.
+    skipping_until_sync: bool,
+
+    /// The answers to the last query, or extended protocol sequence, have an ErrorResponse.
+    failed: bool,
+
+    /// The extended protocol messages we sent last didn't end with a Sync, e.g. the client
+    /// flushed them to execute a portal again. The server may be in an implicit transaction
+    /// its ReadyForQuery didn't tell us about.
+    unsynced: bool,
+
+    /// The client ran LISTEN, so the server gets notifications for it.
+    listening: bool,
+
+    /// The client declared cursors, and those WITH HOLD outlive the transaction.
+    cursors: bool,
+
+    /// The client may have created temporary tables or taken advisory locks, which
+    /// outlive the transaction.
+    pinned: bool,
+
+    /// The client changed the session state, e.g. with SET or by creating
+    /// a temporary table, so it's reset before the next client uses the server.
+    needs_cleanup: bool,
+
+    /// Statements prepared for the clients, and when they were last used.
+    prepared_statements: HashMap<String, u64>,
+    prepared_statements_used: u64,
+
     /// Is the server broken? We'll remote it from the pool if so.
     bad: bool,
 
+    /// The server counts toward the autoscaled pool size while it's in use.
+    permit: Option<OwnedSemaphorePermit>,
+
+    /// The server counts toward the requests in flight of its address while it's in use.
+    in_flight: Option<InFlight>,
+
+    /// The server counts toward max_db_connections until it's closed.
+    db_connection: Option<DbConnection>,
+
     /// Mapping of clients and servers used for query cancellation.
     client_server_map: ClientServerMap,
 
@@ -55,34 +335,43 @@ pub struct Server {
 
     /// Reports various metrics, e.g. data sent & received.
     stats: Reporter,
-
-    /// Application name using the server at the moment.
-    application_name: String,
 }
 
 impl Server {
     /// Pretend to be the Postgres client and connect to the server given host, port and credentials.
     /// Perform the authentication and return the server in a ready for query state.
+    #[allow(clippy::too_many_arguments)]
     pub async fn startup(
         address: &Address,
         user: &User,
         database: &str,
+        application_name: &str,
+        parameters: &[(String, String)],
+        replication: Option<&str>,
         client_server_map: ClientServerMap,
         stats: Reporter,
     ) -> Result<Server, Error> {
-        let mut stream =
-            match TcpStream::connect(&format!("{}:{}", &address.host, &address.port)).await {
-                Ok(stream) => stream,
-                Err(err) => {
-                    error!("Could not connect to server: {}", err);
-                    return Err(Error::SocketError);
-                }
-            };
+        let tls = get_config().server_tls(
+            &address.database,
+            &address.shard.to_string(),
+            &address.host,
+            &address.port,
+        );
+
+        let mut stream = connect(&address.host, &address.port, tls).await?;
 
         trace!("Sending StartupMessage");
 
         // StartupMessage
-        startup(&mut stream, &user.username, database).await?;
+        startup(
+            &mut stream,
+            &user.username,
+            database,
+            application_name,
+            parameters,
+            replication,
+        )
+        .await?;
 
         let mut server_info = BytesMut::new();
         let mut process_id: i32 = 0;
@@ -90,7 +379,12 @@ impl Server {
 
         // We'll be handling multiple packets, but they will all be structured the same.
         // We'll loop here until this exchange is complete.
-        let mut scram = ScramSha256::new(&user.password);
+        // Users found with auth_query may only have a SCRAM verifier; we log in
+        // with the client key we got when their client authenticated with us.
+        let mut scram = match get_scram_keys(&user.password) {
+            Some((client_key, server_key)) => ScramSha256::from_keys(&client_key, &server_key),
+            None => ScramSha256::new(&user.password),
+        };
 
         loop {
             let code = match stream.read_u8().await {
@@ -131,6 +425,11 @@ impl Server {
                                 .await?;
                         }
 
+                        // Used by RDS with IAM authentication, the password is the token.
+                        CLEARTEXT_PASSWORD => {
+                            cleartext_password_response(&mut stream, &user.password).await?;
+                        }
+
                         AUTHENTICATION_SUCCESSFUL => (),
 
                         SASL => {
@@ -143,9 +442,11 @@ impl Server {
                                 Err(_) => return Err(Error::SocketError),
                             };
 
-                            let sasl_type = String::from_utf8_lossy(&sasl_auth[..sasl_len - 2]);
+                            // The server lists all mechanisms it's willing to accept,
+                            // e.g. SCRAM-SHA-256-PLUS is offered alongside SCRAM-SHA-256 over TLS.
+                            let mechanisms = sasl_mechanisms(&sasl_auth);
 
-                            if sasl_type == SCRAM_SHA_256 {
+                            if mechanisms.iter().any(|m| m == SCRAM_SHA_256) {
                                 debug!("Using {}", SCRAM_SHA_256);
 
                                 // Generate client message.
@@ -170,7 +471,7 @@ impl Server {
 
                                 write_all(&mut stream, res).await?;
                             } else {
-                                error!("Unsupported SCRAM version: {}", sasl_type);
+                                error!("Unsupported SASL mechanisms: {:?}", mechanisms);
                                 return Err(Error::ServerError);
                             }
                         }
@@ -252,6 +553,12 @@ impl Server {
                             // present a prettier message to the user.
                             // See: https://www.postgresql.org/docs/12/protocol-error-fields.html
                             error!("Server error: {}", String::from_utf8_lossy(&error));
+
+                            error.insert(0, error_code);
+
+                            if error_response::code(&error) == Some(INVALID_PASSWORD) {
+                                return Err(Error::InvalidPassword);
+                            }
                         }
                     };
 
@@ -275,6 +582,21 @@ impl Server {
                     server_info.put_slice(&param[..]);
                 }
 
+                // NegotiateProtocolVersion: the server doesn't speak all of the protocol we asked for.
+                // We only use protocol 3.0 without options, which every server does.
+                'v' => {
+                    let mut negotiation = vec![0u8; len as usize - 4];
+
+                    match stream.read_exact(&mut negotiation).await {
+                        Ok(_) => (),
+                        Err(_) => return Err(Error::SocketError),
+                    };
+
+                    let mut negotiation = BytesMut::from(&negotiation[..]);
+
+                    debug!("Server speaks protocol 3.{}", negotiation.get_i32());
+                }
+
                 // BackendKeyData
                 'K' => {
                     // The frontend must save these values if it wishes to be able to issue CancelRequest messages later.
@@ -299,27 +621,44 @@ impl Server {
                         Err(_) => return Err(Error::SocketError),
                     };
 
-                    let (read, write) = stream.into_split();
+                    let (read, write) = split(stream);
 
-                    let mut server = Server {
+                    let server = Server {
                         address: address.clone(),
                         read: BufReader::new(read),
                         write: write,
                         buffer: BytesMut::with_capacity(8196),
+                        parameters: ServerParameters::from_messages(&server_info),
                         server_info: server_info,
                         process_id: process_id,
                         secret_key: secret_key,
-                        in_transaction: false,
+                        transaction_status: b'I',
                         data_available: false,
+                        streaming_threshold: get_config().general.message_streaming_threshold,
+                        streaming: 0,
+                        in_copy_mode: false,
+                        in_copy_out: false,
+                        copy_throttle: None,
+                        extended_copy: false,
+                        responses: VecDeque::new(),
+                        skipping_until_sync: false,
+                        failed: false,
+                        unsynced: false,
+                        listening: false,
+                        cursors: false,
+                        pinned: false,
+                        needs_cleanup: false,
+                        prepared_statements: HashMap::new(),
+                        prepared_statements_used: 0,
                         bad: false,
+                        permit: None,
+                        in_flight: None,
+                        db_connection: None,
                         client_server_map: client_server_map,
                         connected_at: chrono::offset::Utc::now().naive_utc(),
                         stats: stats,
-                        application_name: String::new(),
                     };
 
-                    server.set_name("pgcat").await?;
-
                     return Ok(server);
                 }
 
@@ -340,15 +679,16 @@ impl Server {
         port: &str,
         process_id: i32,
         secret_key: i32,
+        delivered: watch::Sender<bool>,
     ) -> Result<(), Error> {
-        let mut stream = match TcpStream::connect(&format!("{}:{}", host, port)).await {
-            Ok(stream) => stream,
-            Err(err) => {
-                error!("Could not connect to server: {}", err);
-                return Err(Error::SocketError);
-            }
+        // Cancel requests don't go through authentication.
+        let tls = ServerTlsSettings {
+            client_certificate: None,
+            ..get_config().find_server_tls(host, port)
         };
 
+        let mut stream = connect(host, port, tls).await?;
+
         debug!("Sending CancelRequest");
 
         let mut bytes = BytesMut::with_capacity(16);
@@ -357,14 +697,93 @@ impl Server {
         bytes.put_i32(process_id);
         bytes.put_i32(secret_key);
 
-        Ok(write_all(&mut stream, bytes).await?)
+        write_all(&mut stream, bytes).await?;
+
+        // Postgres closes the connection once it signalled the backend.
+        let connect_timeout = Duration::from_millis(get_config().general.connect_timeout);
+        let mut buf = [0u8; 1];
+
+        match tokio::time::timeout(connect_timeout, stream.read(&mut buf)).await {
+            Ok(_) => {
+                let _ = delivered.send(true);
+            }
+            Err(_) => warn!(
+                "Server {}:{} didn't close the cancel request connection within connect_timeout",
+                host, port
+            ),
+        };
+
+        Ok(())
+    }
+
+    /// A cancel request is about to be sent to the server. It isn't checked in until
+    /// the backend got it, it would cancel the query of the next client otherwise.
+    /// Call it before the client releases the server, i.e. with the client server map locked.
+    pub fn cancel_requested(process_id: i32, secret_key: i32) -> watch::Sender<bool> {
+        let (delivered, receiver) = watch::channel(false);
+        CANCELS.lock().insert((process_id, secret_key), receiver);
+        delivered
+    }
+
+    /// Wait for the cancel requests sent to the server while the client used it,
+    /// if any, to get to the backend, so they can't cancel the query of the next
+    /// client. The session state may be anything after one, so it's reset when the
+    /// server is checked in. The server is closed if it doesn't get there in time.
+    pub async fn wait_for_cancel(&mut self) {
+        let receiver = CANCELS.lock().remove(&(self.process_id, self.secret_key));
+
+        let mut receiver = match receiver {
+            Some(receiver) => receiver,
+            None => return,
+        };
+
+        debug!(
+            "Waiting for the cancel request to server {:?}",
+            self.address
+        );
+
+        let connect_timeout = Duration::from_millis(get_config().general.connect_timeout);
+
+        let delivered =
+            tokio::time::timeout(connect_timeout, receiver.wait_for(|delivered| *delivered))
+                .await
+                .is_ok_and(|delivered| delivered.is_ok());
+
+        match delivered {
+            true => self.needs_cleanup = true,
+            false => {
+                error!(
+                    "Server {:?} may still get a cancel request, closing it",
+                    self.address
+                );
+                self.bad = true;
+            }
+        }
     }
 
     /// Send messages to the server from the client.
     pub async fn send(&mut self, messages: BytesMut) -> Result<(), Error> {
+        // COPY FROM STDIN data.
+        match self.copy_throttle {
+            Some(ref throttle) if self.in_copy_mode => throttle.wait(messages.len()).await,
+            _ => (),
+        }
+
         self.stats
             .data_sent(messages.len(), self.process_id, self.address.id);
 
+        if !self.in_copy_mode && pins(&messages) {
+            self.pinned = true;
+        }
+
+        if !self.in_copy_mode {
+            self.failed = false;
+        }
+
+        if !self.in_copy_mode && sets(&messages) {
+            self.needs_cleanup = true;
+        }
+
         match write_all_half(&mut self.write, messages).await {
             Ok(_) => Ok(()),
             Err(err) => {
@@ -380,23 +799,40 @@ impl Server {
     /// in order to receive all data the server has to offer.
     pub async fn recv(&mut self) -> Result<BytesMut, Error> {
         loop {
-            let mut message = match read_message(&mut self.read).await {
-                Ok(message) => message,
-                Err(err) => {
-                    error!("Terminating server because of: {:?}", err);
-                    self.bad = true;
-                    return Err(err);
+            if self.streaming > 0 {
+                self.stream().await?;
+
+                // The client gets the rest of the message on the next call.
+                if self.streaming > 0 {
+                    self.data_available = true;
+                    break;
                 }
-            };
 
-            // Buffer the message we'll forward to the client later.
-            self.buffer.put(&message[..]);
+                continue;
+            }
+
+            self.answer();
+
+            let mut message = self.read_message().await?;
+
+            let original = message.clone();
 
             let code = message.get_u8() as char;
             let _len = message.get_i32();
 
             trace!("Message: {}", code);
 
+            // Buffer the message we'll forward to the client later.
+            if self.forward(code, &message) {
+                self.buffer.put(&original[..]);
+            }
+
+            // The client gets the rest of the message on the next call.
+            if self.streaming > 0 {
+                self.data_available = true;
+                break;
+            }
+
             match code {
                 // ReadyForQuery
                 'Z' => {
@@ -405,17 +841,17 @@ impl Server {
                     match transaction_state {
                         // In transaction.
                         'T' => {
-                            self.in_transaction = true;
+                            self.transaction_status = b'T';
                         }
 
                         // Idle, transaction over.
                         'I' => {
-                            self.in_transaction = false;
+                            self.transaction_status = b'I';
                         }
 
-                        // Some error occured, the transaction was rolled back.
+                        // Some error occured, the transaction must be rolled back.
                         'E' => {
-                            self.in_transaction = true;
+                            self.transaction_status = b'E';
                         }
 
                         // Something totally unexpected, this is not a Postgres server we know.
@@ -425,8 +861,21 @@ impl Server {
                         }
                     };
 
-                    // There is no more data available from the server.
-                    self.data_available = false;
+                    self.in_copy_mode = false;
+                    self.in_copy_out = false;
+
+                    // The Sync ends the extended protocol sequence.
+                    while let Some(response) = self.responses.pop_front() {
+                        if response == Response::Sync {
+                            break;
+                        }
+                    }
+
+                    self.skipping_until_sync = false;
+
+                    // There is no more data available from the server,
+                    // unless the client pipelined more sequences.
+                    self.data_available = self.is_pipelining();
 
                     break;
                 }
@@ -443,21 +892,33 @@ impl Server {
                 }
 
                 // CopyInResponse: copy is starting from client to server.
-                'G' => break,
+                // The server answers when the client is done.
+                'G' => {
+                    self.in_copy_mode = true;
+                    self.extended_copy = !self.responses.is_empty();
+
+                    // The server ignored the Sync sent with it.
+                    if let Some(sync) = self.responses.iter().position(|r| *r == Response::Sync) {
+                        self.responses.remove(sync);
+                    }
+
+                    break;
+                }
 
                 // CopyOutResponse: copy is starting from the server to the client.
                 'H' => {
+                    self.in_copy_out = true;
                     self.data_available = true;
                     break;
                 }
 
-                // CopyData: we are not buffering this one because there will be many more
-                // and we don't know how big this packet could be, best not to take a risk.
-                'd' => break,
+                // CopyData: there will be many more, so like rows,
+                // don't buffer too many of them.
+                'd' if self.buffer.len() >= 8196 => break,
 
                 // CopyDone
                 // Buffer until ReadyForQuery shows up, so don't exit the loop yet.
-                'c' => (),
+                'c' => self.in_copy_out = false,
 
                 // Anything else, e.g. errors, notices, etc.
                 // Keep buffering until ReadyForQuery shows up.
@@ -474,14 +935,477 @@ impl Server {
         // Clear the buffer for next query.
         self.buffer.clear();
 
+        // COPY TO STDOUT data.
+        match self.copy_throttle {
+            Some(ref throttle) if self.in_copy_out => throttle.wait(bytes.len()).await,
+            _ => (),
+        }
+
         // Pass the data back to the client.
         Ok(bytes)
     }
 
+    /// Send extended protocol messages ending with a Sync or a Flush, and what to do
+    /// with the server's answers to them. The statements they prepare are remembered,
+    /// and the least recently used ones closed when there are more than `max_prepared_statements`.
+    pub async fn send_extended(
+        &mut self,
+        mut messages: BytesMut,
+        responses: Vec<Response>,
+        max_prepared_statements: usize,
+    ) -> Result<(), Error> {
+        let sync = messages[messages.len() - 5] == b'S';
+
+        // A new sequence, not the rest of one the client flushed.
+        if !self.unsynced {
+            self.failed = false;
+        }
+
+        self.unsynced = !sync;
+
+        // After an error, the server ignores messages until the Sync.
+        if !self.skipping_until_sync {
+            for response in responses {
+                if let Response::Prepare { ref name, .. } = response {
+                    self.prepared_statements_used += 1;
+                    self.prepared_statements
+                        .insert(name.clone(), self.prepared_statements_used);
+                }
+
+                self.responses.push_back(response);
+            }
+
+            // Close them after they're used, before the Sync or Flush.
+            let end = messages.split_off(messages.len() - 5);
+
+            while self.prepared_statements.len() > max_prepared_statements {
+                let name = match self
+                    .prepared_statements
+                    .iter()
+                    .min_by_key(|(_, used)| **used)
+                {
+                    Some((name, _)) => name.clone(),
+                    None => break,
+                };
+
+                debug!("Closing prepared statement {}", name);
+
+                self.prepared_statements.remove(&name);
+                messages.put(close_statement(&name));
+                self.responses.push_back(Response::Close(name));
+            }
+
+            messages.put(end);
+        }
+
+        if sync {
+            self.responses.push_back(Response::Sync);
+            self.skipping_until_sync = false;
+        }
+
+        self.send(messages).await
+    }
+
+    /// The answers to the last query, or extended protocol sequence, have an ErrorResponse,
+    /// e.g. it wasn't run, or rolled back.
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    /// The client pipelined sequences, i.e. sent them without waiting for the
+    /// ReadyForQuery of the ones before, and they're not all answered yet.
+    pub fn is_pipelining(&self) -> bool {
+        self.responses.contains(&Response::Sync)
+    }
+
+    /// In the middle of an extended protocol sequence. A server its client leaves like this
+    /// can't be cleaned up: a Sync would commit what it did, and a query is ignored after an error.
+    pub fn is_unsynced(&self) -> bool {
+        self.unsynced
+    }
+
+    /// If the statement is prepared on this server. It's now the most recently used.
+    pub fn has_prepared_statement(&mut self, name: &str) -> bool {
+        self.prepared_statements_used += 1;
+
+        match self.prepared_statements.get_mut(name) {
+            Some(used) => {
+                *used = self.prepared_statements_used;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Read the next message from the server. DataRow and CopyData messages larger
+    /// than `streaming_threshold` aren't read whole: only their header is returned,
+    /// and their body goes to the client in chunks with `self.stream()`.
+    async fn read_message(&mut self) -> Result<BytesMut, Error> {
+        let result = match read_message_header(&mut self.read).await {
+            Ok((code, len))
+                if (code == b'D' || code == b'd') && len as usize > self.streaming_threshold =>
+            {
+                debug!("Streaming {} bytes message {}", len, code as char);
+
+                self.streaming = len as usize - 4;
+
+                let mut header = BytesMut::with_capacity(5);
+                header.put_u8(code);
+                header.put_i32(len);
+
+                Ok(header)
+            }
+
+            Ok((code, len)) => read_message_body(&mut self.read, code, len).await,
+            Err(err) => Err(err),
+        };
+
+        if let Err(ref err) = result {
+            error!("Terminating server because of: {:?}", err);
+            self.bad = true;
+        }
+
+        result
+    }
+
+    /// Buffer the next chunk of the message being streamed to the client.
+    async fn stream(&mut self) -> Result<(), Error> {
+        let mut chunk = vec![0u8; self.streaming.min(8196)];
+
+        if let Err(err) = self.read.read_exact(&mut chunk).await {
+            error!("Terminating server because of: {:?}", err);
+            self.bad = true;
+            return Err(Error::SocketError);
+        }
+
+        self.streaming -= chunk.len();
+        self.buffer.put_slice(&chunk);
+
+        Ok(())
+    }
+
+    /// Give the client the answers to the messages we didn't send to the server,
+    /// up to the next message we did.
+    fn answer(&mut self) {
+        while let Some(Response::Answer(code)) = self.responses.front() {
+            self.buffer.put_u8(*code);
+            self.buffer.put_i32(4);
+            self.responses.pop_front();
+        }
+    }
+
+    /// Keep track of the session state the commands change.
+    fn command_complete(&mut self, tag: &BytesMut) {
+        match &tag[..] {
+            b"LISTEN\0" => self.listening = true,
+            b"DECLARE CURSOR\0" => self.cursors = true,
+            b"CLOSE CURSOR ALL\0" => self.cursors = false,
+
+            // The settings, the statements and the tables (maybe temporary) outlive the transaction.
+            b"SET\0" | b"RESET\0" | b"PREPARE\0" | b"CREATE TABLE\0" => self.needs_cleanup = true,
+
+            // The statements, the LISTENs, the cursors, the temporary tables,
+            // the advisory locks and the settings are gone.
+            b"DISCARD ALL\0" => {
+                self.parameters.set_options(&ServerParameters::default());
+                self.prepared_statements.clear();
+                self.listening = false;
+                self.cursors = false;
+                self.needs_cleanup = false;
+
+                if self.pinned {
+                    self.pinned = false;
+                    self.stats
+                        .server_pinned(false, self.process_id, self.address.id);
+                }
+            }
+
+            b"DEALLOCATE ALL\0" => self.prepared_statements.clear(),
+
+            _ => (),
+        }
+    }
+
+    /// Keep track of the answers to the extended protocol messages, and
+    /// if the message should be forwarded to the client.
+    fn forward(&mut self, code: char, message: &BytesMut) -> bool {
+        match code {
+            // ParseComplete, BindComplete, CloseComplete, RowDescription, NoData,
+            // CommandComplete, EmptyQueryResponse, PortalSuspended: the last (or only)
+            // answer to one message.
+            '1' | '2' | '3' | 'T' | 'n' | 'C' | 'I' | 's' => {
+                if code == 'C' {
+                    self.command_complete(message);
+                }
+
+                match self.responses.pop_front() {
+                    Some(Response::Prepare { forward, .. }) => forward,
+                    Some(Response::Close(_)) => false,
+                    _ => true,
+                }
+            }
+
+            // ErrorResponse: the rest of the messages are ignored until the Sync,
+            // so the statements weren't prepared, or closed.
+            'E' => {
+                while !matches!(self.responses.front(), None | Some(Response::Sync)) {
+                    match self.responses.pop_front() {
+                        Some(Response::Prepare { name, .. }) => {
+                            self.prepared_statements.remove(&name);
+                        }
+
+                        Some(Response::Close(name)) => {
+                            self.prepared_statements.insert(name, 0);
+                        }
+
+                        _ => (),
+                    }
+                }
+
+                // Unless we sent it already, the messages we send next are ignored too.
+                self.skipping_until_sync = self.responses.is_empty();
+                self.failed = true;
+
+                true
+            }
+
+            // ParameterStatus: a setting changed, e.g. with SET.
+            'S' => {
+                self.parameters.set(message);
+                true
+            }
+
+            // ParameterDescription comes before the RowDescription or NoData,
+            // notices and notifications can come at any time.
+            _ => true,
+        }
+    }
+
+    /// Receive the answers to messages sent with `self.send_extended()` ending with a Flush.
+    /// Like `self.recv()`, call it while `self.is_data_available()` is true.
+    pub async fn recv_flush(&mut self) -> Result<BytesMut, Error> {
+        self.data_available = false;
+
+        loop {
+            if self.streaming > 0 {
+                self.stream().await?;
+
+                // The client gets the rest of the message on the next call.
+                if self.streaming > 0 {
+                    self.data_available = true;
+                    break;
+                }
+
+                continue;
+            }
+
+            self.answer();
+
+            if self.responses.is_empty() {
+                break;
+            }
+
+            let mut message = self.read_message().await?;
+
+            let original = message.clone();
+
+            let code = message.get_u8() as char;
+            let _len = message.get_i32();
+
+            trace!("Message: {}", code);
+
+            if self.forward(code, &message) {
+                self.buffer.put(&original[..]);
+            }
+
+            // The client gets the rest of the message on the next call.
+            if self.streaming > 0 {
+                self.data_available = true;
+                break;
+            }
+
+            match code {
+                // CopyInResponse: the answer comes after the client's COPY data.
+                'G' => {
+                    self.in_copy_mode = true;
+                    self.extended_copy = true;
+                    break;
+                }
+
+                // DataRow, CopyData: don't buffer too many,
+                // the client gets the rest on the next call.
+                'D' | 'd' if self.buffer.len() >= 8196 => {
+                    self.data_available = true;
+                    break;
+                }
+
+                _ => (),
+            }
+        }
+
+        let bytes = self.buffer.clone();
+
+        self.stats
+            .data_received(bytes.len(), self.process_id, self.address.id);
+
+        self.buffer.clear();
+
+        Ok(bytes)
+    }
+
     /// If the server is still inside a transaction.
     /// If the client disconnects while the server is in a transaction, we will clean it up.
     pub fn in_transaction(&self) -> bool {
-        self.in_transaction
+        self.transaction_status != b'I'
+    }
+
+    /// The transaction status the server reported last, for the ReadyForQuery
+    /// messages we send the client ourselves.
+    pub fn transaction_status(&self) -> u8 {
+        self.transaction_status
+    }
+
+    /// Limit the COPY data going through to the pool's throughput, if it has a limit.
+    pub fn set_copy_throttle(&mut self, copy_throttle: Option<CopyThrottle>) {
+        self.copy_throttle = copy_throttle;
+    }
+
+    /// If the server is in COPY FROM STDIN, it can't be used
+    /// by anyone else until it gets all the data.
+    pub fn in_copy_mode(&self) -> bool {
+        self.in_copy_mode
+    }
+
+    /// If the COPY was started by the extended protocol. The server answers
+    /// the client's CopyDone or CopyFail after its next Sync.
+    pub fn in_extended_copy(&self) -> bool {
+        self.in_copy_mode && self.extended_copy
+    }
+
+    /// If the client ran LISTEN, it keeps the server to get the notifications.
+    /// UNLISTEN doesn't say which channels it stopped listening to, only
+    /// DISCARD ALL and `self.unlisten()` tell us there are none left.
+    pub fn is_listening(&self) -> bool {
+        self.listening
+    }
+
+    /// The client left statements prepared on the server, under its own names.
+    pub fn mark_needs_cleanup(&mut self) {
+        self.needs_cleanup = true;
+    }
+
+    /// Reset the session state with the pool's `server_reset_query` before the server
+    /// goes back to the pool, if the client changed it or `always`. Most clients don't,
+    /// and they don't wait for the reset.
+    pub async fn checkin_cleanup(&mut self, reset_query: &str, always: bool) -> Result<(), Error> {
+        if reset_query.is_empty() || !(self.needs_cleanup || always) {
+            return Ok(());
+        }
+
+        debug!("Resetting the session state of server {:?}", self.address);
+
+        // The next client would get what's left of the session state.
+        if self.fetch(reset_query).await.is_err() {
+            error!("Server {:?} failed to run server_reset_query", self.address);
+            self.bad = true;
+            return Err(Error::ServerError);
+        }
+
+        // Whatever the query reset, the client's changes are gone. The settings
+        // of the client's options may be too.
+        self.needs_cleanup = false;
+        self.parameters.forget_options();
+
+        Ok(())
+    }
+
+    /// Stop listening to all channels, so the next client doesn't get the notifications.
+    pub async fn unlisten(&mut self) -> Result<(), Error> {
+        self.query("UNLISTEN *").await?;
+        self.listening = false;
+
+        Ok(())
+    }
+
+    /// If the client has cursors declared WITH HOLD, it keeps the server to fetch from them.
+    /// The others are closed with the transaction, so only ask the server after one
+    /// declared some.
+    pub async fn has_held_cursors(&mut self) -> Result<bool, Error> {
+        if self.cursors {
+            let rows = self
+                .fetch("SELECT 1 FROM pg_cursors WHERE is_holdable")
+                .await?;
+
+            self.cursors = !rows.is_empty();
+        }
+
+        Ok(self.cursors)
+    }
+
+    /// If the client has temporary tables or session advisory locks, it keeps the server
+    /// until it drops or releases them, so the next client doesn't see the tables or get
+    /// the locks. Only ask the server after a query that may have created or taken some.
+    pub async fn is_pinned(&mut self) -> Result<bool, Error> {
+        if self.pinned {
+            let rows = self
+                .fetch(
+                    "SELECT 1 FROM pg_class WHERE relnamespace = pg_my_temp_schema() \
+                     UNION ALL \
+                     SELECT 1 FROM pg_locks WHERE locktype = 'advisory' AND pid = pg_backend_pid() \
+                     LIMIT 1",
+                )
+                .await?;
+
+            self.pinned = !rows.is_empty();
+            self.stats
+                .server_pinned(self.pinned, self.process_id, self.address.id);
+        }
+
+        Ok(self.pinned)
+    }
+
+    /// Drop the temporary tables and release the advisory locks the client left.
+    pub async fn unpin(&mut self) -> Result<(), Error> {
+        self.query("DISCARD TEMP; SELECT pg_advisory_unlock_all()")
+            .await?;
+        self.pinned = false;
+        self.stats
+            .server_pinned(false, self.process_id, self.address.id);
+
+        Ok(())
+    }
+
+    /// Wait until the server sends something without reading it, so it can be
+    /// raced with the client. Returns the code of the message, if it's not gone.
+    pub async fn wait_for_message(&mut self) -> Option<u8> {
+        match self.read.fill_buf().await {
+            Ok(buffer) => buffer.first().copied(),
+            Err(_) => None,
+        }
+    }
+
+    /// Receive a message the server sent on its own while the client is idle,
+    /// e.g. a NoticeResponse or a NotificationResponse.
+    pub async fn recv_async(&mut self) -> Result<BytesMut, Error> {
+        let message = match read_message(&mut self.read).await {
+            Ok(message) => message,
+            Err(err) => {
+                error!("Terminating server because of: {:?}", err);
+                self.bad = true;
+                return Err(err);
+            }
+        };
+
+        trace!("Message: {}", message[0] as char);
+
+        if message[0] == b'S' {
+            self.parameters.set(&message[5..]);
+        }
+
+        self.stats
+            .data_received(message.len(), self.process_id, self.address.id);
+
+        Ok(message)
     }
 
     /// We don't buffer all of server responses, e.g. COPY OUT produces too much data.
@@ -496,6 +1420,54 @@ impl Server {
         self.bad
     }
 
+    /// The server is in use, it counts toward the autoscaled pool size.
+    pub fn hold(&mut self, permit: OwnedSemaphorePermit) {
+        self.permit = Some(permit);
+    }
+
+    /// The server is in use, it counts toward the requests in flight of its address.
+    pub fn track(&mut self, in_flight: InFlight) {
+        self.in_flight = Some(in_flight);
+    }
+
+    /// The server is back in the pool.
+    pub fn release_permit(&mut self) {
+        self.permit = None;
+        self.in_flight = None;
+    }
+
+    /// The server counts toward max_db_connections until it's closed.
+    pub fn count(&mut self, db_connection: DbConnection) {
+        self.db_connection = Some(db_connection);
+    }
+
+    /// When the connection to the server was created.
+    pub fn connected_at(&self) -> chrono::naive::NaiveDateTime {
+        self.connected_at
+    }
+
+    /// The server's settings, the client using it was told about them.
+    pub fn parameters(&self) -> &ServerParameters {
+        &self.parameters
+    }
+
+    /// Set the settings the client expects, e.g. TimeZone, if the server has others.
+    pub async fn set_parameters(&mut self, parameters: &ServerParameters) -> Result<(), Error> {
+        if let Some(query) = parameters.set_query(&self.parameters) {
+            debug!("Setting server parameters: {}", query);
+
+            // Our SETs aren't the client's changes to clean up.
+            let needs_cleanup = self.needs_cleanup;
+
+            // The values of the client's options may be invalid.
+            self.fetch(&query).await?;
+            self.parameters.set_options(parameters);
+            self.needs_cleanup = needs_cleanup;
+        }
+
+        Ok(())
+    }
+
     /// Get server startup information to forward it to the client.
     /// Not used at the moment.
     pub fn server_info(&self) -> BytesMut {
@@ -503,6 +1475,13 @@ impl Server {
     }
 
     /// Indicate that this server connection cannot be re-used and must be discarded.
+    /// Close the server when it's released instead of reusing it, e.g. its pool
+    /// was replaced by a config reload.
+    pub fn retire(&mut self) {
+        info!("Server {:?} retired", self.address);
+        self.bad = true;
+    }
+
     pub fn mark_bad(&mut self) {
         error!("Server {:?} marked bad", self.address);
         self.bad = true;
@@ -522,6 +1501,51 @@ impl Server {
         );
     }
 
+    /// Abort the COPY the server is waiting for data for,
+    /// so it can be used again.
+    pub async fn copy_fail(&mut self, reason: &str) -> Result<(), Error> {
+        let mut messages = copy_fail(reason);
+
+        if self.extended_copy {
+            messages.put_u8(b'S');
+            messages.put_i32(4);
+        }
+
+        self.send(messages).await?;
+
+        loop {
+            let _ = self.recv().await?;
+
+            if !self.data_available {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forward everything between the client and the server as is,
+    /// until either of them disconnects. Replication connections speak
+    /// their own protocol, e.g. START_REPLICATION and CopyBoth.
+    pub async fn proxy<S, T>(&mut self, read: &mut S, write: &mut T) -> Result<(), Error>
+    where
+        S: tokio::io::AsyncRead + std::marker::Unpin,
+        T: tokio::io::AsyncWrite + std::marker::Unpin,
+    {
+        let result = tokio::select! {
+            result = tokio::io::copy(read, &mut self.write) => result,
+            result = tokio::io::copy_buf(&mut self.read, write) => result,
+        };
+
+        // The connection can't be used by anyone else.
+        self.bad = true;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Error::SocketError),
+        }
+    }
+
     /// Execute an arbitrary query against the server.
     /// It will use the simple query protocol.
     /// Result will not be returned, so this is useful for things like `SET` or `ROLLBACK`.
@@ -541,16 +1565,81 @@ impl Server {
         Ok(())
     }
 
-    /// A shorthand for `SET application_name = $1`.
-    #[allow(dead_code)]
-    pub async fn set_name(&mut self, name: &str) -> Result<(), Error> {
-        if self.application_name != name {
-            self.application_name = name.to_string();
-            Ok(self
-                .query(&format!("SET application_name = '{}'", name))
-                .await?)
+    /// Execute a query against the server and return the rows, with every column
+    /// as text or `None` if it's NULL. It will use the simple query protocol.
+    /// Meant for small results, e.g. a health check or a metadata query.
+    pub async fn fetch(&mut self, query: &str) -> Result<Vec<Vec<Option<String>>>, Error> {
+        self.send(simple_query(query)).await?;
+        self.fetch_rows().await
+    }
+
+    /// Like `self.fetch()`, with the parameters bound to the query's `$1`, `$2`, etc.
+    /// with the extended protocol, so their values never end up in the query itself,
+    /// e.g. the user name of a client that isn't authenticated yet with `auth_query`.
+    pub async fn fetch_params(
+        &mut self,
+        query: &str,
+        params: &[&str],
+    ) -> Result<Vec<Vec<Option<String>>>, Error> {
+        self.send(extended_query(query, params)).await?;
+        self.fetch_rows().await
+    }
+
+    /// Read the rows of `self.fetch()` and `self.fetch_params()`, until the ReadyForQuery.
+    async fn fetch_rows(&mut self) -> Result<Vec<Vec<Option<String>>>, Error> {
+        let mut rows = Vec::new();
+        let mut error = false;
+        let mut response = BytesMut::new();
+
+        loop {
+            response.put(self.recv().await?);
+
+            // Large rows are streamed in chunks, so only read the complete messages.
+            while response.len() >= 5 {
+                let len = i32::from_be_bytes([response[1], response[2], response[3], response[4]]);
+
+                if len < 4 {
+                    self.bad = true;
+                    return Err(Error::ProtocolSyncError);
+                }
+
+                if response.len() < len as usize + 1 {
+                    break;
+                }
+
+                let mut message = response.split_to(len as usize + 1);
+                let code = message.get_u8() as char;
+                let _len = message.get_i32();
+
+                match code {
+                    // DataRow
+                    'D' => match parse_data_row(message) {
+                        Some(row) => rows.push(row),
+
+                        // The rest of the answer can't be trusted either.
+                        None => {
+                            error!("Malformed DataRow from server {:?}", self.address);
+                            self.bad = true;
+                            return Err(Error::ProtocolSyncError);
+                        }
+                    },
+
+                    // ErrorResponse
+                    'E' => error = true,
+
+                    _ => (),
+                }
+            }
+
+            if !self.data_available {
+                break;
+            }
+        }
+
+        if error {
+            Err(Error::ServerError)
         } else {
-            Ok(())
+            Ok(rows)
         }
     }
 
@@ -566,6 +1655,37 @@ impl Server {
     }
 }
 
+/// The columns of a DataRow, as text or `None` if NULL. None if the
+/// row is shorter than its column count and lengths say.
+fn parse_data_row(mut message: BytesMut) -> Option<Vec<Option<String>>> {
+    if message.len() < 2 {
+        return None;
+    }
+
+    let columns = message.get_i16();
+    let mut row = Vec::with_capacity(columns.max(0) as usize);
+
+    for _ in 0..columns {
+        if message.len() < 4 {
+            return None;
+        }
+
+        let column_len = message.get_i32();
+
+        // -1 indicates a NULL column value.
+        if column_len < 0 {
+            row.push(None);
+        } else if message.len() < column_len as usize {
+            return None;
+        } else {
+            let column = message.split_to(column_len as usize);
+            row.push(Some(String::from_utf8_lossy(&column).to_string()));
+        }
+    }
+
+    Some(row)
+}
+
 impl Drop for Server {
     /// Try to do a clean shut down. Best effort because
     /// the socket is in non-blocking mode, so it may not be ready
@@ -578,14 +1698,22 @@ impl Drop for Server {
         bytes.put_u8(b'X');
         bytes.put_i32(4);
 
-        match self.write.try_write(&bytes) {
-            Ok(_) => (),
-            Err(_) => debug!("Dirty shutdown"),
+        // Poll the write once instead of waiting for the socket, like try_write.
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+        let mut write = Pin::new(&mut self.write);
+
+        match write.as_mut().poll_write(&mut cx, &bytes) {
+            Poll::Ready(Ok(_)) => {
+                let _ = write.poll_flush(&mut cx);
+            }
+            _ => debug!("Dirty shutdown"),
         };
 
         // Should not matter.
         self.bad = true;
 
+        CANCELS.lock().remove(&(self.process_id, self.secret_key));
+
         let now = chrono::offset::Utc::now().naive_utc();
         let duration = now - self.connected_at;
 
@@ -595,3 +1723,171 @@ impl Drop for Server {
         );
     }
 }
+
+/// The SQL of the Query and Parse messages.
+fn queries(mut messages: &[u8]) -> Vec<&[u8]> {
+    let mut queries = Vec::new();
+
+    while messages.len() >= 5 {
+        let code = messages[0];
+        let len = (&messages[1..5]).get_i32() as usize;
+
+        if len < 4 || messages.len() < len + 1 {
+            break;
+        }
+
+        let body = &messages[5..len + 1];
+
+        // Parse: the statement's name, then the query.
+        let query = match code {
+            b'Q' => Some(body),
+            b'P' => body
+                .iter()
+                .position(|byte| *byte == 0)
+                .map(|end| &body[end + 1..]),
+            _ => None,
+        };
+
+        if let Some(query) = query {
+            let end = query.iter().position(|byte| *byte == 0);
+            queries.push(&query[..end.unwrap_or(query.len())]);
+        }
+
+        messages = &messages[len + 1..];
+    }
+
+    queries
+}
+
+/// If the SQL of the messages has one of the words, in any case, e.g. `TEMP`
+/// but not `temperature`.
+fn mentions(messages: &[u8], words: &[&str]) -> bool {
+    queries(messages).iter().any(|query| {
+        query
+            .split(|byte| !(byte.is_ascii_alphanumeric() || *byte == b'_' || *byte == b'$'))
+            .any(|word| {
+                words
+                    .iter()
+                    .any(|known| word.eq_ignore_ascii_case(known.as_bytes()))
+            })
+    })
+}
+
+/// Could the messages create temporary tables or take session advisory locks?
+fn pins(messages: &[u8]) -> bool {
+    mentions(messages, &PINNING)
+}
+
+/// Could the messages change settings without a SET?
+fn sets(messages: &[u8]) -> bool {
+    mentions(messages, &SETTING)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pins() {
+        assert!(pins(&simple_query("CREATE TEMP TABLE t (id int)")));
+        assert!(pins(&simple_query("create temporary table t (id int)")));
+        assert!(pins(&simple_query("SELECT * INTO TEMP t FROM users")));
+        assert!(pins(&simple_query("SELECT pg_advisory_lock(1)")));
+        assert!(pins(&simple_query("SELECT pg_try_advisory_lock_shared(1)")));
+
+        assert!(pins(&simple_query("select PG_ADVISORY_LOCK_SHARED(1)")));
+
+        assert!(!pins(&simple_query("SELECT pg_advisory_xact_lock(1)")));
+        assert!(!pins(&simple_query("CREATE TABLE t (id int)")));
+        assert!(!pins(&simple_query("SELECT 1")));
+
+        // Whole words only.
+        assert!(!pins(&simple_query("SELECT temperature FROM weather")));
+        assert!(!pins(&simple_query("SELECT * FROM templates")));
+        assert!(!pins(&simple_query("SELECT my_pg_advisory_lock(1)")));
+
+        // In the SQL of Parse messages, not their names or parameters.
+        let mut parse = BytesMut::from(&b"P"[..]);
+        let body = b"temp\0SELECT $1\0\0\0";
+        parse.put_i32(4 + body.len() as i32);
+        parse.put_slice(body);
+        assert!(!pins(&parse));
+
+        let mut bind = BytesMut::from(&b"B"[..]);
+        let body = b"\0\0\0\0\0\x01\0\0\0\x04temp\0\0";
+        bind.put_i32(4 + body.len() as i32);
+        bind.put_slice(body);
+        assert!(!pins(&bind));
+
+        let mut parse = BytesMut::from(&b"P"[..]);
+        let body = b"\0CREATE TEMPORARY TABLE t (id int)\0\0\0";
+        parse.put_i32(4 + body.len() as i32);
+        parse.put_slice(body);
+        assert!(pins(&[bind, parse].concat()));
+    }
+
+    #[test]
+    fn test_sets() {
+        assert!(sets(&simple_query(
+            "SELECT set_config('search_path', 'app', false)"
+        )));
+        assert!(sets(&simple_query(
+            "select pg_catalog.SET_CONFIG('work_mem', '64MB', false)"
+        )));
+
+        assert!(!sets(&simple_query(
+            "SELECT current_setting('search_path')"
+        )));
+        assert!(!sets(&simple_query("SELECT my_set_config()")));
+    }
+
+    #[test]
+    fn test_parse_data_row() {
+        let row = data_row(&vec![String::from("alice"), String::from("md5abc")]);
+        assert_eq!(
+            parse_data_row(BytesMut::from(&row[5..])),
+            Some(vec![
+                Some(String::from("alice")),
+                Some(String::from("md5abc"))
+            ])
+        );
+
+        // NULL
+        let mut row = BytesMut::new();
+        row.put_i16(1);
+        row.put_i32(-1);
+        assert_eq!(parse_data_row(row), Some(vec![None]));
+
+        // More columns, or longer ones, than the message has.
+        let mut row = BytesMut::new();
+        row.put_i16(2);
+        row.put_i32(1);
+        row.put_u8(b'a');
+        assert_eq!(parse_data_row(row), None);
+
+        let mut row = BytesMut::new();
+        row.put_i16(1);
+        row.put_i32(100);
+        row.put_slice(b"short");
+        assert_eq!(parse_data_row(row), None);
+
+        assert_eq!(parse_data_row(BytesMut::from(&b"\x00"[..])), None);
+    }
+
+    #[test]
+    fn test_extended_query() {
+        let messages = extended_query("SELECT $1", &["it's \\"]);
+        let has_param = |message: &[u8]| message.windows(6).any(|bytes| bytes == b"it's \\");
+        let mut rest = &messages[..];
+
+        // The parameter is sent as is, in the Bind, not in the query of the Parse.
+        for code in [b'P', b'B', b'E', b'S'] {
+            assert_eq!(rest[0], code);
+            let len = i32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]) as usize;
+            assert_eq!(has_param(&rest[..len + 1]), code == b'B');
+            rest = &rest[len + 1..];
+        }
+
+        assert!(rest.is_empty());
+    }
+}
@@ -1,6 +1,9 @@
 /// Implements various sharding functions.
+use chrono::NaiveDate;
 use sha1::{Digest, Sha1};
 
+use crate::config::ShardingRange;
+
 /// See: <https://github.com/postgres/postgres/blob/27b77ecf9f4d5be211900eda54d8155ada50d696/src/include/catalog/partition.h#L20>.
 const PARTITION_HASH_SEED: u64 = 0x7A5B22367996DCFD;
 
@@ -9,6 +12,133 @@ const PARTITION_HASH_SEED: u64 = 0x7A5B22367996DCFD;
 pub enum ShardingFunction {
     PgBigintHash,
     Sha1,
+    Murmur,
+}
+
+/// How the hash of the key is turned into a shard.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ShardingMode {
+    /// The hash modulo the number of shards, like Postgres' hash partitions.
+    Modulo,
+
+    /// The hashes are split into as many ranges of the same size as there are shards,
+    /// e.g. the lowest half goes to shard 0 and the highest to shard 1 with two shards.
+    Range,
+}
+
+/// A sharding key: a bigint, a date, a UUID, or any other text.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub enum ShardingKey {
+    Integer(i64),
+    Date(NaiveDate),
+    Uuid([u8; 16]),
+    Text(String),
+}
+
+impl ShardingKey {
+    /// The key of `SET SHARDING KEY`, or of a range's bound, of the pool's
+    /// sharding_key_type: a bigint, a date like `2024-01-31`, a UUID like
+    /// `a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11`, or any text. None if it's empty
+    /// or not of that type.
+    pub fn parse(value: &str, key_type: &str) -> Option<ShardingKey> {
+        if value.is_empty() {
+            return None;
+        }
+
+        match key_type {
+            "bigint" => value.parse::<i64>().ok().map(ShardingKey::Integer),
+            "date" => NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()
+                .map(ShardingKey::Date),
+            "uuid" => parse_uuid(value).map(ShardingKey::Uuid),
+            "text" => Some(ShardingKey::Text(value.to_string())),
+            _ => unreachable!(),
+        }
+    }
+
+    /// The bytes murmur hashes the key as, and pg_bigint_hash too, unless it's an integer.
+    /// An integer is its 8 bytes, little-endian, and a UUID its 16 bytes. Text is UTF-8,
+    /// and a date is its text, `YYYY-MM-DD`. SHA1 hashes the key's text instead.
+    fn bytes(&self) -> Vec<u8> {
+        match self {
+            ShardingKey::Integer(key) => key.to_le_bytes().to_vec(),
+            ShardingKey::Uuid(uuid) => uuid.to_vec(),
+            key => key.to_string().into_bytes(),
+        }
+    }
+}
+
+impl std::fmt::Display for ShardingKey {
+    /// The key as text, the UUIDs in lowercase with hyphens.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShardingKey::Integer(key) => write!(f, "{}", key),
+            ShardingKey::Date(date) => write!(f, "{}", date.format("%Y-%m-%d")),
+            ShardingKey::Uuid(uuid) => {
+                for (index, byte) in uuid.iter().enumerate() {
+                    if [4, 6, 8, 10].contains(&index) {
+                        write!(f, "-")?;
+                    }
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            }
+            ShardingKey::Text(key) => write!(f, "{}", key),
+        }
+    }
+}
+
+/// A UUID, 32 hex digits in groups of 8, 4, 4, 4 and 12 separated by hyphens, in
+/// lower or upper case.
+fn parse_uuid(value: &str) -> Option<[u8; 16]> {
+    let groups = value.split('-').map(str::len).collect::<Vec<usize>>();
+
+    if groups != [8, 4, 4, 4, 12] {
+        return None;
+    }
+
+    let hex = value.replace('-', "");
+    let mut uuid = [0; 16];
+
+    for (index, byte) in uuid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(index * 2..index * 2 + 2)?, 16).ok()?;
+    }
+
+    Some(uuid)
+}
+
+/// Range sharding: the shard of the first range that has the key, from its `from`
+/// (included) to its `to` (excluded). None if no range has it.
+pub fn range_shard(ranges: &[ShardingRange], key: &ShardingKey) -> Option<usize> {
+    ranges
+        .iter()
+        .find(|range| {
+            same_kind(range, key)
+                && range
+                    .from
+                    .as_ref()
+                    .and_then(|from| from.key())
+                    .is_none_or(|from| from <= *key)
+                && range
+                    .to
+                    .as_ref()
+                    .and_then(|to| to.key())
+                    .is_none_or(|to| *key < to)
+        })
+        .map(|range| range.shard)
+}
+
+/// The key is a date for the ranges of dates, a bigint for the others.
+fn same_kind(range: &ShardingRange, key: &ShardingKey) -> bool {
+    match range
+        .from
+        .as_ref()
+        .or(range.to.as_ref())
+        .and_then(|bound| bound.key())
+    {
+        Some(bound) => std::mem::discriminant(&bound) == std::mem::discriminant(key),
+        None => true,
+    }
 }
 
 /// The sharder.
@@ -18,22 +148,88 @@ pub struct Sharder {
 
     /// The sharding function in use.
     sharding_function: ShardingFunction,
+
+    /// Modulo or range.
+    sharding_mode: ShardingMode,
+
+    /// The seed of the hash, instead of the sharding function's own.
+    seed: Option<u64>,
 }
 
 impl Sharder {
     /// Create new instance of the sharder.
-    pub fn new(shards: usize, sharding_function: ShardingFunction) -> Sharder {
+    pub fn new(
+        shards: usize,
+        sharding_function: ShardingFunction,
+        sharding_mode: ShardingMode,
+        seed: Option<u64>,
+    ) -> Sharder {
         Sharder {
             shards,
             sharding_function,
+            sharding_mode,
+            seed,
         }
     }
 
+    /// The sharder of a pool's settings, i.e. its sharding_function and sharding_mode.
+    pub fn from_settings(
+        shards: usize,
+        sharding_function: &str,
+        sharding_mode: &str,
+        seed: Option<u64>,
+    ) -> Sharder {
+        let sharding_function = match sharding_function {
+            "pg_bigint_hash" => ShardingFunction::PgBigintHash,
+            "sha1" => ShardingFunction::Sha1,
+            "murmur" => ShardingFunction::Murmur,
+            _ => unreachable!(),
+        };
+
+        let sharding_mode = match sharding_mode {
+            "modulo" => ShardingMode::Modulo,
+            "range" => ShardingMode::Range,
+            _ => unreachable!(),
+        };
+
+        Sharder::new(shards, sharding_function, sharding_mode, seed)
+    }
+
     /// Compute the shard given sharding key.
-    pub fn shard(&self, key: i64) -> usize {
-        match self.sharding_function {
-            ShardingFunction::PgBigintHash => self.pg_bigint_hash(key),
+    pub fn shard(&self, key: &ShardingKey) -> usize {
+        let hash = match self.sharding_function {
+            ShardingFunction::PgBigintHash => match key {
+                ShardingKey::Integer(key) => self.pg_bigint_hash(*key),
+                key => self.pg_bytes_hash(&key.bytes()),
+            },
             ShardingFunction::Sha1 => self.sha1(key),
+            ShardingFunction::Murmur => self.murmur(key) as u64,
+        };
+
+        match self.sharding_mode {
+            ShardingMode::Modulo => (hash % self.shards as u64) as usize,
+            ShardingMode::Range => ((hash as u128 * self.shards as u128) >> self.bits()) as usize,
+        }
+    }
+
+    /// The hashes of the keys of the shard with the range mode: from (included)
+    /// to (excluded), none for the last shard, up to the largest hash.
+    pub fn hash_range(&self, shard: usize) -> (u64, Option<u64>) {
+        let bound = |shard: usize| ((shard as u128) << self.bits()).div_ceil(self.shards as u128);
+
+        let to = match shard + 1 < self.shards {
+            true => Some(bound(shard + 1) as u64),
+            false => None,
+        };
+
+        (bound(shard) as u64, to)
+    }
+
+    /// The size of the hashes of the sharding function, in bits.
+    fn bits(&self) -> u32 {
+        match self.sharding_function {
+            ShardingFunction::PgBigintHash => 64,
+            ShardingFunction::Sha1 | ShardingFunction::Murmur => 32,
         }
     }
 
@@ -41,18 +237,32 @@ impl Sharder {
     /// to put the row in when using HASH(column) partitioning.
     /// Source: <https://github.com/postgres/postgres/blob/27b77ecf9f4d5be211900eda54d8155ada50d696/src/common/hashfn.c#L631>.
     /// Supports only 1 bigint at the moment, but we can add more later.
-    fn pg_bigint_hash(&self, key: i64) -> usize {
+    fn pg_bigint_hash(&self, key: i64) -> u64 {
         let mut lohalf = key as u32;
         let hihalf = (key >> 32) as u32;
         lohalf ^= if key >= 0 { hihalf } else { !hihalf };
-        Self::combine(0, Self::pg_u32_hash(lohalf)) as usize % self.shards
+        Self::combine(
+            0,
+            Self::pg_u32_hash(lohalf, self.seed.unwrap_or(PARTITION_HASH_SEED)),
+        )
     }
 
-    /// Example of a hashing function based on SHA1.
-    fn sha1(&self, key: i64) -> usize {
+    /// Hash function used by Postgres for HASH(column) partitioning of the text and uuid
+    /// columns, hash_bytes_extended() of their bytes, e.g. of the text in UTF-8 with a
+    /// deterministic collation. Same as pg_u32_hash() with the 4 bytes of a u32.
+    /// Source: <https://github.com/postgres/postgres/blob/27b77ecf9f4d5be211900eda54d8155ada50d696/src/common/hashfn.c#L374>.
+    fn pg_bytes_hash(&self, key: &[u8]) -> u64 {
+        Self::combine(
+            0,
+            Self::pg_hash_bytes(key, self.seed.unwrap_or(PARTITION_HASH_SEED)),
+        )
+    }
+
+    /// Example of a hashing function based on SHA1, of the key as text. It has no seed.
+    fn sha1(&self, key: &ShardingKey) -> u64 {
         let mut hasher = Sha1::new();
 
-        hasher.update(&key.to_string().as_bytes());
+        hasher.update(key.to_string().as_bytes());
 
         let result = hasher.finalize();
 
@@ -60,9 +270,51 @@ impl Sharder {
         let hex = format!("{:x}", result);
 
         // Parse the last 8 bytes as an integer (8 bytes = bigint).
-        let key = i64::from_str_radix(&hex[hex.len() - 8..], 16).unwrap() as usize;
+        u64::from_str_radix(&hex[hex.len() - 8..], 16).unwrap()
+    }
+
+    /// MurmurHash3 (x86, 32 bits) of the key's bytes, e.g. the 8 bytes of a bigint,
+    /// little-endian, with the lowest 32 bits of the seed, 0 by default.
+    /// Source: <https://github.com/aappleby/smhasher/blob/61a0530f28277f2e850bfc39600ce61d02b518de/src/MurmurHash3.cpp#L94>.
+    fn murmur(&self, key: &ShardingKey) -> u32 {
+        Self::murmur3_32(&key.bytes(), self.seed.unwrap_or(0) as u32)
+    }
+
+    fn murmur3_32(data: &[u8], seed: u32) -> u32 {
+        const C1: u32 = 0xcc9e2d51;
+        const C2: u32 = 0x1b873593;
+
+        let mix = |mut k: u32| {
+            k = k.wrapping_mul(C1);
+            k = k.rotate_left(15);
+            k.wrapping_mul(C2)
+        };
+
+        let mut h = seed;
+        let mut blocks = data.chunks_exact(4);
+
+        for block in &mut blocks {
+            h ^= mix(u32::from_le_bytes([block[0], block[1], block[2], block[3]]));
+            h = h.rotate_left(13);
+            h = h.wrapping_mul(5).wrapping_add(0xe6546b64);
+        }
 
-        key % self.shards
+        let tail = blocks.remainder();
+        if !tail.is_empty() {
+            let k = tail
+                .iter()
+                .rev()
+                .fold(0u32, |k, &byte| (k << 8) | byte as u32);
+            h ^= mix(k);
+        }
+
+        h ^= data.len() as u32;
+        h ^= h >> 16;
+        h = h.wrapping_mul(0x85ebca6b);
+        h ^= h >> 13;
+        h = h.wrapping_mul(0xc2b2ae35);
+        h ^= h >> 16;
+        h
     }
 
     #[inline]
@@ -128,14 +380,16 @@ impl Sharder {
     }
 
     #[inline]
-    fn pg_u32_hash(k: u32) -> u64 {
+    fn pg_u32_hash(k: u32, seed: u64) -> u64 {
         let mut a: u32 = 0x9e3779b9 as u32 + std::mem::size_of::<u32>() as u32 + 3923095 as u32;
         let mut b = a;
-        let c = a;
+        let mut c = a;
 
-        a = a.wrapping_add((PARTITION_HASH_SEED >> 32) as u32);
-        b = b.wrapping_add(PARTITION_HASH_SEED as u32);
-        let (mut a, b, c) = Self::mix(a, b, c);
+        if seed != 0 {
+            a = a.wrapping_add((seed >> 32) as u32);
+            b = b.wrapping_add(seed as u32);
+            (a, b, c) = Self::mix(a, b, c);
+        }
 
         a = a.wrapping_add(k);
 
@@ -143,6 +397,51 @@ impl Sharder {
 
         ((b as u64) << 32) | (c as u64)
     }
+
+    fn pg_hash_bytes(k: &[u8], seed: u64) -> u64 {
+        let mut a = 0x9e3779b9_u32
+            .wrapping_add(k.len() as u32)
+            .wrapping_add(3923095);
+        let mut b = a;
+        let mut c = a;
+
+        if seed != 0 {
+            a = a.wrapping_add((seed >> 32) as u32);
+            b = b.wrapping_add(seed as u32);
+            (a, b, c) = Self::mix(a, b, c);
+        }
+
+        // Little-endian words.
+        let word = |bytes: &[u8]| {
+            bytes
+                .iter()
+                .rev()
+                .fold(0u32, |word, &byte| (word << 8) | byte as u32)
+        };
+
+        let mut blocks = k.chunks_exact(12);
+
+        for block in &mut blocks {
+            a = a.wrapping_add(word(&block[0..4]));
+            b = b.wrapping_add(word(&block[4..8]));
+            c = c.wrapping_add(word(&block[8..12]));
+            (a, b, c) = Self::mix(a, b, c);
+        }
+
+        // The last 11 bytes, the lowest byte of c is reserved for the length.
+        let tail = blocks.remainder();
+        a = a.wrapping_add(word(&tail[..tail.len().min(4)]));
+        if tail.len() > 4 {
+            b = b.wrapping_add(word(&tail[4..tail.len().min(8)]));
+        }
+        if tail.len() > 8 {
+            c = c.wrapping_add(word(&tail[8..]) << 8);
+        }
+
+        let (_a, b, c) = Self::_final(a, b, c);
+
+        ((b as u64) << 32) | (c as u64)
+    }
 }
 
 #[cfg(test)]
@@ -154,42 +453,47 @@ mod test {
     // confirming that we implemented Postgres BIGINT hashing correctly.
     #[test]
     fn test_pg_bigint_hash() {
-        let sharder = Sharder::new(5, ShardingFunction::PgBigintHash);
+        let sharder = Sharder::new(
+            5,
+            ShardingFunction::PgBigintHash,
+            ShardingMode::Modulo,
+            None,
+        );
 
         let shard_0 = vec![1, 4, 5, 14, 19, 39, 40, 46, 47, 53];
 
         for v in shard_0 {
-            assert_eq!(sharder.shard(v), 0);
+            assert_eq!(sharder.shard(&ShardingKey::Integer(v)), 0);
         }
 
         let shard_1 = vec![2, 3, 11, 17, 21, 23, 30, 49, 51, 54];
 
         for v in shard_1 {
-            assert_eq!(sharder.shard(v), 1);
+            assert_eq!(sharder.shard(&ShardingKey::Integer(v)), 1);
         }
 
         let shard_2 = vec![6, 7, 15, 16, 18, 20, 25, 28, 34, 35];
 
         for v in shard_2 {
-            assert_eq!(sharder.shard(v), 2);
+            assert_eq!(sharder.shard(&ShardingKey::Integer(v)), 2);
         }
 
         let shard_3 = vec![8, 12, 13, 22, 29, 31, 33, 36, 41, 43];
 
         for v in shard_3 {
-            assert_eq!(sharder.shard(v), 3);
+            assert_eq!(sharder.shard(&ShardingKey::Integer(v)), 3);
         }
 
         let shard_4 = vec![9, 10, 24, 26, 27, 32, 37, 38, 42, 45];
 
         for v in shard_4 {
-            assert_eq!(sharder.shard(v), 4);
+            assert_eq!(sharder.shard(&ShardingKey::Integer(v)), 4);
         }
     }
 
     #[test]
     fn test_sha1_hash() {
-        let sharder = Sharder::new(12, ShardingFunction::Sha1);
+        let sharder = Sharder::new(12, ShardingFunction::Sha1, ShardingMode::Modulo, None);
         let ids = vec![
             0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19,
         ];
@@ -198,7 +502,278 @@ mod test {
         ];
 
         for (i, id) in ids.iter().enumerate() {
-            assert_eq!(sharder.shard(*id), shards[i]);
+            assert_eq!(sharder.shard(&ShardingKey::Integer(*id)), shards[i]);
+        }
+    }
+
+    #[test]
+    fn test_murmur3_32() {
+        assert_eq!(Sharder::murmur3_32(b"", 0), 0);
+        assert_eq!(Sharder::murmur3_32(b"", 1), 0x514e28b7);
+        assert_eq!(Sharder::murmur3_32(b"test", 0), 0xba6bd213);
+        assert_eq!(Sharder::murmur3_32(b"Hello, world!", 1234), 0xfaf6cdb3);
+        assert_eq!(Sharder::murmur3_32(b"abc", 0), 0xb3dd93fa);
+    }
+
+    #[test]
+    fn test_sharding_mode() {
+        // The lowest quarter of the hashes goes to shard 0, the highest to shard 3.
+        let sharder = Sharder::new(4, ShardingFunction::Murmur, ShardingMode::Range, None);
+        let modulo = Sharder::new(4, ShardingFunction::Murmur, ShardingMode::Modulo, None);
+
+        for key in 0..100 {
+            let hash = Sharder::murmur3_32(&i64::to_le_bytes(key), 0);
+            assert_eq!(
+                sharder.shard(&ShardingKey::Integer(key)),
+                (hash / (1 << 30)) as usize
+            );
+            assert_eq!(
+                modulo.shard(&ShardingKey::Integer(key)),
+                (hash % 4) as usize
+            );
+        }
+
+        let sharder = Sharder::new(3, ShardingFunction::PgBigintHash, ShardingMode::Range, None);
+        let mut counts = [0; 3];
+        for key in 0..3000 {
+            counts[sharder.shard(&ShardingKey::Integer(key))] += 1;
         }
+        assert!(counts.iter().all(|&count| (800..=1200).contains(&count)));
+    }
+
+    #[test]
+    fn test_seed() {
+        let default = Sharder::new(
+            5,
+            ShardingFunction::PgBigintHash,
+            ShardingMode::Modulo,
+            None,
+        );
+        let same = Sharder::new(
+            5,
+            ShardingFunction::PgBigintHash,
+            ShardingMode::Modulo,
+            Some(PARTITION_HASH_SEED),
+        );
+        let seeded = Sharder::new(
+            5,
+            ShardingFunction::PgBigintHash,
+            ShardingMode::Modulo,
+            Some(42),
+        );
+
+        assert!((0..100).all(|key| default.shard(&ShardingKey::Integer(key))
+            == same.shard(&ShardingKey::Integer(key))));
+        assert!((0..100).any(|key| default.shard(&ShardingKey::Integer(key))
+            != seeded.shard(&ShardingKey::Integer(key))));
+
+        let default = Sharder::new(5, ShardingFunction::Murmur, ShardingMode::Modulo, None);
+        let seeded = Sharder::new(5, ShardingFunction::Murmur, ShardingMode::Modulo, Some(42));
+        assert!((0..100).any(|key| default.shard(&ShardingKey::Integer(key))
+            != seeded.shard(&ShardingKey::Integer(key))));
+    }
+
+    #[test]
+    fn test_sharding_key() {
+        assert_eq!(
+            ShardingKey::parse("1234", "bigint"),
+            Some(ShardingKey::Integer(1234))
+        );
+        assert_eq!(
+            ShardingKey::parse("2024-01-31", "date"),
+            Some(ShardingKey::Date(
+                NaiveDate::from_ymd_opt(2024, 1, 31).unwrap()
+            ))
+        );
+        assert_eq!(ShardingKey::parse("2024-02-30", "date"), None);
+        assert_eq!(ShardingKey::parse("99999999999999999999", "bigint"), None);
+
+        // Of the pool's type, whatever they look like.
+        assert_eq!(
+            ShardingKey::parse("1234", "text"),
+            Some(ShardingKey::Text(String::from("1234")))
+        );
+        assert_eq!(
+            ShardingKey::parse("2024-01-31", "text"),
+            Some(ShardingKey::Text(String::from("2024-01-31")))
+        );
+        assert_eq!(ShardingKey::parse("2024-01-31", "bigint"), None);
+        assert_eq!(ShardingKey::parse("acme", "uuid"), None);
+    }
+
+    #[test]
+    fn test_range_shard() {
+        use crate::config::RangeBound;
+
+        let range = |shard: usize, from: Option<RangeBound>, to: Option<RangeBound>| {
+            ShardingRange { shard, from, to }
+        };
+        let key = |value: &str| ShardingKey::parse(value, "bigint").unwrap();
+
+        let ranges = vec![
+            range(0, None, Some(RangeBound::Integer(1_000_000))),
+            range(
+                1,
+                Some(RangeBound::Integer(1_000_000)),
+                Some(RangeBound::Integer(2_000_000)),
+            ),
+            range(2, Some(RangeBound::Integer(3_000_000)), None),
+        ];
+
+        assert_eq!(range_shard(&ranges, &key("1")), Some(0));
+        assert_eq!(range_shard(&ranges, &key("999999")), Some(0));
+        assert_eq!(range_shard(&ranges, &key("1000000")), Some(1));
+        assert_eq!(range_shard(&ranges, &key("2500000")), None);
+        assert_eq!(range_shard(&ranges, &key("3000000")), Some(2));
+
+        let key = |value: &str| ShardingKey::parse(value, "date").unwrap();
+        assert_eq!(range_shard(&ranges, &key("2024-01-01")), None);
+
+        let ranges = vec![
+            range(
+                0,
+                Some(RangeBound::Text(String::from("2023-01-01"))),
+                Some(RangeBound::Text(String::from("2024-01-01"))),
+            ),
+            range(1, Some(RangeBound::Text(String::from("2024-01-01"))), None),
+        ];
+
+        assert_eq!(range_shard(&ranges, &key("2023-06-15")), Some(0));
+        assert_eq!(range_shard(&ranges, &key("2024-01-01")), Some(1));
+        assert_eq!(range_shard(&ranges, &key("2022-12-31")), None);
+        assert_eq!(
+            range_shard(&ranges, &ShardingKey::parse("5", "bigint").unwrap()),
+            None
+        );
+    }
+
+    // See the tenants tables in tests/sharding/partition_hash_test_setup.sql.
+    #[test]
+    fn test_pg_text_and_uuid_hash() {
+        let sharder = Sharder::new(
+            3,
+            ShardingFunction::PgBigintHash,
+            ShardingMode::Modulo,
+            None,
+        );
+        let shard =
+            |key: &str, key_type: &str| sharder.shard(&ShardingKey::parse(key, key_type).unwrap());
+
+        for (key, expected) in [
+            ("initech", 0),
+            ("stark industries", 1),
+            ("umbrella", 1),
+            ("wayne enterprises", 1),
+            ("acme", 2),
+            ("globex", 2),
+            ("hooli", 2),
+        ] {
+            assert_eq!(shard(key, "text"), expected, "{}", key);
+        }
+
+        for (key, expected) in [
+            ("00000000-0000-0000-0000-000000000000", 0),
+            ("F47AC10B-58CC-4372-A567-0E02B2C3D479", 0),
+            ("123e4567-e89b-12d3-a456-426614174000", 1),
+            ("6ba7b810-9dad-11d1-80b4-00c04fd430c8", 1),
+            ("a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11", 1),
+        ] {
+            assert_eq!(shard(key, "uuid"), expected, "{}", key);
+        }
+
+        // SELECT hashtextextended('acme', 8816678312871386365), hashtextextended('', 0), ...
+        let hash = |key: &[u8], seed: u64| Sharder::pg_hash_bytes(key, seed) as i64;
+        assert_eq!(hash(b"acme", PARTITION_HASH_SEED), -3006037837944560592);
+        assert_eq!(hash(b"", PARTITION_HASH_SEED), -5700645584453517373);
+        assert_eq!(
+            hash(b"hello world, this is longer", PARTITION_HASH_SEED),
+            -1207500902989864076
+        );
+        assert_eq!(hash(b"abc", 0), -6747756470228489321);
+
+        // hashint8extended(5, 0)
+        assert_eq!(Sharder::pg_u32_hash(5, 0) as i64, -9138765687874534020);
+
+        // The same as the hash of the 4 bytes.
+        for (k, seed) in [(0, 0), (5, PARTITION_HASH_SEED), (u32::MAX, 42)] {
+            assert_eq!(
+                Sharder::pg_u32_hash(k, seed),
+                Sharder::pg_hash_bytes(&k.to_le_bytes(), seed)
+            );
+        }
+    }
+
+    #[test]
+    fn test_text_and_uuid_keys() {
+        let uuid = ShardingKey::parse("A0EEBC99-9c0b-4ef8-bb6d-6bb9bd380a11", "uuid").unwrap();
+        assert!(matches!(uuid, ShardingKey::Uuid(_)));
+        assert_eq!(uuid.to_string(), "a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11");
+
+        assert_eq!(
+            ShardingKey::parse("a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a1g", "uuid"),
+            None
+        );
+        assert_eq!(ShardingKey::parse("", "text"), None);
+
+        // The same keys go to the same shards, whatever the function.
+        for function in [
+            ShardingFunction::PgBigintHash,
+            ShardingFunction::Sha1,
+            ShardingFunction::Murmur,
+        ] {
+            let sharder = Sharder::new(8, function, ShardingMode::Modulo, None);
+            let text = ShardingKey::parse("acme", "text").unwrap();
+            assert_eq!(sharder.shard(&text), sharder.shard(&text.clone()));
+            assert_eq!(
+                sharder.shard(&ShardingKey::parse("42", "bigint").unwrap()),
+                sharder.shard(&ShardingKey::Integer(42))
+            );
+        }
+
+        // The text of the key, the 16 bytes of the UUID.
+        let sharder = Sharder::new(
+            1 << 20,
+            ShardingFunction::Murmur,
+            ShardingMode::Modulo,
+            None,
+        );
+        assert_eq!(
+            sharder.shard(&ShardingKey::parse("acme", "text").unwrap()),
+            Sharder::murmur3_32(b"acme", 0) as usize % (1 << 20)
+        );
+        assert_eq!(
+            sharder.shard(&uuid),
+            Sharder::murmur3_32(
+                &parse_uuid("a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11").unwrap(),
+                0
+            ) as usize
+                % (1 << 20)
+        );
+    }
+
+    #[test]
+    fn test_hash_range() {
+        for (function, bits) in [("pg_bigint_hash", 64), ("sha1", 32)] {
+            let sharder = Sharder::from_settings(3, function, "range", None);
+
+            // The bounds are the first hashes of the shard, and of the next one.
+            for shard in 0..3 {
+                let (from, to) = sharder.hash_range(shard);
+                assert_eq!(((from as u128 * 3) >> bits) as usize, shard);
+
+                match to {
+                    Some(to) => {
+                        assert_eq!(((to as u128 * 3) >> bits) as usize, shard + 1);
+                        assert_eq!((((to - 1) as u128 * 3) >> bits) as usize, shard);
+                    }
+                    None => assert_eq!(shard, 2),
+                }
+            }
+        }
+
+        assert_eq!(
+            Sharder::from_settings(2, "murmur", "range", None).hash_range(1),
+            (1 << 31, None)
+        );
     }
 }
@@ -0,0 +1,219 @@
+/// Lookup-table sharding: the shard of each key of the pools with a sharding_directory,
+/// loaded from a file or a query on a metadata database, and reloaded periodically, e.g.
+/// for tenants of very different sizes placed on the shards by hand.
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::{get_config, Role, ShardingDirectory};
+use crate::pool::get_all_pools;
+
+/// A directory loaded.
+struct Directory {
+    /// The config it was loaded with, so it's loaded again when it changes.
+    config: ShardingDirectory,
+    loaded_at: Instant,
+
+    /// Key to shard.
+    shards: Arc<HashMap<String, usize>>,
+}
+
+/// The directories, by pool name.
+static DIRECTORIES: Lazy<Mutex<HashMap<String, Directory>>> =
+    Lazy::new(|| Mutex::new(HashMap::default()));
+
+/// The shard of the key in the directory of the pool, if it has one.
+pub fn shard(pool_name: &str, key: &str) -> Option<usize> {
+    DIRECTORIES
+        .lock()
+        .get(pool_name)
+        .and_then(|directory| directory.shards.get(key).copied())
+}
+
+/// The number of keys of the shard in the directory of the pool, for SHOW SHARDS.
+pub fn keys(pool_name: &str, shard: usize) -> usize {
+    DIRECTORIES.lock().get(pool_name).map_or(0, |directory| {
+        directory
+            .shards
+            .values()
+            .filter(|key_shard| **key_shard == shard)
+            .count()
+    })
+}
+
+/// Load the directories not loaded yet, changed in the config, or loaded more than their
+/// refresh_interval ago. When one can't be loaded, the pool keeps the one it had.
+pub async fn refresh() {
+    let config = get_config();
+
+    // Forget the directories of pools that were removed from the config, or don't have one anymore.
+    DIRECTORIES.lock().retain(|pool_name, _| {
+        config
+            .pools
+            .get(pool_name)
+            .is_some_and(|pool| pool.sharding_directory.is_some())
+    });
+
+    for (pool_name, pool_config) in &config.pools {
+        let sharding_directory = match pool_config.sharding_directory {
+            Some(ref sharding_directory) => sharding_directory,
+            None => continue,
+        };
+
+        let due = match DIRECTORIES.lock().get(pool_name) {
+            Some(directory) => {
+                directory.config != *sharding_directory
+                    || directory.loaded_at.elapsed()
+                        >= Duration::from_secs(sharding_directory.refresh_interval)
+            }
+            None => true,
+        };
+
+        if !due {
+            continue;
+        }
+
+        let shards = pool_config.shards.len();
+        let loaded = match (&sharding_directory.file, &sharding_directory.query) {
+            (Some(file), _) => match tokio::fs::read_to_string(file).await {
+                Ok(contents) => Ok(parse(
+                    contents.lines().map(|line| line.split(',').collect()),
+                    shards,
+                )),
+                Err(err) => Err(format!("could not read {}: {}", file, err)),
+            },
+
+            (None, Some(query)) => {
+                let metadata_pool = sharding_directory.pool.as_ref().unwrap_or(pool_name);
+                fetch(metadata_pool, query).await.map(|rows| {
+                    parse(
+                        rows.iter().map(|row| {
+                            row.iter()
+                                .map(|value| value.as_deref().unwrap_or(""))
+                                .collect()
+                        }),
+                        shards,
+                    )
+                })
+            }
+
+            (None, None) => continue,
+        };
+
+        let mut directories = DIRECTORIES.lock();
+
+        match loaded {
+            Ok(loaded) => {
+                let changed = directories
+                    .get(pool_name)
+                    .is_none_or(|directory| *directory.shards != loaded);
+
+                if changed {
+                    info!(
+                        "Loaded the sharding directory of pool {}: {} keys",
+                        pool_name,
+                        loaded.len()
+                    );
+                }
+
+                directories.insert(
+                    pool_name.clone(),
+                    Directory {
+                        config: sharding_directory.clone(),
+                        loaded_at: Instant::now(),
+                        shards: Arc::new(loaded),
+                    },
+                );
+            }
+
+            Err(err) => {
+                warn!(
+                    "Could not load the sharding directory of pool {}: {}",
+                    pool_name, err
+                );
+
+                // Tried again at the next refresh_interval.
+                let shards = directories
+                    .get(pool_name)
+                    .map(|directory| directory.shards.clone())
+                    .unwrap_or_default();
+
+                directories.insert(
+                    pool_name.clone(),
+                    Directory {
+                        config: sharding_directory.clone(),
+                        loaded_at: Instant::now(),
+                        shards,
+                    },
+                );
+            }
+        };
+    }
+}
+
+/// Run the query on the first shard's primary of the pool, with its first user.
+async fn fetch(pool_name: &str, query: &str) -> Result<Vec<Vec<Option<String>>>, String> {
+    let pool = get_all_pools()
+        .into_iter()
+        .filter(|((name, _), _)| name == pool_name)
+        .map(|(_, pool)| pool)
+        .next()
+        .ok_or_else(|| format!("pool {} has no users", pool_name))?;
+
+    let primary = (0..pool.servers(0))
+        .find(|&server| pool.address(0, server).role == Role::Primary)
+        .unwrap_or(0);
+
+    pool.fetch(0, primary, query)
+        .await
+        .map_err(|err| format!("query failed: {:?}", err))
+}
+
+/// The keys and shards of the lines of the file, or the rows of the query: the key, then
+/// the shard. The others, e.g. comments starting with `#`, or to shards that don't exist,
+/// are skipped.
+fn parse<'a>(rows: impl Iterator<Item = Vec<&'a str>>, shards: usize) -> HashMap<String, usize> {
+    let mut directory = HashMap::new();
+
+    for row in rows {
+        let (key, shard) = match row.as_slice() {
+            [key, shard] if !key.trim_start().starts_with('#') => (key.trim(), shard.trim()),
+            _ => continue,
+        };
+
+        match shard.parse::<usize>() {
+            Ok(shard) if shard < shards && !key.is_empty() => {
+                directory.insert(key.to_string(), shard);
+            }
+            _ => warn!(
+                "Sharding directory: skipping key '{}' to shard '{}', there are {} shards",
+                key, shard, shards
+            ),
+        };
+    }
+
+    directory
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let contents = "# tenant,shard\nacme,0\n 42 , 1\nglobex,7\nbroken\n\ninitech,1";
+        let directory = parse(contents.lines().map(|line| line.split(',').collect()), 2);
+
+        assert_eq!(
+            directory,
+            HashMap::from([
+                (String::from("acme"), 0),
+                (String::from("42"), 1),
+                (String::from("initech"), 1),
+            ])
+        );
+    }
+}
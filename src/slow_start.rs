@@ -0,0 +1,65 @@
+/// The replicas warming up, with the pools' slow_start: after they're added by a reload
+/// or their ban expires, their share of the transactions grows from nothing to a full
+/// one over slow_start, so their cold caches don't slow down the clients all at once.
+use log::info;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// The servers are forgotten after this long, even if a pool's slow_start is longer.
+const MAX_SLOW_START: Duration = Duration::from_secs(3600);
+
+/// When each server (host and port) started warming up.
+static STARTED: Lazy<Mutex<HashMap<String, Instant>>> =
+    Lazy::new(|| Mutex::new(HashMap::default()));
+
+/// The server starts warming up, from now.
+pub fn start(server: &str) {
+    info!("Slow-starting server {}", server);
+
+    STARTED.lock().insert(server.to_string(), Instant::now());
+}
+
+/// The share of its transactions the server gets, from 0 when it starts warming up
+/// to 1 after slow_start, e.g. 0.5 halfway through. 1 if it's not warming up.
+pub fn share(server: &str, slow_start: Duration) -> f64 {
+    let mut started = STARTED.lock();
+
+    let elapsed = match started.get(server) {
+        Some(since) => since.elapsed(),
+        None => return 1.0,
+    };
+
+    // Warmed up, whatever the other pools' slow_start.
+    if elapsed >= slow_start {
+        if elapsed >= MAX_SLOW_START {
+            started.remove(server);
+        }
+
+        return 1.0;
+    }
+
+    elapsed.as_secs_f64() / slow_start.as_secs_f64()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_share() {
+        assert_eq!(share("127.0.0.1:5433", Duration::from_secs(60)), 1.0);
+
+        start("127.0.0.1:5433");
+        assert!(share("127.0.0.1:5433", Duration::from_secs(60)) < 0.1);
+        assert_eq!(share("127.0.0.1:5433", Duration::ZERO), 1.0);
+
+        STARTED.lock().insert(
+            String::from("127.0.0.1:5433"),
+            Instant::now() - Duration::from_secs(30),
+        );
+        let half = share("127.0.0.1:5433", Duration::from_secs(60));
+        assert!((0.49..0.52).contains(&half));
+    }
+}
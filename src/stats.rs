@@ -3,10 +3,11 @@ use arc_swap::ArcSwap;
 use log::info;
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-use crate::pool::get_number_of_addresses;
+use crate::pool::get_address_ids;
 
 pub static REPORTER: Lazy<ArcSwap<Reporter>> =
     Lazy::new(|| ArcSwap::from_pointee(Reporter::default()));
@@ -37,6 +38,8 @@ enum EventName {
     ServerTested,
     ServerLogin,
     ServerDisconnecting,
+    ServerPinned,
+    ReadFallback,
     UpdateStats,
     UpdateAverages,
 }
@@ -253,6 +256,33 @@ impl Reporter {
         let _ = self.tx.try_send(event);
     }
 
+    /// Reports a server connection identified by `process_id` for
+    /// a configured server identified by `address_id` is pinned to its client,
+    /// e.g. by temporary tables or advisory locks, or no longer is.
+    pub fn server_pinned(&self, pinned: bool, process_id: i32, address_id: usize) {
+        let event = Event {
+            name: EventName::ServerPinned,
+            value: pinned as i64,
+            process_id,
+            address_id,
+        };
+
+        let _ = self.tx.try_send(event);
+    }
+
+    /// Reports a client identified by `process_id` sent its reads to the primary
+    /// identified by `address_id` because all the replicas are down.
+    pub fn read_fallback(&self, process_id: i32, address_id: usize) {
+        let event = Event {
+            name: EventName::ReadFallback,
+            value: 1,
+            process_id,
+            address_id,
+        };
+
+        let _ = self.tx.try_send(event);
+    }
+
     /// Reports a server connection identified by `process_id` is disconecting from the pooler.
     /// The configured server it was connected to is identified by `address_id`.
     pub fn server_disconnecting(&self, process_id: i32, address_id: usize) {
@@ -298,6 +328,7 @@ impl Collector {
             ("total_xact_count", 0),
             ("total_xact_time", 0),
             ("total_wait_time", 0),
+            ("total_fallback_count", 0),
             ("avg_query_count", 0),
             ("avg_query_time", 0),
             ("avg_recv", 0),
@@ -310,6 +341,7 @@ impl Collector {
             ("cl_waiting", 0),
             ("cl_active", 0),
             ("cl_idle", 0),
+            ("cl_pinned", 0),
             ("sv_idle", 0),
             ("sv_active", 0),
             ("sv_login", 0),
@@ -325,6 +357,12 @@ impl Collector {
         // Track which state the client and server are at any given time.
         let mut client_server_states: HashMap<usize, HashMap<i32, EventName>> = HashMap::new();
 
+        // Since when the clients waiting for a server are, for maxwait.
+        let mut waiting_since: HashMap<usize, HashMap<i32, Instant>> = HashMap::new();
+
+        // The servers pinned to their clients.
+        let mut pinned: HashMap<usize, HashSet<i32>> = HashMap::new();
+
         // Flush stats to StatsD and calculate averages every 15 seconds.
         let tx = self.tx.clone();
         tokio::task::spawn(async move {
@@ -332,8 +370,14 @@ impl Collector {
                 tokio::time::interval(tokio::time::Duration::from_millis(STAT_PERIOD / 15));
             loop {
                 interval.tick().await;
-                let address_count = get_number_of_addresses();
-                for address_id in 0..address_count {
+                let address_ids = get_address_ids();
+
+                // The addresses of the pools replaced by a reload are gone.
+                LATEST_STATS
+                    .lock()
+                    .retain(|address_id, _| address_ids.contains(address_id));
+
+                for address_id in address_ids {
                     let _ = tx.try_send(Event {
                         name: EventName::UpdateStats,
                         value: 0,
@@ -350,8 +394,7 @@ impl Collector {
                 tokio::time::interval(tokio::time::Duration::from_millis(STAT_PERIOD));
             loop {
                 interval.tick().await;
-                let address_count = get_number_of_addresses();
-                for address_id in 0..address_count {
+                for address_id in get_address_ids() {
                     let _ = tx.try_send(Event {
                         name: EventName::UpdateAverages,
                         value: 0,
@@ -379,6 +422,8 @@ impl Collector {
                 .entry(stat.address_id)
                 .or_insert(HashMap::new());
             let old_stats = old_stats.entry(stat.address_id).or_insert(HashMap::new());
+            let waiting_since = waiting_since.entry(stat.address_id).or_default();
+            let pinned = pinned.entry(stat.address_id).or_default();
 
             // Some are counters, some are gauges...
             match stat.name {
@@ -405,21 +450,11 @@ impl Collector {
                 EventName::CheckoutTime => {
                     let counter = stats.entry("total_wait_time").or_insert(0);
                     *counter += stat.value;
+                }
 
-                    let counter = stats.entry("maxwait_us").or_insert(0);
-                    let mic_part = stat.value % 1_000_000;
-
-                    // Report max time here
-                    if mic_part > *counter {
-                        *counter = mic_part;
-                    }
-
-                    let counter = stats.entry("maxwait").or_insert(0);
-                    let seconds = *counter / 1_000_000;
-
-                    if seconds > *counter {
-                        *counter = seconds;
-                    }
+                EventName::ReadFallback => {
+                    let counter = stats.entry("total_fallback_count").or_insert(0);
+                    *counter += stat.value;
                 }
 
                 EventName::ClientActive
@@ -429,11 +464,29 @@ impl Collector {
                 | EventName::ServerIdle
                 | EventName::ServerTested
                 | EventName::ServerLogin => {
+                    if let EventName::ClientWaiting = stat.name {
+                        waiting_since
+                            .entry(stat.process_id)
+                            .or_insert_with(Instant::now);
+                    } else {
+                        waiting_since.remove(&stat.process_id);
+                    }
+
                     client_server_states.insert(stat.process_id, stat.name);
                 }
 
                 EventName::ClientDisconnecting | EventName::ServerDisconnecting => {
                     client_server_states.remove(&stat.process_id);
+                    waiting_since.remove(&stat.process_id);
+                    pinned.remove(&stat.process_id);
+                }
+
+                EventName::ServerPinned => {
+                    if stat.value > 0 {
+                        pinned.insert(stat.process_id);
+                    } else {
+                        pinned.remove(&stat.process_id);
+                    }
                 }
 
                 EventName::UpdateStats => {
@@ -479,6 +532,17 @@ impl Collector {
                         };
                     }
 
+                    // Each pinned server has one client.
+                    stats.insert("cl_pinned", pinned.len() as i64);
+
+                    // How long the first client in the queue has been waiting.
+                    if let Some(since) = waiting_since.values().min() {
+                        let maxwait = since.elapsed();
+
+                        stats.insert("maxwait", maxwait.as_secs() as i64);
+                        stats.insert("maxwait_us", maxwait.subsec_micros() as i64);
+                    }
+
                     // Update latest stats used in SHOW STATS
                     let mut guard = LATEST_STATS.lock();
                     for (key, value) in stats.iter() {
@@ -1,13 +1,28 @@
 // Stream wrapper.
 
+use arc_swap::ArcSwap;
+use log::{error, info};
+use once_cell::sync::Lazy;
 use rustls_pemfile::{certs, rsa_private_keys};
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::collections::HashMap;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio_rustls::rustls::{self, Certificate, PrivateKey};
+use std::time::SystemTime;
+use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, NoClientAuth, NoServerSessionStorage, ProducesTickets,
+    ServerSessionMemoryCache, StoresServerSessions,
+};
+use tokio_rustls::rustls::{self, Certificate, PrivateKey, RootCertStore, ServerName};
 use tokio_rustls::TlsAcceptor;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
 
-use crate::config::get_config;
+use crate::config::{get_config, ServerTlsSettings};
 use crate::errors::Error;
+use crate::revocation;
 
 // TLS
 pub fn load_certs(path: &Path) -> std::io::Result<Vec<Certificate>> {
@@ -22,27 +37,355 @@ pub fn load_keys(path: &Path) -> std::io::Result<Vec<PrivateKey>> {
         .map(|mut keys| keys.drain(..).map(PrivateKey).collect())
 }
 
+/// Names the client certificate was issued to: the subject common names
+/// and the DNS and email subject alternative names.
+pub fn certificate_names(cert: &Certificate) -> Vec<String> {
+    let cert = match X509Certificate::from_der(&cert.0) {
+        Ok((_, cert)) => cert,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut names = cert
+        .subject()
+        .iter_common_name()
+        .filter_map(|cn| cn.as_str().ok())
+        .map(|cn| cn.to_string())
+        .collect::<Vec<String>>();
+
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in &san.value.general_names {
+            match name {
+                GeneralName::DNSName(name) | GeneralName::RFC822Name(name) => {
+                    names.push(name.to_string())
+                }
+                _ => (),
+            }
+        }
+    }
+
+    names
+}
+
+/// The tls-server-end-point channel binding data (RFC 5929): the hash of our certificate,
+/// with the hash function of its signature, except MD5 and SHA-1 are replaced with SHA-256.
+pub fn server_end_point(cert: &Certificate) -> Vec<u8> {
+    let algorithm = match X509Certificate::from_der(&cert.0) {
+        Ok((_, cert)) => cert.signature_algorithm.algorithm.to_id_string(),
+        Err(_) => String::new(),
+    };
+
+    match algorithm.as_ref() {
+        // sha384WithRSAEncryption, ecdsa-with-SHA384
+        "1.2.840.113549.1.1.12" | "1.2.840.10045.4.3.3" => Sha384::digest(&cert.0).to_vec(),
+
+        // sha512WithRSAEncryption, ecdsa-with-SHA512
+        "1.2.840.113549.1.1.13" | "1.2.840.10045.4.3.4" => Sha512::digest(&cert.0).to_vec(),
+
+        _ => Sha256::digest(&cert.0).to_vec(),
+    }
+}
+
+/// What we know about a client's TLS connection.
+#[derive(Clone, Debug, Default)]
+pub struct TlsInfo {
+    /// Names in the client certificate, if the client sent one.
+    pub client_cert_names: Vec<String>,
+
+    /// Our tls-server-end-point channel binding data.
+    pub server_end_point: Vec<u8>,
+
+    /// The host name the client connected to (SNI), if it sent one.
+    pub server_name: Option<String>,
+}
+
+/// Client TLS handshakes, and how many of them resumed a session
+/// instead of doing a full handshake.
+static HANDSHAKES: AtomicUsize = AtomicUsize::new(0);
+static RESUMED_HANDSHAKES: AtomicUsize = AtomicUsize::new(0);
+
+/// Count a client TLS handshake.
+pub fn handshake() {
+    HANDSHAKES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The number of full and resumed client TLS handshakes.
+pub fn handshakes() -> (usize, usize) {
+    let resumed = RESUMED_HANDSHAKES.load(Ordering::Relaxed);
+    let total = HANDSHAKES.load(Ordering::Relaxed);
+
+    (total.saturating_sub(resumed), resumed)
+}
+
+/// Count the handshake as resumed if the session was found.
+fn resumed(session: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    if session.is_some() {
+        RESUMED_HANDSHAKES.fetch_add(1, Ordering::Relaxed);
+    }
+
+    session
+}
+
+/// Session cache that counts resumed sessions.
+struct SessionCache(Arc<dyn StoresServerSessions>);
+
+impl StoresServerSessions for SessionCache {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.0.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        resumed(self.0.get(key))
+    }
+
+    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+        resumed(self.0.take(key))
+    }
+
+    fn can_cache(&self) -> bool {
+        self.0.can_cache()
+    }
+}
+
+/// Session tickets that count resumed sessions.
+struct SessionTickets(Arc<dyn ProducesTickets>);
+
+impl ProducesTickets for SessionTickets {
+    fn enabled(&self) -> bool {
+        self.0.enabled()
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.0.lifetime()
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.0.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        resumed(self.0.decrypt(cipher))
+    }
+}
+
+/// TLS acceptors of each certificate and key, rebuilt when their files change,
+/// so certificates can be rotated without a restart. Connected clients keep theirs.
+static ACCEPTORS: Lazy<ArcSwap<HashMap<TlsSettings, Arc<Tls>>>> =
+    Lazy::new(|| ArcSwap::from_pointee(HashMap::default()));
+
+/// The files and settings a TLS acceptor is made of.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+struct TlsSettings {
+    certificate: String,
+    private_key: String,
+    ca_certificate: Option<String>,
+    min_version: String,
+    ciphers: Vec<String>,
+    session_cache_size: usize,
+    session_tickets: bool,
+}
+
+impl TlsSettings {
+    fn new(certificate: &str, private_key: &str) -> TlsSettings {
+        let config = get_config();
+
+        TlsSettings {
+            certificate: certificate.to_string(),
+            private_key: private_key.to_string(),
+            ca_certificate: config.general.tls_ca_certificate,
+            min_version: config.general.tls_min_version,
+            ciphers: config.general.tls_ciphers,
+            session_cache_size: config.general.tls_session_cache_size,
+            session_tickets: config.general.tls_session_tickets,
+        }
+    }
+
+    /// When the files were last modified, to know when to reload them.
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        [Some(&self.certificate), Some(&self.private_key)]
+            .into_iter()
+            .chain([self.ca_certificate.as_ref()])
+            .flatten()
+            .map(|path| {
+                std::fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+/// Get the TLS acceptor for this certificate and key.
+pub fn acceptor(certificate: &str, private_key: &str) -> Result<Arc<Tls>, Error> {
+    let files = TlsSettings::new(certificate, private_key);
+
+    if let Some(tls) = ACCEPTORS.load().get(&files) {
+        return Ok(tls.clone());
+    }
+
+    let tls = Arc::new(Tls::new(&files)?);
+
+    ACCEPTORS.rcu(|acceptors| {
+        let mut acceptors = HashMap::clone(acceptors);
+        acceptors.insert(files.clone(), tls.clone());
+        acceptors
+    });
+
+    Ok(tls)
+}
+
+/// Rebuild the TLS acceptors whose files changed, and forget the ones
+/// no longer configured. If the new files can't be loaded, e.g. because they're
+/// being replaced, clients keep getting the old certificate until they can.
+pub fn reload() {
+    revocation::reload();
+
+    let config = get_config();
+
+    reload_server_tls_configs(
+        &config.general.server_tls_min_version,
+        &config.general.server_tls_ciphers,
+    );
+
+    let current = ACCEPTORS.load();
+
+    let configured = config
+        .general
+        .tls()
+        .into_iter()
+        .chain(
+            config
+                .general
+                .listeners
+                .iter()
+                .filter_map(|listener| listener.tls(&config.general)),
+        )
+        .map(|(certificate, private_key)| TlsSettings::new(&certificate, &private_key));
+
+    let mut acceptors = HashMap::new();
+    let mut changed = false;
+
+    for files in configured {
+        if acceptors.contains_key(&files) {
+            continue;
+        }
+
+        let old = current.get(&files);
+
+        if let Some(tls) = reload_acceptor(&files, old) {
+            changed |= !old.is_some_and(|old| Arc::ptr_eq(old, &tls));
+            acceptors.insert(files, tls);
+        }
+    }
+
+    if changed || acceptors.len() != current.len() {
+        ACCEPTORS.store(Arc::new(acceptors));
+    }
+}
+
+/// The acceptor of the files after a reload: the old one if they didn't change, a new one
+/// if they did, or the old one until the new files can be loaded, e.g. while they're
+/// being replaced. None if they can't be loaded and there's no old one.
+fn reload_acceptor(files: &TlsSettings, old: Option<&Arc<Tls>>) -> Option<Arc<Tls>> {
+    if let Some(tls) = old {
+        if tls.modified == files.modified() {
+            return Some(tls.clone());
+        }
+    }
+
+    match Tls::new(files) {
+        Ok(tls) => {
+            info!("Loaded TLS certificate {}", files.certificate);
+            Some(Arc::new(tls))
+        }
+
+        Err(_) => {
+            error!(
+                "Could not load TLS certificate {} and key {}",
+                files.certificate, files.private_key
+            );
+
+            // Keep the old one, and don't try again until the files change.
+            old.map(|tls| {
+                Arc::new(Tls {
+                    modified: files.modified(),
+                    ..Tls::clone(tls)
+                })
+            })
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Tls {
     pub acceptor: TlsAcceptor,
+    pub server_end_point: Vec<u8>,
+
+    /// The CAs client certificates are signed by.
+    pub ca_certificates: Vec<Certificate>,
+
+    /// When the files were last modified when we loaded them.
+    modified: Vec<Option<SystemTime>>,
 }
 
 impl Tls {
-    pub fn new() -> Result<Self, Error> {
-        let config = get_config();
+    fn new(files: &TlsSettings) -> Result<Self, Error> {
+        // Before reading them, so changes made while we read are picked up next time.
+        let modified = files.modified();
 
-        let certs = match load_certs(&Path::new(&config.general.tls_certificate.unwrap())) {
+        let certs = match load_certs(Path::new(&files.certificate)) {
             Ok(certs) => certs,
             Err(_) => return Err(Error::TlsError),
         };
 
-        let mut keys = match load_keys(&Path::new(&config.general.tls_private_key.unwrap())) {
-            Ok(keys) => keys,
+        let mut keys = match load_keys(Path::new(&files.private_key)) {
+            Ok(keys) if !keys.is_empty() => keys,
+            _ => return Err(Error::TlsError),
+        };
+
+        let ca_certificates = match files.ca_certificate {
+            Some(ref ca_certificate) => match load_certs(Path::new(ca_certificate)) {
+                Ok(certs) => certs,
+                Err(_) => return Err(Error::TlsError),
+            },
+            None => Vec::new(),
+        };
+
+        // Ask clients for a certificate signed by our CA, used by auth_type = "cert".
+        // Clients without one can still use other authentication methods.
+        let client_cert_verifier = match files.ca_certificate {
+            Some(_) => {
+                let mut roots = RootCertStore::empty();
+
+                for cert in &ca_certificates {
+                    if roots.add(cert).is_err() {
+                        return Err(Error::TlsError);
+                    }
+                }
+
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+            }
+
+            None => NoClientAuth::new(),
+        };
+
+        let server_end_point = match certs.first() {
+            Some(cert) => server_end_point(cert),
+            None => return Err(Error::TlsError),
+        };
+
+        let (versions, cipher_suites) = protocols(&files.min_version, &files.ciphers)?;
+
+        let builder = match rustls::ServerConfig::builder()
+            .with_cipher_suites(&cipher_suites)
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&versions)
+        {
+            Ok(builder) => builder,
             Err(_) => return Err(Error::TlsError),
         };
 
-        let config = match rustls::ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
+        let mut config = match builder
+            .with_client_cert_verifier(client_cert_verifier)
             .with_single_cert(certs, keys.remove(0))
             .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))
         {
@@ -50,8 +393,585 @@ impl Tls {
             Err(_) => return Err(Error::TlsError),
         };
 
+        // Let clients that reconnect often resume their session
+        // instead of doing a full handshake.
+        config.session_storage = match files.session_cache_size {
+            0 => Arc::new(NoServerSessionStorage {}),
+            size => Arc::new(SessionCache(ServerSessionMemoryCache::new(size))),
+        };
+
+        if files.session_tickets {
+            config.ticketer = match rustls::Ticketer::new() {
+                Ok(ticketer) => Arc::new(SessionTickets(ticketer)),
+                Err(_) => return Err(Error::TlsError),
+            };
+        }
+
         Ok(Tls {
             acceptor: TlsAcceptor::from(Arc::new(config)),
+            server_end_point,
+            ca_certificates,
+            modified,
         })
     }
 }
+
+/// Accepts any server certificate, for server_tls_sslmode "prefer" and "require":
+/// the connection is encrypted, but we don't check who's on the other end, like libpq.
+struct NoServerVerification;
+
+impl ServerCertVerifier for NoServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Checks the server certificate is signed by our CA, but not that it was
+/// issued to the server's host name, for server_tls_sslmode "verify-ca".
+struct CaVerification {
+    ca_certificates: Vec<Certificate>,
+}
+
+/// The signature algorithms rustls accepts in server certificates.
+static SIGNATURE_ALGORITHMS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+impl ServerCertVerifier for CaVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let invalid = |err: webpki::Error| {
+            rustls::Error::InvalidCertificateData(format!("invalid peer certificate: {}", err))
+        };
+
+        let anchors = self
+            .ca_certificates
+            .iter()
+            .filter_map(|cert| webpki::TrustAnchor::try_from_cert_der(&cert.0).ok())
+            .collect::<Vec<webpki::TrustAnchor>>();
+
+        let intermediates = intermediates
+            .iter()
+            .map(|cert| cert.0.as_slice())
+            .collect::<Vec<&[u8]>>();
+
+        let now = match webpki::Time::try_from(now) {
+            Ok(now) => now,
+            Err(_) => return Err(rustls::Error::FailedToGetCurrentTime),
+        };
+
+        // The chain up to our CA, the validity dates and the server key usage,
+        // like verify-full, but not the name.
+        webpki::EndEntityCert::try_from(end_entity.0.as_slice())
+            .and_then(|cert| {
+                cert.verify_is_valid_tls_server_cert(
+                    SIGNATURE_ALGORITHMS,
+                    &webpki::TlsServerTrustAnchors(&anchors),
+                    &intermediates,
+                    now,
+                )
+            })
+            .map_err(invalid)?;
+
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// TLS versions and cipher suites.
+type Protocols = (
+    Vec<&'static rustls::SupportedProtocolVersion>,
+    Vec<rustls::SupportedCipherSuite>,
+);
+
+/// The TLS versions and cipher suites allowed by a minimum version, `1.2` or `1.3`,
+/// and a list of cipher suites named like in rustls, e.g. `TLS13_AES_256_GCM_SHA384`.
+/// No cipher suites means all the ones we support.
+pub fn protocols(min_version: &str, ciphers: &[String]) -> Result<Protocols, Error> {
+    let versions = match min_version {
+        "1.2" => vec![&rustls::version::TLS12, &rustls::version::TLS13],
+        "1.3" => vec![&rustls::version::TLS13],
+        other => {
+            error!("Supported TLS versions are: '1.2', '1.3', got: '{}'", other);
+            return Err(Error::BadConfig);
+        }
+    };
+
+    let names = rustls::ALL_CIPHER_SUITES
+        .iter()
+        .map(|suite| format!("{:?}", suite.suite()))
+        .collect::<Vec<String>>();
+
+    if let Some(cipher) = ciphers.iter().find(|cipher| !names.contains(cipher)) {
+        error!(
+            "Unknown TLS cipher suite '{}', supported cipher suites are: {}",
+            cipher,
+            names.join(", ")
+        );
+        return Err(Error::BadConfig);
+    }
+
+    let cipher_suites = rustls::ALL_CIPHER_SUITES
+        .iter()
+        .zip(names)
+        .filter(|(suite, name)| {
+            (ciphers.is_empty() || ciphers.contains(name)) && versions.contains(&suite.version())
+        })
+        .map(|(suite, _)| *suite)
+        .collect::<Vec<rustls::SupportedCipherSuite>>();
+
+    if cipher_suites.is_empty() {
+        error!(
+            "None of the TLS cipher suites {} can be used with TLS {}",
+            ciphers.join(", "),
+            min_version
+        );
+        return Err(Error::BadConfig);
+    }
+
+    Ok((versions, cipher_suites))
+}
+
+/// The TLS configs we connect to the servers with, by their settings, built once.
+/// Built again when their files or the general server TLS settings change, like the acceptors.
+static SERVER_CONFIGS: Lazy<ArcSwap<HashMap<ServerTlsSettings, ServerTlsConfig>>> =
+    Lazy::new(|| ArcSwap::from_pointee(HashMap::default()));
+
+#[derive(Clone)]
+struct ServerTlsConfig {
+    config: Arc<rustls::ClientConfig>,
+
+    /// The CA certificates, to check the server's chain for revocation.
+    ca_certificates: Arc<Vec<Certificate>>,
+
+    /// What it was built with.
+    min_version: String,
+    ciphers: Vec<String>,
+    modified: Vec<Option<SystemTime>>,
+}
+
+/// When the CA, certificate and key files were last modified.
+fn server_tls_modified(settings: &ServerTlsSettings) -> Vec<Option<SystemTime>> {
+    let (certificate, private_key) = settings.client_certificate.clone().unzip();
+
+    [settings.ca_file.clone(), certificate, private_key]
+        .into_iter()
+        .flatten()
+        .map(|path| {
+            std::fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .collect()
+}
+
+/// TLS settings for connecting to a server with its sslmode and CA,
+/// and the client certificate and key we log in with, if any. With the CA certificates.
+pub fn server_tls_config(
+    settings: &ServerTlsSettings,
+) -> Result<(Arc<rustls::ClientConfig>, Arc<Vec<Certificate>>), Error> {
+    if let Some(config) = SERVER_CONFIGS.load().get(settings) {
+        return Ok((config.config.clone(), config.ca_certificates.clone()));
+    }
+
+    let general = get_config().general;
+
+    // Before reading them, so changes made while we read are picked up next time.
+    let modified = server_tls_modified(settings);
+
+    let (config, ca_certificates) = build_server_tls_config(
+        settings,
+        &general.server_tls_min_version,
+        &general.server_tls_ciphers,
+    )?;
+
+    let config = ServerTlsConfig {
+        config,
+        ca_certificates,
+        min_version: general.server_tls_min_version,
+        ciphers: general.server_tls_ciphers,
+        modified,
+    };
+
+    SERVER_CONFIGS.rcu(|configs| {
+        let mut configs = HashMap::clone(configs);
+        configs.insert(settings.clone(), config.clone());
+        configs
+    });
+
+    Ok((config.config, config.ca_certificates))
+}
+
+/// Forget the server TLS configs whose files or settings changed,
+/// so the next connections use the new ones.
+fn reload_server_tls_configs(min_version: &str, ciphers: &[String]) {
+    let configs = SERVER_CONFIGS.load();
+
+    let unchanged = |settings: &ServerTlsSettings, config: &ServerTlsConfig| {
+        config.min_version == min_version
+            && config.ciphers == ciphers
+            && config.modified == server_tls_modified(settings)
+    };
+
+    if configs
+        .iter()
+        .any(|(settings, config)| !unchanged(settings, config))
+    {
+        SERVER_CONFIGS.store(Arc::new(
+            configs
+                .iter()
+                .filter(|(settings, config)| unchanged(settings, config))
+                .map(|(settings, config)| (settings.clone(), config.clone()))
+                .collect(),
+        ));
+    }
+}
+
+fn build_server_tls_config(
+    settings: &ServerTlsSettings,
+    min_version: &str,
+    ciphers: &[String],
+) -> Result<(Arc<rustls::ClientConfig>, Arc<Vec<Certificate>>), Error> {
+    let (versions, cipher_suites) = protocols(min_version, ciphers)?;
+
+    let ca_certificates = match settings.ca_file {
+        Some(ref ca_file) => match load_certs(Path::new(ca_file)) {
+            Ok(certs) => certs,
+            Err(_) => return Err(Error::TlsError),
+        },
+        None => Vec::new(),
+    };
+
+    let mut roots = RootCertStore::empty();
+
+    for cert in &ca_certificates {
+        if roots.add(cert).is_err() {
+            return Err(Error::TlsError);
+        }
+    }
+    let builder = match rustls::ClientConfig::builder()
+        .with_cipher_suites(&cipher_suites)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&versions)
+    {
+        Ok(builder) => builder.with_root_certificates(roots),
+        Err(err) => {
+            error!("Could not configure server TLS: {}", err);
+            return Err(Error::TlsError);
+        }
+    };
+
+    let mut config = match &settings.client_certificate {
+        Some((certificate, private_key)) => {
+            let certs = match load_certs(Path::new(certificate)) {
+                Ok(certs) => certs,
+                Err(_) => return Err(Error::TlsError),
+            };
+
+            let mut keys = match load_keys(Path::new(private_key)) {
+                Ok(keys) if !keys.is_empty() => keys,
+                _ => return Err(Error::TlsError),
+            };
+
+            match builder.with_single_cert(certs, keys.remove(0)) {
+                Ok(config) => config,
+                Err(err) => {
+                    error!(
+                        "Could not use server TLS certificate {}: {}",
+                        certificate, err
+                    );
+                    return Err(Error::TlsError);
+                }
+            }
+        }
+
+        None => builder.with_no_client_auth(),
+    };
+
+    match settings.sslmode.as_str() {
+        // Checks the chain and the host name.
+        "verify-full" => (),
+
+        "verify-ca" => config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(CaVerification {
+                ca_certificates: ca_certificates.clone(),
+            })),
+
+        _ => config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoServerVerification)),
+    };
+
+    Ok((Arc::new(config), Arc::new(ca_certificates)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Connect to a server with the certificate, with the sslmode and the test CA,
+    /// as the name. True if the handshake succeeds.
+    async fn handshake(certificate: &str, sslmode: &str, server_name: &str) -> bool {
+        let tls = Tls::new(&TlsSettings {
+            certificate: format!("{}.cert", certificate),
+            private_key: format!("{}.key", certificate),
+            ca_certificate: None,
+            min_version: String::from("1.2"),
+            ciphers: Vec::new(),
+            session_cache_size: 0,
+            session_tickets: false,
+        })
+        .unwrap();
+
+        let (config, _) = build_server_tls_config(
+            &ServerTlsSettings {
+                sslmode: sslmode.to_string(),
+                ca_file: Some(String::from("tests/tls/ca.cert")),
+                client_certificate: None,
+            },
+            "1.2",
+            &[],
+        )
+        .unwrap();
+
+        let (client, server) = tokio::io::duplex(16384);
+        let server = tokio::spawn(async move { tls.acceptor.accept(server).await.is_ok() });
+
+        let connected = tokio_rustls::TlsConnector::from(config)
+            .connect(ServerName::try_from(server_name).unwrap(), client)
+            .await
+            .is_ok();
+
+        let _ = server.await;
+
+        connected
+    }
+
+    #[test]
+    fn test_reload_acceptor() {
+        let dir = std::env::temp_dir().join(format!("pgcat_test_reload_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let files = TlsSettings {
+            certificate: dir.join("server.cert").to_str().unwrap().to_string(),
+            private_key: dir.join("server.key").to_str().unwrap().to_string(),
+            ca_certificate: None,
+            min_version: String::from("1.2"),
+            ciphers: Vec::new(),
+            session_cache_size: 0,
+            session_tickets: false,
+        };
+
+        // Replace the files, as if they were modified a second later.
+        let replace = |name: &str, modified: u64| {
+            for (from, to) in [
+                (format!("{}.cert", name), &files.certificate),
+                (format!("{}.key", name), &files.private_key),
+            ] {
+                std::fs::copy(from, to).unwrap();
+                std::fs::File::options()
+                    .write(true)
+                    .open(to)
+                    .unwrap()
+                    .set_modified(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(modified))
+                    .unwrap();
+            }
+        };
+
+        replace("tests/tls/server", 1);
+        let tls = reload_acceptor(&files, None).unwrap();
+
+        // Unchanged.
+        assert!(Arc::ptr_eq(
+            &tls,
+            &reload_acceptor(&files, Some(&tls)).unwrap()
+        ));
+
+        // Rotated.
+        replace("tests/tls/client", 2);
+        let rotated = reload_acceptor(&files, Some(&tls)).unwrap();
+        assert_ne!(rotated.server_end_point, tls.server_end_point);
+
+        // Half written: the old one is kept, and not loaded again until the files change.
+        std::fs::write(&files.certificate, "-----BEGIN CERTIFICATE-----\n").unwrap();
+        let kept = reload_acceptor(&files, Some(&rotated)).unwrap();
+        assert_eq!(kept.server_end_point, rotated.server_end_point);
+        assert!(Arc::ptr_eq(
+            &kept,
+            &reload_acceptor(&files, Some(&kept)).unwrap()
+        ));
+
+        std::fs::remove_file(&files.private_key).unwrap();
+        assert!(reload_acceptor(&files, None).is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Connect twice with the same client config, so the second time can resume the session.
+    /// Returns how many of them resumed.
+    async fn resumed_handshakes(session_cache_size: usize, session_tickets: bool) -> usize {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let tls = Tls::new(&TlsSettings {
+            certificate: String::from("tests/tls/server.cert"),
+            private_key: String::from("tests/tls/server.key"),
+            ca_certificate: None,
+            min_version: String::from("1.2"),
+            ciphers: Vec::new(),
+            session_cache_size,
+            session_tickets,
+        })
+        .unwrap();
+
+        let (config, _) = build_server_tls_config(
+            &ServerTlsSettings {
+                sslmode: String::from("verify-full"),
+                ca_file: Some(String::from("tests/tls/ca.cert")),
+                client_certificate: None,
+            },
+            "1.2",
+            &[],
+        )
+        .unwrap();
+
+        let before = RESUMED_HANDSHAKES.load(Ordering::Relaxed);
+
+        for _ in 0..2 {
+            let (client, server) = tokio::io::duplex(16384);
+            let acceptor = tls.acceptor.clone();
+
+            let server = tokio::spawn(async move {
+                let mut stream = acceptor.accept(server).await.unwrap();
+                stream.write_all(b"S").await.unwrap();
+                stream.flush().await.unwrap();
+            });
+
+            // Reading makes the client get the session tickets sent after the handshake.
+            let mut stream = tokio_rustls::TlsConnector::from(config.clone())
+                .connect(ServerName::try_from("localhost").unwrap(), client)
+                .await
+                .unwrap();
+            stream.read_u8().await.unwrap();
+
+            server.await.unwrap();
+        }
+
+        RESUMED_HANDSHAKES.load(Ordering::Relaxed) - before
+    }
+
+    #[tokio::test]
+    async fn test_session_resumption() {
+        assert_eq!(resumed_handshakes(256, false).await, 1);
+        assert_eq!(resumed_handshakes(0, true).await, 1);
+        assert_eq!(resumed_handshakes(0, false).await, 0);
+    }
+
+    #[test]
+    fn test_server_tls_config() {
+        let settings = ServerTlsSettings {
+            sslmode: String::from("verify-ca"),
+            ca_file: Some(String::from("tests/tls/ca.cert")),
+            client_certificate: None,
+        };
+        let general = get_config().general;
+
+        // Built once.
+        let (config, ca_certificates) = server_tls_config(&settings).unwrap();
+        assert_eq!(ca_certificates.len(), 1);
+        assert!(Arc::ptr_eq(
+            &config,
+            &server_tls_config(&settings).unwrap().0
+        ));
+
+        reload_server_tls_configs(&general.server_tls_min_version, &general.server_tls_ciphers);
+        assert!(Arc::ptr_eq(
+            &config,
+            &server_tls_config(&settings).unwrap().0
+        ));
+
+        // Built again when the settings change.
+        reload_server_tls_configs("1.3", &general.server_tls_ciphers);
+        assert!(!SERVER_CONFIGS.load().contains_key(&settings));
+    }
+
+    #[tokio::test]
+    async fn test_server_verification() {
+        // Signed by the CA, for localhost.
+        let ours = "tests/tls/server";
+
+        assert!(handshake(ours, "verify-full", "localhost").await);
+        assert!(!handshake(ours, "verify-full", "db.example.com").await);
+
+        assert!(handshake(ours, "verify-ca", "localhost").await);
+        assert!(handshake(ours, "verify-ca", "db.example.com").await);
+
+        // Self-signed.
+        let other = ".circleci/server";
+
+        assert!(!handshake(other, "verify-full", "localhost").await);
+        assert!(!handshake(other, "verify-ca", "localhost").await);
+        assert!(handshake(other, "require", "localhost").await);
+    }
+
+    #[test]
+    fn test_certificate_names() {
+        let certs = load_certs(Path::new("tests/tls/client.cert")).unwrap();
+
+        assert_eq!(
+            certificate_names(&certs[0]),
+            vec!["alice", "alice.example.com", "alice@example.com"]
+        );
+        assert!(certificate_names(&Certificate(b"not a certificate".to_vec())).is_empty());
+    }
+
+    #[test]
+    fn test_protocols() {
+        let (versions, cipher_suites) = protocols("1.2", &[]).unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(cipher_suites.len(), rustls::ALL_CIPHER_SUITES.len());
+
+        let (versions, cipher_suites) = protocols("1.3", &[]).unwrap();
+        assert_eq!(versions.len(), 1);
+        assert!(cipher_suites
+            .iter()
+            .all(|suite| suite.version() == &rustls::version::TLS13));
+
+        let ciphers = vec![String::from("TLS13_AES_256_GCM_SHA384")];
+        let (_, cipher_suites) = protocols("1.2", &ciphers).unwrap();
+        assert_eq!(cipher_suites.len(), 1);
+
+        // TLS 1.2 cipher suites can't be used with TLS 1.3 only.
+        let ciphers = vec![String::from("TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384")];
+        assert!(protocols("1.2", &ciphers).is_ok());
+        assert!(protocols("1.3", &ciphers).is_err());
+
+        assert!(protocols("1.1", &[]).is_err());
+        assert!(protocols("1.2", &[String::from("RC4")]).is_err());
+    }
+}
@@ -0,0 +1,409 @@
+/// Lease server credentials from HashiCorp Vault's database secrets engine,
+/// renew the leases in the background and rotate the credentials before they expire.
+/// See <https://developer.hashicorp.com/vault/docs/secrets/databases>.
+use chrono::naive::NaiveDateTime;
+use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config::{get_config, Vault};
+use crate::errors::Error;
+
+/// Current lease of each Vault role. Locked while talking to Vault,
+/// so connections created at the same time share one lease.
+static LEASES: Lazy<tokio::sync::Mutex<HashMap<String, Lease>>> =
+    Lazy::new(|| tokio::sync::Mutex::new(HashMap::default()));
+
+/// When the credentials of each Vault role were last rotated. Server connections
+/// created before that use the old credentials and are recycled.
+static ROTATED_AT: Lazy<parking_lot::Mutex<HashMap<String, NaiveDateTime>>> =
+    Lazy::new(|| parking_lot::Mutex::new(HashMap::default()));
+
+/// A Vault token and when it expires.
+type Token = (String, Option<Instant>);
+
+/// Vault tokens we got by logging in with AppRole.
+static TOKENS: Lazy<parking_lot::Mutex<HashMap<String, Token>>> =
+    Lazy::new(|| parking_lot::Mutex::new(HashMap::default()));
+
+#[derive(Clone, Debug)]
+struct Lease {
+    config: Vault,
+    lease_id: String,
+    username: String,
+    password: String,
+
+    /// How long the lease was granted for, and when.
+    duration: Duration,
+    renewable: bool,
+    renewed_at: Instant,
+}
+
+impl Lease {
+    /// Renew the lease when half of it has passed.
+    fn needs_renewal(&self) -> bool {
+        self.renewed_at.elapsed() >= self.duration / 2
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginResponse {
+    auth: LoginAuth,
+}
+
+#[derive(Deserialize)]
+struct LoginAuth {
+    client_token: String,
+    lease_duration: u64,
+}
+
+#[derive(Deserialize)]
+struct LeaseResponse {
+    lease_id: String,
+    lease_duration: u64,
+    renewable: bool,
+    data: Option<Credentials>,
+}
+
+#[derive(Deserialize)]
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+/// Leases are per Vault server, secrets engine and role.
+fn key(config: &Vault) -> String {
+    format!(
+        "{}/v1/{}/creds/{}",
+        config.address, config.mount, config.role
+    )
+}
+
+fn client() -> Result<reqwest::Client, Error> {
+    match reqwest::Client::builder()
+        .timeout(Duration::from_millis(get_config().general.connect_timeout))
+        .build()
+    {
+        Ok(client) => Ok(client),
+        Err(err) => {
+            error!("Could not create HTTP client: {}", err);
+            Err(Error::BadConfig)
+        }
+    }
+}
+
+/// Send the request and parse the JSON response.
+async fn send<T: serde::de::DeserializeOwned>(
+    request: reqwest::RequestBuilder,
+    what: &str,
+) -> Result<T, Error> {
+    let response = match request.send().await {
+        Ok(response) => response.error_for_status(),
+        Err(err) => Err(err),
+    };
+
+    let response = match response {
+        Ok(response) => response.json::<T>().await,
+        Err(err) => Err(err),
+    };
+
+    match response {
+        Ok(response) => Ok(response),
+        Err(err) => {
+            error!("Vault {} failed: {}", what, err);
+            Err(Error::AllServersDown)
+        }
+    }
+}
+
+/// Get a Vault token, either the configured one or by logging in with AppRole.
+async fn token(config: &Vault) -> Result<String, Error> {
+    match config.auth_method.as_ref() {
+        "approle" => {
+            let key = format!(
+                "{}/{}",
+                config.address,
+                config.role_id.clone().unwrap_or_default()
+            );
+
+            if let Some((token, expires_at)) = TOKENS.lock().get(&key) {
+                if expires_at.is_none_or(|expires_at| Instant::now() < expires_at) {
+                    return Ok(token.clone());
+                }
+            }
+
+            let response: LoginResponse = send(
+                client()?
+                    .post(format!("{}/v1/auth/approle/login", config.address))
+                    .json(&HashMap::from([
+                        ("role_id", config.role_id.clone().unwrap_or_default()),
+                        ("secret_id", config.secret_id.clone().unwrap_or_default()),
+                    ])),
+                "login",
+            )
+            .await?;
+
+            // Log in again a bit before the token expires. Tokens with no TTL don't expire.
+            let expires_at = match response.auth.lease_duration {
+                0 => None,
+                ttl => Some(Instant::now() + Duration::from_secs(ttl) * 9 / 10),
+            };
+
+            TOKENS
+                .lock()
+                .insert(key, (response.auth.client_token.clone(), expires_at));
+
+            Ok(response.auth.client_token)
+        }
+
+        _ => match config.token {
+            Some(ref token) => Ok(token.clone()),
+            None => match std::env::var("VAULT_TOKEN") {
+                Ok(token) => Ok(token),
+                Err(_) => {
+                    error!("Vault token is not set in the config or in VAULT_TOKEN");
+                    Err(Error::BadConfig)
+                }
+            },
+        },
+    }
+}
+
+/// Get new credentials from Vault.
+async fn create_lease(config: &Vault) -> Result<Lease, Error> {
+    let response: LeaseResponse = send(
+        client()?
+            .get(key(config))
+            .header("X-Vault-Token", token(config).await?),
+        "credentials request",
+    )
+    .await?;
+
+    let credentials = match response.data {
+        Some(credentials) => credentials,
+        None => {
+            error!("Vault returned no credentials for role {}", config.role);
+            return Err(Error::AllServersDown);
+        }
+    };
+
+    info!(
+        "Leased credentials for {} from Vault role {}, valid for {}s",
+        credentials.username, config.role, response.lease_duration
+    );
+
+    Ok(Lease {
+        config: config.clone(),
+        lease_id: response.lease_id,
+        username: credentials.username,
+        password: credentials.password,
+        duration: Duration::from_secs(response.lease_duration),
+        renewable: response.renewable,
+        renewed_at: Instant::now(),
+    })
+}
+
+/// Extend the lease. Returns how long it was extended by.
+async fn renew_lease(lease: &Lease) -> Result<Duration, Error> {
+    let response: LeaseResponse = send(
+        client()?
+            .put(format!("{}/v1/sys/leases/renew", lease.config.address))
+            .header("X-Vault-Token", token(&lease.config).await?)
+            .json(&serde_json::json!({
+                "lease_id": lease.lease_id,
+                "increment": lease.duration.as_secs(),
+            })),
+        "lease renewal",
+    )
+    .await?;
+
+    Ok(Duration::from_secs(response.lease_duration))
+}
+
+/// Get the user name and password to log into the server with.
+pub async fn credentials(config: &Vault) -> Result<(String, String), Error> {
+    let key = key(config);
+    let mut leases = LEASES.lock().await;
+
+    if let Some(lease) = leases.get(&key) {
+        if lease.renewed_at.elapsed() < lease.duration {
+            return Ok((lease.username.clone(), lease.password.clone()));
+        }
+    }
+
+    let lease = create_lease(config).await?;
+    let credentials = (lease.username.clone(), lease.password.clone());
+
+    // The old lease expired before we could renew or rotate it.
+    if leases.insert(key.clone(), lease).is_some() {
+        ROTATED_AT
+            .lock()
+            .insert(key, chrono::offset::Utc::now().naive_utc());
+    }
+
+    Ok(credentials)
+}
+
+/// Renew the leases that are half way through. Leases that can't be renewed,
+/// or that are reaching their maximum TTL, are replaced with new credentials
+/// and the server connections using the old ones are recycled.
+pub async fn renew() {
+    let config = get_config();
+
+    // Copied out, so new connections don't wait for Vault to renew the others.
+    let due = {
+        let mut leases = LEASES.lock().await;
+
+        // Forget the leases of pools that were removed from the config.
+        leases.retain(|key, _| {
+            config
+                .pools
+                .values()
+                .filter_map(|pool| pool.vault.as_ref())
+                .any(|vault| self::key(vault) == *key)
+        });
+
+        leases
+            .iter()
+            .filter(|(_, lease)| lease.needs_renewal())
+            .map(|(key, lease)| (key.clone(), lease.clone()))
+            .collect::<Vec<(String, Lease)>>()
+    };
+
+    for (key, lease) in due {
+        let lease_id = lease.lease_id.clone();
+
+        // Leases that can't be renewed are rotated half way through instead.
+        let renewal = match lease.renewable {
+            true => renew_lease(&lease).await,
+            false => Ok(Duration::ZERO),
+        };
+
+        match renewal {
+            // Vault gives us less than we asked for when the lease
+            // is reaching its maximum TTL, so it's time to rotate.
+            Ok(duration) if duration >= lease.duration / 2 => {
+                debug!("Renewed Vault lease {} for {:?}", lease_id, duration);
+                replace(
+                    &key,
+                    &lease_id,
+                    Lease {
+                        duration,
+                        renewed_at: Instant::now(),
+                        ..lease
+                    },
+                )
+                .await;
+                continue;
+            }
+
+            Ok(_) => info!("Vault lease {} is expiring, rotating", lease_id),
+            Err(_) => warn!("Could not renew Vault lease {}, rotating", lease_id),
+        };
+
+        match create_lease(&lease.config).await {
+            Ok(new_lease) => {
+                if replace(&key, &lease_id, new_lease).await {
+                    ROTATED_AT
+                        .lock()
+                        .insert(key, chrono::offset::Utc::now().naive_utc());
+                }
+            }
+
+            // Keep using the old credentials, and try again next time.
+            Err(err) => error!("Could not rotate Vault credentials: {:?}", err),
+        };
+    }
+}
+
+/// Replace the lease with its renewal or with new credentials, unless it was replaced
+/// while we talked to Vault, e.g. because it expired. Returns true if it was replaced.
+async fn replace(key: &str, lease_id: &str, lease: Lease) -> bool {
+    match LEASES.lock().await.get_mut(key) {
+        Some(current) if current.lease_id == lease_id => {
+            *current = lease;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Were the credentials rotated since the server connection was created.
+pub fn rotated_since(config: &Vault, connected_at: NaiveDateTime) -> bool {
+    ROTATED_AT
+        .lock()
+        .get(&key(config))
+        .is_some_and(|rotated_at| *rotated_at > connected_at)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lease(lease_id: &str, duration: u64, renewed_ago: u64) -> Lease {
+        Lease {
+            config: Vault {
+                address: String::from("http://vault.test:8200"),
+                role: String::from("pgcat"),
+                mount: String::from("database"),
+                auth_method: String::from("token"),
+                token: None,
+                role_id: None,
+                secret_id: None,
+            },
+            lease_id: lease_id.to_string(),
+            username: String::from("v-pgcat-user"),
+            password: String::from("secret"),
+            duration: Duration::from_secs(duration),
+            renewable: true,
+            renewed_at: Instant::now() - Duration::from_secs(renewed_ago),
+        }
+    }
+
+    #[test]
+    fn test_needs_renewal() {
+        assert_eq!(
+            key(&lease("a", 60, 0).config),
+            "http://vault.test:8200/v1/database/creds/pgcat"
+        );
+
+        assert!(!lease("a", 60, 0).needs_renewal());
+        assert!(!lease("a", 60, 29).needs_renewal());
+        assert!(lease("a", 60, 30).needs_renewal());
+    }
+
+    #[tokio::test]
+    async fn test_replace() {
+        let key = String::from("test_replace");
+        LEASES
+            .lock()
+            .await
+            .insert(key.clone(), lease("old", 60, 30));
+
+        // The lease was replaced while it was renewed.
+        assert!(!replace(&key, "older", lease("new", 60, 0)).await);
+        assert_eq!(LEASES.lock().await[&key].lease_id, "old");
+
+        assert!(replace(&key, "old", lease("new", 60, 0)).await);
+        assert_eq!(LEASES.lock().await[&key].lease_id, "new");
+
+        assert!(!replace("test_replace_missing", "old", lease("new", 60, 0)).await);
+    }
+
+    #[test]
+    fn test_rotated_since() {
+        let config = Vault {
+            role: String::from("test_rotated_since"),
+            ..lease("a", 60, 0).config
+        };
+        let now = chrono::offset::Utc::now().naive_utc();
+
+        assert!(!rotated_since(&config, now));
+
+        ROTATED_AT.lock().insert(key(&config), now);
+        assert!(rotated_since(&config, now - chrono::Duration::seconds(1)));
+        assert!(!rotated_since(&config, now));
+    }
+}
@@ -24,3 +24,37 @@ SELECT * FROM shard_1 ORDER BY id LIMIT 10;
 SELECT * FROM shard_2 ORDER BY id LIMIT 10;
 SELECT * FROM shard_3 ORDER BY id LIMIT 10;
 SELECT * FROM shard_4 ORDER BY id LIMIT 10;
+
+-- Text and UUID sharding keys, see test_pg_text_and_uuid_hash in src/sharding.rs.
+DROP TABLE IF EXISTS tenants CASCADE;
+
+CREATE TABLE tenants (
+    name TEXT
+) PARTITION BY HASH (name);
+
+CREATE TABLE tenants_0 PARTITION OF tenants FOR VALUES WITH (MODULUS 3, REMAINDER 0);
+CREATE TABLE tenants_1 PARTITION OF tenants FOR VALUES WITH (MODULUS 3, REMAINDER 1);
+CREATE TABLE tenants_2 PARTITION OF tenants FOR VALUES WITH (MODULUS 3, REMAINDER 2);
+
+INSERT INTO tenants VALUES ('acme'), ('globex'), ('initech'), ('umbrella'), ('hooli'), ('stark industries'), ('wayne enterprises');
+
+SELECT tableoid::regclass, name FROM tenants ORDER BY 1, 2;
+
+DROP TABLE IF EXISTS tenant_ids CASCADE;
+
+CREATE TABLE tenant_ids (
+    id UUID
+) PARTITION BY HASH (id);
+
+CREATE TABLE tenant_ids_0 PARTITION OF tenant_ids FOR VALUES WITH (MODULUS 3, REMAINDER 0);
+CREATE TABLE tenant_ids_1 PARTITION OF tenant_ids FOR VALUES WITH (MODULUS 3, REMAINDER 1);
+CREATE TABLE tenant_ids_2 PARTITION OF tenant_ids FOR VALUES WITH (MODULUS 3, REMAINDER 2);
+
+INSERT INTO tenant_ids VALUES
+    ('a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11'),
+    ('123e4567-e89b-12d3-a456-426614174000'),
+    ('f47ac10b-58cc-4372-a567-0e02b2c3d479'),
+    ('00000000-0000-0000-0000-000000000000'),
+    ('6ba7b810-9dad-11d1-80b4-00c04fd430c8');
+
+SELECT tableoid::regclass, id FROM tenant_ids ORDER BY 1, 2;
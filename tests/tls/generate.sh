@@ -0,0 +1,72 @@
+#!/bin/bash
+#
+# Certificates used by the unit tests. They're checked in; run this to make new ones.
+#
+set -e
+cd "$(dirname "$0")"
+
+# The CA that signs the others.
+openssl genrsa -traditional -out ca.key 2048
+openssl req -x509 -new -key ca.key -days 36500 -subj "/CN=pgcat test CA" -out ca.cert \
+    -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign"
+
+# A client certificate, for auth_type = "cert".
+openssl genrsa -traditional -out client.key 2048
+openssl req -new -key client.key -subj "/CN=alice" |
+    openssl x509 -req -CA ca.cert -CAkey ca.key -CAcreateserial -days 36500 -out client.cert \
+        -extfile <(printf "subjectAltName=DNS:alice.example.com,email:alice@example.com\nbasicConstraints=CA:FALSE\nextendedKeyUsage=clientAuth\n")
+
+# A server certificate for localhost, with an OCSP responder.
+openssl genrsa -traditional -out server.key 2048
+openssl req -new -key server.key -subj "/CN=localhost" |
+    openssl x509 -req -CA ca.cert -CAkey ca.key -CAcreateserial -days 36500 -out server.cert \
+        -extfile <(printf "subjectAltName=DNS:localhost\nbasicConstraints=CA:FALSE\nextendedKeyUsage=serverAuth\nauthorityInfoAccess=OCSP;URI:http://127.0.0.1:9999\n")
+
+rm -f ca.srl
+
+# A CRL revoking the client certificate, and one that expired.
+tmp=$(mktemp -d)
+printf "[ca]\ndefault_ca = test\n[test]\ndatabase = $tmp/index.txt\ncrlnumber = $tmp/crlnumber\ndefault_md = sha256\ndefault_crl_days = 36500\n" > $tmp/ca.cnf
+touch $tmp/index.txt
+echo 01 > $tmp/crlnumber
+openssl ca -config $tmp/ca.cnf -keyfile ca.key -cert ca.cert -valid server.cert
+openssl ca -config $tmp/ca.cnf -keyfile ca.key -cert ca.cert -revoke client.cert
+openssl ca -config $tmp/ca.cnf -keyfile ca.key -cert ca.cert -gencrl -out crl.pem
+openssl ca -config $tmp/ca.cnf -keyfile ca.key -cert ca.cert -gencrl -out crl_expired.pem \
+    -crl_lastupdate 20000101000000Z -crl_nextupdate 20000102000000Z
+
+# OCSP answers for the server and client certificates, to requests with the nonce
+# "pgcat test nonce". The other one is signed by the server's key, which can't answer.
+ocsp() {
+    openssl ocsp -issuer ca.cert -cert $1.cert -no_nonce -reqout $tmp/request.der
+    python3 - $tmp/request.der <<EOF
+import sys
+
+def der(tag, content):
+    n = len(content)
+    length = bytes([n]) if n < 0x80 else bytes([0x80 | ((n.bit_length() + 7) // 8)]) + n.to_bytes((n.bit_length() + 7) // 8, "big")
+    return bytes([tag]) + length + content
+
+def content(data):
+    n = data[1]
+    if n < 0x80:
+        return data[2:2 + n]
+    size = n & 0x7f
+    n = int.from_bytes(data[2:2 + size], "big")
+    return data[2 + size:2 + size + n]
+
+request = open(sys.argv[1], "rb").read()
+tbs_request = content(content(request))
+nonce = der(0x30, der(0x06, bytes([0x2b, 6, 1, 5, 5, 7, 48, 1, 2])) + der(0x04, der(0x04, b"pgcat test nonce")))
+tbs_request += der(0xa2, der(0x30, nonce))
+open(sys.argv[1], "wb").write(der(0x30, der(0x30, tbs_request)))
+EOF
+    openssl ocsp -index $tmp/index.txt -CA ca.cert -rsigner $2.cert -rkey $2.key \
+        -reqin $tmp/request.der -respout $3 -ndays 36500
+}
+
+ocsp server ca server.ocsp
+ocsp client ca client.ocsp
+ocsp server server server_other.ocsp
+
+rm -rf $tmp